@@ -1,37 +1,69 @@
 use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
 use rand::{seq::IteratorRandom, thread_rng};
-use rust_kv::{KvEngine, KvStore, SledStore};
+use rust_kv::{FlushMode, KvEngine, KvStore, KvStoreOptions, LogFormat, MemStore, SledStore};
+use std::time::Duration;
 use tempfile::TempDir;
 
+const KVS_FORMATS: [(&str, LogFormat); 2] =
+    [("kvs", LogFormat::Bincode), ("kvs_json", LogFormat::Json)];
+
+const SLED_FLUSH_MODES: [(&str, FlushMode); 2] = [
+    ("sled", FlushMode::EveryOp),
+    (
+        "sled_interval",
+        FlushMode::Interval(Duration::from_millis(100)),
+    ),
+];
+
 fn set_bench(c: &mut Criterion) {
     let mut group = c.benchmark_group("set_bench");
     let rng = &mut thread_rng();
     let set_range = (0..100000).choose_multiple(rng, 1000);
 
-    group.bench_function("kvs", |b| {
-        b.iter_batched(
-            || {
-                let temp_dir = TempDir::new().expect("failed to new temp dir");
-                KvStore::open(temp_dir.path()).expect("failed to open KvStore")
-            },
-            |mut kv_store| {
-                for &i in &set_range {
-                    kv_store
-                        .set(format!("key{}", i), format!("value{}", i))
-                        .expect("failed to set");
-                }
-            },
-            BatchSize::SmallInput,
-        )
-    });
+    for (name, format) in KVS_FORMATS {
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().expect("failed to new temp dir");
+                    KvStore::open_with(temp_dir.path(), KvStoreOptions::new().format(format))
+                        .expect("failed to open KvStore")
+                },
+                |kv_store| {
+                    for &i in &set_range {
+                        kv_store
+                            .set(format!("key{}", i), format!("value{}", i))
+                            .expect("failed to set");
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
 
-    group.bench_function("sled", |b| {
+    for (name, flush_mode) in SLED_FLUSH_MODES {
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().expect("failed to new temp dir");
+                    SledStore::open_with(temp_dir.path(), flush_mode)
+                        .expect("failed to open SledStore")
+                },
+                |kv_store| {
+                    for &i in &set_range {
+                        kv_store
+                            .set(format!("key{}", i), format!("value{}", i))
+                            .expect("failed to set");
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+
+    group.bench_function("mem", |b| {
         b.iter_batched(
-            || {
-                let temp_dir = TempDir::new().expect("failed to new temp dir");
-                SledStore::open(temp_dir.path()).expect("failed to open SledStore")
-            },
-            |mut kv_store| {
+            MemStore::new,
+            |kv_store| {
                 for &i in &set_range {
                     kv_store
                         .set(format!("key{}", i), format!("value{}", i))
@@ -51,11 +83,39 @@ fn get_bench(c: &mut Criterion) {
     let set_range = (0..100000).choose_multiple(rng, 1000);
     let get_range = set_range.iter().choose_multiple(rng, 300);
 
-    group.bench_function("kvs", |b| {
+    for (name, format) in KVS_FORMATS {
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().expect("failed to new temp dir");
+                    let kv_store =
+                        KvStore::open_with(temp_dir.path(), KvStoreOptions::new().format(format))
+                            .expect("failed to open KvStore");
+                    for &i in &set_range {
+                        kv_store
+                            .set(format!("key{}", i), format!("value{}", i))
+                            .expect("failed to set");
+                    }
+                    kv_store
+                },
+                |kv_store| {
+                    for &&i in &get_range {
+                        kv_store
+                            .get(format!("key{}", i))
+                            .expect("failed to get key")
+                            .expect("the value cannot be None");
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+
+    group.bench_function("sled", |b| {
         b.iter_batched(
             || {
                 let temp_dir = TempDir::new().expect("failed to new temp dir");
-                let mut kv_store = KvStore::open(temp_dir.path()).expect("failed to open KvStore");
+                let kv_store = SledStore::open(temp_dir.path()).expect("failed to open SledStore");
                 for &i in &set_range {
                     kv_store
                         .set(format!("key{}", i), format!("value{}", i))
@@ -63,7 +123,7 @@ fn get_bench(c: &mut Criterion) {
                 }
                 kv_store
             },
-            |mut kv_store| {
+            |kv_store| {
                 for &&i in &get_range {
                     kv_store
                         .get(format!("key{}", i))
@@ -75,12 +135,10 @@ fn get_bench(c: &mut Criterion) {
         )
     });
 
-    group.bench_function("sled", |b| {
+    group.bench_function("mem", |b| {
         b.iter_batched(
             || {
-                let temp_dir = TempDir::new().expect("failed to new temp dir");
-                let mut kv_store =
-                    SledStore::open(temp_dir.path()).expect("failed to open SledStore");
+                let kv_store = MemStore::new();
                 for &i in &set_range {
                     kv_store
                         .set(format!("key{}", i), format!("value{}", i))
@@ -88,7 +146,7 @@ fn get_bench(c: &mut Criterion) {
                 }
                 kv_store
             },
-            |mut kv_store| {
+            |kv_store| {
                 for &&i in &get_range {
                     kv_store
                         .get(format!("key{}", i))
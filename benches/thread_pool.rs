@@ -1,23 +1,21 @@
 use std::{
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc, Once,
-    },
-    thread,
-    time::Duration,
+    sync::{mpsc, Arc, Mutex, Once},
+    thread::{self, JoinHandle},
 };
 
-use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
 use crossbeam_utils::sync::WaitGroup;
-use log::{warn, LevelFilter};
+use log::LevelFilter;
 use rust_kv::{
-    KvClient, KvServer, KvStore, RayonThreadPool, SharedQueueThreadPool, SledStore, ThreadPool,
+    KvClient, KvServer, KvStore, KvStoreOptions, RayonThreadPool, SharedQueueThreadPool, SledStore,
+    ThreadPool,
 };
 use tempfile::TempDir;
 
 static LOGGER_INIT: Once = Once::new();
 const THREAD_COUNT: [usize; 4] = [1, 2, 4, 8];
 const ENTRY_COUNT: usize = 100;
+const DISPATCH_JOBS: usize = 10_000;
 
 fn write_queued_kvstore(c: &mut Criterion) {
     LOGGER_INIT.call_once(|| {
@@ -29,40 +27,31 @@ fn write_queued_kvstore(c: &mut Criterion) {
             BenchmarkId::from_parameter(thread_num),
             &thread_num,
             |b, &thread_num| {
-                let addr = "127.0.0.1:8888";
                 let temp_dir = TempDir::new().unwrap();
                 let pool = SharedQueueThreadPool::new(thread_num).unwrap();
                 let engine = KvStore::open(temp_dir.path()).unwrap();
-                let mut server = KvServer::new(engine, pool);
-                let is_stop = Arc::new(AtomicBool::new(false));
-
-                let is_stop_clone = is_stop.clone();
-                let child_handle = thread::spawn(move || {
-                    server
-                        .run(addr.to_owned(), is_stop_clone)
-                        .expect("kv server failed");
-                });
+                let server = KvServer::builder(engine, pool)
+                    .addr("127.0.0.1:0")
+                    .build()
+                    .start()
+                    .expect("kv server failed");
+                let addr = server.addr().to_string();
 
                 let values = String::from("value");
                 let keys: Vec<String> = (0..ENTRY_COUNT).map(|i| format!("key{}", i)).collect();
                 let client_pool = RayonThreadPool::new(ENTRY_COUNT).unwrap();
 
-                thread::sleep(Duration::from_secs(1));
+                // the tasks share one connection, each clone sending its requests on it
+                let client = KvClient::new(&addr).unwrap();
                 b.iter(|| {
                     let wg = WaitGroup::new();
-                    for i in 0..ENTRY_COUNT {
-                        let key = keys[i].clone();
+                    for key in &keys {
+                        let key = key.clone();
                         let value = values.clone();
                         let wg = wg.clone();
+                        let mut client = client.clone();
                         client_pool.spawn(move || {
-                            match KvClient::new(&addr.to_owned()) {
-                                Ok(mut client) => {
-                                    client.set(key, value).expect("client set error");
-                                }
-                                Err(err) => {
-                                    warn!("failed to new kv client: {}", err);
-                                }
-                            }
+                            client.set(key, value).expect("client set error");
                             drop(wg);
                         });
                     }
@@ -70,12 +59,7 @@ fn write_queued_kvstore(c: &mut Criterion) {
                     wg.wait();
                 });
 
-                is_stop.store(true, Ordering::SeqCst);
-
-                // trigger server stop
-                let _ = KvClient::new(&addr.to_owned());
-
-                child_handle.join().expect("child thread err");
+                server.shutdown().expect("kv server failed");
             },
         );
     }
@@ -93,45 +77,34 @@ fn read_queued_kvstore(c: &mut Criterion) {
             BenchmarkId::from_parameter(thread_num),
             &thread_num,
             |b, &thread_num| {
-                let addr = "127.0.0.1:8888";
                 let temp_dir = TempDir::new().unwrap();
                 let pool = SharedQueueThreadPool::new(thread_num).unwrap();
                 let engine = KvStore::open(temp_dir.path()).unwrap();
-                let mut server = KvServer::new(engine, pool);
-                let is_stop = Arc::new(AtomicBool::new(false));
-
-                let is_stop_clone = is_stop.clone();
-                let child_handle = thread::spawn(move || {
-                    server
-                        .run(addr.to_owned(), is_stop_clone)
-                        .expect("kv server failed");
-                });
+                let server = KvServer::builder(engine, pool)
+                    .addr("127.0.0.1:0")
+                    .build()
+                    .start()
+                    .expect("kv server failed");
+                let addr = server.addr().to_string();
 
                 let values = String::from("value");
                 let keys: Vec<String> = (0..ENTRY_COUNT).map(|i| format!("key{}", i)).collect();
                 let client_pool = RayonThreadPool::new(ENTRY_COUNT).unwrap();
 
-                thread::sleep(Duration::from_secs(1));
-
-                for i in 0..ENTRY_COUNT {
-                    let mut client = KvClient::new(&addr.to_owned()).unwrap();
-                    client.set(keys[i].clone(), values.clone()).unwrap();
+                // the tasks share one connection, each clone sending its requests on it
+                let mut client = KvClient::new(&addr).unwrap();
+                for key in &keys {
+                    client.set(key.clone(), values.clone()).unwrap();
                 }
 
                 b.iter(|| {
                     let wg = WaitGroup::new();
-                    for i in 0..ENTRY_COUNT {
-                        let key = keys[i].clone();
+                    for key in &keys {
+                        let key = key.clone();
                         let wg = wg.clone();
+                        let mut client = client.clone();
                         client_pool.spawn(move || {
-                            match KvClient::new(&addr.to_owned()) {
-                                Ok(mut client) => {
-                                    client.get(key).expect("client get error");
-                                }
-                                Err(err) => {
-                                    warn!("failed to new kv client: {}", err);
-                                }
-                            }
+                            client.get(key).expect("client get error");
                             drop(wg);
                         });
                     }
@@ -139,12 +112,7 @@ fn read_queued_kvstore(c: &mut Criterion) {
                     wg.wait();
                 });
 
-                is_stop.store(true, Ordering::SeqCst);
-
-                // trigger server stop
-                let _ = KvClient::new(&addr.to_owned());
-
-                child_handle.join().expect("child thread err");
+                server.shutdown().expect("kv server failed");
             },
         );
     }
@@ -162,40 +130,31 @@ fn write_rayon_kvstore(c: &mut Criterion) {
             BenchmarkId::from_parameter(thread_num),
             &thread_num,
             |b, &thread_num| {
-                let addr = "127.0.0.1:8888";
                 let temp_dir = TempDir::new().unwrap();
                 let pool = RayonThreadPool::new(thread_num).unwrap();
                 let engine = KvStore::open(temp_dir.path()).unwrap();
-                let mut server = KvServer::new(engine, pool);
-                let is_stop = Arc::new(AtomicBool::new(false));
-
-                let is_stop_clone = is_stop.clone();
-                let child_handle = thread::spawn(move || {
-                    server
-                        .run(addr.to_owned(), is_stop_clone)
-                        .expect("kv server failed");
-                });
+                let server = KvServer::builder(engine, pool)
+                    .addr("127.0.0.1:0")
+                    .build()
+                    .start()
+                    .expect("kv server failed");
+                let addr = server.addr().to_string();
 
                 let values = String::from("value");
                 let keys: Vec<String> = (0..ENTRY_COUNT).map(|i| format!("key{}", i)).collect();
                 let client_pool = RayonThreadPool::new(ENTRY_COUNT).unwrap();
 
-                thread::sleep(Duration::from_secs(1));
+                // the tasks share one connection, each clone sending its requests on it
+                let client = KvClient::new(&addr).unwrap();
                 b.iter(|| {
                     let wg = WaitGroup::new();
-                    for i in 0..ENTRY_COUNT {
-                        let key = keys[i].clone();
+                    for key in &keys {
+                        let key = key.clone();
                         let value = values.clone();
                         let wg = wg.clone();
+                        let mut client = client.clone();
                         client_pool.spawn(move || {
-                            match KvClient::new(&addr.to_owned()) {
-                                Ok(mut client) => {
-                                    client.set(key, value).expect("client set error");
-                                }
-                                Err(err) => {
-                                    warn!("failed to new kv client: {}", err);
-                                }
-                            }
+                            client.set(key, value).expect("client set error");
                             drop(wg);
                         });
                     }
@@ -203,12 +162,7 @@ fn write_rayon_kvstore(c: &mut Criterion) {
                     wg.wait();
                 });
 
-                is_stop.store(true, Ordering::SeqCst);
-
-                // trigger server stop
-                let _ = KvClient::new(&addr.to_owned());
-
-                child_handle.join().expect("child thread err");
+                server.shutdown().expect("kv server failed");
             },
         );
     }
@@ -226,45 +180,34 @@ fn read_rayon_kvstore(c: &mut Criterion) {
             BenchmarkId::from_parameter(thread_num),
             &thread_num,
             |b, &thread_num| {
-                let addr = "127.0.0.1:8888";
                 let temp_dir = TempDir::new().unwrap();
                 let pool = RayonThreadPool::new(thread_num).unwrap();
                 let engine = KvStore::open(temp_dir.path()).unwrap();
-                let mut server = KvServer::new(engine, pool);
-                let is_stop = Arc::new(AtomicBool::new(false));
-
-                let is_stop_clone = is_stop.clone();
-                let child_handle = thread::spawn(move || {
-                    server
-                        .run(addr.to_owned(), is_stop_clone)
-                        .expect("kv server failed");
-                });
+                let server = KvServer::builder(engine, pool)
+                    .addr("127.0.0.1:0")
+                    .build()
+                    .start()
+                    .expect("kv server failed");
+                let addr = server.addr().to_string();
 
                 let values = String::from("value");
                 let keys: Vec<String> = (0..ENTRY_COUNT).map(|i| format!("key{}", i)).collect();
                 let client_pool = RayonThreadPool::new(ENTRY_COUNT).unwrap();
-                
-                thread::sleep(Duration::from_secs(1));
-                
-                for i in 0..ENTRY_COUNT {
-                    let mut client = KvClient::new(&addr.to_owned()).unwrap();
-                    client.set(keys[i].clone(), values.clone()).unwrap();
+
+                // the tasks share one connection, each clone sending its requests on it
+                let mut client = KvClient::new(&addr).unwrap();
+                for key in &keys {
+                    client.set(key.clone(), values.clone()).unwrap();
                 }
 
                 b.iter(|| {
                     let wg = WaitGroup::new();
-                    for i in 0..ENTRY_COUNT {
-                        let key = keys[i].clone();
+                    for key in &keys {
+                        let key = key.clone();
                         let wg = wg.clone();
+                        let mut client = client.clone();
                         client_pool.spawn(move || {
-                            match KvClient::new(&addr.to_owned()) {
-                                Ok(mut client) => {
-                                    client.get(key).expect("client get error");
-                                }
-                                Err(err) => {
-                                    warn!("failed to new kv client: {}", err);
-                                }
-                            }
+                            client.get(key).expect("client get error");
                             drop(wg);
                         });
                     }
@@ -272,12 +215,7 @@ fn read_rayon_kvstore(c: &mut Criterion) {
                     wg.wait();
                 });
 
-                is_stop.store(true, Ordering::SeqCst);
-
-                // trigger server stop
-                let _ = KvClient::new(&addr.to_owned());
-
-                child_handle.join().expect("child thread err");
+                server.shutdown().expect("kv server failed");
             },
         );
     }
@@ -295,40 +233,31 @@ fn write_rayon_sledstore(c: &mut Criterion) {
             BenchmarkId::from_parameter(thread_num),
             &thread_num,
             |b, &thread_num| {
-                let addr = "127.0.0.1:8888";
                 let temp_dir = TempDir::new().unwrap();
                 let pool = RayonThreadPool::new(thread_num).unwrap();
                 let engine = SledStore::open(temp_dir.path()).unwrap();
-                let mut server = KvServer::new(engine, pool);
-                let is_stop = Arc::new(AtomicBool::new(false));
-
-                let is_stop_clone = is_stop.clone();
-                let child_handle = thread::spawn(move || {
-                    server
-                        .run(addr.to_owned(), is_stop_clone)
-                        .expect("kv server failed");
-                });
+                let server = KvServer::builder(engine, pool)
+                    .addr("127.0.0.1:0")
+                    .build()
+                    .start()
+                    .expect("kv server failed");
+                let addr = server.addr().to_string();
 
                 let values = String::from("value");
                 let keys: Vec<String> = (0..ENTRY_COUNT).map(|i| format!("key{}", i)).collect();
                 let client_pool = RayonThreadPool::new(ENTRY_COUNT).unwrap();
 
-                thread::sleep(Duration::from_secs(1));
+                // the tasks share one connection, each clone sending its requests on it
+                let client = KvClient::new(&addr).unwrap();
                 b.iter(|| {
                     let wg = WaitGroup::new();
-                    for i in 0..ENTRY_COUNT {
-                        let key = keys[i].clone();
+                    for key in &keys {
+                        let key = key.clone();
                         let value = values.clone();
                         let wg = wg.clone();
+                        let mut client = client.clone();
                         client_pool.spawn(move || {
-                            match KvClient::new(&addr.to_owned()) {
-                                Ok(mut client) => {
-                                    client.set(key, value).expect("client set error");
-                                }
-                                Err(err) => {
-                                    warn!("failed to new kv client: {}", err);
-                                }
-                            }
+                            client.set(key, value).expect("client set error");
                             drop(wg);
                         });
                     }
@@ -336,12 +265,7 @@ fn write_rayon_sledstore(c: &mut Criterion) {
                     wg.wait();
                 });
 
-                is_stop.store(true, Ordering::SeqCst);
-
-                // trigger server stop
-                let _ = KvClient::new(&addr.to_owned());
-
-                child_handle.join().expect("child thread err");
+                server.shutdown().expect("kv server failed");
             },
         );
     }
@@ -359,45 +283,34 @@ fn read_rayon_sledstore(c: &mut Criterion) {
             BenchmarkId::from_parameter(thread_num),
             &thread_num,
             |b, &thread_num| {
-                let addr = "127.0.0.1:8888";
                 let temp_dir = TempDir::new().unwrap();
                 let pool = RayonThreadPool::new(thread_num).unwrap();
                 let engine = SledStore::open(temp_dir.path()).unwrap();
-                let mut server = KvServer::new(engine, pool);
-                let is_stop = Arc::new(AtomicBool::new(false));
-
-                let is_stop_clone = is_stop.clone();
-                let child_handle = thread::spawn(move || {
-                    server
-                        .run(addr.to_owned(), is_stop_clone)
-                        .expect("kv server failed");
-                });
+                let server = KvServer::builder(engine, pool)
+                    .addr("127.0.0.1:0")
+                    .build()
+                    .start()
+                    .expect("kv server failed");
+                let addr = server.addr().to_string();
 
                 let values = String::from("value");
                 let keys: Vec<String> = (0..ENTRY_COUNT).map(|i| format!("key{}", i)).collect();
                 let client_pool = RayonThreadPool::new(ENTRY_COUNT).unwrap();
 
-                thread::sleep(Duration::from_secs(1));
-
-                for i in 0..ENTRY_COUNT {
-                    let mut client = KvClient::new(&addr.to_owned()).unwrap();
-                    client.set(keys[i].clone(), values.clone()).unwrap();
+                // the tasks share one connection, each clone sending its requests on it
+                let mut client = KvClient::new(&addr).unwrap();
+                for key in &keys {
+                    client.set(key.clone(), values.clone()).unwrap();
                 }
 
                 b.iter(|| {
                     let wg = WaitGroup::new();
-                    for i in 0..ENTRY_COUNT {
-                        let key = keys[i].clone();
+                    for key in &keys {
+                        let key = key.clone();
                         let wg = wg.clone();
+                        let mut client = client.clone();
                         client_pool.spawn(move || {
-                            match KvClient::new(&addr.to_owned()) {
-                                Ok(mut client) => {
-                                    client.get(key).expect("client get error");
-                                }
-                                Err(err) => {
-                                    warn!("failed to new kv client: {}", err);
-                                }
-                            }
+                            client.get(key).expect("client get error");
                             drop(wg);
                         });
                     }
@@ -405,16 +318,131 @@ fn read_rayon_sledstore(c: &mut Criterion) {
                     wg.wait();
                 });
 
-                is_stop.store(true, Ordering::SeqCst);
+                server.shutdown().expect("kv server failed");
+            },
+        );
+    }
+
+    group.finish();
+}
+
+// a get of a cached value is answered on the network threads, while get_bytes
+// goes through the thread pool, so the difference is the round trip to the pool
+fn get_latency(c: &mut Criterion) {
+    LOGGER_INIT.call_once(|| {
+        env_logger::builder().filter_level(LevelFilter::Warn).init();
+    });
+    let temp_dir = TempDir::new().unwrap();
+    let pool = SharedQueueThreadPool::new(4).unwrap();
+    let options = KvStoreOptions::new().value_cache_size(1024 * 1024);
+    let engine = KvStore::open_with(temp_dir.path(), options).unwrap();
+    let server = KvServer::builder(engine, pool)
+        .addr("127.0.0.1:0")
+        .build()
+        .start()
+        .expect("kv server failed");
+    let mut client = KvClient::new(&server.addr().to_string()).unwrap();
+    client.set("key".to_owned(), "value".to_owned()).unwrap();
+    client.get("key".to_owned()).unwrap();
+
+    let mut group = c.benchmark_group("get_latency");
+    group.bench_function("in_memory", |b| {
+        b.iter(|| client.get("key".to_owned()).expect("client get error"))
+    });
+    group.bench_function("thread_pool", |b| {
+        b.iter(|| {
+            client
+                .get_bytes("key".to_owned())
+                .expect("client get error")
+        })
+    });
+    group.finish();
+
+    server.shutdown().expect("kv server failed");
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// the dispatch of the shared-queue pool before it moved to a crossbeam channel, its
+// threads taking turns to lock one receiver, as the baseline of `dispatch`
+struct MutexQueuePool {
+    sender: Option<mpsc::Sender<Job>>,
+    threads: Vec<JoinHandle<()>>,
+}
 
-                // trigger server stop
-                let _ = KvClient::new(&addr.to_owned());
+impl MutexQueuePool {
+    fn new(threads_num: usize) -> MutexQueuePool {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let threads = (0..threads_num)
+            .map(|_| {
+                let receiver = receiver.clone();
+                thread::spawn(move || loop {
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
+        MutexQueuePool {
+            sender: Some(sender),
+            threads,
+        }
+    }
 
-                child_handle.join().expect("child thread err");
+    fn spawn<F: FnOnce() + Send + 'static>(&self, job: F) {
+        self.sender.as_ref().unwrap().send(Box::new(job)).unwrap();
+    }
+}
+
+impl Drop for MutexQueuePool {
+    fn drop(&mut self) {
+        self.sender.take();
+        for thread in self.threads.drain(..) {
+            thread.join().unwrap();
+        }
+    }
+}
+
+// spawn jobs doing nothing and wait for them, so that the time is the one of handing the
+// jobs to the threads
+fn dispatch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("dispatch");
+    group.throughput(Throughput::Elements(DISPATCH_JOBS as u64));
+    for thread_num in THREAD_COUNT {
+        group.bench_with_input(
+            BenchmarkId::new("mutex_receiver", thread_num),
+            &thread_num,
+            |b, &thread_num| {
+                let pool = MutexQueuePool::new(thread_num);
+                b.iter(|| {
+                    let wg = WaitGroup::new();
+                    for _ in 0..DISPATCH_JOBS {
+                        let wg = wg.clone();
+                        pool.spawn(move || drop(wg));
+                    }
+                    wg.wait();
+                });
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("shared_queue", thread_num),
+            &thread_num,
+            |b, &thread_num| {
+                let pool = SharedQueueThreadPool::new(thread_num).unwrap();
+                b.iter(|| {
+                    let wg = WaitGroup::new();
+                    for _ in 0..DISPATCH_JOBS {
+                        let wg = wg.clone();
+                        pool.spawn(move || drop(wg));
+                    }
+                    wg.wait();
+                });
             },
         );
     }
-
     group.finish();
 }
 
@@ -422,7 +450,8 @@ criterion_group! {
     name = benches;
     config = Criterion::default().sample_size(20);
     targets = write_queued_kvstore, read_queued_kvstore, write_rayon_kvstore,
-                read_rayon_kvstore, write_rayon_sledstore, read_rayon_sledstore
+                read_rayon_kvstore, write_rayon_sledstore, read_rayon_sledstore, get_latency,
+                dispatch
 }
 
 criterion_main!(benches);
@@ -0,0 +1,165 @@
+//! Line editing for the interactive client, with `rustyline`: the arrow keys
+//! move in the line and through the history, Ctrl-R searches the history, and
+//! the lines entered are appended to a history file, so they outlive the session.
+//! Tab completes the word before the cursor.
+//!
+//! When stdin is not a terminal, like when commands are piped in, the lines are
+//! read as they are after the prompt, without editing or history.
+
+use std::{
+    io::{self, IsTerminal, Write},
+    path::PathBuf,
+};
+
+use rustyline::{
+    completion::{Completer, Pair},
+    config::Config,
+    error::ReadlineError,
+    highlight::Highlighter,
+    hint::Hinter,
+    history::FileHistory,
+    validate::Validator,
+    CompletionType, Context, Helper,
+};
+
+/// The number of lines the history keeps, the oldest ones dropped first.
+const MAX_HISTORY: usize = 1000;
+
+/// Reads the lines of the client, with editing and a history on a terminal.
+pub struct Editor<F: Fn(&[&str], &str) -> Vec<String>> {
+    editor: rustyline::Editor<Completion<F>, FileHistory>,
+    history: Option<PathBuf>,
+    terminal: bool,
+}
+
+impl<F: Fn(&[&str], &str) -> Vec<String>> Editor<F> {
+    /// Creates an editor whose history is kept in the file `history`, if any.
+    ///
+    /// Tab completes the word before the cursor with `complete`, which returns
+    /// the candidates starting with the word, given the words before it.
+    ///
+    /// The client works without a history, so one that cannot be read is only
+    /// kept for the session, with a warning.
+    pub fn new(history: Option<PathBuf>, complete: F) -> io::Result<Editor<F>> {
+        let config = Config::builder()
+            .max_history_size(MAX_HISTORY)
+            .and_then(|builder| builder.history_ignore_dups(true))
+            .map_err(io_error)?
+            .completion_type(CompletionType::List)
+            .build();
+        let mut editor = rustyline::Editor::with_config(config).map_err(io_error)?;
+        editor.set_helper(Some(Completion(complete)));
+        let terminal = io::stdin().is_terminal();
+        let history = history.filter(|_| terminal);
+        if let Some(path) = &history {
+            match editor.load_history(path) {
+                Err(ReadlineError::Io(err)) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => eprintln!(
+                    "Warning: failed to read history {}: {}",
+                    path.display(),
+                    err
+                ),
+                Ok(_) => {}
+            }
+        }
+        Ok(Editor {
+            editor,
+            history,
+            terminal,
+        })
+    }
+
+    /// Reads a line after printing `prompt`, `None` at the end of the input.
+    ///
+    /// Ctrl-C drops the line being typed and reads another one.
+    pub fn read_line(&mut self, prompt: &str) -> io::Result<Option<String>> {
+        if !self.terminal {
+            print!("{}", prompt);
+            io::stdout().flush()?;
+            let mut line = String::new();
+            return match io::stdin().read_line(&mut line)? {
+                0 => Ok(None),
+                _ => Ok(Some(line.trim_end_matches(['\r', '\n']).to_owned())),
+            };
+        }
+        let line = loop {
+            match self.editor.readline(prompt) {
+                Ok(line) => break line,
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => return Ok(None),
+                Err(err) => return Err(io_error(err)),
+            }
+        };
+        if line.trim().is_empty()
+            || !self
+                .editor
+                .add_history_entry(line.as_str())
+                .map_err(io_error)?
+        {
+            return Ok(Some(line));
+        }
+        // appended as it is entered, so that a client killed, like to end a
+        // subscription, keeps it
+        if let Some(path) = &self.history {
+            if let Err(err) = self.editor.append_history(path) {
+                eprintln!(
+                    "Warning: failed to write history {}: {}",
+                    path.display(),
+                    err
+                );
+                self.history = None;
+            }
+        }
+        Ok(Some(line))
+    }
+}
+
+/// Completes the word before the cursor with the function given to the editor.
+struct Completion<F>(F);
+
+impl<F: Fn(&[&str], &str) -> Vec<String>> Completer for Completion<F> {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let start = line[..pos].rfind(char::is_whitespace).map_or(0, |space| {
+            space + line[space..].chars().next().map_or(1, char::len_utf8)
+        });
+        let words: Vec<&str> = line[..start].split_whitespace().collect();
+        let mut candidates: Vec<Pair> = (self.0)(&words, &line[start..pos])
+            .into_iter()
+            .map(|candidate| Pair {
+                display: candidate.clone(),
+                replacement: candidate,
+            })
+            .collect();
+        // the only candidate is completed with the space before the next word
+        if let [candidate] = &mut candidates[..] {
+            if !line[pos..].starts_with(' ') {
+                candidate.replacement.push(' ');
+            }
+        }
+        Ok((start, candidates))
+    }
+}
+
+impl<F> Hinter for Completion<F> {
+    type Hint = String;
+}
+
+impl<F> Highlighter for Completion<F> {}
+
+impl<F> Validator for Completion<F> {}
+
+impl<F: Fn(&[&str], &str) -> Vec<String>> Helper for Completion<F> {}
+
+fn io_error(err: ReadlineError) -> io::Error {
+    match err {
+        ReadlineError::Io(err) => err,
+        err => io::Error::other(err),
+    }
+}
@@ -0,0 +1,740 @@
+use std::{
+    cell::RefCell,
+    env,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::PathBuf,
+    process::exit,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use clap::{arg, value_parser, ArgMatches, Command};
+use rust_kv::{client_tls_config, Codec, KvClient, KvError, Result};
+
+use editor::Editor;
+use output::{Format, Output};
+
+mod editor;
+mod output;
+
+const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
+
+/// The commands of the client, which Tab completes.
+const COMMANDS: &[&str] = &[
+    "\\help",
+    "bucket",
+    "changes",
+    "clients",
+    "compact",
+    "connections",
+    "custom",
+    "decr",
+    "exit",
+    "expire",
+    "flush",
+    "get",
+    "incr",
+    "info",
+    "kill",
+    "len",
+    "mget",
+    "monitor",
+    "ping",
+    "publish",
+    "reload",
+    "rm",
+    "scan",
+    "set",
+    "setnx",
+    "setxx",
+    "stats",
+    "subscribe",
+    "ttl",
+    "watch",
+];
+
+/// The commands whose first argument is a key, which Tab completes from the
+/// keys of the server, like every argument of `mget`.
+const KEY_COMMANDS: &[&str] = &[
+    "decr", "expire", "get", "incr", "rm", "set", "setnx", "setxx", "ttl", "watch",
+];
+
+/// The most keys Tab completes a key with.
+const MAX_KEY_COMPLETIONS: usize = 100;
+
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("Error: {}", err);
+        exit(2)
+    }
+}
+
+fn run() -> Result<()> {
+    let matches = Command::new(env!("CARGO_PKG_NAME"))
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about(env!("CARGO_PKG_DESCRIPTION"))
+        .disable_help_subcommand(true)
+        .arg(
+            arg!(--addr <IP_PORT> "The address of the server, or the comma-separated addresses of the servers to fail over between")
+                .default_value(DEFAULT_LISTENING_ADDRESS),
+        )
+        .arg(
+            arg!(--"tls-ca" <PEM> "The CA certificates to verify the server with, connecting over TLS")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(--"tls-cert" <PEM> "The certificate chain to present to the server")
+                .value_parser(value_parser!(PathBuf))
+                .requires("tls-key")
+                .requires("tls-ca"),
+        )
+        .arg(
+            arg!(--"tls-key" <PEM> "The private key of the client certificate")
+                .value_parser(value_parser!(PathBuf))
+                .requires("tls-cert"),
+        )
+        .arg(
+            arg!(--codec <CODEC> "The encoding of the frames sent to the server")
+                .value_parser(["json", "bincode"])
+                .default_value("json"),
+        )
+        .arg(
+            arg!(--"max-frame-size" <BYTES> "The size of the largest request sent and response read, 8 MB by default")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            arg!(--history <FILE> "The file keeping the lines entered on a terminal, ~/.kv_client_history by default")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(--file <FILE> "Run the commands of the file line by line, or of stdin for -, printing their results as JSON lines")
+                .value_parser(value_parser!(PathBuf))
+                .conflicts_with("history"),
+        )
+        .arg(
+            arg!(--"continue-on-error" "Run the next commands of a script once one failed, rather than stopping")
+                .requires("file"),
+        )
+        .subcommand(
+            Command::new("get")
+                .about("Print the value of a key, exiting with 1 if it does not exist")
+                .arg(arg!(<KEY> "The key")),
+        )
+        .subcommand(
+            Command::new("set")
+                .about("Set the value of a key")
+                .arg(arg!(<KEY> "The key"))
+                .arg(arg!(<VALUE> "The value")),
+        )
+        .subcommand(
+            Command::new("rm")
+                .about("Remove a key, exiting with 1 if it does not exist")
+                .arg(arg!(<KEY> "The key")),
+        )
+        .get_matches();
+
+    let addrs = matches.get_one::<String>("addr").unwrap();
+    let addrs: Vec<_> = addrs.split(',').map(str::to_owned).collect();
+    let codec = match matches.get_one::<String>("codec").unwrap().as_str() {
+        "bincode" => Codec::Bincode,
+        _ => Codec::Json,
+    };
+    let failover = addrs.len() > 1;
+    let mut builder = KvClient::builder_multi(addrs).codec(codec);
+    if failover {
+        builder = builder.reconnect(Duration::from_secs(5));
+    }
+    if let Some(&max_frame_size) = matches.get_one::<usize>("max-frame-size") {
+        builder = builder.max_frame_size(max_frame_size);
+    }
+    if let Some(ca) = matches.get_one::<PathBuf>("tls-ca") {
+        let identity = matches
+            .get_one::<PathBuf>("tls-cert")
+            .zip(matches.get_one::<PathBuf>("tls-key"))
+            .map(|(cert, key)| (cert.as_path(), key.as_path()));
+        builder = builder.tls(client_tls_config(ca, identity)?);
+    }
+    let mut client = builder.build()?;
+    if let Some((command, args)) = matches.subcommand() {
+        exit(run_once(&mut client, command, args));
+    }
+    let keep_going = matches.get_flag("continue-on-error");
+    match matches.get_one::<PathBuf>("file") {
+        Some(file) if file.as_os_str() == "-" => {
+            exit(run_script(&mut client, io::stdin().lock(), keep_going)?);
+        }
+        Some(file) => {
+            let script = BufReader::new(File::open(file)?);
+            exit(run_script(&mut client, script, keep_going)?);
+        }
+        None => {}
+    }
+    let history = matches
+        .get_one::<PathBuf>("history")
+        .cloned()
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".kv_client_history")));
+    // the client is borrowed by Tab between the commands it runs
+    let client = RefCell::new(client);
+    let mut editor = Editor::new(history, |before, word| {
+        complete(&mut client.borrow_mut(), before, word)
+    })?;
+
+    println!("Use \\help to get usage.");
+    let mut out = Output::new(Format::Text);
+    loop {
+        let line = editor.read_line("> ")?;
+        let line = match line.as_deref().map(str::trim) {
+            None | Some("q" | "exit") => {
+                println!("client exited...");
+                break;
+            }
+            Some(line) => line,
+        };
+        if line.is_empty() {
+            continue;
+        }
+        out.start(0, line);
+        let reading = execute(&mut client.borrow_mut(), line, &mut out)?;
+        out.finish();
+        if !reading {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+/// Runs the command `line`, typed or read from a script, printing its result to `out`.
+///
+/// Returns whether the client goes on reading commands, which it does not once
+/// a command printing what it receives until exit ended.
+fn execute(client: &mut KvClient, line: &str, out: &mut Output) -> Result<bool> {
+    if line == "\\help" {
+        out.line("set <key> <value>: set the value of a string key");
+        out.line("get <key>: get the string value of a given string key");
+        out.line("mget <key>...: get the string values of many keys");
+        out.line("rm <key>: remove a given key");
+        out.line("setnx <key> <value>: set a string key only if it does not exist");
+        out.line("setxx <key> <value>: set a string key only if it already exists");
+        out.line("expire <key> <value> <seconds>: set a string key that expires after seconds");
+        out.line("ttl <key>: get the remaining seconds to live of a given key");
+        out.line("incr <key> [delta]: add delta (default 1) to the integer value of a key");
+        out.line("decr <key> [delta]: subtract delta (default 1) from the integer value of a key");
+        out.line("len: get the number of keys");
+        out.line("compact: reclaim the space of the stale records of the server now");
+        out.line("stats: get the statistics of the engine of the server");
+        out.line("flush: sync the writes of the server to the disk");
+        out.line("reload: reload the settings of the server from its config file");
+        out.line("connections: get the number of connections open from each IP");
+        out.line("info: get the version, uptime, engine and counts of the server");
+        out.line("clients: list the connections open on the server, with their traffic");
+        out.line("kill <id>: close the connection of a given id on the server");
+        out.line("ping [payload]: check the server is alive, printing the round trip time");
+        out.line("scan <pattern>: list the keys matching a glob pattern, like user:*");
+        out.line("bucket [name]: use the named bucket, or the default one without name");
+        out.line("publish <channel> <message>: publish a message on a channel");
+        out.line("custom <name> [payload]: run a custom command of the server");
+        out.line("subscribe <channel>...: print the messages published on channels until exit");
+        out.line("watch <prefix>: print the changes of the keys starting with prefix until exit");
+        out.line("changes [offset]: print the changes of the server from offset, or the oldest one kept, until exit");
+        out.line("monitor: print the requests the server reads, with their peer, until exit");
+        out.line("exit: exit the client");
+        return Ok(true);
+    } else if line == "bucket" {
+        client.set_bucket(None);
+        out.line("Ok");
+        return Ok(true);
+    } else if line == "len" {
+        match client.len() {
+            Ok(len) => out.line(len),
+            Err(err) => out.error(err),
+        }
+        return Ok(true);
+    } else if line == "compact" || line == "flush" || line == "reload" {
+        let result = match line {
+            "compact" => client.compact(),
+            "flush" => client.flush(),
+            _ => client.reload(),
+        };
+        match result {
+            Ok(_) => out.line("Ok"),
+            Err(err) => out.error(err),
+        }
+        return Ok(true);
+    } else if line == "ping" || line.starts_with("ping ") {
+        let payload = line.strip_prefix("ping ").map(str::to_owned);
+        let started = Instant::now();
+        match client.ping(payload) {
+            Ok(payload) => {
+                let rtt = started.elapsed();
+                out.line(format!(
+                    "{} in {:?}",
+                    payload.as_deref().unwrap_or("PONG"),
+                    rtt
+                ));
+            }
+            Err(err) => out.error(err),
+        }
+        return Ok(true);
+    } else if line == "changes" || line.starts_with("changes ") {
+        let offset = match line.strip_prefix("changes ").map(str::parse) {
+            None => None,
+            Some(Ok(offset)) => Some(offset),
+            Some(Err(_)) => {
+                out.error("invalid offset");
+                return Ok(true);
+            }
+        };
+        let changes = match client.clone().changes(offset) {
+            Ok(changes) => changes,
+            Err(err) => {
+                out.error(err);
+                return Ok(false);
+            }
+        };
+        out.line("Ok");
+        out.finish();
+        for change in changes {
+            let change = change?;
+            match change.value {
+                Some(value) => out.event(format!("{} set {} {}", change.offset, change.key, value)),
+                None => out.event(format!("{} rm {}", change.offset, change.key)),
+            }
+        }
+        out.event("connection closed");
+        return Ok(false);
+    } else if line == "monitor" {
+        let events = match client.clone().monitor() {
+            Ok(events) => events,
+            Err(err) => {
+                out.error(err);
+                return Ok(false);
+            }
+        };
+        out.line("Ok");
+        out.finish();
+        for event in events {
+            let event = event?;
+            if event.dropped > 0 {
+                out.event(format!("({} requests dropped)", event.dropped));
+            }
+            let time = event.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            out.event(format!(
+                "{}.{:06} [{}] {}{}",
+                time.as_secs(),
+                time.subsec_micros(),
+                event.addr,
+                event.op,
+                event.key.map(|key| format!(" {}", key)).unwrap_or_default()
+            ));
+        }
+        out.event("connection closed");
+        return Ok(false);
+    } else if line == "connections" {
+        match client.connections() {
+            Ok(counts) => {
+                for (ip, count) in counts {
+                    out.line(format!("{}: {}", ip, count));
+                }
+            }
+            Err(err) => out.error(err),
+        }
+        return Ok(true);
+    } else if line == "clients" {
+        match client.client_list() {
+            Ok(clients) => {
+                for info in clients {
+                    out.line(format!(
+                        "{} {}{} age={}s idle={}s op={} in={} out={}",
+                        info.id,
+                        info.addr,
+                        info.name
+                            .map(|name| format!(" ({})", name))
+                            .unwrap_or_default(),
+                        info.age.as_secs(),
+                        info.idle.as_secs(),
+                        info.last_op.as_deref().unwrap_or("-"),
+                        info.bytes_in,
+                        info.bytes_out
+                    ));
+                }
+            }
+            Err(err) => out.error(err),
+        }
+        return Ok(true);
+    } else if let Some(id) = line.strip_prefix("kill ") {
+        let Ok(id) = id.parse() else {
+            out.error("invalid id");
+            return Ok(true);
+        };
+        match client.client_kill(id) {
+            Ok(()) => out.line("Ok"),
+            Err(err) => out.error(err),
+        }
+        return Ok(true);
+    } else if line == "stats" {
+        match client.stats() {
+            Ok(stats) => {
+                out.line(format!("keys: {}", stats.keys));
+                if let Some(disk_size) = stats.disk_size {
+                    out.line(format!("disk size: {} bytes", disk_size));
+                }
+                if let Some(stale_size) = stats.stale_size {
+                    out.line(format!("stale size: {} bytes", stale_size));
+                }
+                if let Some(memory_size) = stats.memory_size {
+                    out.line(format!("memory size: {} bytes", memory_size));
+                }
+            }
+            Err(err) => out.error(err),
+        }
+        return Ok(true);
+    } else if line == "info" {
+        match client.info() {
+            Ok(info) => {
+                out.line(format!("version: {}", info.version));
+                out.line(format!("uptime: {}s", info.uptime.as_secs()));
+                out.line(format!("engine: {}", info.engine));
+                out.line(format!("keys: {}", info.stats.keys));
+                if let Some(disk_size) = info.stats.disk_size {
+                    out.line(format!("disk size: {} bytes", disk_size));
+                }
+                if let Some(memory_size) = info.stats.memory_size {
+                    out.line(format!("memory size: {} bytes", memory_size));
+                }
+                if let Some(counts) = info.counts {
+                    out.line(format!("connections: {}", counts.connections));
+                    out.line(format!("active connections: {}", counts.active_connections));
+                    out.line(format!("requests: {}", counts.requests));
+                    out.line(format!("failed requests: {}", counts.failed_requests));
+                }
+                for (op, stats) in info.ops {
+                    let mut requests = format!("{}: {} requests", op, stats.requests);
+                    if stats.hits + stats.misses > 0 {
+                        requests += &format!(", {} hits, {} misses", stats.hits, stats.misses);
+                    }
+                    out.line(format!(
+                        "{}, mean {:?}, p50 {:?}, p99 {:?}",
+                        requests,
+                        stats.latency.mean(),
+                        stats.latency.percentile(0.5),
+                        stats.latency.percentile(0.99)
+                    ));
+                }
+            }
+            Err(err) => out.error(err),
+        }
+        return Ok(true);
+    }
+
+    let inputs: Vec<&str> = line.split(' ').collect();
+    if inputs.len() < 2 {
+        if COMMANDS.contains(&inputs[0]) {
+            out.error(format!("invalid {} command", inputs[0]));
+        } else {
+            out.error("unknown command");
+        }
+        return Ok(true);
+    }
+    match inputs[0] {
+        "set" => {
+            if inputs.len() != 3 {
+                out.error("invalid set command");
+                return Ok(true);
+            }
+            let key = inputs[1].to_string();
+            let value = inputs[2].to_string();
+            match client.set(key, value) {
+                Ok(_) => out.line("Ok"),
+                Err(err) => out.error(err),
+            }
+        }
+        "get" => {
+            let key = inputs[1].to_string();
+            match client.get(key) {
+                Ok(Some(value)) => out.line(value),
+                Ok(None) => out.none("Key not found"),
+                Err(err) => out.error(err),
+            }
+        }
+        "mget" => {
+            let keys = inputs[1..].iter().map(|key| key.to_string()).collect();
+            match client.multi_get(keys) {
+                Ok(values) => {
+                    for value in values {
+                        match value {
+                            Some(value) => out.line(value),
+                            None => out.none("Key not found"),
+                        }
+                    }
+                }
+                Err(err) => out.error(err),
+            }
+        }
+        "rm" => {
+            let key = inputs[1].to_string();
+            match client.remove(key) {
+                Ok(_) => out.line("Ok"),
+                Err(err) => out.error(err),
+            }
+        }
+        "expire" => {
+            let seconds = match inputs.get(3).map(|secs| secs.parse::<u64>()) {
+                Some(Ok(seconds)) if inputs.len() == 4 => seconds,
+                _ => {
+                    out.error("invalid expire command");
+                    return Ok(true);
+                }
+            };
+            let key = inputs[1].to_string();
+            let value = inputs[2].to_string();
+            match client.set_with_ttl(key, value, Duration::from_secs(seconds)) {
+                Ok(_) => out.line("Ok"),
+                Err(err) => out.error(err),
+            }
+        }
+        "ttl" => {
+            let key = inputs[1].to_string();
+            match client.ttl(key) {
+                Ok(Some(ttl)) => out.line(ttl.as_secs()),
+                Ok(None) => out.none("No expiration"),
+                Err(err) => out.error(err),
+            }
+        }
+        "setnx" | "setxx" => {
+            if inputs.len() != 3 {
+                out.error(format!("invalid {} command", inputs[0]));
+                return Ok(true);
+            }
+            let key = inputs[1].to_string();
+            let value = inputs[2].to_string();
+            let result = if inputs[0] == "setnx" {
+                client.set_if_absent(key, value)
+            } else {
+                client.set_if_present(key, value)
+            };
+            match result {
+                Ok(true) => out.line("Ok"),
+                Ok(false) => out.line("Not set"),
+                Err(err) => out.error(err),
+            }
+        }
+        "bucket" => {
+            client.set_bucket(Some(inputs[1].to_string()));
+            out.line("Ok");
+        }
+        "incr" | "decr" => {
+            let delta = match inputs.get(2).map(|delta| delta.parse::<i64>()) {
+                None => 1,
+                Some(Ok(delta)) if inputs.len() == 3 => delta,
+                _ => {
+                    out.error(format!("invalid {} command", inputs[0]));
+                    return Ok(true);
+                }
+            };
+            let key = inputs[1].to_string();
+            let result = if inputs[0] == "incr" {
+                client.incr(key, delta)
+            } else {
+                client.decr(key, delta)
+            };
+            match result {
+                Ok(value) => out.line(value),
+                Err(err) => out.error(err),
+            }
+        }
+        "publish" => {
+            if inputs.len() < 3 {
+                out.error("invalid publish command");
+                return Ok(true);
+            }
+            let channel = inputs[1].to_string();
+            let message = inputs[2..].join(" ");
+            match client.publish(channel, message) {
+                Ok(receivers) => out.line(receivers),
+                Err(err) => out.error(err),
+            }
+        }
+        "custom" => {
+            let name = inputs[1].to_string();
+            let payload = inputs[2..].join(" ");
+            match client.custom(name, payload) {
+                Ok(Some(value)) => out.line(value),
+                Ok(None) => out.line("Ok"),
+                Err(err) => out.error(err),
+            }
+        }
+        "subscribe" => {
+            for channel in &inputs[1..] {
+                client.subscribe(channel.to_string())?;
+            }
+            out.line("Ok");
+            out.finish();
+            // a subscriber only listens, until the connection or the client is closed
+            loop {
+                let (channel, message) = client.next_message()?;
+                out.event(format!("{}: {}", channel, message));
+            }
+        }
+        "scan" => {
+            let pattern = Some(inputs[1].to_string());
+            let mut cursor = None;
+            loop {
+                let (keys, next) = match client.scan(cursor, 100, pattern.clone()) {
+                    Ok(page) => page,
+                    Err(err) => {
+                        out.error(err);
+                        break;
+                    }
+                };
+                for key in keys {
+                    out.line(key);
+                }
+                cursor = next;
+                if cursor.is_none() {
+                    break;
+                }
+            }
+        }
+        "watch" => {
+            let events = match client.clone().watch(inputs[1].to_string()) {
+                Ok(events) => events,
+                Err(err) => {
+                    out.error(err);
+                    return Ok(false);
+                }
+            };
+            out.line("Ok");
+            out.finish();
+            for event in events {
+                let event = event?;
+                match event.value {
+                    Some(value) => out.event(format!("set {} {}", event.key, value)),
+                    None => out.event(format!("rm {}", event.key)),
+                }
+            }
+            out.event("connection closed");
+            return Ok(false);
+        }
+        _ => {
+            out.error("unknown command");
+        }
+    }
+    Ok(true)
+}
+
+/// Runs the commands of `script` line by line, skipping the blank lines and
+/// the comments starting with `#`, and printing the result of each command as
+/// a JSON object on a line of its own.
+///
+/// Returns the exit code of the client: 0 once every command succeeded, and 2
+/// if one failed, stopping there unless `keep_going`.
+fn run_script(client: &mut KvClient, script: impl BufRead, keep_going: bool) -> Result<i32> {
+    let mut out = Output::new(Format::Json);
+    let mut failed = false;
+    for (number, line) in script.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line == "q" || line == "exit" {
+            break;
+        }
+        out.start(number + 1, line);
+        let reading = execute(client, line, &mut out)?;
+        if !out.finish() {
+            failed = true;
+            if !keep_going {
+                break;
+            }
+        }
+        if !reading {
+            break;
+        }
+    }
+    Ok(if failed { 2 } else { 0 })
+}
+
+/// Runs the `command` given on the command line, rather than typed, returning
+/// the exit code of the client: 0 once done, 1 if the key does not exist, and
+/// 2 for an error.
+fn run_once(client: &mut KvClient, command: &str, args: &ArgMatches) -> i32 {
+    let arg = |name: &str| args.get_one::<String>(name).unwrap().to_owned();
+    // whether the key exists
+    let result = match command {
+        "get" => client.get(arg("KEY")).map(|value| match value {
+            Some(value) => {
+                println!("{}", value);
+                true
+            }
+            None => false,
+        }),
+        "set" => client.set(arg("KEY"), arg("VALUE")).map(|_| true),
+        _ => match client.remove(arg("KEY")) {
+            // the error of the server only reaches the client as its message
+            Err(err) if err.to_string() == KvError::KeyNotFound.to_string() => Ok(false),
+            result => result.map(|_| true),
+        },
+    };
+    match result {
+        Ok(true) => 0,
+        Ok(false) => {
+            eprintln!("{}", KvError::KeyNotFound);
+            1
+        }
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            2
+        }
+    }
+}
+
+/// Returns the completions of `word`, following the words `before` it: the
+/// commands first, then the keys of the server for an argument which is a key.
+fn complete(client: &mut KvClient, before: &[&str], word: &str) -> Vec<String> {
+    match before {
+        [] => COMMANDS
+            .iter()
+            .filter(|command| command.starts_with(word))
+            .map(|command| command.to_string())
+            .collect(),
+        [command] if KEY_COMMANDS.contains(command) => complete_key(client, word),
+        ["mget", ..] => complete_key(client, word),
+        _ => Vec::new(),
+    }
+}
+
+/// Returns the keys of the server starting with `prefix`, scanning from the
+/// prefix on until the keys no longer start with it.
+///
+/// A key failing to complete only completes nothing, so errors are ignored.
+fn complete_key(client: &mut KvClient, prefix: &str) -> Vec<String> {
+    let mut pattern = String::new();
+    for c in prefix.chars() {
+        if matches!(c, '*' | '?' | '[' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('*');
+    // the keys after the prefix cut of its last character, which include the prefix
+    let mut cursor = prefix
+        .char_indices()
+        .last()
+        .map(|(last, _)| prefix[..last].to_owned());
+    let mut keys = Vec::new();
+    while keys.len() < MAX_KEY_COMPLETIONS {
+        let (page, next) = match client.scan(cursor, 100, Some(pattern.clone())) {
+            Ok(page) => page,
+            Err(_) => break,
+        };
+        keys.extend(page);
+        cursor = match next {
+            Some(next) if next.as_str() < prefix || next.starts_with(prefix) => Some(next),
+            _ => break,
+        };
+    }
+    keys.truncate(MAX_KEY_COMPLETIONS);
+    keys
+}
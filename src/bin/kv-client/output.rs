@@ -0,0 +1,110 @@
+//! The results of the commands of the client, printed as they come for a
+//! person at the prompt, or as one JSON object per command for a script.
+
+use std::{fmt::Display, mem};
+
+use serde_json::{json, Value};
+
+/// How the results of the commands are printed.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// The lines of each result as they come, errors prefixed with `Error:`.
+    Text,
+    /// One JSON object per command, on a line of its own, like
+    /// `{"command":"get key1","line":2,"ok":true,"output":["value1"]}`, a
+    /// missing value being `null`, or `{"command":..,"error":"..","line":..,"ok":false}`.
+    Json,
+}
+
+/// Collects the result of the command running, printed once it ends.
+pub struct Output {
+    format: Format,
+    /// The number of the line of the command in the script, and the command.
+    command: (usize, String),
+    lines: Vec<Value>,
+    error: Option<String>,
+    /// Whether the result is not printed yet.
+    running: bool,
+}
+
+impl Output {
+    pub fn new(format: Format) -> Output {
+        Output {
+            format,
+            command: (0, String::new()),
+            lines: Vec::new(),
+            error: None,
+            running: false,
+        }
+    }
+
+    /// Starts the result of `command`, read on the line `number` of the script.
+    pub fn start(&mut self, number: usize, command: &str) {
+        self.command = (number, command.to_owned());
+        self.lines.clear();
+        self.error = None;
+        self.running = true;
+    }
+
+    /// Adds a line to the result.
+    pub fn line(&mut self, line: impl Display) {
+        match self.format {
+            Format::Text => println!("{}", line),
+            Format::Json => self.lines.push(Value::String(line.to_string())),
+        }
+    }
+
+    /// Adds a missing value to the result, like the value of a key which does
+    /// not exist, told by `text` at the prompt.
+    pub fn none(&mut self, text: &str) {
+        match self.format {
+            Format::Text => println!("{}", text),
+            Format::Json => self.lines.push(Value::Null),
+        }
+    }
+
+    /// Fails the command with `err`.
+    pub fn error(&mut self, err: impl Display) {
+        if self.format == Format::Text {
+            println!("Error: {}", err);
+        }
+        self.error = Some(err.to_string());
+    }
+
+    /// Prints a line right away, for the commands printing what they receive
+    /// until the client exits, once their result was printed.
+    pub fn event(&mut self, line: impl Display) {
+        match self.format {
+            Format::Text => println!("{}", line),
+            Format::Json => {
+                let (number, command) = &self.command;
+                let event = json!({"line": number, "command": command, "event": line.to_string()});
+                println!("{}", event);
+            }
+        }
+    }
+
+    /// Ends the result of the command, unless it already ended, returning
+    /// whether it succeeded.
+    pub fn finish(&mut self) -> bool {
+        if !mem::replace(&mut self.running, false) {
+            return true;
+        }
+        let error = self.error.take();
+        let ok = error.is_none();
+        if self.format == Format::Json {
+            let (number, command) = &self.command;
+            let result = match error {
+                Some(err) => json!({"line": number, "command": command, "ok": false, "error": err}),
+                None => json!({
+                    "line": number,
+                    "command": command,
+                    "ok": true,
+                    "output": mem::take(&mut self.lines),
+                }),
+            };
+            println!("{}", result);
+        }
+        ok
+    }
+}
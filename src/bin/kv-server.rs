@@ -1,59 +1,309 @@
 use std::{
+    collections::BTreeMap,
     env::current_dir,
     fmt::Display,
     fs,
+    io::{self, IsTerminal},
+    path::{Path, PathBuf},
     process::exit,
-    sync::{atomic::AtomicBool, Arc},
+    sync::Arc,
+    time::Duration,
 };
 
 use clap::{Parser, ValueEnum};
-use log::{error, info, LevelFilter};
-use rust_kv::{KvEngine, KvServer, KvStore, Result, SharedQueueThreadPool, SledStore, ThreadPool};
+use rust_kv::{
+    server_tls_config, ClientIdentity, Compression, FlushMode, KvEngine, KvError, KvServer,
+    KvStore, KvStoreOptions, MemStore, NaiveThreadPool, RayonThreadPool, ReloadHandle, Replica,
+    Result, SharedQueueThreadPool, SledStore, SyncPolicy, ThreadPool,
+};
+use serde::{Deserialize, Deserializer};
+use tokio_rustls::rustls::ServerConfig;
+use tracing::{error, info};
+use tracing_subscriber::{
+    fmt, layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Registry,
+};
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
+/// The prefix of an address which is the path of a unix socket.
+const UNIX_PREFIX: &str = "unix:";
 const DEFAULT_ENGINE: Engine = Engine::Kvs;
+const DEFAULT_LOG_LEVEL: &str = "info";
+const DEFAULT_MAX_FRAME_SIZE: usize = 8 * 1024 * 1024;
+const DEFAULT_MAX_IN_FLIGHT: usize = 16;
 
 fn main() -> Result<()> {
-    env_logger::builder().filter_level(LevelFilter::Info).init();
-
-    let mut args = Arg::parse();
-    let curr_engine = current_engine()?;
-    if args.engine.is_none() {
-        args.engine = curr_engine
-    } else if curr_engine.is_some() && args.engine != curr_engine {
-        error!("engine type not match, current: {}", curr_engine.unwrap());
-        exit(-1)
+    // e.g. RUST_LOG=debug to log every request with its latency
+    let (filter, log) = reload::Layer::new(
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_LOG_LEVEL)),
+    );
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(
+            fmt::layer()
+                .with_writer(io::stderr)
+                .with_ansi(io::stderr().is_terminal()),
+        )
+        .init();
+
+    let args = Arg::parse();
+    let reloader = args.config.clone().map(|path| Reloader {
+        path,
+        args: args.clone(),
+        log: log.clone(),
+    });
+    let mut config = match &args.config {
+        Some(path) => match Config::load(path) {
+            Ok(config) => config,
+            Err(err) => {
+                error!("invalid config file {}: {}", path.display(), err);
+                exit(-1)
+            }
+        },
+        None => Config::default(),
+    };
+    config.merge(args);
+    let dir = match config.dir.take() {
+        Some(dir) => dir,
+        None => current_dir()?,
+    };
+
+    match (config.engine, current_engine(&dir)?) {
+        // nothing is read from or written to the db dir
+        (Some(Engine::Mem), _) => {}
+        (None, curr_engine) => config.engine = curr_engine,
+        (Some(engine), Some(curr_engine)) if engine != curr_engine => {
+            error!("engine type not match, current: {}", curr_engine);
+            exit(-1)
+        }
+        _ => {}
     }
 
-    if let Err(err) = run(args.engine.unwrap_or(DEFAULT_ENGINE), args.addr) {
+    let engine = config.engine.unwrap_or(DEFAULT_ENGINE);
+    if config.max_memory.is_some() && engine != Engine::Kvs {
+        error!("--max-memory is only supported by the kvs engine");
+        exit(-1)
+    }
+    if config.pool.queue_capacity.is_some() && !matches!(config.pool.kind, Pool::SharedQueue) {
+        error!("--queue-capacity is only supported by the shared-queue pool");
+        exit(-1)
+    }
+    let tls = match (&config.tls.cert, &config.tls.key) {
+        (Some(cert), Some(key)) => {
+            match server_tls_config(cert, key, config.tls.client_ca.as_deref()) {
+                Ok(config) => Some(config),
+                Err(err) => {
+                    error!("{}", err);
+                    exit(-1)
+                }
+            }
+        }
+        (None, None) if config.tls.client_ca.is_none() => None,
+        _ => {
+            error!("TLS needs both a certificate and a key");
+            exit(-1)
+        }
+    };
+    if let Err(err) = config.check_clients() {
+        error!("{}", err);
+        exit(-1)
+    }
+    if let Err(err) = config.apply_log_level(&log) {
+        error!("{}", err);
+        exit(-1)
+    }
+    let settings = Settings {
+        tls,
+        reloader,
+        snapshots: engine == Engine::Kvs,
+    };
+    if let Err(err) = run(engine, &dir, &config, settings) {
         error!("{}", err);
         exit(-1)
     }
     Ok(())
 }
 
-fn run(engine: Engine, addr: String) -> Result<()> {
-    let engine_path = current_dir()?.join("engine");
-    fs::write(engine_path, format!("{}", engine))?;
+fn run(engine: Engine, dir: &Path, config: &Config, settings: Settings) -> Result<()> {
+    if engine != Engine::Mem {
+        fs::create_dir_all(dir)?;
+        fs::write(dir.join("engine"), format!("{}", engine))?;
+    }
 
+    let addrs = if config.addr.is_empty() {
+        vec![DEFAULT_LISTENING_ADDRESS.to_owned()]
+    } else {
+        config.addr.clone()
+    };
+    if config.resp && addrs.len() > 1 {
+        return Err(KvError::StringError(
+            "the Redis protocol is served on one address only".to_owned(),
+        ));
+    }
     info!("kv-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {}", engine);
-    info!("Listening on: {}", addr);
+    info!("Listening on: {}", addrs.join(", "));
+    if settings.tls.is_some() {
+        info!("Serving over TLS");
+    }
+    if config.resp {
+        info!("Speaking the Redis protocol");
+    }
+    if let Some(primary) = &config.replica_of {
+        info!("Replicating: {}", primary);
+    }
+    if !config.admin_clients.is_empty() {
+        info!("Admin clients: {}", config.admin_clients.join(", "));
+    }
 
     match engine {
-        Engine::Kvs => run_server(KvStore::open(current_dir()?)?, addr),
-        Engine::Sled => run_server(SledStore::open(current_dir()?)?, addr),
+        Engine::Kvs => {
+            let mut options = KvStoreOptions::new()
+                .sync_policy(config.durability.sync_policy())
+                .compression(config.compaction.compression.into());
+            if let Some(threshold) = config.compaction.threshold {
+                options = options.compaction_threshold(threshold);
+            }
+            if let Some(max_segment_size) = config.compaction.max_segment_size {
+                options = options.max_segment_size(max_segment_size);
+            }
+            if let Some(max_memory) = config.max_memory {
+                info!(
+                    "Evicting least recently used keys over {} bytes",
+                    max_memory
+                );
+                options = options.max_memory(max_memory);
+            }
+            run_pool(KvStore::open_with(dir, options)?, addrs, config, settings)
+        }
+        Engine::Sled => {
+            let flush_mode = config.durability.flush_mode();
+            run_pool(
+                SledStore::open_with(dir, flush_mode)?,
+                addrs,
+                config,
+                settings,
+            )
+        }
+        Engine::Mem => run_pool(MemStore::new(), addrs, config, settings),
     }
 }
 
-fn run_server<E: KvEngine>(kv_engine: E, addr: String) -> Result<()> {
-    let mut server = KvServer::new(kv_engine, SharedQueueThreadPool::new(num_cpus::get())?);
-    server.run(addr, Arc::new(AtomicBool::new(false)))
+fn run_pool<E: KvEngine>(
+    kv_engine: E,
+    addrs: Vec<String>,
+    config: &Config,
+    settings: Settings,
+) -> Result<()> {
+    let threads = config.pool.threads.unwrap_or_else(num_cpus::get);
+    match config.pool.kind {
+        Pool::Naive => run_server(
+            kv_engine,
+            NaiveThreadPool::new(threads)?,
+            addrs,
+            config,
+            settings,
+        ),
+        Pool::SharedQueue => run_server(
+            kv_engine,
+            match config.pool.queue_capacity {
+                Some(capacity) => SharedQueueThreadPool::with_queue_capacity(threads, capacity)?,
+                None => SharedQueueThreadPool::new(threads)?,
+            },
+            addrs,
+            config,
+            settings,
+        ),
+        Pool::Rayon => run_server(
+            kv_engine,
+            RayonThreadPool::new(threads)?,
+            addrs,
+            config,
+            settings,
+        ),
+    }
+}
+
+fn run_server<E: KvEngine, T: ThreadPool>(
+    kv_engine: E,
+    pool: T,
+    addrs: Vec<String>,
+    config: &Config,
+    settings: Settings,
+) -> Result<()> {
+    if let Some(primary) = &config.replica_of {
+        Replica::new(kv_engine.clone(), primary.as_str())
+            .snapshots(settings.snapshots)
+            .start();
+    }
+    let mut builder = KvServer::builder(kv_engine, pool)
+        .addrs(addrs.iter().map(String::as_str))
+        .read_only(config.replica_of.is_some())
+        .metrics(true);
+    if let Some(max_connections) = config.max_connections_per_ip {
+        builder = builder.max_connections_per_ip(max_connections);
+    }
+    if let Some(capacity) = config.change_log {
+        builder = builder.change_log(capacity);
+    }
+    if let Some(tls) = settings.tls {
+        builder = builder.tls(tls);
+    }
+    if let Some(reloader) = settings.reloader {
+        builder = builder.on_reload(move |handle| reloader.reload(handle));
+    }
+    let mut server = builder.build();
+    config.apply(&server.reload_handle());
+    if config.resp {
+        server.run_resp(&addrs[0])
+    } else {
+        server.run()
+    }
+}
+
+/// The settings of the server besides its config.
+struct Settings {
+    tls: Option<Arc<ServerConfig>>,
+    // reloads the config file, if any
+    reloader: Option<Reloader>,
+    // whether a replica copies its primary from a snapshot, which only kvs installs
+    snapshots: bool,
+}
+
+type LogHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Reloads the config file of a running server, on SIGHUP or with the reload admin command.
+///
+/// Only the logs, admin clients and limits change, the other settings need a restart.
+struct Reloader {
+    path: PathBuf,
+    // the command line, which still overrides the file
+    args: Arg,
+    log: LogHandle,
+}
+
+impl Reloader {
+    fn reload(&self, handle: &ReloadHandle) -> Result<()> {
+        let mut config = Config::load(&self.path).map_err(|err| {
+            KvError::StringError(format!(
+                "invalid config file {}: {}",
+                self.path.display(),
+                err
+            ))
+        })?;
+        config.merge(self.args.clone());
+        config
+            .check_clients()
+            .map_err(|err| KvError::StringError(err.to_owned()))?;
+        config.apply_log_level(&self.log)?;
+        config.apply(handle);
+        info!("reloaded {}", self.path.display());
+        Ok(())
+    }
 }
 
 /// retrieve engine from db dir
-fn current_engine() -> Result<Option<Engine>> {
-    let engine_path = current_dir()?.join("engine");
+fn current_engine(dir: &Path) -> Result<Option<Engine>> {
+    let engine_path = dir.join("engine");
     if !engine_path.exists() {
         return Ok(None);
     }
@@ -66,22 +316,333 @@ fn current_engine() -> Result<Option<Engine>> {
     Ok(None)
 }
 
-#[derive(Parser)]
+#[derive(Parser, Clone)]
 #[command(version, about, long_about = None)]
 struct Arg {
-    /// The address that server listening
-    #[arg(short, long, default_value=DEFAULT_LISTENING_ADDRESS)]
-    addr: String,
+    /// The TOML config file of the server.
+    /// The other options override its values.
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// The address that server listening. Default to 127.0.0.1:4000.
+    /// unix:<path> listens on a unix socket. Can be repeated to listen on
+    /// several addresses.
+    #[arg(short, long)]
+    addr: Vec<String>,
     /// The storage engine that server use.
     /// Can be retrieved from the db dir. Default to kvs.
+    /// The mem engine keeps nothing on disk.
     #[arg(value_enum, short, long)]
     engine: Option<Engine>,
+    /// The db dir. Default to the current dir.
+    #[arg(short, long)]
+    dir: Option<PathBuf>,
+    /// The thread pool running the requests. Default to shared-queue.
+    #[arg(value_enum, long)]
+    pool: Option<Pool>,
+    /// The number of threads of the pool. Default to the number of CPUs.
+    #[arg(long)]
+    threads: Option<usize>,
+    /// Answer requests with a busy error once this many wait for a thread,
+    /// rather than queueing them. Default to an unbounded queue.
+    /// Only supported by the shared-queue pool.
+    #[arg(long)]
+    queue_capacity: Option<usize>,
+    /// Evict the least recently used keys once the live keys and values
+    /// take more than this many bytes, so the server works as a persistent cache.
+    /// Only supported by the kvs engine.
+    #[arg(long)]
+    max_memory: Option<u64>,
+    /// The PEM file of the certificate chain to serve clients over TLS with.
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// The PEM file of the private key of the TLS certificate.
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// The PEM file of the CA certificates that sign client certificates.
+    /// Clients must then present a certificate signed by one of them.
+    #[arg(long)]
+    tls_client_ca: Option<PathBuf>,
+    /// Speak the Redis protocol (RESP2) instead of JSON, for Redis clients
+    /// like redis-cli. Supports the GET, SET, DEL, EXISTS and PING commands.
+    #[arg(long)]
+    resp: bool,
+    /// Replicate the primary server at this address, whose engine must be
+    /// kvs. The server then serves reads only.
+    #[arg(long)]
+    replica_of: Option<String>,
+    /// Close the connections of an IP which already has this many open.
+    /// Unlimited by default.
+    #[arg(long)]
+    max_connections_per_ip: Option<usize>,
+    /// Keep the last this many changes of the engine for clients to tail
+    /// with the changes command. Needs the kvs engine. None by default.
+    #[arg(long)]
+    change_log: Option<usize>,
+    /// Run the admin commands (compact, stats, flush) only for the clients
+    /// presenting a certificate with this common name. Can be repeated.
+    /// Needs --tls-client-ca. By default every client may run them.
+    #[arg(long)]
+    admin_client: Vec<String>,
+}
+
+/// The settings of the server, read from the `--config` file.
+///
+/// Every key is optional, and unknown keys are refused. Relative paths are
+/// relative to the dir of the file.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    engine: Option<Engine>,
+    dir: Option<PathBuf>,
+    // a string, or an array of them
+    #[serde(deserialize_with = "one_or_many")]
+    addr: Vec<String>,
+    max_memory: Option<u64>,
+    resp: bool,
+    replica_of: Option<String>,
+    max_connections_per_ip: Option<usize>,
+    change_log: Option<usize>,
+    admin_clients: Vec<String>,
+    // the clients each namespace is restricted to, by name
+    namespaces: BTreeMap<String, NamespaceConfig>,
+    // used unless RUST_LOG is set
+    log_level: Option<String>,
+    pool: PoolConfig,
+    durability: DurabilityConfig,
+    compaction: CompactionConfig,
+    tls: TlsConfig,
+    limits: LimitsConfig,
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+impl Config {
+    fn load(path: &Path) -> std::result::Result<Config, String> {
+        let content = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        let mut config: Config = toml::from_str(&content).map_err(|err| err.to_string())?;
+        let base = path.parent().unwrap_or(Path::new(""));
+        for path in [
+            &mut config.dir,
+            &mut config.tls.cert,
+            &mut config.tls.key,
+            &mut config.tls.client_ca,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *path = base.join(&*path);
+        }
+        for addr in &mut config.addr {
+            if let Some(socket) = addr.strip_prefix(UNIX_PREFIX) {
+                *addr = format!("{}{}", UNIX_PREFIX, base.join(socket).display());
+            }
+        }
+        Ok(config)
+    }
+
+    fn check_clients(&self) -> std::result::Result<(), &'static str> {
+        if !self.admin_clients.is_empty() && self.tls.client_ca.is_none() {
+            return Err(
+                "admin clients are identified by TLS client certificates, which need a client CA",
+            );
+        }
+        if !self.namespaces.is_empty() && self.tls.client_ca.is_none() {
+            return Err(
+                "namespace clients are identified by TLS client certificates, which need a client CA",
+            );
+        }
+        Ok(())
+    }
+
+    /// Sets the level of the logs, unless `RUST_LOG` sets it.
+    fn apply_log_level(&self, log: &LogHandle) -> Result<()> {
+        if std::env::var_os(EnvFilter::DEFAULT_ENV).is_some() {
+            return Ok(());
+        }
+        let level = self.log_level.as_deref().unwrap_or(DEFAULT_LOG_LEVEL);
+        let filter = EnvFilter::try_new(level)
+            .map_err(|err| KvError::StringError(format!("invalid log level: {}", err)))?;
+        log.reload(filter)
+            .map_err(|err| KvError::StringError(err.to_string()))
+    }
+
+    /// Applies the settings a running server reloads, besides the level of
+    /// the logs, the keys missing from the file taking their default.
+    fn apply(&self, handle: &ReloadHandle) {
+        if self.admin_clients.is_empty() && self.namespaces.is_empty() {
+            handle.allow_all();
+        } else {
+            let admins = self.admin_clients.clone();
+            let namespaces = self.namespaces.clone();
+            handle.authorize(move |identity, request| {
+                let name = identity.and_then(ClientIdentity::common_name);
+                let listed = |clients: &[String]| {
+                    name.is_some_and(|name| clients.iter().any(|client| client == name))
+                };
+                (admins.is_empty() || !request.is_admin() || listed(&admins))
+                    && request.namespaces().into_iter().all(|namespace| {
+                        namespaces
+                            .get(namespace)
+                            .is_none_or(|config| listed(&config.clients))
+                    })
+            });
+        }
+        let limits = &self.limits;
+        handle.set_idle_timeout(limits.idle_timeout_ms.map(Duration::from_millis));
+        handle.set_request_timeout(limits.request_timeout_ms.map(Duration::from_millis));
+        handle.set_max_frame_size(limits.max_frame_size.unwrap_or(DEFAULT_MAX_FRAME_SIZE));
+        handle.set_max_in_flight(limits.max_in_flight.unwrap_or(DEFAULT_MAX_IN_FLIGHT));
+    }
+
+    /// Overrides the values of the file with the ones given on the command line.
+    fn merge(&mut self, args: Arg) {
+        self.engine = args.engine.or(self.engine);
+        self.dir = args.dir.or(self.dir.take());
+        if !args.addr.is_empty() {
+            self.addr = args.addr;
+        }
+        self.max_memory = args.max_memory.or(self.max_memory);
+        self.resp |= args.resp;
+        self.replica_of = args.replica_of.or(self.replica_of.take());
+        self.max_connections_per_ip = args.max_connections_per_ip.or(self.max_connections_per_ip);
+        self.change_log = args.change_log.or(self.change_log);
+        if !args.admin_client.is_empty() {
+            self.admin_clients = args.admin_client;
+        }
+        self.pool.kind = args.pool.unwrap_or(self.pool.kind);
+        self.pool.threads = args.threads.or(self.pool.threads);
+        self.pool.queue_capacity = args.queue_capacity.or(self.pool.queue_capacity);
+        if args.tls_cert.is_some() {
+            self.tls.cert = args.tls_cert;
+            self.tls.key = args.tls_key;
+        }
+        self.tls.client_ca = args.tls_client_ca.or(self.tls.client_ca.take());
+    }
+}
+
+/// Reads a string, or an array of strings.
+fn one_or_many<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PoolConfig {
+    kind: Pool,
+    threads: Option<usize>,
+    queue_capacity: Option<usize>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct DurabilityConfig {
+    // the default of each engine if `None`
+    sync: Option<SyncMode>,
+    sync_interval_ms: Option<u64>,
+}
+
+impl DurabilityConfig {
+    fn sync_interval(&self) -> Duration {
+        Duration::from_millis(self.sync_interval_ms.unwrap_or(1000))
+    }
+
+    fn sync_policy(&self) -> SyncPolicy {
+        match self.sync {
+            None => SyncPolicy::default(),
+            Some(SyncMode::Never) => SyncPolicy::Never,
+            Some(SyncMode::Always) => SyncPolicy::Always,
+            Some(SyncMode::Interval) => SyncPolicy::Interval(self.sync_interval()),
+        }
+    }
+
+    /// Sled writes to the disk only when it flushes.
+    fn flush_mode(&self) -> FlushMode {
+        match self.sync {
+            None => FlushMode::default(),
+            Some(SyncMode::Never) => FlushMode::OnDrop,
+            Some(SyncMode::Always) => FlushMode::EveryOp,
+            Some(SyncMode::Interval) => FlushMode::Interval(self.sync_interval()),
+        }
+    }
+}
+
+/// When writes are synced to the disk, for either engine.
+#[derive(Copy, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SyncMode {
+    Never,
+    Always,
+    Interval,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CompactionConfig {
+    threshold: Option<u64>,
+    max_segment_size: Option<u64>,
+    compression: CompressionConfig,
+}
+
+#[derive(Copy, Clone, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum CompressionConfig {
+    #[default]
+    None,
+    Lz4,
+}
+
+impl From<CompressionConfig> for Compression {
+    fn from(compression: CompressionConfig) -> Compression {
+        match compression {
+            CompressionConfig::None => Compression::None,
+            CompressionConfig::Lz4 => Compression::Lz4,
+        }
+    }
+}
+
+/// The limits of the connections, none by default but the frame size and
+/// requests in flight of the server.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LimitsConfig {
+    idle_timeout_ms: Option<u64>,
+    request_timeout_ms: Option<u64>,
+    max_frame_size: Option<usize>,
+    max_in_flight: Option<usize>,
+}
+
+/// A namespace, the bucket of that name, run only for some clients.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NamespaceConfig {
+    // the common names of the certificates of the clients
+    clients: Vec<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TlsConfig {
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    client_ca: Option<PathBuf>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
 enum Engine {
     Kvs,
     Sled,
+    Mem,
 }
 
 impl Display for Engine {
@@ -89,6 +650,16 @@ impl Display for Engine {
         match self {
             Engine::Kvs => write!(f, "kvs"),
             Engine::Sled => write!(f, "sled"),
+            Engine::Mem => write!(f, "mem"),
         }
     }
 }
+
+#[derive(Copy, Clone, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Pool {
+    Naive,
+    #[default]
+    SharedQueue,
+    Rayon,
+}
@@ -0,0 +1,189 @@
+use std::{
+    fmt::Display,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    mem,
+    path::{Path, PathBuf},
+    process::exit,
+};
+
+use clap::{Parser, Subcommand, ValueEnum};
+use rust_kv::{KvEngine, KvStore, Result, SledStore};
+
+/// The number of keys `scan` reads from the engine at once.
+const SCAN_PAGE: usize = 1000;
+
+fn main() {
+    let args = Arg::parse();
+    if let Err(err) = run(args) {
+        eprintln!("Error: {}", err);
+        exit(-1)
+    }
+}
+
+fn run(args: Arg) -> Result<()> {
+    let curr_engine = current_engine(&args.dir)?;
+    let engine = match (args.engine, curr_engine) {
+        (Some(engine), Some(curr_engine)) if engine != curr_engine => {
+            eprintln!("engine type not match, current: {}", curr_engine);
+            exit(-1)
+        }
+        (engine, curr_engine) => engine.or(curr_engine).unwrap_or(Engine::Kvs),
+    };
+    if curr_engine.is_none() {
+        // let kv-server pick the same engine for this dir
+        fs::create_dir_all(&args.dir)?;
+        fs::write(args.dir.join("engine"), engine.to_string())?;
+    }
+    match engine {
+        Engine::Kvs => run_command(KvStore::open(&args.dir)?, args.command),
+        Engine::Sled => run_command(SledStore::open(&args.dir)?, args.command),
+    }
+}
+
+fn run_command<E: KvEngine>(engine: E, command: Commands) -> Result<()> {
+    match command {
+        Commands::Export { file } => {
+            let count = match file {
+                Some(file) => engine.export(BufWriter::new(File::create(file)?))?,
+                None => engine.export(BufWriter::new(io::stdout().lock()))?,
+            };
+            eprintln!("exported {} pairs", count);
+        }
+        Commands::Import { file } => {
+            let count = match file {
+                Some(file) => engine.import(BufReader::new(File::open(file)?))?,
+                None => engine.import(io::stdin().lock())?,
+            };
+            eprintln!("imported {} pairs", count);
+        }
+        Commands::Scan {
+            prefix,
+            limit,
+            values,
+        } => {
+            let count = scan(&engine, &prefix, limit.unwrap_or(usize::MAX), values)?;
+            eprintln!("listed {} keys", count);
+        }
+    }
+    Ok(())
+}
+
+/// Prints at most `limit` keys starting with `prefix` in key order, with
+/// their values if `values`, returning the number of keys printed.
+///
+/// The keys are read a page at a time, so a large store is not loaded at once.
+fn scan<E: KvEngine>(engine: &E, prefix: &str, limit: usize, values: bool) -> Result<usize> {
+    let mut out = BufWriter::new(io::stdout().lock());
+    let mut page = Vec::new();
+    let mut after = None;
+    // the pages follow the prefix, which may be a key of its own
+    if !prefix.is_empty() {
+        if engine.get(prefix.to_owned())?.is_some() {
+            page.push(prefix.to_owned());
+        }
+        after = Some(prefix.to_owned());
+    }
+    let mut count = 0;
+    while count < limit {
+        let keys = engine.scan_keys(after.take(), SCAN_PAGE)?;
+        let mut end = keys.len() < SCAN_PAGE;
+        after = keys.last().cloned();
+        page.extend(keys);
+        let matching = page
+            .iter()
+            .take_while(|key| key.starts_with(prefix))
+            .count();
+        end |= matching < page.len();
+        page.truncate(matching.min(limit - count));
+        count += page.len();
+
+        let keys = mem::take(&mut page);
+        if values {
+            let values = engine.multi_get(keys.clone())?;
+            for (key, value) in keys.iter().zip(values) {
+                // a key expiring meanwhile is skipped
+                if let Some(value) = value {
+                    writeln!(out, "{}\t{}", key, value)?;
+                }
+            }
+        } else {
+            for key in &keys {
+                writeln!(out, "{}", key)?;
+            }
+        }
+        if end {
+            break;
+        }
+    }
+    out.flush()?;
+    Ok(count)
+}
+
+/// retrieve engine from db dir
+fn current_engine(dir: &Path) -> Result<Option<Engine>> {
+    let engine_path = dir.join("engine");
+    if !engine_path.exists() {
+        return Ok(None);
+    }
+    let engine_str = fs::read_to_string(engine_path)?;
+    Ok(Engine::value_variants()
+        .iter()
+        .find(|engine| engine.to_string() == engine_str)
+        .copied())
+}
+
+#[derive(Parser)]
+#[command(version, about = "Offline tools for a kv-server db dir", long_about = None)]
+struct Arg {
+    /// The db dir of the server.
+    #[arg(short, long, default_value = ".")]
+    dir: PathBuf,
+    /// The storage engine of the db dir.
+    /// Can be retrieved from the db dir. Default to kvs.
+    #[arg(value_enum, short, long)]
+    engine: Option<Engine>,
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Write all key/value pairs as JSON lines
+    Export {
+        /// The output file, stdout if omitted
+        file: Option<PathBuf>,
+    },
+    /// Set the key/value pairs read from JSON lines
+    Import {
+        /// The input file, stdin if omitted
+        file: Option<PathBuf>,
+    },
+    /// List the keys in key order, one per line
+    Scan {
+        /// Only list the keys starting with the prefix
+        #[arg(long, default_value = "")]
+        prefix: String,
+        /// The maximum number of keys to list, all if omitted
+        #[arg(long)]
+        limit: Option<usize>,
+        /// Print the value of each key after it, separated by a tab
+        #[arg(long)]
+        values: bool,
+    },
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum Engine {
+    Kvs,
+    Sled,
+}
+
+impl Display for Engine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Engine::Kvs => write!(f, "kvs"),
+            Engine::Sled => write!(f, "sled"),
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use std::{
+    collections::VecDeque,
+    sync::{mpsc::Receiver, Arc, Mutex, Weak},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    select,
+    sync::watch,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::{
+    replication::write_response, ChangeEvent, ChangeKind, Codec, KvEngine, KvError, Response,
+    Result,
+};
+
+/// Changes written to a consumer at once.
+const CONSUMER_BATCH: usize = 1024;
+
+/// The last committed changes of an engine, numbered by their offset, which
+/// clients tail with `Request::Changes`.
+///
+/// The offsets of a server start at the time it started, in microseconds,
+/// so they grow across restarts: the offsets of a previous run look like
+/// changes no longer retained, rather than like other changes.
+pub(crate) struct ChangeLog {
+    changes: Mutex<VecDeque<ChangeEvent>>,
+    capacity: usize,
+    // the offset of the next change, which the consumers wait for
+    next: watch::Sender<u64>,
+}
+
+impl ChangeLog {
+    /// Starts recording the changes of `engine`, keeping the last `capacity` ones.
+    pub(crate) fn start<E: KvEngine>(engine: &Arc<E>, capacity: usize) -> Result<Arc<ChangeLog>> {
+        let changes = engine.subscribe()?;
+        let first = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_micros() as u64);
+        let (next, _) = watch::channel(first);
+        let log = Arc::new(ChangeLog {
+            changes: Mutex::new(VecDeque::new()),
+            capacity: capacity.max(1),
+            next,
+        });
+        let engine = Arc::downgrade(engine);
+        let recorder = log.clone();
+        // reading the engine blocks, so it runs on a thread of its own
+        thread::spawn(move || recorder.record(&engine, changes));
+        Ok(log)
+    }
+
+    /// Appends the `changes` of `engine` until the server drops the engine.
+    fn record<E: KvEngine>(&self, engine: &Weak<E>, changes: Receiver<(String, ChangeKind)>) {
+        for (key, kind) in changes {
+            let Some(engine) = engine.upgrade() else {
+                return;
+            };
+            let value = match kind {
+                ChangeKind::Set => match engine.get(key.clone()) {
+                    Ok(value) => value,
+                    Err(err) => {
+                        warn!("cannot record the change of key {}: {}", key, err);
+                        continue;
+                    }
+                },
+                ChangeKind::Remove => None,
+            };
+            // a key set, then removed before being read, is recorded removed
+            let kind = match value {
+                Some(_) => ChangeKind::Set,
+                None => ChangeKind::Remove,
+            };
+            let mut log = self.changes.lock().unwrap();
+            let offset = *self.next.borrow();
+            if log.len() == self.capacity {
+                log.pop_front();
+            }
+            log.push_back(ChangeEvent {
+                offset,
+                key,
+                kind,
+                value,
+            });
+            self.next.send_replace(offset + 1);
+        }
+    }
+
+    /// Returns the offset of the oldest change retained, or of the next one
+    /// if none is.
+    fn oldest(&self) -> u64 {
+        let log = self.changes.lock().unwrap();
+        log.front()
+            .map_or_else(|| *self.next.borrow(), |change| change.offset)
+    }
+
+    /// Returns up to `max` changes from `offset` on, failing if the change at
+    /// `offset` is no longer retained or is not an offset of the log yet.
+    fn read(&self, offset: u64, max: usize) -> Result<Vec<ChangeEvent>> {
+        let log = self.changes.lock().unwrap();
+        let next = *self.next.borrow();
+        let oldest = log.front().map_or(next, |change| change.offset);
+        if offset < oldest {
+            return Err(KvError::StringError(format!(
+                "Offset {} is no longer retained, the oldest is {}",
+                offset, oldest
+            )));
+        }
+        if offset > next {
+            return Err(KvError::StringError(format!(
+                "Offset {} is past the end of the change log at {}",
+                offset, next
+            )));
+        }
+        let start = (offset - oldest) as usize;
+        Ok(log.range(start..).take(max).cloned().collect())
+    }
+}
+
+/// Streams the changes of `log` from `offset`, from the oldest one retained
+/// if `None`, to a client connected over `stream`, whose request numbered
+/// `id` asked for them, until the server stops.
+///
+/// A client falling behind the changes retained is disconnected, and
+/// resuming from its next offset then fails, so it knows it missed some.
+pub(crate) async fn serve_consumer<S>(
+    log: Option<Arc<ChangeLog>>,
+    mut stream: S,
+    codec: Codec,
+    id: u64,
+    offset: Option<u64>,
+    stop: CancellationToken,
+) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let Some(log) = log else {
+        let err = KvError::Unsupported("changes without a change log".to_owned());
+        write_response(&mut stream, codec, id, Response::Err(format!("{}", err))).await?;
+        return Ok(());
+    };
+    // before reading, so that no change comes unnoticed
+    let mut appended = log.next.subscribe();
+    let mut offset = offset.unwrap_or_else(|| log.oldest());
+    if let Err(err) = log.read(offset, 0) {
+        write_response(&mut stream, codec, id, Response::Err(format!("{}", err))).await?;
+        return Ok(());
+    }
+    write_response(&mut stream, codec, id, Response::Ok(None)).await?;
+    info!("consuming the changes from offset {}", offset);
+
+    while !stop.is_cancelled() {
+        let changes = match log.read(offset, CONSUMER_BATCH) {
+            Ok(changes) => changes,
+            Err(err) => {
+                warn!("closing a consumer of the changes: {}", err);
+                break;
+            }
+        };
+        let Some(last) = changes.last() else {
+            select! {
+                biased;
+                _ = stop.cancelled() => break,
+                _ = appended.changed() => continue,
+            }
+        };
+        offset = last.offset + 1;
+        let mut data = Vec::new();
+        for change in &changes {
+            codec.encode_into(&mut data, change)?;
+        }
+        stream.write_all(&data).await?;
+        stream.flush().await?;
+    }
+    stream.shutdown().await?;
+    info!("consumer of the changes disconnected");
+    Ok(())
+}
@@ -1,30 +1,253 @@
 use std::{
-    io::{BufReader, BufWriter, Write},
-    net::TcpStream,
+    collections::{hash_map::RandomState, BTreeMap, HashMap},
+    hash::{BuildHasher, Hasher},
+    io::{self, BufReader, Write},
+    iter, mem,
+    net::IpAddr,
+    sync::{Arc, Mutex, Weak},
+    thread,
+    time::{Duration, Instant},
+    vec,
 };
 
-use crate::{KvError, Request, Response, Result};
-use serde::Deserialize;
-use serde_json::{de::IoRead, Deserializer};
+use crate::{
+    client_cache::ReadCache,
+    common::{AdminCommand, BulkFrame, LoadSummary, RequestFrame, ResponseFrame},
+    connection::{Connector, SharedConnection, Stream},
+    replication::Replication,
+    ChangeEvent, ClientInfo, Codec, EngineStats, KvError, MonitorEvent, Request, Response, Result,
+    ServerInfo, WatchEvent, WriteBatch,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio_rustls::rustls::ClientConfig;
+use tracing::{debug, debug_span, field};
+
+// the delay before the first retry of a request, doubled at each retry
+const INITIAL_BACKOFF: Duration = Duration::from_millis(20);
+// the longest delay between two retries of a request
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+// how long the clients of `KvClient::new_multi` retry a request, failing over between
+// the servers
+const FAILOVER_BUDGET: Duration = Duration::from_secs(5);
+// the number of pairs from which a bulk load sends a frame
+const BULK_FRAME_PAIRS: usize = 1024;
+// the bytes of keys and values from which a bulk load sends a frame, well under the
+// max frame size of servers
+const BULK_FRAME_SIZE: usize = 1024 * 1024;
+// the keys a prefix scan examines a page
+const SCAN_PAGE_KEYS: usize = 256;
+// the times `KvClient::transaction` runs a transaction before giving up on conflicts
+const TRANSACTION_ATTEMPTS: usize = 10;
+
+// The hook a client reports each request to, from `KvClientBuilder::on_request`
+type RequestHook = Arc<dyn Fn(&RequestEvent) + Send + Sync>;
 
+// A client of a server. Its clones share its connection, on which the requests of every
+// clone are in flight at once, so one client serves many threads; each clone targets its
+// own bucket
+#[derive(Clone)]
 pub struct KvClient {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
-    writer: BufWriter<TcpStream>,
+    connector: Connector,
+    // replaced by a new connection once broken, if the client reconnects
+    connection: Arc<Mutex<SharedConnection>>,
+    retry: Retry,
+    // how long a request waits for its response, forever if `None`
+    request_timeout: Option<Duration>,
+    // the bucket that requests target, the default keyspace if `None`
+    bucket: Option<String>,
+    on_request: Option<RequestHook>,
+    // shared by the clones, as they talk to the same server
+    breaker: Option<Arc<Breaker>>,
+    cache: Option<Arc<ReadCache>>,
+}
+
+// What a client reports of a request it sent, to the hook of `KvClientBuilder::on_request`
+#[derive(Debug)]
+pub struct RequestEvent<'a> {
+    // the name of the operation, like get
+    pub op: &'static str,
+    // the time from sending the request to its response, the retries included
+    pub latency: Duration,
+    // the times the request was sent again on a new connection
+    pub retries: u32,
+    // the error the request failed with, if any; the errors of the server are
+    // `KvError::StringError`s
+    pub error: Option<&'a KvError>,
+    // the state of the circuit breaker once the request completed, `None` without one
+    pub circuit: Option<CircuitState>,
+}
+
+// The state of the circuit breaker of a client, from `KvClientBuilder::circuit_breaker`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    // the requests are sent
+    Closed,
+    // the requests fail fast with `KvError::CircuitOpen` until the cooldown passed
+    Open,
+    // the cooldown passed, so the next request is sent to probe the server; the
+    // others fail fast until it completes
+    HalfOpen,
+}
+
+// A circuit breaker, failing the requests fast once too many failed in a row
+struct Breaker {
+    // the failures in a row which open the circuit
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Default)]
+struct BreakerState {
+    // the failures in a row
+    failures: u32,
+    // when the circuit opened, `None` while closed
+    opened: Option<Instant>,
+    // whether a request probes the server once the cooldown passed
+    probing: bool,
+}
+
+impl Breaker {
+    // whether a request may be sent, failing with `KvError::CircuitOpen` otherwise
+    fn allow(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        match state.opened {
+            None => Ok(()),
+            Some(opened) if opened.elapsed() < self.cooldown || state.probing => {
+                Err(KvError::CircuitOpen)
+            }
+            Some(_) => {
+                state.probing = true;
+                Ok(())
+            }
+        }
+    }
+
+    // record the outcome of a request sent, opening the circuit after threshold
+    // failures in a row, or once a probe failed
+    fn record(&self, failed: bool) {
+        let mut state = self.state.lock().unwrap();
+        if !failed {
+            *state = BreakerState::default();
+            return;
+        }
+        state.failures += 1;
+        if state.probing || state.failures >= self.threshold {
+            if state.opened.is_none() || state.probing {
+                debug!("opening the circuit after {} failures", state.failures);
+            }
+            state.opened = Some(Instant::now());
+            state.probing = false;
+        }
+    }
+
+    fn state(&self) -> CircuitState {
+        let state = self.state.lock().unwrap();
+        match state.opened {
+            None => CircuitState::Closed,
+            Some(opened) if opened.elapsed() < self.cooldown => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+}
+
+// How a client reconnects and retries its requests once its connection broke
+#[derive(Clone, Copy, Default)]
+struct Retry {
+    // how long a request is retried for, after it first failed; `None` to neither
+    // reconnect nor retry
+    budget: Option<Duration>,
+    // whether the sets and removes are retried too
+    writes: bool,
 }
 
 impl KvClient {
     // create a KvClient with server addr
-    pub fn new(addr: &String) -> Result<KvClient> {
-        let tcp_reader = TcpStream::connect(addr)?;
-        let tcp_writer = tcp_reader.try_clone()?;
-        Ok(KvClient {
-            reader: Deserializer::from_reader(BufReader::new(tcp_reader)),
-            writer: BufWriter::new(tcp_writer),
-        })
+    pub fn new(addr: &str) -> Result<KvClient> {
+        KvClient::builder(addr).build()
+    }
+
+    // create a KvClient talking TLS with server addr, whose host is the name verified
+    // against the server certificate, with a config built by `client_tls_config`
+    pub fn connect_tls(addr: &str, config: Arc<ClientConfig>) -> Result<KvClient> {
+        KvClient::builder(addr).tls(config).build()
+    }
+
+    // create a KvClient with the addrs of the same data, like the servers of a replicated
+    // pair, connected to the first server accepting the connection; once the connection
+    // broke, it reconnects to the same server, or else fails over to the next ones in
+    // turn, retrying the requests for a few seconds like `KvClientBuilder::reconnect`
+    pub fn new_multi(addrs: Vec<String>) -> Result<KvClient> {
+        KvClient::builder_multi(addrs)
+            .reconnect(FAILOVER_BUDGET)
+            .build()
+    }
+
+    // start building a KvClient with server addr, to choose how it connects
+    pub fn builder(addr: &str) -> KvClientBuilder {
+        KvClient::builder_multi(vec![addr.to_owned()])
+    }
+
+    // start building a KvClient with the addrs of the same data, failing over from one to
+    // the next when connecting fails; the names are resolved again at each connection,
+    // so the client follows a name moved to another host. It only fails over once
+    // connected if it reconnects, with `KvClientBuilder::reconnect`
+    pub fn builder_multi(addrs: Vec<String>) -> KvClientBuilder {
+        KvClientBuilder {
+            addrs,
+            tls: None,
+            codec: Codec::default(),
+            retry: Retry::default(),
+            request_timeout: None,
+            keepalive: None,
+            on_request: None,
+            max_frame_size: None,
+            breaker: None,
+            cache: None,
+        }
     }
 
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        self.request(Request::Get(key))
+        let cache = self.cache.clone().filter(|_| self.bucket.is_none());
+        let Some(cache) = cache else {
+            return match self.request(Request::Get(key))? {
+                Response::Ok(value) => Ok(value),
+                _ => Err(KvError::UnexpectedResponse),
+            };
+        };
+        if let Some(value) = cache.get(&key) {
+            return Ok(value);
+        }
+        let epoch = cache.epoch();
+        let value = match self.request(Request::Get(key.clone()))? {
+            Response::Ok(value) => value,
+            _ => return Err(KvError::UnexpectedResponse),
+        };
+        if let Some(epoch) = epoch {
+            cache.insert(key, value.clone(), epoch);
+        }
+        Ok(value)
+    }
+
+    // get the value of key decoded from JSON, like the values written by `set_as` or a
+    // `TypedStore`; fails with `KvError::Serde` if the value is not valid JSON for T
+    pub fn get_as<T: DeserializeOwned>(&mut self, key: String) -> Result<Option<T>> {
+        self.get(key)?
+            .map(|value| Ok(serde_json::from_str(&value)?))
+            .transpose()
+    }
+
+    // set the value of key to value encoded as JSON
+    pub fn set_as<T: Serialize + ?Sized>(&mut self, key: String, value: &T) -> Result<()> {
+        self.set(key, serde_json::to_string(value)?)
+    }
+
+    // get the values of many keys in one round trip, in the order of keys
+    pub fn multi_get(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        match self.request(Request::MultiGet(keys))? {
+            Response::Values(values) => Ok(values),
+            _ => Err(KvError::UnexpectedResponse),
+        }
     }
 
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
@@ -37,12 +260,1149 @@ impl KvClient {
         Ok(())
     }
 
-    fn request(&mut self, req: Request) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &req)?;
-        self.writer.flush()?;
-        match Response::deserialize(&mut self.reader)? {
-            Response::Ok(resp) => Ok(resp),
-            Response::Err(msg) => Err(KvError::StringError(msg)),
+    // apply all writes of the batch atomically
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.request(Request::WriteBatch(batch))?;
+        Ok(())
+    }
+
+    // set the value of key, which expires after ttl
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.request(Request::Expire(key, value, ttl))?;
+        Ok(())
+    }
+
+    // get the remaining time to live of key, `None` if it never expires
+    pub fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        match self.request(Request::Ttl(key))? {
+            Response::Ttl(ttl) => Ok(ttl),
+            _ => Err(KvError::UnexpectedResponse),
+        }
+    }
+
+    // set the value of key to bytes
+    pub fn set_bytes(&mut self, key: String, value: Vec<u8>) -> Result<()> {
+        self.request(Request::SetBytes(key, value))?;
+        Ok(())
+    }
+
+    // get the value of key as bytes
+    pub fn get_bytes(&mut self, key: String) -> Result<Option<Vec<u8>>> {
+        match self.request(Request::GetBytes(key))? {
+            Response::Bytes(value) => Ok(value),
+            _ => Err(KvError::UnexpectedResponse),
+        }
+    }
+
+    // set key value only if key does not exist, returning whether it was set
+    pub fn set_if_absent(&mut self, key: String, value: String) -> Result<bool> {
+        match self.request(Request::SetIfAbsent(key, value))? {
+            Response::Bool(set) => Ok(set),
+            _ => Err(KvError::UnexpectedResponse),
+        }
+    }
+
+    // set key value only if key already exists, returning whether it was set
+    pub fn set_if_present(&mut self, key: String, value: String) -> Result<bool> {
+        match self.request(Request::SetIfPresent(key, value))? {
+            Response::Bool(set) => Ok(set),
+            _ => Err(KvError::UnexpectedResponse),
+        }
+    }
+
+    // add delta to the integer value of key, returning the new value
+    pub fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        match self.request(Request::Incr(key, delta))? {
+            Response::Int(value) => Ok(value),
+            _ => Err(KvError::UnexpectedResponse),
+        }
+    }
+
+    // subtract delta from the integer value of key, returning the new value
+    pub fn decr(&mut self, key: String, delta: i64) -> Result<i64> {
+        self.incr(key, delta.checked_neg().ok_or(KvError::NotAnInteger)?)
+    }
+
+    // get the number of live keys
+    pub fn len(&mut self) -> Result<usize> {
+        match self.request(Request::Len)? {
+            Response::Len(len) => Ok(len),
+            _ => Err(KvError::UnexpectedResponse),
+        }
+    }
+
+    // whether there is no live key
+    pub fn is_empty(&mut self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    // reclaim the space of the stale records of the engine now
+    pub fn compact(&mut self) -> Result<()> {
+        self.request(Request::Admin(AdminCommand::Compact))?;
+        Ok(())
+    }
+
+    // get the statistics of the engine
+    pub fn stats(&mut self) -> Result<EngineStats> {
+        match self.request(Request::Admin(AdminCommand::Stats))? {
+            Response::Stats(stats) => Ok(stats),
+            _ => Err(KvError::UnexpectedResponse),
+        }
+    }
+
+    // sync the writes made so far to the disk, whatever the engine syncs writes after
+    pub fn flush(&mut self) -> Result<()> {
+        self.request(Request::Admin(AdminCommand::Flush))?;
+        Ok(())
+    }
+
+    // reload the settings of the server, with the reload hook it was built with; the
+    // settings belong to the server, so this ignores the bucket
+    pub fn reload(&mut self) -> Result<()> {
+        self.send(Request::Admin(AdminCommand::Reload))?;
+        Ok(())
+    }
+
+    // run the custom command name the server registered, with the payload, returning the
+    // value its handler answered with
+    pub fn custom(&mut self, name: String, payload: String) -> Result<Option<String>> {
+        match self.request(Request::Custom(name, payload))? {
+            Response::Ok(value) => Ok(value),
+            _ => Err(KvError::UnexpectedResponse),
+        }
+    }
+
+    // check the server is alive, returning the payload it echoes; the server answers
+    // without running anything against the engine, so this ignores the bucket
+    pub fn ping(&mut self, payload: Option<String>) -> Result<Option<String>> {
+        match self.send(Request::Ping(payload))? {
+            Response::Pong(payload) => Ok(payload),
+            _ => Err(KvError::UnexpectedResponse),
+        }
+    }
+
+    // get the state of the server, its version, uptime, engine and counts among them; the
+    // state belongs to the server, so this ignores the bucket
+    pub fn info(&mut self) -> Result<ServerInfo> {
+        match self.send(Request::Info)? {
+            Response::Info(info) => Ok(info),
+            _ => Err(KvError::UnexpectedResponse),
+        }
+    }
+
+    // get the number of connections open from each peer IP, ordered by IP; the connections
+    // belong to the server, so this ignores the bucket
+    pub fn connections(&mut self) -> Result<Vec<(IpAddr, usize)>> {
+        match self.send(Request::Admin(AdminCommand::Connections))? {
+            Response::Connections(counts) => Ok(counts),
+            _ => Err(KvError::UnexpectedResponse),
+        }
+    }
+
+    // get the connections open on the server, ordered by id; the connections belong to the
+    // server, so this ignores the bucket
+    pub fn client_list(&mut self) -> Result<Vec<ClientInfo>> {
+        match self.send(Request::Admin(AdminCommand::ClientList))? {
+            Response::Clients(clients) => Ok(clients),
+            _ => Err(KvError::UnexpectedResponse),
+        }
+    }
+
+    // close the connection of that id on the server right away, dropping its requests in
+    // flight; fails if no connection has that id
+    pub fn client_kill(&mut self, id: u64) -> Result<()> {
+        self.send(Request::Admin(AdminCommand::ClientKill(id)))?;
+        Ok(())
+    }
+
+    // get the keys matching the glob pattern among the count keys following cursor, from
+    // the first key if `None`, and the cursor of the next page, `None` once every key was
+    // examined; a page may hold fewer keys than count, or none, before the end
+    pub fn scan(
+        &mut self,
+        cursor: Option<String>,
+        count: usize,
+        pattern: Option<String>,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        match self.request(Request::Scan {
+            cursor,
+            count,
+            pattern,
+        })? {
+            Response::Keys(keys, cursor) => Ok((keys, cursor)),
+            _ => Err(KvError::UnexpectedResponse),
+        }
+    }
+
+    // make the following requests target the bucket, or the default keyspace if `None`
+    pub fn set_bucket(&mut self, bucket: Option<String>) {
+        self.bucket = bucket;
+    }
+
+    // a clone of the client whose requests wait at most timeout for their response, to
+    // override the request timeout of the client for one call, like
+    // `client.with_request_timeout(timeout).get(key)`
+    pub fn with_request_timeout(&self, timeout: Duration) -> KvClient {
+        KvClient {
+            request_timeout: Some(timeout),
+            ..self.clone()
+        }
+    }
+
+    // set many keys in the bucket of the client, much faster than one request per key:
+    // the pairs are streamed in frames the server applies as batches, syncing to the disk
+    // only at the end; the load is not atomic, so a failed load may have set part of the
+    // keys
+    pub fn bulk_load<I>(&mut self, pairs: I) -> Result<LoadSummary>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let codec = self.connector.codec();
+        let max_frame_size = self.connector.max_frame_size();
+        let mut stream = self.take_over(self.in_bucket(Request::BulkLoad))?;
+        let mut frame = Vec::new();
+        let mut size = 0;
+        for (key, value) in pairs {
+            size += key.len() + value.len();
+            frame.push((key, value));
+            if frame.len() >= BULK_FRAME_PAIRS || size >= BULK_FRAME_SIZE {
+                let pairs = BulkFrame::Pairs(mem::take(&mut frame));
+                write_frame(&mut stream, codec, max_frame_size, &pairs)?;
+                size = 0;
+            }
         }
+        if !frame.is_empty() {
+            write_frame(&mut stream, codec, max_frame_size, &BulkFrame::Pairs(frame))?;
+        }
+        write_frame(&mut stream, codec, max_frame_size, &BulkFrame::End)?;
+        let resp = read_response(&mut stream, codec, max_frame_size);
+        self.invalidate(&self.in_bucket(Request::BulkLoad));
+        match into_result(resp?)? {
+            Response::Loaded(summary) => Ok(summary),
+            _ => Err(KvError::UnexpectedResponse),
+        }
+    }
+
+    // subscribe the connection to the messages published on channel, which
+    // `next_message` returns; channels are shared by all buckets
+    pub fn subscribe(&mut self, channel: String) -> Result<()> {
+        match self.send(Request::Subscribe(channel))? {
+            Response::Ok(_) => Ok(()),
+            _ => Err(KvError::UnexpectedResponse),
+        }
+    }
+
+    // publish message on channel, returning the number of connections it was sent to
+    pub fn publish(&mut self, channel: String, message: String) -> Result<usize> {
+        match self.send(Request::Publish(channel, message))? {
+            Response::Receivers(receivers) => Ok(receivers),
+            _ => Err(KvError::UnexpectedResponse),
+        }
+    }
+
+    // wait for the next message of the subscribed channels, returning its channel
+    // and the message; the clones of a client share its subscriptions, each message
+    // returned to one of them
+    pub fn next_message(&mut self) -> Result<(String, String)> {
+        let connection = self.connection.lock().unwrap().clone();
+        connection.next_message()
+    }
+
+    // queue many requests to send back to back, without waiting for each response, like
+    // `client.pipeline().get(key1).set(key2, value).send()`, which returns their
+    // responses in the order of the requests; the server runs them concurrently, so a
+    // request should not depend on an earlier one of the same pipeline
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline {
+            client: self,
+            reqs: Vec::new(),
+        }
+    }
+
+    // send reqs at once, and wait for all their responses
+    fn send_pipeline(&mut self, reqs: Vec<Request>) -> Result<Vec<Result<Response>>> {
+        let span = debug_span!("pipeline", requests = reqs.len(), latency_us = field::Empty);
+        let _entered = span.enter();
+        let started = Instant::now();
+        let ops: Vec<_> = reqs.iter().map(Request::op).collect();
+        let reqs: Vec<_> = reqs.into_iter().map(|req| self.in_bucket(req)).collect();
+        let timeout = self.request_timeout;
+        if let Err(err) = self.allow() {
+            for op in ops {
+                self.report(op, started, 0, Some(&err));
+            }
+            return Err(err);
+        }
+        let writes: Vec<_> = match &self.cache {
+            Some(_) => reqs.iter().filter(|req| req.is_write()).cloned().collect(),
+            None => Vec::new(),
+        };
+        for req in &writes {
+            self.invalidate(req);
+        }
+        let pendings = match self
+            .connected()
+            .and_then(|connection| connection.send(reqs))
+        {
+            Ok(pendings) => pendings,
+            Err(err) => {
+                self.record(is_broken(&err));
+                return Err(err);
+            }
+        };
+        let mut failed = false;
+        let resps = pendings
+            .into_iter()
+            .zip(ops)
+            .map(|(pending, op)| {
+                // the timeout is the one of the whole pipeline
+                let left = timeout.map(|timeout| timeout.saturating_sub(started.elapsed()));
+                let resp = pending.wait(left).map_err(|err| match (err, timeout) {
+                    (KvError::RequestTimeout(_), Some(timeout)) => KvError::RequestTimeout(timeout),
+                    (err, _) => err,
+                });
+                failed |= trips_circuit(&resp);
+                let resp = match resp {
+                    // the request fails alone, the other responses may still be read
+                    Err(err @ KvError::ResponseTooLarge(_)) => Ok(Err(err)),
+                    resp => resp.map(into_result),
+                };
+                let error = match &resp {
+                    Ok(resp) => resp.as_ref().err(),
+                    Err(err) => Some(err),
+                };
+                self.report(op, started, 0, error);
+                resp
+            })
+            .collect::<Result<Vec<_>>>();
+        self.record(failed || resps.is_err());
+        for req in &writes {
+            self.invalidate(req);
+        }
+        let resps = resps?;
+        span.record("latency_us", started.elapsed().as_micros() as u64);
+        debug!("pipeline sent");
+        Ok(resps)
+    }
+
+    // iterate over the keys starting with prefix and their values, in key order, following
+    // the scan cursor from page to page; the keys set or removed meanwhile show up or not
+    // like with `scan`. The iterator sends its requests on a clone of the client, and
+    // ends after an error
+    pub fn scan_prefix(&self, prefix: String) -> impl Iterator<Item = Result<(String, String)>> {
+        let mut pattern = String::with_capacity(prefix.len() + 1);
+        for c in prefix.chars() {
+            if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+                pattern.push('\\');
+            }
+            pattern.push(c);
+        }
+        pattern.push('*');
+        PrefixScan {
+            client: self.clone(),
+            // the prefix itself, which the cursor skips, is read with the first page
+            cursor: Some(prefix.clone()),
+            prefix,
+            pattern,
+            pairs: Vec::new().into_iter(),
+            first: true,
+        }
+    }
+
+    // start a transaction, whose reads go to the server while its writes wait in the
+    // transaction until `Transaction::commit`, which applies them atomically if no key
+    // read changed meanwhile; it runs in the bucket of the client, on a clone of it
+    pub fn txn(&self) -> Transaction {
+        // the reads must see the committed values, not the cached ones
+        let mut client = self.clone();
+        client.cache = None;
+        Transaction {
+            client,
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    // run func in a transaction and commit its writes, running it again in a new
+    // transaction, after a backoff, while the commit fails with
+    // `KvError::TransactionConflict`, up to 10 times; nothing is written if func fails
+    pub fn transaction<F, T>(&self, mut func: F) -> Result<T>
+    where
+        F: FnMut(&mut Transaction) -> Result<T>,
+    {
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let mut txn = self.txn();
+            let result = func(&mut txn)?;
+            match txn.commit() {
+                Ok(()) => return Ok(result),
+                Err(KvError::TransactionConflict) if attempts < TRANSACTION_ATTEMPTS => {
+                    debug!("retrying the transaction after a conflict");
+                    thread::sleep(jitter(backoff));
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    // run the requests in order in one request, returning their responses in their order;
+    // the sets and removes in a row are committed together, and unlike a pipeline a
+    // request may depend on an earlier one of the same batch
+    pub fn batch(&mut self, reqs: Vec<Request>) -> Result<Vec<Result<Response>>> {
+        match self.request(Request::Batch(reqs))? {
+            Response::Batch(resps) => Ok(resps.into_iter().map(into_result).collect()),
+            _ => Err(KvError::UnexpectedResponse),
+        }
+    }
+
+    // stream the changes of the keys starting with prefix in the bucket of the client, in
+    // commit order, over a connection of their own; the server engine must report its
+    // changes, like the kvs engine. If the client reconnects, the stream watches the keys
+    // again on a new connection once its connection broke, like when the server restarts,
+    // missing the changes committed meanwhile
+    pub fn watch(self, prefix: String) -> Result<impl Iterator<Item = Result<WatchEvent>>> {
+        let req = self.in_bucket(Request::Watch(prefix));
+        let stream = self.take_over(req.clone())?;
+        Ok(Watch {
+            client: self,
+            req,
+            stream: Some(stream),
+        })
+    }
+
+    // stream the changes committed on the server from offset, from the oldest one it
+    // retains if `None`, over a connection of their own; the server must keep a change
+    // log, see `KvServerBuilder::change_log`. To resume after reconnecting, pass the
+    // offset following the last event seen: if the server no longer retains it, the
+    // request fails and the changes in between are lost
+    pub fn changes(self, offset: Option<u64>) -> Result<impl Iterator<Item = Result<ChangeEvent>>> {
+        let codec = self.connector.codec();
+        let max_frame_size = self.connector.max_frame_size();
+        let stream = self.take_over(Request::Changes(offset))?;
+        Ok(read_frames(stream, codec, max_frame_size))
+    }
+
+    // stream the requests the server reads from every connection, with their peer and
+    // time, over a connection of their own, for debugging; the server sends a limited
+    // number a second, see `KvServerBuilder::monitor_rate`, and each event counts the ones
+    // missed before it. The requests belong to the server, so this ignores the bucket
+    pub fn monitor(self) -> Result<impl Iterator<Item = Result<MonitorEvent>>> {
+        let codec = self.connector.codec();
+        let max_frame_size = self.connector.max_frame_size();
+        let stream = self.take_over(Request::Monitor)?;
+        Ok(read_frames(stream, codec, max_frame_size))
+    }
+
+    // stream the changes of the server over a connection of their own, for a replica,
+    // after a snapshot of its engine if snapshot, or else after its keys
+    pub(crate) fn replicate(
+        self,
+        snapshot: bool,
+    ) -> Result<impl Iterator<Item = Result<Replication>>> {
+        let codec = self.connector.codec();
+        let max_frame_size = self.connector.max_frame_size();
+        let request = match snapshot {
+            true => Request::ReplicateSnapshot,
+            false => Request::Replicate,
+        };
+        let stream = self.take_over(request)?;
+        Ok(read_frames(stream, codec, max_frame_size))
+    }
+
+    // open a connection of its own for req, which the server then takes over once it
+    // accepts the request
+    fn take_over(&self, req: Request) -> Result<BufReader<Stream>> {
+        let codec = self.connector.codec();
+        let max_frame_size = self.connector.max_frame_size();
+        let mut stream = self.connector.connect()?;
+        write_frame(
+            &mut stream,
+            codec,
+            max_frame_size,
+            &RequestFrame {
+                id: 0,
+                request: req,
+            },
+        )?;
+        into_result(read_response(&mut stream, codec, max_frame_size)?)?;
+        Ok(stream)
+    }
+
+    // wrap a request into the bucket that requests target
+    fn in_bucket(&self, req: Request) -> Request {
+        match &self.bucket {
+            Some(bucket) => Request::Bucket(bucket.clone(), Box::new(req)),
+            None => req,
+        }
+    }
+
+    fn request(&mut self, req: Request) -> Result<Response> {
+        let req = self.in_bucket(req);
+        self.send(req)
+    }
+
+    fn send(&mut self, req: Request) -> Result<Response> {
+        let span = debug_span!(
+            "request",
+            op = req.op(),
+            key = req.key(),
+            latency_us = field::Empty,
+            outcome = field::Empty,
+        );
+        let _entered = span.enter();
+        let op = req.op();
+        let started = Instant::now();
+        let mut retries = 0;
+        let resp = match self.allow() {
+            Ok(()) => {
+                // before the write too, so that no read in flight meanwhile is cached
+                self.invalidate(&req);
+                let cached = self.cache.as_ref().map(|_| req.clone());
+                let resp = self.round_trip(req, &mut retries);
+                self.record(trips_circuit(&resp));
+                if let Some(req) = cached {
+                    self.invalidate(&req);
+                }
+                resp
+            }
+            Err(err) => Err(err),
+        };
+        let outcome = match &resp {
+            Ok(Response::Err(_)) => "error",
+            Ok(Response::Timeout(_)) => "timeout",
+            Ok(Response::Busy) => "busy",
+            Ok(_) => "ok",
+            Err(_) => "failed",
+        };
+        span.record("latency_us", started.elapsed().as_micros() as u64);
+        span.record("outcome", outcome);
+        debug!("request sent");
+        let resp = resp.and_then(into_result);
+        self.report(op, started, retries, resp.as_ref().err());
+        resp
+    }
+
+    // report a request to the hook of the client, if any
+    fn report(&self, op: &'static str, started: Instant, retries: u32, error: Option<&KvError>) {
+        if let Some(hook) = &self.on_request {
+            hook(&RequestEvent {
+                op,
+                latency: started.elapsed(),
+                retries,
+                error,
+                circuit: self.breaker.as_ref().map(|breaker| breaker.state()),
+            });
+        }
+    }
+
+    // drop the cached values req may change, if it is a write; the watch drops the values
+    // written in a bucket
+    fn invalidate(&self, req: &Request) {
+        let Some(cache) = &self.cache else {
+            return;
+        };
+        if !req.is_write() || matches!(req, Request::Bucket(..)) {
+            return;
+        }
+        match req.key() {
+            Some(key) => cache.invalidate(key),
+            None => cache.clear(),
+        }
+    }
+
+    // whether the circuit breaker of the client, if any, lets a request be sent
+    fn allow(&self) -> Result<()> {
+        match &self.breaker {
+            Some(breaker) => breaker.allow(),
+            None => Ok(()),
+        }
+    }
+
+    // record whether a request sent failed in the circuit breaker of the client, if any
+    fn record(&self, failed: bool) {
+        if let Some(breaker) = &self.breaker {
+            breaker.record(failed);
+        }
+    }
+
+    // send req, and once the connection broke, send it again on a new connection while
+    // the retry budget lasts if it is idempotent, or if it was not sent at all, counting
+    // the retries
+    fn round_trip(&self, req: Request, retries: &mut u32) -> Result<Response> {
+        let Some(budget) = self.retry.budget else {
+            return send_one(&self.connected()?, req, self.request_timeout);
+        };
+        let idempotent = req.is_idempotent(self.retry.writes);
+        let mut started = None;
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            let (err, sent) = match self.connected() {
+                Ok(connection) => match send_one(&connection, req.clone(), self.request_timeout) {
+                    Err(err) if is_broken(&err) => (err, true),
+                    resp => return resp,
+                },
+                Err(err) if is_broken(&err) => (err, false),
+                Err(err) => return Err(err),
+            };
+            let started = *started.get_or_insert_with(Instant::now);
+            if (sent && !idempotent) || started.elapsed() + backoff > budget {
+                return Err(err);
+            }
+            debug!("retrying after {}", err);
+            thread::sleep(jitter(backoff));
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+            *retries += 1;
+        }
+    }
+
+    // the connection of the client, replaced by a new one first if it broke and the
+    // client reconnects, or if a JSON response too large to read closed it; the clones
+    // share the new one
+    fn connected(&self) -> Result<SharedConnection> {
+        let mut connection = self.connection.lock().unwrap();
+        let reconnect = self.retry.budget.is_some() || connection.is_closed_too_large();
+        if reconnect && connection.is_closed() {
+            debug!("reconnecting");
+            *connection = self.connector.connect_shared()?;
+        }
+        Ok(connection.clone())
+    }
+}
+
+// A builder of KvClient, from `KvClient::builder`
+pub struct KvClientBuilder {
+    addrs: Vec<String>,
+    tls: Option<Arc<ClientConfig>>,
+    codec: Codec,
+    retry: Retry,
+    request_timeout: Option<Duration>,
+    keepalive: Option<Duration>,
+    on_request: Option<RequestHook>,
+    max_frame_size: Option<usize>,
+    breaker: Option<(u32, Duration)>,
+    cache: Option<usize>,
+}
+
+impl KvClientBuilder {
+    // talk TLS with the server, whose host is the name verified against the server
+    // certificate, with a config built by `client_tls_config`
+    pub fn tls(mut self, config: Arc<ClientConfig>) -> KvClientBuilder {
+        self.tls = Some(config);
+        self
+    }
+
+    // encode the frames with codec, negotiated when connecting; JSON by default, which
+    // every server speaks
+    pub fn codec(mut self, codec: Codec) -> KvClientBuilder {
+        self.codec = codec;
+        self
+    }
+
+    // once the connection broke, like when the server restarts, open a new one and send
+    // the requests failed with it again, waiting longer and longer between the attempts,
+    // with jitter, for up to budget; only the idempotent requests are sent again, like
+    // gets, and the requests not sent yet. By default the requests fail with the IO error
+    pub fn reconnect(mut self, budget: Duration) -> KvClientBuilder {
+        self.retry.budget = Some(budget);
+        self
+    }
+
+    // take the sets and removes as idempotent, so that a client reconnecting sends them
+    // again; a remove sent again then fails if the first one removed the key
+    pub fn idempotent_writes(mut self, idempotent: bool) -> KvClientBuilder {
+        self.retry.writes = idempotent;
+        self
+    }
+
+    // make the requests wait at most timeout for their response, failing with
+    // `KvError::RequestTimeout` rather than hanging when the server stalls; forever by
+    // default. A request given up on may still run on the server
+    pub fn request_timeout(mut self, timeout: Duration) -> KvClientBuilder {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    // ping the server once the connection stayed idle for interval, so that NATs and
+    // firewalls do not forget it, and close the connection if the server does not answer
+    // within interval, so that a dead server is noticed before the next request, which
+    // then reconnects if the client does; no pings by default
+    pub fn keepalive(mut self, interval: Duration) -> KvClientBuilder {
+        self.keepalive = Some(interval);
+        self
+    }
+
+    // refuse to send a request larger than bytes, failing it with
+    // `KvError::FrameTooLarge` before it reaches the server, and to read a response
+    // larger than bytes rather than allocating it, failing its request with
+    // `KvError::ResponseTooLarge`. With JSON, whose frames have no length, the response
+    // also closes the connection, failing the other requests in flight the same way,
+    // and the next request opens a new one; 8 MB by default, the max frame size of
+    // servers by default
+    pub fn max_frame_size(mut self, bytes: usize) -> KvClientBuilder {
+        self.max_frame_size = Some(bytes);
+        self
+    }
+
+    // cache the values `get` reads outside of a bucket, up to bytes of keys and values,
+    // the least recently read dropped first, so that reading them again sends no request.
+    // A connection of its own watches the changes of every key, dropping the values they
+    // change, so a value changed by another client may be read for as long as its change
+    // takes to arrive; the writes of the client drop their values at once. While the keys
+    // are not watched, like once the connection broke until the client reconnects, or if
+    // the server engine does not report its changes, nothing is cached
+    pub fn read_cache(mut self, bytes: usize) -> KvClientBuilder {
+        self.cache = Some(bytes);
+        self
+    }
+
+    // once failures requests in a row failed to reach the server, like when it is down,
+    // make the requests fail fast with `KvError::CircuitOpen` for cooldown, then send the
+    // next request to probe the server, closing the circuit if it succeeds; failing to
+    // connect, a broken connection, a timeout or a busy server count as failures, the
+    // errors the server answers with do not. The hook of `on_request` gets the state
+    pub fn circuit_breaker(mut self, failures: u32, cooldown: Duration) -> KvClientBuilder {
+        self.breaker = Some((failures.max(1), cooldown));
+        self
+    }
+
+    // report each request to hook once it completed, with its latency, its retries and
+    // its error, to feed the telemetry of the application; the requests of a pipeline are
+    // reported each, with the time until their response. The hook runs on the thread of
+    // the request, so it should not block
+    pub fn on_request<F>(mut self, hook: F) -> KvClientBuilder
+    where
+        F: Fn(&RequestEvent) + Send + Sync + 'static,
+    {
+        self.on_request = Some(Arc::new(hook));
+        self
+    }
+
+    // connect to the server; a server refusing the codec fails the first request
+    pub fn build(self) -> Result<KvClient> {
+        let mut connector = match self.tls {
+            Some(config) => Connector::tls(&self.addrs, config)?,
+            None => Connector::tcp(&self.addrs),
+        }
+        .with_codec(self.codec);
+        if let Some(interval) = self.keepalive {
+            connector = connector.with_keepalive(interval);
+        }
+        if let Some(max_frame_size) = self.max_frame_size {
+            connector = connector.with_max_frame_size(max_frame_size);
+        }
+        let mut client = KvClient {
+            connection: Arc::new(Mutex::new(connector.connect_shared()?)),
+            connector,
+            retry: self.retry,
+            request_timeout: self.request_timeout,
+            bucket: None,
+            on_request: self.on_request,
+            breaker: self.breaker.map(|(threshold, cooldown)| {
+                Arc::new(Breaker {
+                    threshold,
+                    cooldown,
+                    state: Mutex::default(),
+                })
+            }),
+            cache: None,
+        };
+        if let Some(budget) = self.cache {
+            let cache = Arc::new(ReadCache::new(budget));
+            let watcher = client.clone();
+            // the first watch is set up before returning, so the first reads are cached
+            let stream = match watcher.take_over(Request::Watch(String::new())) {
+                Ok(stream) => Some(stream),
+                Err(err) if is_broken(&err) => None,
+                Err(err) => {
+                    debug!("not caching the values read: {}", err);
+                    return Ok(client);
+                }
+            };
+            if let Some(stream) = &stream {
+                cache.watching(stream.get_ref().socket().try_clone()?);
+            }
+            let weak = Arc::downgrade(&cache);
+            thread::Builder::new()
+                .name("kv-client-cache".to_owned())
+                .spawn(move || watch_for_cache(watcher, weak, stream))?;
+            client.cache = Some(cache);
+        }
+        Ok(client)
+    }
+}
+
+// Drop the values of cache the changes of every key change, watching them with client
+// and watching them again after a backoff once the connection broke, until the clients
+// sharing the cache are gone
+fn watch_for_cache(
+    client: KvClient,
+    cache: Weak<ReadCache>,
+    mut stream: Option<BufReader<Stream>>,
+) {
+    let codec = client.connector.codec();
+    let max_frame_size = client.connector.max_frame_size();
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        if let Some(mut watch) = stream.take() {
+            backoff = INITIAL_BACKOFF;
+            while let Ok(Some(event)) = codec.read::<WatchEvent, _>(&mut watch, max_frame_size) {
+                match cache.upgrade() {
+                    Some(cache) => cache.invalidate(&event.key),
+                    None => return,
+                }
+            }
+            match cache.upgrade() {
+                Some(cache) => cache.unwatched(),
+                None => return,
+            }
+            debug!("watching the keys again for the cache");
+        }
+        thread::sleep(jitter(backoff));
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+        let Some(live) = cache.upgrade() else {
+            return;
+        };
+        match client.take_over(Request::Watch(String::new())) {
+            Ok(watch) => match watch.get_ref().socket().try_clone() {
+                Ok(socket) => {
+                    live.watching(socket);
+                    stream = Some(watch);
+                }
+                Err(err) => debug!("not watching the keys for the cache: {}", err),
+            },
+            Err(err) if is_broken(&err) => {}
+            Err(err) => {
+                debug!("not caching the values read: {}", err);
+                return;
+            }
+        }
+    }
+}
+
+// The changes of the keys starting with a prefix, from `KvClient::watch`
+struct Watch {
+    client: KvClient,
+    // the watch request, sent again on a new connection once the connection broke
+    req: Request,
+    // `None` once the stream ended
+    stream: Option<BufReader<Stream>>,
+}
+
+impl Watch {
+    // watch the keys again on a new connection, waiting longer and longer between the
+    // attempts like a request, while the retry budget of the client lasts
+    fn rewatch(&self, budget: Duration, mut err: KvError) -> Result<BufReader<Stream>> {
+        let started = Instant::now();
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            if started.elapsed() + backoff > budget {
+                return Err(err);
+            }
+            debug!("watching again after {}", err);
+            thread::sleep(jitter(backoff));
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+            match self.client.take_over(self.req.clone()) {
+                Err(broken) if is_broken(&broken) => err = broken,
+                stream => return stream,
+            }
+        }
+    }
+}
+
+impl Iterator for Watch {
+    type Item = Result<WatchEvent>;
+
+    fn next(&mut self) -> Option<Result<WatchEvent>> {
+        let stream = self.stream.as_mut()?;
+        let connector = &self.client.connector;
+        let err = match connector.codec().read(stream, connector.max_frame_size()) {
+            Ok(Some(event)) => return Some(Ok(event)),
+            Ok(None) => KvError::Io(io::ErrorKind::UnexpectedEof.into()),
+            Err(err) if is_broken(&err) => err,
+            Err(err) => return Some(Err(err)),
+        };
+        let Some(budget) = self.client.retry.budget else {
+            self.stream = None;
+            return match err {
+                // the server closed the stream
+                KvError::Io(err) if err.kind() == io::ErrorKind::UnexpectedEof => None,
+                err => Some(Err(err)),
+            };
+        };
+        match self.rewatch(budget, err) {
+            Ok(stream) => {
+                self.stream = Some(stream);
+                self.next()
+            }
+            Err(err) => {
+                self.stream = None;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+// An iterator over the keys starting with a prefix and their values, from
+// `KvClient::scan_prefix`
+struct PrefixScan {
+    client: KvClient,
+    prefix: String,
+    // the glob pattern matching the keys starting with prefix
+    pattern: String,
+    // the key the next page follows, `None` once every key of the prefix was examined
+    cursor: Option<String>,
+    // the pairs of the current page, not returned yet
+    pairs: vec::IntoIter<(String, String)>,
+    first: bool,
+}
+
+impl PrefixScan {
+    // read the keys of the page following cursor, and their values
+    fn next_page(&mut self, cursor: String) -> Result<Vec<(String, String)>> {
+        let (mut keys, next) =
+            self.client
+                .scan(Some(cursor), SCAN_PAGE_KEYS, Some(self.pattern.clone()))?;
+        if mem::take(&mut self.first) {
+            keys.insert(0, self.prefix.clone());
+        }
+        // the keys following the prefix do not start with it
+        self.cursor = next.filter(|next| next.starts_with(&self.prefix));
+        let values = self.client.multi_get(keys.clone())?;
+        // the keys removed since the scan are skipped
+        Ok(keys
+            .into_iter()
+            .zip(values)
+            .filter_map(|(key, value)| Some((key, value?)))
+            .collect())
+    }
+}
+
+impl Iterator for PrefixScan {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Result<(String, String)>> {
+        loop {
+            if let Some(pair) = self.pairs.next() {
+                return Some(Ok(pair));
+            }
+            let cursor = self.cursor.take()?;
+            match self.next_page(cursor) {
+                Ok(pairs) => self.pairs = pairs.into_iter(),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+// A transaction run optimistically on the server, from `KvClient::txn`. The commit
+// fails with `KvError::TransactionConflict` if a key the transaction read was changed
+// since, and may then be retried with a new transaction
+pub struct Transaction {
+    client: KvClient,
+    // key -> value seen by the first read of the key
+    reads: HashMap<String, Option<String>>,
+    // key -> buffered value, `None` for a removal
+    writes: BTreeMap<String, Option<String>>,
+}
+
+impl Transaction {
+    // get the value of key, seeing the writes of the transaction
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        self.read(key)
+    }
+
+    // get the value of key on the server, as first read by the transaction
+    fn read(&mut self, key: String) -> Result<Option<String>> {
+        if let Some(value) = self.reads.get(&key) {
+            return Ok(value.clone());
+        }
+        let value = self.client.get(key.clone())?;
+        self.reads.insert(key, value.clone());
+        Ok(value)
+    }
+
+    // set the value of key once the transaction commits
+    pub fn set(&mut self, key: String, value: String) {
+        self.writes.insert(key, Some(value));
+    }
+
+    // remove key once the transaction commits; fails with `KvError::KeyNotFound` if
+    // the key does not exist
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        if self.get(key.clone())?.is_none() {
+            return Err(KvError::KeyNotFound);
+        }
+        // a key only the transaction set is not on the server to remove
+        if self.read(key.clone())?.is_none() {
+            self.writes.remove(&key);
+        } else {
+            self.writes.insert(key, None);
+        }
+        Ok(())
+    }
+
+    // apply the writes atomically if every key read still has the value read, or fail
+    // with `KvError::TransactionConflict` without writing anything
+    pub fn commit(self) -> Result<()> {
+        let Transaction {
+            mut client,
+            reads,
+            writes,
+        } = self;
+        let mut batch = WriteBatch::new();
+        for (key, value) in writes {
+            match value {
+                Some(value) => batch.put(key, value),
+                None => batch.delete(key),
+            };
+        }
+        match client.request(Request::Transaction(reads, batch))? {
+            Response::Bool(true) => Ok(()),
+            Response::Bool(false) => Err(KvError::TransactionConflict),
+            _ => Err(KvError::UnexpectedResponse),
+        }
+    }
+}
+
+// Requests queued to be sent back to back on the connection of a client, from
+// `KvClient::pipeline`
+pub struct Pipeline<'a> {
+    client: &'a mut KvClient,
+    reqs: Vec<Request>,
+}
+
+impl Pipeline<'_> {
+    // queue getting the value of key, answered with `Response::Ok`
+    pub fn get(&mut self, key: String) -> &mut Self {
+        self.request(Request::Get(key))
+    }
+
+    // queue setting the value of key
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.request(Request::Set(key, value))
+    }
+
+    // queue removing key, whose response is an error if the key does not exist
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.request(Request::Remove(key))
+    }
+
+    // queue any request, targeting the bucket of the client like the others
+    pub fn request(&mut self, req: Request) -> &mut Self {
+        self.reqs.push(req);
+        self
+    }
+
+    // queue many requests
+    pub fn requests(&mut self, reqs: impl IntoIterator<Item = Request>) -> &mut Self {
+        self.reqs.extend(reqs);
+        self
+    }
+
+    // the number of requests queued
+    pub fn len(&self) -> usize {
+        self.reqs.len()
+    }
+
+    // whether no request is queued
+    pub fn is_empty(&self) -> bool {
+        self.reqs.is_empty()
+    }
+
+    // send the requests queued at once, and wait for all their responses, returned in the
+    // order of the requests, each failed request with its error; the pipeline is then
+    // empty, to queue the next requests. Within the request timeout of the client, if any,
+    // for the whole pipeline
+    pub fn send(&mut self) -> Result<Vec<Result<Response>>> {
+        let reqs = mem::take(&mut self.reqs);
+        self.client.send_pipeline(reqs)
+    }
+}
+
+// Write a frame to a connection of its own, failing with `KvError::FrameTooLarge` if
+// it is larger than max_frame_size.
+fn write_frame<T: Serialize>(
+    stream: &mut BufReader<Stream>,
+    codec: Codec,
+    max_frame_size: usize,
+    frame: &T,
+) -> Result<()> {
+    // the server expects a whole frame per read, so write it at once
+    let data = codec.encode(frame)?;
+    if data.len() > max_frame_size {
+        return Err(KvError::FrameTooLarge(max_frame_size));
+    }
+    let stream = stream.get_mut();
+    stream.write_all(&data)?;
+    stream.flush()?;
+    Ok(())
+}
+
+// Read the response of the request of a connection of its own.
+fn read_response(
+    stream: &mut BufReader<Stream>,
+    codec: Codec,
+    max_frame_size: usize,
+) -> Result<Response> {
+    match codec.read::<ResponseFrame, _>(stream, max_frame_size)? {
+        Some(frame) => Ok(frame.response),
+        None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+    }
+}
+
+// Read the frames a server streams on a connection it took over, until it closes.
+fn read_frames<T: DeserializeOwned>(
+    mut stream: BufReader<Stream>,
+    codec: Codec,
+    max_frame_size: usize,
+) -> impl Iterator<Item = Result<T>> {
+    iter::from_fn(move || codec.read(&mut stream, max_frame_size).transpose())
+}
+
+// Send req alone on connection and wait for its response, for at most timeout.
+fn send_one(
+    connection: &SharedConnection,
+    req: Request,
+    timeout: Option<Duration>,
+) -> Result<Response> {
+    let mut pending = connection.send(vec![req])?;
+    pending.pop().expect("one request sent").wait(timeout)
+}
+
+// Whether err tells the connection broke, rather than the request failed.
+fn is_broken(err: &KvError) -> bool {
+    matches!(err, KvError::Io(_) | KvError::ShuttingDown)
+}
+
+// Whether resp counts as a failure to reach the server for the circuit breaker, unlike
+// the errors the server answers with.
+fn trips_circuit(resp: &Result<Response>) -> bool {
+    match resp {
+        Ok(Response::Timeout(_) | Response::Busy) => true,
+        Ok(_) => false,
+        Err(err) => is_broken(err) || matches!(err, KvError::RequestTimeout(_) | KvError::Busy),
+    }
+}
+
+// A random delay up to backoff, so that the clients which lost their connection at once
+// do not all reconnect at once.
+fn jitter(backoff: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    backoff.mul_f64((random % 1024) as f64 / 1024.0)
+}
+
+// Turn the responses of failed requests into their error.
+fn into_result(resp: Response) -> Result<Response> {
+    match resp {
+        Response::Err(msg) => Err(KvError::StringError(msg)),
+        Response::Timeout(timeout) => Err(KvError::RequestTimeout(timeout)),
+        Response::Busy => Err(KvError::Busy),
+        resp => Ok(resp),
     }
 }
@@ -0,0 +1,147 @@
+//! The cache of the values a client read.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{Shutdown, TcpStream},
+    sync::Mutex,
+};
+
+/// The values a client read, shared by its clones, up to a budget of bytes
+/// of keys and values, the least recently read evicted first.
+///
+/// The values are cached only while a connection watches the changes of
+/// every key, which drop the values they change.
+pub(crate) struct ReadCache {
+    budget: usize,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<String, Entry>,
+    /// The keys by the last time they were read, the least recent first.
+    order: BTreeMap<u64, String>,
+    /// Counts the reads, to order the entries.
+    reads: u64,
+    /// Bytes of the keys and values cached.
+    size: usize,
+    /// Counts the changes dropping values, so that a value read before a
+    /// change is not cached after it.
+    epoch: u64,
+    /// The socket of the connection watching the changes, `None` while the
+    /// keys are not watched.
+    watch: Option<TcpStream>,
+}
+
+/// A value cached, `None` if the key does not exist.
+struct Entry {
+    value: Option<String>,
+    read: u64,
+}
+
+impl ReadCache {
+    /// Creates an empty cache of up to `budget` bytes, caching nothing until
+    /// the keys are watched.
+    pub(crate) fn new(budget: usize) -> ReadCache {
+        ReadCache {
+            budget,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Returns the cached value of `key`, `None` if it is not cached.
+    pub(crate) fn get(&self, key: &str) -> Option<Option<String>> {
+        let state = &mut *self.state.lock().unwrap();
+        let entry = state.entries.get_mut(key)?;
+        state.reads += 1;
+        let key = state.order.remove(&entry.read)?;
+        entry.read = state.reads;
+        state.order.insert(entry.read, key);
+        Some(entry.value.clone())
+    }
+
+    /// Returns the epoch to pass to `insert` once the value of a key was
+    /// read, `None` while the keys are not watched.
+    pub(crate) fn epoch(&self) -> Option<u64> {
+        let state = self.state.lock().unwrap();
+        state.watch.as_ref().map(|_| state.epoch)
+    }
+
+    /// Caches the `value` of `key` read in `epoch`, unless a value was
+    /// dropped since, evicting the least recently read values over budget.
+    pub(crate) fn insert(&self, key: String, value: Option<String>, epoch: u64) {
+        let size = key.len() + value.as_ref().map_or(0, String::len);
+        let state = &mut *self.state.lock().unwrap();
+        if state.watch.is_none() || state.epoch != epoch || size > self.budget {
+            return;
+        }
+        state.remove(&key);
+        while state.size + size > self.budget {
+            let Some((_, oldest)) = state.order.pop_first() else {
+                break;
+            };
+            state.remove(&oldest);
+        }
+        state.reads += 1;
+        state.order.insert(state.reads, key.clone());
+        state.size += size;
+        let read = state.reads;
+        state.entries.insert(key, Entry { value, read });
+    }
+
+    /// Drops the value of `key`, which changed.
+    pub(crate) fn invalidate(&self, key: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.epoch += 1;
+        state.remove(key);
+    }
+
+    /// Drops every value, like after a write whose keys are not known.
+    pub(crate) fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.epoch += 1;
+        state.clear();
+    }
+
+    /// Starts caching the values, the changes of every key watched on the
+    /// connection of `socket`.
+    pub(crate) fn watching(&self, socket: TcpStream) {
+        let mut state = self.state.lock().unwrap();
+        state.epoch += 1;
+        state.clear();
+        state.watch = Some(socket);
+    }
+
+    /// Stops caching the values once the changes are no longer watched, as
+    /// the ones missed could have changed.
+    pub(crate) fn unwatched(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.epoch += 1;
+        state.clear();
+        state.watch = None;
+    }
+}
+
+impl Drop for ReadCache {
+    /// Closes the connection watching the changes once every client is gone.
+    fn drop(&mut self) {
+        if let Some(socket) = &self.state.get_mut().unwrap().watch {
+            drop(socket.shutdown(Shutdown::Both));
+        }
+    }
+}
+
+impl CacheState {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.read);
+            self.size -= key.len() + entry.value.as_ref().map_or(0, String::len);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+        self.size = 0;
+    }
+}
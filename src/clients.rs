@@ -0,0 +1,180 @@
+use std::{
+    collections::BTreeMap,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    task::{ready, Context, Poll},
+    time::Instant,
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_util::sync::CancellationToken;
+
+use crate::ClientInfo;
+
+/// The connections open on a server, by id, which the client list and client
+/// kill admin commands see.
+#[derive(Default)]
+pub(crate) struct Clients {
+    next_id: AtomicU64,
+    open: Mutex<BTreeMap<u64, Arc<ClientState>>>,
+}
+
+impl Clients {
+    /// Registers a connection from `addr`, stopping with `stop`, until the
+    /// returned guard drops.
+    pub(crate) fn open(self: &Arc<Self>, addr: String, stop: CancellationToken) -> ClientGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        let state = Arc::new(ClientState {
+            id,
+            addr,
+            name: OnceLock::new(),
+            connected: now,
+            last_active: Mutex::new(now),
+            last_op: Mutex::new(None),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            killed: AtomicBool::new(false),
+            stop,
+        });
+        self.open.lock().unwrap().insert(id, state.clone());
+        ClientGuard {
+            clients: self.clone(),
+            state,
+        }
+    }
+
+    /// Returns the connections open, ordered by id.
+    pub(crate) fn list(&self) -> Vec<ClientInfo> {
+        let open = self.open.lock().unwrap();
+        open.values().map(|state| state.info()).collect()
+    }
+
+    /// Closes the connection `id` right away, dropping its requests in flight,
+    /// returning whether it was open.
+    pub(crate) fn kill(&self, id: u64) -> bool {
+        let Some(state) = self.open.lock().unwrap().get(&id).cloned() else {
+            return false;
+        };
+        state.killed.store(true, Ordering::SeqCst);
+        state.stop.cancel();
+        true
+    }
+}
+
+/// What a server knows of one of its connections.
+pub(crate) struct ClientState {
+    id: u64,
+    addr: String,
+    // the common name of the certificate of the client, once the handshake is done
+    name: OnceLock<String>,
+    connected: Instant,
+    // when the last request was read
+    last_active: Mutex<Instant>,
+    last_op: Mutex<Option<&'static str>>,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    killed: AtomicBool,
+    // stops the connection, on a kill or once the server stops
+    stop: CancellationToken,
+}
+
+impl ClientState {
+    /// Records the common name of the certificate the client presented.
+    pub(crate) fn set_name(&self, name: &str) {
+        let _ = self.name.set(name.to_owned());
+    }
+
+    /// Records a request of the operation `op`.
+    pub(crate) fn record(&self, op: &'static str) {
+        *self.last_active.lock().unwrap() = Instant::now();
+        *self.last_op.lock().unwrap() = Some(op);
+    }
+
+    /// Returns whether an admin killed the connection.
+    pub(crate) fn is_killed(&self) -> bool {
+        self.killed.load(Ordering::SeqCst)
+    }
+
+    fn info(&self) -> ClientInfo {
+        ClientInfo {
+            id: self.id,
+            addr: self.addr.clone(),
+            name: self.name.get().cloned(),
+            age: self.connected.elapsed(),
+            idle: self.last_active.lock().unwrap().elapsed(),
+            last_op: self.last_op.lock().unwrap().map(str::to_owned),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A connection registered among the ones of its server, until dropped.
+pub(crate) struct ClientGuard {
+    clients: Arc<Clients>,
+    state: Arc<ClientState>,
+}
+
+impl ClientGuard {
+    pub(crate) fn state(&self) -> &Arc<ClientState> {
+        &self.state
+    }
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        self.clients.open.lock().unwrap().remove(&self.state.id);
+    }
+}
+
+/// A stream counting the bytes read from and written to it in the state of
+/// its client, as they go over the wire, TLS included.
+pub(crate) struct Metered<S> {
+    stream: S,
+    state: Arc<ClientState>,
+}
+
+impl<S> Metered<S> {
+    pub(crate) fn new(stream: S, state: Arc<ClientState>) -> Metered<S> {
+        Metered { stream, state }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.stream).poll_read(cx, buf))?;
+        let n = buf.filled().len() - filled;
+        self.state.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let n = ready!(Pin::new(&mut self.stream).poll_write(cx, buf))?;
+        self.state.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
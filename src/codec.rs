@@ -0,0 +1,238 @@
+//! The encodings of the frames of a connection.
+
+use std::io::{self, BufRead, Read};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Deserializer;
+
+use crate::{common::ResponseFrame, KvError, Response, Result};
+
+/// The first byte of a preamble, which no JSON frame starts with.
+const PREAMBLE_START: u8 = 0;
+
+/// The bytes a client starts its connection with to negotiate a codec: the
+/// start byte, `KV`, then the id of the codec. The server accepts the codec
+/// by sending the same bytes back.
+pub(crate) const PREAMBLE_LEN: usize = 4;
+
+/// The bytes of the length prefixing a bincode frame.
+const LENGTH_LEN: usize = 4;
+
+/// A response frame read by `Codec::read_response`.
+pub(crate) enum Incoming {
+    /// A whole response frame.
+    Frame(ResponseFrame),
+    /// A response larger than the max frame size, skipped, to the request
+    /// with this id if any.
+    TooLarge(Option<u64>),
+}
+
+/// The encoding of the frames of a connection, negotiated when it starts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    /// JSON, which clients not negotiating a codec speak.
+    #[default]
+    Json,
+    /// Bincode, each frame prefixed with its length, smaller and faster to
+    /// encode and decode than JSON.
+    Bincode,
+}
+
+impl Codec {
+    /// Returns the name of the codec, for logs and errors.
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Json => "json",
+            Codec::Bincode => "bincode",
+        }
+    }
+
+    /// Returns the bytes negotiating the codec.
+    pub(crate) fn preamble(self) -> [u8; PREAMBLE_LEN] {
+        let id = match self {
+            Codec::Json => 0,
+            Codec::Bincode => 1,
+        };
+        [PREAMBLE_START, b'K', b'V', id]
+    }
+
+    /// Returns whether `buf`, the first bytes of a connection, starts with a preamble.
+    pub(crate) fn is_preamble(buf: &[u8]) -> bool {
+        buf.first() == Some(&PREAMBLE_START)
+    }
+
+    /// Returns the codec negotiated by `preamble`.
+    pub(crate) fn from_preamble(preamble: &[u8]) -> Result<Codec> {
+        match preamble {
+            [PREAMBLE_START, b'K', b'V', 0] => Ok(Codec::Json),
+            [PREAMBLE_START, b'K', b'V', 1] => Ok(Codec::Bincode),
+            [PREAMBLE_START, b'K', b'V', id] => Err(KvError::Unsupported(format!("codec {}", id))),
+            _ => Err(KvError::StringError("Invalid codec preamble".to_owned())),
+        }
+    }
+
+    /// Reads the answer of the server to the preamble of the codec, which
+    /// JSON, the codec of clients not negotiating, goes without.
+    ///
+    /// A server refusing the codec answers with an error frame in JSON.
+    pub(crate) fn read_accepted<R: BufRead>(self, reader: &mut R) -> Result<()> {
+        if self == Codec::Json {
+            return Ok(());
+        }
+        if !Codec::is_preamble(reader.fill_buf()?) {
+            let frame = ResponseFrame::deserialize(&mut Deserializer::from_reader(reader))?;
+            return Err(match frame.response {
+                Response::Err(message) => KvError::StringError(message),
+                _ => KvError::UnexpectedResponse,
+            });
+        }
+        let mut preamble = [0; PREAMBLE_LEN];
+        reader.read_exact(&mut preamble)?;
+        if Codec::from_preamble(&preamble)? != self {
+            return Err(KvError::UnexpectedResponse);
+        }
+        Ok(())
+    }
+
+    /// Encodes `frame`.
+    pub(crate) fn encode<T: Serialize>(self, frame: &T) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.encode_into(&mut data, frame)?;
+        Ok(data)
+    }
+
+    /// Encodes `frame` at the end of `data`.
+    pub(crate) fn encode_into<T: Serialize>(self, data: &mut Vec<u8>, frame: &T) -> Result<()> {
+        match self {
+            Codec::Json => serde_json::to_writer(data, frame)?,
+            Codec::Bincode => {
+                let len = bincode::serialized_size(frame)?;
+                let len = u32::try_from(len).map_err(|_| {
+                    KvError::StringError(format!("Frame of {} bytes too large to encode", len))
+                })?;
+                data.extend_from_slice(&len.to_le_bytes());
+                bincode::serialize_into(data, frame)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes the first whole frame out of `buf`, or returns `None` if more bytes are needed.
+    pub(crate) fn decode<T: DeserializeOwned>(self, buf: &mut Vec<u8>) -> Result<Option<T>> {
+        match self {
+            Codec::Json => {
+                let mut frames = Deserializer::from_slice(buf).into_iter::<T>();
+                let frame = match frames.next() {
+                    Some(Ok(frame)) => frame,
+                    Some(Err(err)) if err.is_eof() => return Ok(None),
+                    Some(Err(err)) => return Err(err.into()),
+                    None => {
+                        // only whitespace
+                        buf.clear();
+                        return Ok(None);
+                    }
+                };
+                let offset = frames.byte_offset();
+                buf.drain(..offset);
+                Ok(Some(frame))
+            }
+            Codec::Bincode => {
+                let Some(len) = buf.get(..LENGTH_LEN) else {
+                    return Ok(None);
+                };
+                let end = LENGTH_LEN + u32::from_le_bytes(len.try_into().unwrap()) as usize;
+                if buf.len() < end {
+                    return Ok(None);
+                }
+                let frame = bincode::deserialize(&buf[LENGTH_LEN..end])?;
+                buf.drain(..end);
+                Ok(Some(frame))
+            }
+        }
+    }
+
+    /// Reads the next frame of `reader`, or returns `None` once it ends
+    /// between two frames.
+    ///
+    /// A frame larger than `max_len` fails with `KvError::ResponseTooLarge`
+    /// before it is read whole, so that its length cannot force a large
+    /// allocation; the rest of the frame is left unread.
+    pub(crate) fn read<T: DeserializeOwned, R: Read>(
+        self,
+        reader: &mut R,
+        max_len: usize,
+    ) -> Result<Option<T>> {
+        match self {
+            Codec::Json => {
+                let mut limited = reader.take(max_len as u64);
+                let frame = Deserializer::from_reader(&mut limited).into_iter().next();
+                match frame {
+                    Some(Ok(frame)) => Ok(Some(frame)),
+                    Some(Err(err)) if err.is_eof() && limited.limit() == 0 => {
+                        Err(KvError::ResponseTooLarge(max_len))
+                    }
+                    Some(Err(err)) => Err(err.into()),
+                    None => Ok(None),
+                }
+            }
+            Codec::Bincode => {
+                let Some(len) = read_len(reader)? else {
+                    return Ok(None);
+                };
+                if len > max_len {
+                    return Err(KvError::ResponseTooLarge(max_len));
+                }
+                read_body(reader, len).map(Some)
+            }
+        }
+    }
+
+    /// Reads the next response frame of `reader` like `read`, except that a
+    /// bincode frame larger than `max_len` is skipped rather than read, its
+    /// length telling where the next frame starts, so that only its request
+    /// fails.
+    ///
+    /// A JSON frame has no length, so one larger than `max_len` still fails
+    /// with `KvError::ResponseTooLarge`, leaving the connection unusable.
+    pub(crate) fn read_response<R: Read>(
+        self,
+        reader: &mut R,
+        max_len: usize,
+    ) -> Result<Option<Incoming>> {
+        if self == Codec::Json {
+            return Ok(self.read(reader, max_len)?.map(Incoming::Frame));
+        }
+        let Some(len) = read_len(reader)? else {
+            return Ok(None);
+        };
+        if len <= max_len {
+            return read_body(reader, len).map(|frame| Some(Incoming::Frame(frame)));
+        }
+        // the id of the request comes first in the frame, before the response
+        let mut frame = reader.take(len as u64);
+        let id: Option<u64> = bincode::deserialize_from(&mut frame)?;
+        io::copy(&mut frame, &mut io::sink())?;
+        if frame.limit() > 0 {
+            return Err(KvError::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+        Ok(Some(Incoming::TooLarge(id)))
+    }
+}
+
+/// Reads the length of the next bincode frame of `reader`, or returns `None`
+/// once it ends between two frames.
+fn read_len<R: Read>(reader: &mut R) -> Result<Option<usize>> {
+    let mut len = [0; LENGTH_LEN];
+    match reader.read_exact(&mut len) {
+        Ok(()) => Ok(Some(u32::from_le_bytes(len) as usize)),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Reads the `len` bytes of a bincode frame following its length.
+fn read_body<T: DeserializeOwned, R: Read>(reader: &mut R, len: usize) -> Result<T> {
+    let mut data = vec![0; len];
+    reader.read_exact(&mut data)?;
+    Ok(bincode::deserialize(&data)?)
+}
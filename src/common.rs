@@ -1,7 +1,19 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    time::{Duration, SystemTime},
+};
+
 use serde::{Deserialize, Serialize};
 
+use crate::{ChangeKind, EngineStats, WriteBatch};
+
+// The buckets of a latency histogram, the last one counting the latencies of 2^30
+// microseconds, about 18 minutes, and above
+const LATENCY_BUCKETS: usize = 32;
+
 // The request struct that client use to send request
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Request {
     // get key
     Get(String),
@@ -9,6 +21,282 @@ pub enum Request {
     Set(String, String),
     // remove key
     Remove(String),
+    // set key value, expiring after the duration
+    Expire(String, String, Duration),
+    // get the remaining time to live of key
+    Ttl(String),
+    // apply all writes of the batch atomically
+    WriteBatch(WriteBatch),
+    // set key to a binary value
+    SetBytes(String, #[serde(with = "serde_bytes")] Vec<u8>),
+    // get the value of key as bytes
+    GetBytes(String),
+    // get the number of live keys
+    Len,
+    // add the delta to the integer value of key
+    Incr(String, i64),
+    // run the request against the named bucket
+    Bucket(String, Box<Request>),
+    // set key value only if key does not exist
+    SetIfAbsent(String, String),
+    // set key value only if key already exists
+    SetIfPresent(String, String),
+    // get the values of many keys
+    MultiGet(Vec<String>),
+    // turn the connection into the stream of the changes of the server, for a replica
+    Replicate,
+    // subscribe the connection to the messages published on channel
+    Subscribe(String),
+    // publish message on channel, to every connection subscribed to it
+    Publish(String, String),
+    // turn the connection into the stream of the changes of the keys starting with prefix
+    Watch(String),
+    // get the keys matching the glob pattern among the count keys following cursor,
+    // from the first key if `None`
+    Scan {
+        cursor: Option<String>,
+        count: usize,
+        pattern: Option<String>,
+    },
+    // turn the connection into a bulk load, which takes `BulkFrame`s until the end frame
+    BulkLoad,
+    // run an operator command against the engine
+    Admin(AdminCommand),
+    // run the custom command name the server registered, with the payload
+    Custom(String, String),
+    // run the requests in order, answered together by a `Response::Batch`
+    Batch(Vec<Request>),
+    // turn the connection into the stream of the changes committed on the server from
+    // offset, from the oldest change the server retains if `None`
+    Changes(Option<u64>),
+    // check the server is alive, answered by a `Response::Pong` echoing the payload
+    Ping(Option<String>),
+    // like `Replicate`, but the stream starts with a snapshot of the engine of the
+    // server, as a compacted segment in chunks, rather than with its keys
+    ReplicateSnapshot,
+    // make the following requests of the connection run in the namespace, the bucket of
+    // that name, or in the default keyspace if `None`; the server wraps them into
+    // `Bucket` requests, but for the ones belonging to the server rather than a keyspace
+    Use(Option<String>),
+    // get the state of the server: its version, uptime, engine and counts
+    Info,
+    // turn the connection into the stream of the requests the server reads from then on,
+    // from every connection, for debugging
+    Monitor,
+    // apply the batch atomically if every key read still has the value read, `None` for
+    // a key read absent; answered by `Response::Bool(false)` if a key changed
+    Transaction(HashMap<String, Option<String>>, WriteBatch),
+}
+
+// The commands operators manage a running server with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AdminCommand {
+    // reclaim the space of the stale records now
+    Compact,
+    // get the statistics of the engine
+    Stats,
+    // sync the writes made so far to the disk, whatever the engine syncs writes after
+    Flush,
+    // reload the settings of the server, with the hook it was built with
+    Reload,
+    // get the number of connections open from each peer IP
+    Connections,
+    // get the connections open, with their peer, age and traffic
+    ClientList,
+    // close the connection of that id right away, dropping its requests in flight
+    ClientKill(u64),
+}
+
+impl AdminCommand {
+    // the name of the command, for logs
+    pub fn op(&self) -> &'static str {
+        match self {
+            AdminCommand::Compact => "compact",
+            AdminCommand::Stats => "stats",
+            AdminCommand::Flush => "flush",
+            AdminCommand::Reload => "reload",
+            AdminCommand::Connections => "connections",
+            AdminCommand::ClientList => "client_list",
+            AdminCommand::ClientKill(_) => "client_kill",
+        }
+    }
+}
+
+impl Request {
+    // the name of the operation, for logs
+    pub fn op(&self) -> &'static str {
+        match self {
+            Request::Get(_) => "get",
+            Request::Set(..) => "set",
+            Request::Remove(_) => "remove",
+            Request::Expire(..) => "expire",
+            Request::Ttl(_) => "ttl",
+            Request::WriteBatch(_) => "write_batch",
+            Request::SetBytes(..) => "set_bytes",
+            Request::GetBytes(_) => "get_bytes",
+            Request::Len => "len",
+            Request::Incr(..) => "incr",
+            Request::Bucket(_, request) => request.op(),
+            Request::SetIfAbsent(..) => "set_if_absent",
+            Request::SetIfPresent(..) => "set_if_present",
+            Request::MultiGet(_) => "multi_get",
+            Request::Replicate => "replicate",
+            Request::Subscribe(_) => "subscribe",
+            Request::Publish(..) => "publish",
+            Request::Watch(_) => "watch",
+            Request::Scan { .. } => "scan",
+            Request::BulkLoad => "bulk_load",
+            Request::Admin(command) => command.op(),
+            Request::Custom(..) => "custom",
+            Request::Batch(_) => "batch",
+            Request::Ping(_) => "ping",
+            Request::Changes(_) => "changes",
+            Request::ReplicateSnapshot => "replicate_snapshot",
+            Request::Use(_) => "use",
+            Request::Info => "info",
+            Request::Monitor => "monitor",
+            Request::Transaction(..) => "transaction",
+        }
+    }
+
+    // whether the request writes to the engine
+    pub fn is_write(&self) -> bool {
+        match self {
+            Request::Set(..)
+            | Request::Remove(_)
+            | Request::Expire(..)
+            | Request::WriteBatch(_)
+            | Request::SetBytes(..)
+            | Request::Incr(..)
+            | Request::SetIfAbsent(..)
+            | Request::SetIfPresent(..)
+            | Request::BulkLoad
+            | Request::Transaction(..) => true,
+            // the server cannot tell what the handler of the command does
+            Request::Custom(..) => true,
+            Request::Batch(requests) => requests.iter().any(Request::is_write),
+            Request::Bucket(_, request) => request.is_write(),
+            Request::Get(_)
+            | Request::Ttl(_)
+            | Request::GetBytes(_)
+            | Request::Len
+            | Request::MultiGet(_)
+            | Request::Replicate
+            | Request::Subscribe(_)
+            | Request::Publish(..)
+            | Request::Watch(_)
+            | Request::Scan { .. }
+            | Request::Ping(_)
+            | Request::Changes(_)
+            | Request::ReplicateSnapshot
+            | Request::Use(_)
+            | Request::Info
+            | Request::Monitor => false,
+            // the commands keep the keys as they are, so a replica runs them too
+            Request::Admin(_) => false,
+        }
+    }
+
+    // whether running the request twice does the same as running it once, so a client
+    // may send it again when its connection broke before the response came; with writes,
+    // the sets and removes are taken as such too, although a remove sent again fails
+    pub fn is_idempotent(&self, writes: bool) -> bool {
+        match self {
+            Request::Get(_)
+            | Request::Ttl(_)
+            | Request::GetBytes(_)
+            | Request::Len
+            | Request::MultiGet(_)
+            | Request::Scan { .. }
+            | Request::Ping(_)
+            | Request::Info
+            | Request::Admin(
+                AdminCommand::Stats | AdminCommand::Connections | AdminCommand::ClientList,
+            ) => true,
+            Request::Set(..)
+            | Request::Remove(_)
+            | Request::Expire(..)
+            | Request::SetBytes(..)
+            | Request::WriteBatch(_) => writes,
+            Request::Bucket(_, request) => request.is_idempotent(writes),
+            Request::Batch(requests) => {
+                requests.iter().all(|request| request.is_idempotent(writes))
+            }
+            _ => false,
+        }
+    }
+
+    // whether the request is an operator command
+    pub fn is_admin(&self) -> bool {
+        match self {
+            Request::Admin(_) | Request::Info | Request::Monitor => true,
+            Request::Bucket(_, request) => request.is_admin(),
+            Request::Batch(requests) => requests.iter().any(Request::is_admin),
+            _ => false,
+        }
+    }
+
+    // the key of the request, if it is about a single key
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Request::Get(key)
+            | Request::Set(key, _)
+            | Request::Remove(key)
+            | Request::Expire(key, ..)
+            | Request::Ttl(key)
+            | Request::SetBytes(key, _)
+            | Request::GetBytes(key)
+            | Request::Incr(key, _)
+            | Request::SetIfAbsent(key, _)
+            | Request::SetIfPresent(key, _) => Some(key),
+            Request::Bucket(_, request) => request.key(),
+            Request::WriteBatch(_)
+            | Request::Len
+            | Request::MultiGet(_)
+            | Request::Replicate
+            | Request::Subscribe(_)
+            | Request::Publish(..)
+            | Request::Watch(_)
+            | Request::Scan { .. }
+            | Request::BulkLoad
+            | Request::Admin(_)
+            | Request::Custom(..)
+            | Request::Batch(_)
+            | Request::Ping(_)
+            | Request::Changes(_)
+            | Request::ReplicateSnapshot
+            | Request::Use(_)
+            | Request::Info
+            | Request::Monitor
+            | Request::Transaction(..) => None,
+        }
+    }
+
+    // the namespaces the request runs in, the buckets of its bucket requests, or the one
+    // a `Use` request switches to; none for the default keyspace
+    pub fn namespaces(&self) -> Vec<&str> {
+        match self {
+            Request::Bucket(name, _) | Request::Use(Some(name)) => vec![name],
+            Request::Batch(requests) => requests.iter().flat_map(Request::namespaces).collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+// A request on the wire, with the id the client assigned it, which its response echoes;
+// the responses of a connection come as the requests complete, not in their order
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RequestFrame {
+    pub id: u64,
+    pub request: Request,
+}
+
+// A response on the wire, with the id of its request; `None` for the messages of the
+// subscribed channels, and for an error failing the whole connection
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseFrame {
+    pub id: Option<u64>,
+    pub response: Response,
 }
 
 // The repsone struct that server return
@@ -17,6 +305,227 @@ pub enum Response {
     // Successful request
     // For Set and Remove request, there is no need to consider the value in Ok
     Ok(Option<String>),
+    // Remaining time to live of a key, `None` if it never expires
+    Ttl(Option<Duration>),
+    // Number of live keys
+    Len(usize),
+    // New value of an incremented key
+    Int(i64),
+    // Successful GetBytes request
+    Bytes(#[serde(with = "serde_bytes")] Option<Vec<u8>>),
+    // Values of a MultiGet request, in the order of its keys
+    Values(Vec<Option<String>>),
+    // Whether a conditional set happened
+    Bool(bool),
     // Failed request
     Err(String),
+    // Request that did not complete within the request timeout of the server
+    Timeout(Duration),
+    // Keys of a Scan request, and the cursor of the next page, `None` once done
+    Keys(Vec<String>, Option<String>),
+    // Summary of a bulk load
+    Loaded(LoadSummary),
+    // Number of connections a published message was sent to
+    Receivers(usize),
+    // Statistics of the engine
+    Stats(EngineStats),
+    // Message published on a channel the connection subscribed to, sent
+    // between the responses: the channel, then the message
+    Message(String, String),
+    // Request refused as the thread pool of the server had no room to queue it
+    Busy,
+    // Number of connections open from each peer IP, ordered by IP
+    Connections(Vec<(IpAddr, usize)>),
+    // Responses of a Batch request, in the order of its requests
+    Batch(Vec<Response>),
+    // Answer to a Ping request, with its payload
+    Pong(Option<String>),
+    // State of the server
+    Info(ServerInfo),
+    // Connections open on the server, ordered by id
+    Clients(Vec<ClientInfo>),
+    // The server is shutting down, sent unnumbered: the connection answers the requests
+    // in flight until the shutdown timeout, then closes, without reading more requests
+    ShuttingDown,
+}
+
+// The state of a server, answering an Info request
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    // the version of the server
+    pub version: String,
+    // the time since the server started
+    pub uptime: Duration,
+    // the name of the engine, like kvs
+    pub engine: String,
+    // the statistics of the engine, its memory and disk usage among them
+    pub stats: EngineStats,
+    // the counts of connections and requests, `None` unless the server keeps metrics
+    pub counts: Option<ServerCounts>,
+    // the statistics of the requests of each operation, like get, empty unless the server
+    // keeps metrics
+    pub ops: BTreeMap<String, OpStats>,
+}
+
+// A connection open on a server, answering a ClientList admin command
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientInfo {
+    // the id to kill the connection with, numbering the connections of the server
+    pub id: u64,
+    // the peer address of the client
+    pub addr: String,
+    // the common name of the certificate of the client, if it authenticated with one
+    pub name: Option<String>,
+    // the time since the connection opened
+    pub age: Duration,
+    // the time since the last request of the connection, or since it opened
+    pub idle: Duration,
+    // the operation of the last request of the connection, if any
+    pub last_op: Option<String>,
+    // the bytes read from and written to the connection, TLS included
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+// The counts of the connections and requests of a server since it started
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerCounts {
+    pub connections: u64,
+    pub active_connections: u64,
+    // the connections closed right away, as their IP had as many open as allowed
+    pub refused_connections: u64,
+    pub requests: u64,
+    pub failed_requests: u64,
+    // the requests refused as the thread pool had no room to queue them
+    pub busy_requests: u64,
+}
+
+// A request the server read, streamed to the connections monitoring it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MonitorEvent {
+    // when the server read the request
+    pub time: SystemTime,
+    // the peer address of the client which sent it
+    pub addr: String,
+    // the operation of the request, like get
+    pub op: String,
+    // the key of the request, if it is about a single key
+    pub key: Option<String>,
+    // the requests the monitor missed just before this one, as it was over the rate of
+    // the server or too slow to read them
+    pub dropped: u64,
+}
+
+// The statistics of the requests of one operation a server answered since it started
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpStats {
+    pub requests: u64,
+    // the requests answered with an error, the refused ones included
+    pub failed_requests: u64,
+    // the lookups, like gets, whose key was found, then the ones whose key was not
+    pub hits: u64,
+    pub misses: u64,
+    // how long the requests took to answer
+    pub latency: LatencyHistogram,
+}
+
+// The latencies of requests, counted in buckets whose bounds are powers of two microseconds
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LatencyHistogram {
+    // the bucket i counts the latencies of at least 2^(i-1) microseconds and below 2^i, the
+    // first one those below a microsecond and the last one the latencies above too
+    pub buckets: Vec<u64>,
+    // the sum of the latencies in microseconds
+    pub total_us: u64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> LatencyHistogram {
+        LatencyHistogram {
+            buckets: vec![0; LATENCY_BUCKETS],
+            total_us: 0,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    // count a request answered in latency
+    pub(crate) fn record(&mut self, latency: Duration) {
+        let us = latency.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = ((u64::BITS - us.leading_zeros()) as usize).min(self.buckets.len() - 1);
+        self.buckets[bucket] += 1;
+        self.total_us = self.total_us.saturating_add(us);
+    }
+
+    // the number of requests counted
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    // the mean latency, zero without requests
+    pub fn mean(&self) -> Duration {
+        let count = self.count();
+        match count {
+            0 => Duration::ZERO,
+            _ => Duration::from_micros(self.total_us / count),
+        }
+    }
+
+    // the latency below which the fraction q of the requests were answered, like 0.99 for
+    // the 99th percentile, rounded up to the bound of its bucket; zero without requests
+    pub fn percentile(&self, q: f64) -> Duration {
+        let count = self.count();
+        if count == 0 {
+            return Duration::ZERO;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Duration::from_micros(1 << i);
+            }
+        }
+        Duration::from_micros(1 << (self.buckets.len() - 1))
+    }
+}
+
+// A change to a watched key, with the value of the key when the event was sent
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WatchEvent {
+    pub key: String,
+    pub kind: ChangeKind,
+    // the new value of the key, `None` once removed
+    pub value: Option<String>,
+}
+
+// A change committed on the server, numbered by its offset in the change log of the
+// server, with the value of the key when it was recorded
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeEvent {
+    pub offset: u64,
+    pub key: String,
+    pub kind: ChangeKind,
+    // the new value of the key, `None` once removed
+    pub value: Option<String>,
+}
+
+// A frame of a bulk load, which the client sends once its BulkLoad request is accepted
+#[derive(Debug, Serialize, Deserialize)]
+pub enum BulkFrame {
+    // set the keys to the values, as one batch
+    Pairs(Vec<(String, String)>),
+    // end the bulk load, which the server answers with its summary
+    End,
+}
+
+// The summary of a bulk load
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LoadSummary {
+    // the number of keys set
+    pub keys: usize,
+    // the number of batches applied
+    pub batches: usize,
+    // the time the server took, from the request to the end of the load
+    pub elapsed: Duration,
 }
@@ -0,0 +1,751 @@
+//! The connections of clients to a server.
+
+use std::{
+    collections::HashMap,
+    io::{self, BufReader, Read, Write},
+    net::{Shutdown, TcpStream},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex, MutexGuard, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use tokio_rustls::rustls::{pki_types::ServerName, ClientConfig, ClientConnection, StreamOwned};
+use tracing::debug;
+
+use crate::{
+    codec::Incoming,
+    common::{RequestFrame, ResponseFrame},
+    Codec, KvError, Request, Response, Result,
+};
+
+/// Bytes of a TLS connection read from its socket at once.
+const TLS_READ_BUFFER_SIZE: usize = 16 * 1024;
+/// The largest frame a client sends or reads by default, like the largest
+/// request a server reads by default.
+const DEFAULT_MAX_FRAME_SIZE: usize = 8 * 1024 * 1024;
+
+/// A message published on a channel: the channel, then the message.
+type Message = (String, String);
+
+/// How to open connections to a server, plain or over TLS.
+///
+/// A server may be reached at several addresses, like the servers of a
+/// replicated pair: the connections go to the address that last accepted
+/// one, and to the next addresses in turn when connecting to it fails.
+#[derive(Clone)]
+pub(crate) struct Connector {
+    addrs: Arc<[Addr]>,
+    // the index of the address that last accepted a connection, shared by the clones
+    current: Arc<AtomicUsize>,
+    tls: Option<Arc<ClientConfig>>,
+    codec: Codec,
+    // how long a shared connection stays idle before it pings the server
+    keepalive: Option<Duration>,
+    // the largest frame sent or read
+    max_frame_size: usize,
+}
+
+/// An address of a server, resolved again at each connection so that the
+/// connections follow a name moved to another host.
+struct Addr {
+    addr: String,
+    // the name the server certificate is verified against, over TLS
+    server_name: Option<ServerName<'static>>,
+}
+
+impl Connector {
+    /// Connects to `addrs` in plain TCP.
+    pub(crate) fn tcp(addrs: &[String]) -> Connector {
+        let addrs = addrs.iter().map(|addr| Addr {
+            addr: addr.clone(),
+            server_name: None,
+        });
+        Connector::new(addrs.collect(), None)
+    }
+
+    /// Connects to `addrs` over TLS with `config`, verifying the certificate
+    /// of each server against the host of its address.
+    pub(crate) fn tls(addrs: &[String], config: Arc<ClientConfig>) -> Result<Connector> {
+        let addrs = addrs
+            .iter()
+            .map(|addr| {
+                let host = addr
+                    .rsplit_once(':')
+                    .map_or(addr.as_str(), |(host, _)| host);
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                let server_name = ServerName::try_from(host.to_owned())
+                    .map_err(|err| KvError::Tls(format!("{}: {}", host, err)))?;
+                Ok(Addr {
+                    addr: addr.clone(),
+                    server_name: Some(server_name),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Connector::new(addrs, Some(config)))
+    }
+
+    fn new(addrs: Arc<[Addr]>, tls: Option<Arc<ClientConfig>>) -> Connector {
+        Connector {
+            addrs,
+            current: Arc::new(AtomicUsize::new(0)),
+            tls,
+            codec: Codec::Json,
+            keepalive: None,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+
+    /// Negotiates `codec` for the frames of the connections.
+    pub(crate) fn with_codec(mut self, codec: Codec) -> Connector {
+        self.codec = codec;
+        self
+    }
+
+    /// Pings the server on the shared connections idle for `interval`,
+    /// closing a connection whose ping is not answered within `interval`.
+    pub(crate) fn with_keepalive(mut self, interval: Duration) -> Connector {
+        self.keepalive = Some(interval);
+        self
+    }
+
+    /// Refuses to send or read frames larger than `max_frame_size` bytes on
+    /// the connections.
+    pub(crate) fn with_max_frame_size(mut self, max_frame_size: usize) -> Connector {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Returns the codec of the frames of the connections.
+    pub(crate) fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Returns the size of the largest frame sent or read on the connections.
+    pub(crate) fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    /// Opens a connection used by one request at a time, like a watch taking
+    /// the connection over.
+    pub(crate) fn connect(&self) -> Result<BufReader<Stream>> {
+        self.fail_over(|addr| {
+            let session = self.session(addr)?;
+            let socket = TcpStream::connect(&addr.addr)?;
+            let mut stream = BufReader::new(match session {
+                Some(session) => Stream::Tls(Box::new(StreamOwned::new(session, socket))),
+                None => Stream::Tcp(socket),
+            });
+            if self.codec != Codec::Json {
+                let socket = stream.get_mut();
+                socket.write_all(&self.codec.preamble())?;
+                socket.flush()?;
+            }
+            self.codec.read_accepted(&mut stream)?;
+            Ok(stream)
+        })
+    }
+
+    /// Opens a connection shared by the clones of a client.
+    pub(crate) fn connect_shared(&self) -> Result<SharedConnection> {
+        self.fail_over(|addr| {
+            let session = self.session(addr)?.map(|mut session| {
+                // the requests written during the handshake wait in the session
+                session.set_buffer_limit(None);
+                Arc::new(Session {
+                    connection: Mutex::new(session),
+                    sending: Mutex::new(()),
+                })
+            });
+            let socket = Arc::new(TcpStream::connect(&addr.addr)?);
+            let connection =
+                SharedConnection::start(socket, session, self.codec, self.max_frame_size)?;
+            if let Some(interval) = self.keepalive {
+                connection.keep_alive(interval)?;
+            }
+            Ok(connection)
+        })
+    }
+
+    /// Connects with `connect` to the address that last accepted a
+    /// connection, then to the next addresses in turn while connecting fails
+    /// with an IO error, returning the last error if every address failed.
+    fn fail_over<T>(&self, connect: impl Fn(&Addr) -> Result<T>) -> Result<T> {
+        let current = self.current.load(Ordering::Relaxed);
+        let mut last_err = None;
+        for i in 0..self.addrs.len() {
+            let index = (current + i) % self.addrs.len();
+            let addr = &self.addrs[index];
+            match connect(addr) {
+                Ok(connection) => {
+                    if index != current {
+                        debug!("failed over to {}", addr.addr);
+                        self.current.store(index, Ordering::Relaxed);
+                    }
+                    return Ok(connection);
+                }
+                Err(KvError::Io(err)) => {
+                    debug!("connecting to {} failed: {}", addr.addr, err);
+                    last_err = Some(KvError::Io(err));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            KvError::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no address to connect to",
+            ))
+        }))
+    }
+
+    /// Returns a new TLS session with the server at `addr`, if the
+    /// connections are over TLS.
+    fn session(&self, addr: &Addr) -> Result<Option<ClientConnection>> {
+        self.tls
+            .as_ref()
+            .zip(addr.server_name.as_ref())
+            .map(|(config, server_name)| {
+                ClientConnection::new(config.clone(), server_name.clone())
+                    .map_err(|err| KvError::Tls(err.to_string()))
+            })
+            .transpose()
+    }
+}
+
+/// A connection shared by the clones of a client.
+///
+/// Each request has an id, which its response echoes, so many requests may
+/// be in flight at once whatever the clone that sent them. A thread reads
+/// the responses, handing each to the request of its id.
+#[derive(Clone)]
+pub(crate) struct SharedConnection {
+    shared: Arc<Shared>,
+}
+
+/// The state of a shared connection, the socket closed once every clone is dropped.
+struct Shared {
+    socket: Arc<TcpStream>,
+    writer: Mutex<Writer>,
+    // when the last requests were written
+    last_sent: Mutex<Instant>,
+    codec: Codec,
+    max_frame_size: usize,
+    waiting: Arc<Mutex<Waiting>>,
+    next_id: AtomicU64,
+    // the messages of the channels the connection subscribed to
+    messages: Mutex<mpsc::Receiver<Message>>,
+}
+
+/// The requests waiting for their response.
+#[derive(Default)]
+struct Waiting {
+    senders: HashMap<u64, mpsc::Sender<Result<Response>>>,
+    // why the connection closed, failing the requests sent since
+    closed: Option<Closed>,
+}
+
+/// Why a shared connection closed.
+#[derive(Clone)]
+enum Closed {
+    // the server failed the connection with this message
+    Server(String),
+    // the server closed the connection, or reading it failed
+    Io(String),
+    // the server is shutting down, answering the requests in flight only
+    ShuttingDown,
+    // the server sent a JSON response larger than the max frame size, whose
+    // rest cannot be told from the next frames
+    TooLarge(usize),
+}
+
+impl Closed {
+    fn error(&self) -> KvError {
+        match self {
+            Closed::Server(message) => KvError::StringError(message.clone()),
+            Closed::Io(message) => KvError::Io(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                message.clone(),
+            )),
+            Closed::ShuttingDown => KvError::ShuttingDown,
+            Closed::TooLarge(max_frame_size) => KvError::ResponseTooLarge(*max_frame_size),
+        }
+    }
+}
+
+/// The response of a request sent on a shared connection, once it comes.
+pub(crate) struct PendingResponse {
+    id: u64,
+    rx: mpsc::Receiver<Result<Response>>,
+    waiting: Arc<Mutex<Waiting>>,
+}
+
+impl PendingResponse {
+    /// Waits for the response, failing with a timeout error if it did not
+    /// come within `timeout`.
+    ///
+    /// A request given up on is forgotten, its response dropped if it comes.
+    pub(crate) fn wait(self, timeout: Option<Duration>) -> Result<Response> {
+        let received = match timeout {
+            Some(timeout) => self.rx.recv_timeout(timeout).map_err(|err| match err {
+                mpsc::RecvTimeoutError::Timeout => Some(timeout),
+                mpsc::RecvTimeoutError::Disconnected => None,
+            }),
+            None => self.rx.recv().map_err(|_| None),
+        };
+        match received {
+            Ok(resp) => resp,
+            Err(Some(timeout)) => {
+                self.waiting.lock().unwrap().senders.remove(&self.id);
+                Err(KvError::RequestTimeout(timeout))
+            }
+            Err(None) => Err(Closed::Io("connection closed".to_owned()).error()),
+        }
+    }
+}
+
+impl SharedConnection {
+    /// Starts reading the responses of the connection over `socket`, with
+    /// the TLS `session` if any, negotiating `codec`, its frames at most
+    /// `max_frame_size` bytes.
+    ///
+    /// The requests are sent right after the preamble of the codec, as the
+    /// server either speaks the codec or closes the connection.
+    fn start(
+        socket: Arc<TcpStream>,
+        session: Option<Arc<Session>>,
+        codec: Codec,
+        max_frame_size: usize,
+    ) -> Result<SharedConnection> {
+        let mut writer = Writer {
+            socket: socket.clone(),
+            session: session.clone(),
+        };
+        if codec != Codec::Json {
+            writer.write_all(&codec.preamble())?;
+        }
+        // sends the first message of the handshake, which the reader goes on with
+        writer.flush()?;
+        let reader = Reader {
+            socket: socket.clone(),
+            session,
+            incoming: vec![0; TLS_READ_BUFFER_SIZE],
+            start: 0,
+            end: 0,
+        };
+        let waiting = Arc::new(Mutex::new(Waiting::default()));
+        let (tx, rx) = mpsc::channel();
+        let reader_waiting = waiting.clone();
+        thread::Builder::new()
+            .name("kv-client-reader".to_owned())
+            .spawn(move || read_responses(reader, codec, max_frame_size, &reader_waiting, tx))?;
+        Ok(SharedConnection {
+            shared: Arc::new(Shared {
+                socket,
+                writer: Mutex::new(writer),
+                last_sent: Mutex::new(Instant::now()),
+                codec,
+                max_frame_size,
+                waiting,
+                next_id: AtomicU64::new(0),
+                messages: Mutex::new(rx),
+            }),
+        })
+    }
+
+    /// Sends `requests` at once, returning their pending responses in the
+    /// order of the requests.
+    ///
+    /// A request larger than the max frame size fails the whole batch with
+    /// `KvError::FrameTooLarge` before anything is sent, leaving the
+    /// connection open.
+    pub(crate) fn send(&self, requests: Vec<Request>) -> Result<Vec<PendingResponse>> {
+        let max_frame_size = self.shared.max_frame_size;
+        let mut data = Vec::new();
+        let mut ids = Vec::with_capacity(requests.len());
+        for request in requests {
+            let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+            let start = data.len();
+            self.shared
+                .codec
+                .encode_into(&mut data, &RequestFrame { id, request })?;
+            if data.len() - start > max_frame_size {
+                return Err(KvError::FrameTooLarge(max_frame_size));
+            }
+            ids.push(id);
+        }
+
+        let mut pending = Vec::with_capacity(ids.len());
+        {
+            let mut waiting = self.shared.waiting.lock().unwrap();
+            if let Some(closed) = &waiting.closed {
+                return Err(closed.error());
+            }
+            // before writing, so that no response comes before its request waits
+            for &id in &ids {
+                let (tx, rx) = mpsc::channel();
+                waiting.senders.insert(id, tx);
+                pending.push(PendingResponse {
+                    id,
+                    rx,
+                    waiting: self.shared.waiting.clone(),
+                });
+            }
+        }
+        let written = {
+            let mut writer = self.shared.writer.lock().unwrap();
+            // the server expects a whole frame per read, so write them at once
+            writer.write_all(&data).and_then(|_| writer.flush())
+        };
+        if let Err(err) = written {
+            let mut waiting = self.shared.waiting.lock().unwrap();
+            for id in ids {
+                waiting.senders.remove(&id);
+            }
+            return Err(err.into());
+        }
+        *self.shared.last_sent.lock().unwrap() = Instant::now();
+        Ok(pending)
+    }
+
+    /// Starts a thread pinging the server once the connection stayed idle
+    /// for `interval`, so that the middleboxes keep its state, until every
+    /// clone is dropped.
+    ///
+    /// A ping not answered within `interval` closes the connection, failing
+    /// its requests in flight, so that the client notices a dead server
+    /// before its next request.
+    fn keep_alive(&self, interval: Duration) -> Result<()> {
+        let shared = Arc::downgrade(&self.shared);
+        thread::Builder::new()
+            .name("kv-client-keepalive".to_owned())
+            .spawn(move || keep_alive(shared, interval))?;
+        Ok(())
+    }
+
+    /// Closes the connection, failing the requests in flight and the ones
+    /// sent from now on with an IO error of `message`.
+    fn abort(&self, message: String) {
+        self.shared.waiting.lock().unwrap().closed = Some(Closed::Io(message));
+        // the reader stops, failing the requests in flight
+        drop(self.shared.socket.shutdown(Shutdown::Both));
+    }
+
+    /// Returns whether the connection closed, or is closing as the server
+    /// shuts down, so the requests sent from now on fail.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.shared.waiting.lock().unwrap().closed.is_some()
+    }
+
+    /// Returns whether the connection closed on a JSON response larger than
+    /// the max frame size, the server and the connection to it being fine.
+    pub(crate) fn is_closed_too_large(&self) -> bool {
+        matches!(
+            self.shared.waiting.lock().unwrap().closed,
+            Some(Closed::TooLarge(_))
+        )
+    }
+
+    /// Waits for the next message of the subscribed channels.
+    pub(crate) fn next_message(&self) -> Result<Message> {
+        let messages = self.shared.messages.lock().unwrap();
+        messages.recv().map_err(|_| {
+            let waiting = self.shared.waiting.lock().unwrap();
+            let closed = waiting.closed.clone();
+            closed
+                .unwrap_or_else(|| Closed::Io("connection closed".to_owned()))
+                .error()
+        })
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        if let Ok(writer) = self.writer.get_mut() {
+            writer.close();
+        }
+    }
+}
+
+/// Pings the server of the connection of `shared` once idle for `interval`,
+/// until the connection is dropped or closes.
+fn keep_alive(shared: Weak<Shared>, interval: Duration) {
+    let mut wait = interval;
+    loop {
+        thread::sleep(wait);
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        let connection = SharedConnection { shared };
+        if connection.is_closed() {
+            return;
+        }
+        let idle = connection.shared.last_sent.lock().unwrap().elapsed();
+        if idle < interval {
+            wait = interval - idle;
+            continue;
+        }
+        wait = interval;
+        let pong = connection
+            .send(vec![Request::Ping(None)])
+            .and_then(|mut pending| pending.remove(0).wait(Some(interval)));
+        if let Err(err) = pong {
+            debug!("keepalive ping failed: {}", err);
+            connection.abort(format!("keepalive ping failed: {}", err));
+            return;
+        }
+    }
+}
+
+/// Hands the responses read from `reader` in `codec`, of at most
+/// `max_frame_size` bytes, to the requests waiting for them in `waiting`,
+/// and the messages of the subscribed channels to `messages`, until the
+/// connection closes.
+fn read_responses(
+    reader: Reader,
+    codec: Codec,
+    max_frame_size: usize,
+    waiting: &Mutex<Waiting>,
+    messages: mpsc::Sender<Message>,
+) {
+    let mut reader = BufReader::new(reader);
+    let closed = match codec.read_accepted(&mut reader) {
+        Ok(()) => dispatch_frames(&mut reader, codec, max_frame_size, waiting, &messages),
+        Err(KvError::StringError(message)) => Closed::Server(message),
+        Err(err) => Closed::Io(err.to_string()),
+    };
+
+    debug!("connection closed");
+    let mut waiting = waiting.lock().unwrap();
+    // the requests not answered before a shutdown, or once aborted, fail as such
+    let closed = waiting.closed.take().unwrap_or(closed);
+    for (_, sender) in waiting.senders.drain() {
+        drop(sender.send(Err(closed.error())));
+    }
+    waiting.closed = Some(closed);
+}
+
+/// Dispatches the frames of `reader` until the connection closes, returning why.
+fn dispatch_frames(
+    reader: &mut BufReader<Reader>,
+    codec: Codec,
+    max_frame_size: usize,
+    waiting: &Mutex<Waiting>,
+    messages: &mpsc::Sender<Message>,
+) -> Closed {
+    loop {
+        let frame = match codec.read_response(reader, max_frame_size) {
+            Ok(Some(Incoming::Frame(frame))) => frame,
+            Ok(Some(Incoming::TooLarge(Some(id)))) => {
+                let sender = waiting.lock().unwrap().senders.remove(&id);
+                if let Some(sender) = sender {
+                    drop(sender.send(Err(KvError::ResponseTooLarge(max_frame_size))));
+                }
+                continue;
+            }
+            Ok(Some(Incoming::TooLarge(None))) => {
+                debug!("dropping a message larger than {} bytes", max_frame_size);
+                continue;
+            }
+            Ok(None) => return Closed::Io("connection closed".to_owned()),
+            Err(KvError::ResponseTooLarge(_)) => return Closed::TooLarge(max_frame_size),
+            Err(err) => return Closed::Io(err.to_string()),
+        };
+        match frame {
+            ResponseFrame {
+                id: Some(id),
+                response,
+            } => {
+                let sender = waiting.lock().unwrap().senders.remove(&id);
+                match sender {
+                    // the request may have given up waiting
+                    Some(sender) => drop(sender.send(Ok(response))),
+                    None => debug!("dropping the response of unknown request {}", id),
+                }
+            }
+            ResponseFrame {
+                id: None,
+                response: Response::Message(channel, message),
+            } => drop(messages.send((channel, message))),
+            ResponseFrame {
+                id: None,
+                response: Response::Err(message),
+            } => return Closed::Server(message),
+            // the requests sent from now on fail, the ones in flight are still answered
+            ResponseFrame {
+                id: None,
+                response: Response::ShuttingDown,
+            } => waiting.lock().unwrap().closed = Some(Closed::ShuttingDown),
+            ResponseFrame { id: None, .. } => {
+                return Closed::Io(format!("{}", KvError::UnexpectedResponse))
+            }
+        }
+    }
+}
+
+/// The TLS session of a shared connection, used by its writer and its reader.
+struct Session {
+    connection: Mutex<ClientConnection>,
+    // held while writing records to the socket, taken before the connection
+    // is unlocked so that the records are written in the order they were
+    // encrypted
+    sending: Mutex<()>,
+}
+
+/// The writing half of a shared connection.
+///
+/// Over TLS, the session is only locked while encrypting, so the reader does
+/// not wait for the writer to get bytes to the socket, which the server may
+/// not read until the reader read its responses.
+struct Writer {
+    socket: Arc<TcpStream>,
+    session: Option<Arc<Session>>,
+}
+
+impl Writer {
+    /// Closes the connection, which makes its reader stop.
+    fn close(&mut self) {
+        if let Some(session) = &self.session {
+            let mut connection = session.connection.lock().unwrap();
+            connection.send_close_notify();
+            drop(write_tls(session, connection, &self.socket));
+        }
+        drop(self.socket.shutdown(Shutdown::Both));
+    }
+}
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &self.session {
+            Some(session) => {
+                let mut connection = session.connection.lock().unwrap();
+                let n = connection.writer().write(buf)?;
+                write_tls(session, connection, &self.socket)?;
+                Ok(n)
+            }
+            None => (&*self.socket).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &self.session {
+            Some(session) => write_tls(session, session.connection.lock().unwrap(), &self.socket),
+            None => (&*self.socket).flush(),
+        }
+    }
+}
+
+/// The reading half of a shared connection.
+///
+/// Over TLS, the session is only locked while decrypting, so the writer does
+/// not wait for the reader to get bytes from the socket.
+struct Reader {
+    socket: Arc<TcpStream>,
+    session: Option<Arc<Session>>,
+    // the bytes read from the socket, of which those from `start` to `end`
+    // are not given to the session yet
+    incoming: Vec<u8>,
+    start: usize,
+    end: usize,
+}
+
+impl Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(session) = &self.session else {
+            return (&*self.socket).read(buf);
+        };
+        loop {
+            let mut locked = session.connection.lock().unwrap();
+            match locked.reader().read(buf) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                res => return res,
+            }
+            if self.start < self.end {
+                let n = locked.read_tls(&mut &self.incoming[self.start..self.end])?;
+                self.start += n;
+                locked
+                    .process_new_packets()
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                // the handshake, and the requests written during it
+                write_tls(session, locked, &self.socket)?;
+                continue;
+            }
+            drop(locked);
+
+            self.start = 0;
+            self.end = (&*self.socket).read(&mut self.incoming)?;
+            if self.end == 0 {
+                // the session tells a closed connection from a truncated one
+                let mut locked = session.connection.lock().unwrap();
+                locked.read_tls(&mut io::empty())?;
+                return match locked.reader().read(buf) {
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(0),
+                    res => res,
+                };
+            }
+        }
+    }
+}
+
+/// Writes the TLS records `session` has to send to `socket`, encrypted while
+/// `connection`, its locked connection, is held, then written once it is
+/// unlocked.
+fn write_tls(
+    session: &Session,
+    mut connection: MutexGuard<ClientConnection>,
+    socket: &TcpStream,
+) -> io::Result<()> {
+    let mut records = Vec::new();
+    while connection.wants_write() {
+        connection.write_tls(&mut records)?;
+    }
+    if records.is_empty() {
+        return Ok(());
+    }
+    let _sending = session.sending.lock().unwrap();
+    drop(connection);
+    (&*socket).write_all(&records)
+}
+
+/// A connection to the server, plain or over TLS, used by one request at a time.
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Stream {
+    /// Returns the socket of the connection.
+    pub(crate) fn socket(&self) -> &TcpStream {
+        match self {
+            Stream::Tcp(stream) => stream,
+            Stream::Tls(stream) => stream.get_ref(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+
+/// A single operation of a `WriteBatch`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BatchOp {
+    /// Sets the value of a key.
+    Put(String, String),
+    /// Removes a key.
+    Delete(String),
+}
+
+/// A group of writes that an engine commits atomically.
+///
+/// Operations are applied in insertion order, so a later operation on a key
+/// overrides an earlier one in the same batch.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Queues setting the value of a key.
+    pub fn put(&mut self, key: String, value: String) -> &mut Self {
+        self.ops.push(BatchOp::Put(key, value));
+        self
+    }
+
+    /// Queues removing a key.
+    ///
+    /// Committing the batch fails with `KvError::KeyNotFound` if the key does
+    /// not exist at that point of the batch.
+    pub fn delete(&mut self, key: String) -> &mut Self {
+        self.ops.push(BatchOp::Delete(key));
+        self
+    }
+
+    /// Returns the number of queued operations.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns whether no operation is queued.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Returns an iterator over the queued operations.
+    pub fn iter(&self) -> std::slice::Iter<'_, BatchOp> {
+        self.ops.iter()
+    }
+}
+
+impl IntoIterator for WriteBatch {
+    type Item = BatchOp;
+    type IntoIter = std::vec::IntoIter<BatchOp>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a WriteBatch {
+    type Item = &'a BatchOp;
+    type IntoIter = std::slice::Iter<'a, BatchOp>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.iter()
+    }
+}
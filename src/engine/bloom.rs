@@ -0,0 +1,97 @@
+/// Bits of filter per key, giving a false positive rate of about 1%.
+const BITS_PER_KEY: usize = 10;
+
+/// Number of bit positions set for each key.
+const NUM_HASHES: u64 = 7;
+
+/// Length of the encoded header: number of keys and number of bit words.
+const HEADER_LEN: usize = 16;
+
+/// A bloom filter over the keys of one log segment.
+///
+/// `may_contain` never returns `false` for an inserted key, so a segment
+/// can be skipped whenever it does.
+#[derive(Clone)]
+pub(super) struct BloomFilter {
+    bits: Vec<u64>,
+    // number of keys inserted
+    len: usize,
+}
+
+impl BloomFilter {
+    /// Creates an empty filter sized for `keys` keys.
+    pub(super) fn with_capacity(keys: usize) -> BloomFilter {
+        let words = (keys.max(1) * BITS_PER_KEY).div_ceil(64);
+        BloomFilter {
+            bits: vec![0; words],
+            len: 0,
+        }
+    }
+
+    /// Returns the number of keys the filter was sized for.
+    pub(super) fn capacity(&self) -> usize {
+        self.bits.len() * 64 / BITS_PER_KEY
+    }
+
+    /// Returns the number of keys inserted.
+    pub(super) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(super) fn insert(&mut self, key: &str) {
+        for bit in self.bit_positions(key) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+        self.len += 1;
+    }
+
+    /// Returns `false` if `key` was definitely never inserted.
+    pub(super) fn may_contain(&self, key: &str) -> bool {
+        self.bit_positions(key)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    fn bit_positions(&self, key: &str) -> impl Iterator<Item = usize> {
+        // double hashing derives all positions from one 64-bit hash
+        let hash = fnv1a(key.as_bytes());
+        let (h1, h2) = (hash & 0xFFFF_FFFF, (hash >> 32) | 1);
+        let num_bits = self.bits.len() as u64 * 64;
+        (0..NUM_HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+    }
+
+    /// Encodes the filter with a trailing CRC32, to be persisted next to its segment.
+    pub(super) fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.bits.len() * 8 + 4);
+        bytes.extend_from_slice(&(self.len as u64).to_le_bytes());
+        bytes.extend_from_slice(&(self.bits.len() as u64).to_le_bytes());
+        for word in &self.bits {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        let crc = crc32fast::hash(&bytes);
+        bytes.extend_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    /// Decodes a filter written by `encode`, or returns `None` if it is damaged.
+    pub(super) fn decode(bytes: &[u8]) -> Option<BloomFilter> {
+        let (body, crc) = bytes.split_at_checked(bytes.len().checked_sub(4)?)?;
+        if crc32fast::hash(body).to_le_bytes() != crc || body.len() < HEADER_LEN {
+            return None;
+        }
+        let read_u64 = |chunk: &[u8]| u64::from_le_bytes(chunk.try_into().unwrap());
+        let len = read_u64(&body[0..8]) as usize;
+        let words = read_u64(&body[8..16]) as usize;
+        let bits: Vec<u64> = body[HEADER_LEN..].chunks_exact(8).map(read_u64).collect();
+        if words == 0 || bits.len() != words || body.len() != HEADER_LEN + words * 8 {
+            return None;
+        }
+        Some(BloomFilter { bits, len })
+    }
+}
+
+/// 64-bit FNV-1a, which unlike the std hasher is stable across releases.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
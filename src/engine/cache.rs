@@ -0,0 +1,81 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// Position of a record: file id, offset, and offset within its compressed block.
+pub(super) type CacheKey = (u64, u64, Option<u32>);
+
+/// Approximate memory used by an entry besides its value.
+const ENTRY_OVERHEAD: usize = 64;
+
+/// A least recently used cache of values, keyed by the position of their record.
+///
+/// Records are never modified in place, so an entry can only become unused,
+/// never stale: an overwritten key is read from its new position.
+pub(super) struct ValueCache {
+    capacity: usize,
+    size: usize,
+    // key -> (value, tick of the last access)
+    entries: HashMap<CacheKey, (Vec<u8>, u64)>,
+    // tick of the last access -> key, the first one is evicted first
+    lru: BTreeMap<u64, CacheKey>,
+    tick: u64,
+}
+
+impl ValueCache {
+    /// Creates a cache holding up to about `capacity` bytes.
+    pub(super) fn new(capacity: usize) -> ValueCache {
+        ValueCache {
+            capacity,
+            size: 0,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub(super) fn get(&mut self, key: &CacheKey) -> Option<Vec<u8>> {
+        self.tick += 1;
+        let (value, tick) = self.entries.get_mut(key)?;
+        self.lru.remove(tick);
+        *tick = self.tick;
+        self.lru.insert(self.tick, *key);
+        Some(value.clone())
+    }
+
+    pub(super) fn insert(&mut self, key: CacheKey, value: Vec<u8>) {
+        let size = value.len() + ENTRY_OVERHEAD;
+        if size > self.capacity {
+            return;
+        }
+        self.remove(&key);
+        while self.size + size > self.capacity {
+            match self.lru.first_key_value() {
+                Some((_, &oldest)) => self.remove(&oldest),
+                None => break,
+            }
+        }
+        self.tick += 1;
+        self.size += size;
+        self.entries.insert(key, (value, self.tick));
+        self.lru.insert(self.tick, key);
+    }
+
+    /// Drops the entries of the log files older than `file_id`.
+    pub(super) fn remove_before(&mut self, file_id: u64) {
+        let stale: Vec<CacheKey> = self
+            .entries
+            .keys()
+            .filter(|key| key.0 < file_id)
+            .copied()
+            .collect();
+        for key in stale {
+            self.remove(&key);
+        }
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some((value, tick)) = self.entries.remove(key) {
+            self.lru.remove(&tick);
+            self.size -= value.len() + ENTRY_OVERHEAD;
+        }
+    }
+}
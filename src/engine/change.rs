@@ -0,0 +1,32 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use serde::{Deserialize, Serialize};
+
+/// The kind of a committed change to a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChangeKind {
+    /// The key was set to a new value.
+    Set,
+    /// The key was removed.
+    Remove,
+}
+
+/// The receivers of change events of a store.
+#[derive(Default)]
+pub(super) struct Subscribers {
+    senders: Vec<Sender<(String, ChangeKind)>>,
+}
+
+impl Subscribers {
+    pub(super) fn subscribe(&mut self) -> Receiver<(String, ChangeKind)> {
+        let (sender, receiver) = mpsc::channel();
+        self.senders.push(sender);
+        receiver
+    }
+
+    /// Sends the event to every subscriber, forgetting those that dropped their receiver.
+    pub(super) fn notify(&mut self, key: &str, kind: ChangeKind) {
+        self.senders
+            .retain(|sender| sender.send((key.to_owned(), kind)).is_ok());
+    }
+}
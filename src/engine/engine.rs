@@ -1,19 +1,347 @@
-use crate::Result;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    io::{BufRead, Read, Write},
+    ops::{Bound, RangeBounds},
+    sync::mpsc::Receiver,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{ChangeKind, KvError, KvSnapshot, Result, WriteBatch};
+
+/// Number of imported pairs written per batch.
+const IMPORT_BATCH_SIZE: usize = 1024;
+
+/// A key/value pair, written as one JSON line by `export`.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ExportRecord<'a> {
+    #[serde(borrow)]
+    key: Cow<'a, str>,
+    #[serde(borrow)]
+    value: Cow<'a, str>,
+}
+
+/// Writes one key/value pair as a JSON line.
+pub(crate) fn write_export_record<W: Write>(writer: &mut W, key: &str, value: &str) -> Result<()> {
+    let record = ExportRecord {
+        key: Cow::Borrowed(key),
+        value: Cow::Borrowed(value),
+    };
+    serde_json::to_writer(&mut *writer, &record)?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Statistics of an engine, for operators.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EngineStats {
+    /// The number of live keys.
+    pub keys: usize,
+    /// The bytes the engine takes on the disk, `None` if it keeps nothing there.
+    pub disk_size: Option<u64>,
+    /// The bytes of stale records compaction would reclaim, `None` if the
+    /// engine does not track them.
+    pub stale_size: Option<u64>,
+    /// The approximate bytes of memory the keys take, `None` if the engine
+    /// does not track them.
+    pub memory_size: Option<u64>,
+}
 
 /// Trait for a key value storage engine.
-pub trait KvEngine: Clone + Send + 'static {
+pub trait KvEngine: Clone + Send + Sync + 'static {
     /// Sets the value of a string key to a string.
     ///
     /// If the key already exists, the previous value will be overwritten.
-    fn set(&mut self, key: String, value: String) -> Result<()>;
+    fn set(&self, key: String, value: String) -> Result<()>;
+
+    /// Sets the value of a string key to a string, which expires after `ttl`.
+    ///
+    /// An expired key behaves as if it has been removed.
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()>;
+
+    /// Gets the remaining time to live of a given key.
+    ///
+    /// Returns `None` if the key has no expiration,
+    /// and `KvError::KeyNotFound` if the given key does not exist.
+    fn ttl(&self, key: String) -> Result<Option<Duration>>;
 
     /// Gets the string value of a given string key.
     ///
     /// Returns `None` if the given key does not exist.
-    fn get(&mut self, key: String) -> Result<Option<String>>;
+    fn get(&self, key: String) -> Result<Option<String>>;
+
+    /// Gets the string value of a given string key like `get`, if the engine
+    /// has it in memory, and `None` if getting it would read the disk.
+    ///
+    /// A server answers the gets this answers on its network threads rather
+    /// than on its thread pool, so it must not block. Engines which cannot
+    /// tell return `None`.
+    fn get_in_memory(&self, _key: &str) -> Option<Result<Option<String>>> {
+        None
+    }
+
+    /// Gets the string values of many keys in one call.
+    ///
+    /// The values are returned in the order of `keys`, with `None` for the
+    /// keys that do not exist. Like `get`, it fails with `KvError::Utf8` if a
+    /// value is binary and not valid UTF-8, which `get_bytes` reads.
+    fn multi_get(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        keys.into_iter().map(|key| self.get(key)).collect()
+    }
+
+    /// Sets the value of a string key to arbitrary bytes.
+    ///
+    /// Keys share one keyspace whatever their value type, so `remove`, `ttl`
+    /// and `scan` also apply to keys set with bytes.
+    fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()>;
+
+    /// Gets the value of a given string key as bytes.
+    ///
+    /// Values set as strings are returned as their UTF-8 bytes, while `get`
+    /// fails with `KvError::Utf8` on a binary value that is not valid UTF-8.
+    /// Returns `None` if the given key does not exist.
+    fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>>;
 
     /// Removes a given key.
     ///
     /// Returns `KvsError::KeyNotFound` if the given key is not found.
-    fn remove(&mut self, key: String) -> Result<()>;
+    fn remove(&self, key: String) -> Result<()>;
+
+    /// Sets the value of a key only if it does not exist, and returns whether it was set.
+    ///
+    /// The check and the write happen atomically, so of several concurrent
+    /// callers exactly one sets the key. An expired key counts as absent.
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool>;
+
+    /// Sets the value of a key only if it already exists, and returns whether it was set.
+    ///
+    /// The check and the write happen atomically. As with `set`, the new
+    /// value has no expiration.
+    fn set_if_present(&self, key: String, value: String) -> Result<bool>;
+
+    /// Adds `delta` to the integer value of a key, and returns the new value.
+    ///
+    /// The read and the write happen atomically. A missing key counts as zero,
+    /// and the expiration of an existing key is kept. Returns
+    /// `KvError::NotAnInteger` if the value is not a 64-bit integer or the
+    /// result overflows.
+    fn incr(&self, key: String, delta: i64) -> Result<i64>;
+
+    /// Subtracts `delta` from the integer value of a key, and returns the new value.
+    fn decr(&self, key: String, delta: i64) -> Result<i64> {
+        self.incr(key, delta.checked_neg().ok_or(KvError::NotAnInteger)?)
+    }
+
+    /// Returns the number of live keys, not counting expired ones.
+    fn len(&self) -> Result<usize>;
+
+    /// Returns whether there is no live key.
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Applies all operations of `batch` atomically.
+    ///
+    /// Either every operation is persisted or none is. Returns
+    /// `KvError::KeyNotFound` without writing anything if the batch deletes
+    /// a key that does not exist.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Applies all operations of `batch` atomically like `write_batch`, but
+    /// may leave them unsynced to the disk until `sync`.
+    ///
+    /// Bulk loads sync once at the end instead of after every batch.
+    fn write_batch_unsynced(&self, batch: WriteBatch) -> Result<()> {
+        self.write_batch(batch)
+    }
+
+    /// Applies `batch` atomically like `write_batch` if every key of `reads`
+    /// still has the value read, `None` for a key read absent.
+    ///
+    /// Returns `KvError::TransactionConflict` without writing anything if a
+    /// key changed, so that clients commit the transactions they ran
+    /// optimistically. The default implementation returns
+    /// `KvError::Unsupported`.
+    fn write_batch_if(
+        &self,
+        _reads: HashMap<String, Option<String>>,
+        _batch: WriteBatch,
+    ) -> Result<()> {
+        Err(KvError::Unsupported("transaction".to_owned()))
+    }
+
+    /// Syncs the writes made so far to the disk, if the engine syncs writes.
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Syncs the writes made so far to the disk, whatever the engine syncs
+    /// writes after.
+    ///
+    /// An engine keeping nothing on the disk does nothing.
+    fn fsync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Reclaims the space of the stale records now, rather than once the
+    /// engine would on its own.
+    fn compact(&self) -> Result<()> {
+        Err(KvError::Unsupported("compact".to_owned()))
+    }
+
+    /// Returns the name of the engine, like `kvs`, for operators.
+    ///
+    /// Engines override it, the others are named `custom`.
+    fn name(&self) -> &'static str {
+        "custom"
+    }
+
+    /// Returns the statistics of the engine.
+    ///
+    /// Engines override it to report more than the number of keys.
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            keys: self.len()?,
+            ..EngineStats::default()
+        })
+    }
+
+    /// Returns the key/value pairs whose key falls in `range`, ordered by key.
+    ///
+    /// Pages through the keyspace can be fetched by starting the next range
+    /// just after the last key returned.
+    ///
+    /// The keys whose value is binary and not valid UTF-8 are skipped, rather
+    /// than failing the scan of every other key: they are not in the pairs
+    /// returned, and `scan_keys` and `get_bytes` read them.
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>>;
+
+    /// Returns at most `limit` keys following `after`, or from the first key
+    /// if `None`, ordered by key.
+    ///
+    /// Engines override it to page through their keys without reading values.
+    fn scan_keys(&self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        let pairs = self.scan((start, Bound::Unbounded))?;
+        Ok(pairs.into_iter().take(limit).map(|(key, _)| key).collect())
+    }
+
+    /// Returns a handle to the bucket `name`, an independent keyspace of the engine.
+    ///
+    /// The bucket is created if it does not exist. Handles to the same bucket
+    /// share their data. Keys of a bucket are not visible from the engine it
+    /// was opened from, and neither are its keys visible from the bucket.
+    fn bucket(&self, name: &str) -> Result<Self> {
+        check_bucket_name(name)?;
+        Err(KvError::Unsupported("buckets".to_owned()))
+    }
+
+    /// Returns a receiver of the changes committed to the engine from now on,
+    /// in commit order.
+    ///
+    /// A server streams these changes to its replicas, so only an engine
+    /// supporting it can be a primary.
+    fn subscribe(&self) -> Result<Receiver<(String, ChangeKind)>> {
+        Err(KvError::Unsupported("subscribe".to_owned()))
+    }
+
+    /// Returns a point-in-time view of the engine, which a primary streams
+    /// to a replica as a compacted segment, see `KvSnapshot::write_segment`.
+    ///
+    /// Only the kvs engine supports it. A primary without it copies its keys
+    /// to its replicas one by one.
+    fn replication_snapshot(&self) -> Result<KvSnapshot> {
+        Err(KvError::Unsupported("snapshot".to_owned()))
+    }
+
+    /// Replaces every key of the engine with the records of the segment read
+    /// from `segment`, as written by `KvSnapshot::write_segment`.
+    ///
+    /// The segment is read and checked in full before it replaces anything,
+    /// so an engine failing to install it keeps its keys.
+    fn install_snapshot<R: Read>(&self, _segment: R) -> Result<()> {
+        Err(KvError::Unsupported("install snapshot".to_owned()))
+    }
+
+    /// Writes all live key/value pairs to `writer` as JSON lines, in key order.
+    ///
+    /// Each line is an object like `{"key":"k","value":"v"}`. Returns the
+    /// number of pairs written, which does not count the keys whose value is
+    /// binary and not valid UTF-8: they are skipped, like by `scan`.
+    fn export<W: Write>(&self, mut writer: W) -> Result<usize> {
+        let pairs = self.scan(..)?;
+        for (key, value) in &pairs {
+            write_export_record(&mut writer, key, value)?;
+        }
+        writer.flush()?;
+        Ok(pairs.len())
+    }
+
+    /// Sets every key/value pair read from JSON lines written by `export`.
+    ///
+    /// Blank lines are skipped. Pairs are written in batches, so a malformed
+    /// line may leave the pairs before it imported. Returns the number of
+    /// pairs imported.
+    fn import<R: BufRead>(&self, reader: R) -> Result<usize> {
+        let mut count = 0;
+        let mut batch = WriteBatch::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: ExportRecord = serde_json::from_str(&line)?;
+            batch.put(record.key.into_owned(), record.value.into_owned());
+            count += 1;
+            if batch.len() >= IMPORT_BATCH_SIZE {
+                self.write_batch(std::mem::take(&mut batch))?;
+            }
+        }
+        self.write_batch(batch)?;
+        Ok(count)
+    }
+}
+
+/// Checks that `name` is a valid bucket name, which is also usable as a file name.
+pub(crate) fn check_bucket_name(name: &str) -> Result<()> {
+    let valid = (1..=64).contains(&name.len())
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_');
+    if !valid {
+        return Err(KvError::InvalidBucketName(name.to_owned()));
+    }
+    Ok(())
+}
+
+/// Parses `value` as an integer and adds `delta` to it, a missing value counting as zero.
+pub(crate) fn incr_value(value: Option<&[u8]>, delta: i64) -> Result<i64> {
+    let current = match value {
+        Some(value) => std::str::from_utf8(value)
+            .ok()
+            .and_then(|value| value.parse::<i64>().ok())
+            .ok_or(KvError::NotAnInteger)?,
+        None => 0,
+    };
+    current.checked_add(delta).ok_or(KvError::NotAnInteger)
+}
+
+/// Returns the current unix time in milliseconds.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Returns the unix time in milliseconds at which an entry with `ttl` expires.
+pub(crate) fn expire_at(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+/// Returns whether an entry expiring at `expire_at` has expired.
+pub(crate) fn is_expired(expire_at: u64) -> bool {
+    expire_at <= now_millis()
 }
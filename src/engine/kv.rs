@@ -1,29 +1,86 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    collections::{BTreeMap, HashMap, HashSet},
     ffi::OsStr,
-    fs::{self, File, OpenOptions},
-    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    mem,
+    ops::{Bound, RangeBounds},
     path::{Path, PathBuf},
     sync::{
         atomic::{AtomicU64, Ordering},
-        Arc, Mutex,
+        mpsc::Receiver,
+        Arc, Mutex, RwLock,
     },
+    time::{Duration, Instant},
 };
 
-use dashmap::DashMap;
+use im::OrdMap;
 use log::warn;
 use serde::{Deserialize, Serialize};
 
-use crate::{KvEngine, KvError, Result};
+use super::bloom::BloomFilter;
+use super::cache::ValueCache;
+use super::change::{ChangeKind, Subscribers};
+use super::engine::{
+    check_bucket_name, expire_at, incr_value, is_expired, now_millis, write_export_record,
+};
+use super::options::{Compression, KvStoreOptions, SyncPolicy};
+use super::record::{
+    read_record, read_segment_version, write_block, write_segment_header, Command, LogCodec,
+    LogFormat, ReadRecord, ValuePointer, FORMAT_VERSION, SEGMENT_HEADER_LEN,
+};
+use super::txn::Txn;
+use crate::{BatchOp, EngineStats, KvEngine, KvError, Result, WriteBatch};
+
+/// Compacted records are compressed in blocks of about this many bytes.
+const COMPRESSION_BLOCK_SIZE: usize = 32 * 1024;
+
+/// The in-memory index, ordered by key so that range scans are possible.
+///
+/// It is a persistent map, so cloning it for a snapshot is cheap.
+type Index = Arc<RwLock<OrdMap<String, RecordInfo>>>;
+
+/// Bloom filters of the log files by file id, covering every key in the index.
+///
+/// A key missing from all of them is not in the store, so it is looked up
+/// without taking the index lock.
+type Filters = Arc<RwLock<BTreeMap<u64, BloomFilter>>>;
+
+/// Open files by file id, shared by the clones of a reader.
+type Files = Arc<RwLock<HashMap<u64, Arc<File>>>>;
+
+/// Name of the file locked by the store that has the directory open.
+const LOCK_FILE: &str = "LOCK";
+
+/// Name of the file holding the index as of a position in the log.
+const INDEX_SNAPSHOT_FILE: &str = "index.snapshot";
 
-const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+/// Extension of the log files holding the records.
+const LOG_EXTENSION: &str = "log";
+
+/// Extension of the value log files holding the values above the value log threshold.
+const VALUE_LOG_EXTENSION: &str = "vlog";
+
+/// Extension of a segment being installed, not a log file of the store yet.
+const STAGED_EXTENSION: &str = "staged";
+
+/// Logical clock of key accesses, which orders them for the eviction of the
+/// least recently used keys.
+static ACCESS_CLOCK: AtomicU64 = AtomicU64::new(0);
+
+/// Number of keys the filter of a new active log is sized for.
+const INITIAL_FILTER_CAPACITY: usize = 1024;
 
 /// The `KvStore` stores string key/value pairs.
 #[derive(Clone)]
 pub struct KvStore {
-    index: Arc<DashMap<String, RecordInfo>>,
+    index: Index,
+    filters: Filters,
     reader: KvReader,
     writer: Arc<Mutex<KvWriter>>,
+    pins: Arc<SnapshotPins>,
+    // opened buckets by name, so every handle to a bucket shares one writer
+    buckets: Arc<Mutex<HashMap<String, KvStore>>>,
 }
 
 impl KvStore {
@@ -31,339 +88,2069 @@ impl KvStore {
     ///
     /// This will create a new directory if the given one does not exist.
     pub fn open(dir_path: impl Into<PathBuf>) -> Result<KvStore> {
+        Self::open_with(dir_path, KvStoreOptions::default())
+    }
+
+    /// Opens a `KvStore` with the given dir_path and options.
+    ///
+    /// Existing records are readable regardless of the options they were written with.
+    pub fn open_with(dir_path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
         let dir_path = dir_path.into();
         fs::create_dir_all(&dir_path)?;
+        let lock = lock_dir(&dir_path)?;
 
-        let mut index = DashMap::new();
-        let mut readers = HashMap::new();
-        let (current_file_id, uncompacted) = Self::recover(&dir_path, &mut index, &mut readers)?;
+        let mut index = OrdMap::new();
+        let (mut current_file_id, uncompacted, index_snapshot_at) =
+            Self::recover(&dir_path, &options, &mut index)?;
+        // appending to a log of an older version would keep it at that version
+        let current_log = log_path(&dir_path, current_file_id);
+        if current_log.exists() && segment_version(&current_log)? < FORMAT_VERSION {
+            current_file_id += 1;
+        }
 
-        let log_path = log_path(&dir_path, current_file_id);
-        let current_writer = BufWriterWithPosition::new(
-            OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(&log_path)?,
-        )?;
+        let current_writer = new_log_writer(&dir_path, current_file_id)?;
 
-        if !readers.contains_key(&current_file_id) {
-            readers.insert(current_file_id, BufReader::new(File::open(&log_path)?));
+        let filters = load_filters(&dir_path, &index, current_file_id)?;
+
+        let mut live = 0;
+        if options.max_memory.is_some() {
+            // access times are not persisted, every key starts out as accessed now
+            index = index
+                .into_iter()
+                .map(|(key, mut record)| {
+                    record.accessed = Some(Arc::new(AtomicU64::new(next_access())));
+                    (key, record)
+                })
+                .collect();
+            live = live_size(&index);
         }
 
         let dir_path = Arc::new(dir_path);
-        let index = Arc::new(index);
+        let index = Arc::new(RwLock::new(index));
+        let filters = Arc::new(RwLock::new(filters));
         let safe_point = Arc::new(AtomicU64::new(0));
+        let pins = Arc::new(SnapshotPins {
+            dir_path: dir_path.clone(),
+            safe_point: safe_point.clone(),
+            pins: Mutex::new(BTreeMap::new()),
+        });
 
         let reader = KvReader {
             dir_path: dir_path.clone(),
-            readers,
+            files: Arc::default(),
+            value_files: Arc::default(),
             safe_point,
+            read_buffer_size: options.read_buffer_size,
+            cache: (options.value_cache_size > 0)
+                .then(|| Arc::new(Mutex::new(ValueCache::new(options.value_cache_size)))),
         };
 
-        let writer = KvWriter {
+        // values go to the last value log, which is created once a value is written to it
+        let value_file_ids = sorted_file_ids(&dir_path, VALUE_LOG_EXTENSION)?;
+        let mut current_value_file_id = value_file_ids.last().copied().unwrap_or(0);
+        for &file_id in &value_file_ids {
+            let version = segment_version(&value_log_path(&dir_path, file_id))?;
+            if file_id == current_value_file_id && version < FORMAT_VERSION {
+                current_value_file_id += 1;
+            }
+        }
+        let value_writer = match options.value_log_threshold {
+            Some(_) => Some(new_value_log_writer(&dir_path, current_value_file_id)?),
+            None => None,
+        };
+        let mut writer = KvWriter {
             dir_path: dir_path.clone(),
             index: index.clone(),
+            filters: filters.clone(),
             reader: reader.clone(),
+            pins: pins.clone(),
+            codec: options.format.codec(),
+            options,
             current_writer,
             current_file_id,
+            value_writer,
+            current_value_file_id,
             uncompacted,
+            last_sync: Instant::now(),
+            sealed_size: 0,
+            subscribers: Subscribers::default(),
+            index_snapshot_at,
+            live_size: live,
+            _lock: lock,
         };
+        writer.sealed_size = writer.sealed_files_size()?;
 
         Ok(KvStore {
             index,
+            filters,
             reader,
             writer: Arc::new(Mutex::new(writer)),
+            pins,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
         })
     }
 
+    /// Returns a read-only view of the store as of now.
+    ///
+    /// Taking a snapshot does not copy any data, and later writes to the
+    /// store are not visible through it. Log files the snapshot reads from
+    /// are kept on disk by compaction until the snapshot is dropped.
+    pub fn snapshot(&self) -> KvSnapshot {
+        // the safe point only moves under the index write lock
+        let index = self.index.read().unwrap();
+        let pin = self.pins.pin();
+        KvSnapshot {
+            index: index.clone(),
+            reader: KvReader {
+                dir_path: self.reader.dir_path.clone(),
+                // the store closes the files before its safe point, which the snapshot may read
+                files: Arc::default(),
+                value_files: self.reader.value_files.clone(),
+                safe_point: Arc::new(AtomicU64::new(pin.file_id)),
+                read_buffer_size: self.reader.read_buffer_size,
+                cache: self.reader.cache.clone(),
+            },
+            _pin: Arc::new(pin),
+        }
+    }
+
+    /// Returns a receiver of the changes committed to the store from now on.
+    ///
+    /// Every set and remove, including those of batches and transactions, is
+    /// sent in commit order. Keys that expire are not reported. The store stops
+    /// sending to a receiver once it is dropped.
+    pub fn subscribe(&self) -> Receiver<(String, ChangeKind)> {
+        self.writer.lock().unwrap().subscribers.subscribe()
+    }
+
+    /// Runs `func` in a transaction and commits its writes atomically.
+    ///
+    /// Nothing is written if `func` returns an error. Returns
+    /// `KvError::TransactionConflict` if a key read in the transaction was
+    /// changed by a concurrent writer before the commit.
+    pub fn transaction<F, T>(&self, func: F) -> Result<T>
+    where
+        F: FnOnce(&mut Txn) -> Result<T>,
+    {
+        let mut txn = Txn::new(self);
+        let result = func(&mut txn)?;
+        txn.commit()?;
+        Ok(result)
+    }
+
+    /// Writes `batch` if every key in `reads` still has the observed value.
+    pub(super) fn commit(
+        &self,
+        reads: HashMap<String, Option<String>>,
+        batch: WriteBatch,
+    ) -> Result<()> {
+        // holding the writer lock keeps other writers out between check and write
+        let writer = self.writer.clone();
+        let mut writer = writer.lock().unwrap();
+        for (key, value) in reads {
+            if self.get(key)? != value {
+                return Err(KvError::TransactionConflict);
+            }
+        }
+        writer.write_batch(batch, true)
+    }
+
+    /// Sets `key` to `value` if whether the key exists matches `present`.
+    fn set_if(&self, key: String, value: String, present: bool) -> Result<bool> {
+        // holding the writer lock keeps other writers out between check and write
+        let writer = self.writer.clone();
+        let mut writer = writer.lock().unwrap();
+        let exists =
+            matches!(self.index.read().unwrap().get(&key), Some(record) if !record.is_expired());
+        if exists != present {
+            return Ok(false);
+        }
+        writer.set(Command::Set(key, value, None))?;
+        Ok(true)
+    }
+
+    /// Upgrades the files of the store to the current format version, in place.
+    ///
+    /// Files written by an older version stay readable, and new records
+    /// always go to files of the current version. Migrating compacts the
+    /// store, which rewrites the live records of older files and removes
+    /// them. Files a live snapshot reads are removed once it is dropped, and
+    /// value logs by the next compaction after that. Buckets are stores of
+    /// their own, migrated separately.
+    pub fn migrate(&self) -> Result<()> {
+        self.writer.lock().unwrap().migrate()
+    }
+
+    /// Returns `false` if `key` is definitely not in the store.
+    fn may_contain(&self, key: &str) -> bool {
+        let filters = self.filters.read().unwrap();
+        filters.values().any(|filter| filter.may_contain(key))
+    }
+
     /// Recover the KvStore from the dir_path
     ///
-    /// Return the maximum file_id that has been used
+    /// Starts from the persisted index if it is still valid, and replays the
+    /// log after it. Return the maximum file_id that has been used, the stale
+    /// bytes in the log and the log position of the persisted index.
     fn recover(
         dir_path: &Path,
-        index: &mut DashMap<String, RecordInfo>,
-        readers: &mut HashMap<u64, BufReader<File>>,
-    ) -> Result<(u64, u64)> {
-        let mut file_ids: Vec<u64> = fs::read_dir(dir_path)?
-            .flat_map(|dir| -> Result<_> { Ok(dir?.path()) })
-            .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
-            .flat_map(|path| {
-                path.file_name()
-                    .and_then(OsStr::to_str)
-                    .map(|file_name| file_name.trim_end_matches(".log"))
-                    .map(str::parse::<u64>)
-            })
-            .flatten()
-            .collect();
-
-        file_ids.sort_unstable();
+        options: &KvStoreOptions,
+        index: &mut OrdMap<String, RecordInfo>,
+    ) -> Result<(u64, u64, (u64, u64))> {
+        let file_ids = sorted_file_ids(dir_path, LOG_EXTENSION)?;
+        // every file is checked, also those the persisted index covers
+        let versions = file_ids
+            .iter()
+            .map(|&file_id| segment_version(&log_path(dir_path, file_id)))
+            .collect::<Result<Vec<u32>>>()?;
 
         let mut uncompacted = 0;
-        for &file_id in &file_ids {
-            let mut prev_offset = 0;
+        // the log position the replay starts from
+        let mut start = (0, 0);
+        if let Some(snapshot) = load_index_snapshot(dir_path, &file_ids)? {
+            start = (*snapshot.file_ids.last().unwrap(), snapshot.offset);
+            uncompacted = snapshot.uncompacted;
+            *index = snapshot
+                .entries
+                .into_iter()
+                .filter(|(_, record)| !record.is_expired())
+                .collect();
+        }
+        for (&file_id, &version) in file_ids.iter().zip(&versions) {
+            if file_id < start.0 {
+                continue;
+            }
+            let start_offset = if file_id == start.0 { start.1 } else { 0 };
+            let start_offset = start_offset.max(first_record_offset(version));
+            let mut prev_offset = start_offset;
+            // end of the last complete command or batch
+            let mut valid_offset = start_offset;
+            // the size of the batch being read, and its commands read so far
+            let mut batch_size = None;
+            let mut batch = Vec::new();
             let path = log_path(dir_path, file_id);
-            let mut reader = BufReader::new(File::open(&path)?);
-            let mut iters =
-                serde_json::Deserializer::from_reader(&mut reader).into_iter::<Command>();
-            // cannot use for loop, it will move the ownership of iters
-            while let Some(cmd) = iters.next() {
-                let curr_offset = iters.byte_offset() as u64;
-                match cmd? {
-                    Command::Set(key, _) => {
-                        uncompacted += index
-                            .insert(
-                                key,
-                                RecordInfo {
-                                    file_id,
-                                    offset: prev_offset,
-                                    length: curr_offset - prev_offset,
-                                },
-                            )
-                            .map(|record| record.length)
-                            .unwrap_or(0);
+            let mut reader = BufReader::with_capacity(options.read_buffer_size, File::open(&path)?);
+            reader.seek(SeekFrom::Start(start_offset))?;
+            loop {
+                let corruption = KvError::Corruption {
+                    file_id,
+                    offset: prev_offset,
+                };
+                let (cmd, length) = match read_record(&mut reader)? {
+                    ReadRecord::Command(cmd, length) => (cmd, length),
+                    ReadRecord::Block(block, length) if batch_size.is_none() => {
+                        // records of a compacted segment, addressed within the block
+                        let mut block_reader = &block[..];
+                        let mut block_offset = 0;
+                        loop {
+                            match read_record(&mut block_reader)? {
+                                ReadRecord::Command(cmd, length) => {
+                                    let mut record = RecordInfo::new(file_id, prev_offset, length);
+                                    record.block_offset = Some(block_offset);
+                                    uncompacted +=
+                                        replay(index, cmd, record, options.inline_value_size);
+                                    block_offset += length as u32;
+                                }
+                                ReadRecord::End => break,
+                                _ => return Err(corruption),
+                            }
+                        }
+                        prev_offset += length;
+                        valid_offset = prev_offset;
+                        continue;
+                    }
+                    // the end of the log, or a torn write at the end of it
+                    ReadRecord::End => break,
+                    ReadRecord::Block(..) | ReadRecord::Corrupted => return Err(corruption),
+                };
+                let curr_offset = prev_offset + length;
+                match (cmd, batch_size) {
+                    (Command::Batch(size), None) => {
+                        uncompacted += length;
+                        batch_size = Some(size);
+                    }
+                    (Command::Batch(_), Some(_)) => return Err(KvError::UnexpectedCommandType),
+                    (cmd, Some(size)) => {
+                        batch.push((cmd, prev_offset, length));
+                        if batch.len() as u64 == size {
+                            for (cmd, offset, length) in batch.drain(..) {
+                                let record = RecordInfo::new(file_id, offset, length);
+                                uncompacted +=
+                                    replay(index, cmd, record, options.inline_value_size);
+                            }
+                            batch_size = None;
+                            valid_offset = curr_offset;
+                        }
                     }
-                    Command::Remove(key) => {
-                        uncompacted += index
-                            .remove(&key)
-                            .map(|(_, record)| record.length)
-                            .unwrap_or(0);
-                        uncompacted += curr_offset - prev_offset;
+                    (cmd, None) => {
+                        let record = RecordInfo::new(file_id, prev_offset, length);
+                        uncompacted += replay(index, cmd, record, options.inline_value_size);
+                        valid_offset = curr_offset;
                     }
                 }
                 prev_offset = curr_offset;
             }
-            readers.insert(file_id, reader);
+
+            if valid_offset < fs::metadata(&path)?.len() {
+                // drop the torn tail, so that new commands are appended to a valid log
+                warn!(
+                    "truncate incomplete log {} at {}",
+                    path.display(),
+                    valid_offset
+                );
+                OpenOptions::new()
+                    .write(true)
+                    .open(&path)?
+                    .set_len(valid_offset)?;
+            }
         }
 
-        Ok((*file_ids.last().unwrap_or(&0), uncompacted))
+        Ok((*file_ids.last().unwrap_or(&0), uncompacted, start))
+    }
+}
+
+/// Applies a command read from the log at `record` to the index.
+///
+/// Values smaller than `inline_value_size` are kept in the index.
+/// Returns the number of bytes that become stale.
+fn replay(
+    index: &mut OrdMap<String, RecordInfo>,
+    cmd: Command,
+    mut record: RecordInfo,
+    inline_value_size: usize,
+) -> u64 {
+    let stale_size = |record: Option<RecordInfo>| record.map_or(0, |record| record.stale_size());
+    let (key, expire_at) = match cmd {
+        Command::Set(key, value, expire_at) => {
+            record.inline_value = (value.len() < inline_value_size).then(|| value.into_bytes());
+            (key, expire_at)
+        }
+        Command::SetBytes(key, value, expire_at) => {
+            record.inline_value = (value.len() < inline_value_size).then_some(value);
+            (key, expire_at)
+        }
+        Command::SetRef(key, value, expire_at) => {
+            record.value = Some(value);
+            (key, expire_at)
+        }
+        Command::Remove(key) => return stale_size(index.remove(&key)) + record.length,
+        Command::Batch(_) => return record.length,
+    };
+    if expire_at.is_some_and(is_expired) {
+        // an expired set still shadows the older value of the key
+        return stale_size(index.remove(&key)) + record.stale_size();
     }
+    record.expire_at = expire_at;
+    stale_size(index.insert(key, record))
 }
 
 impl KvEngine for KvStore {
     /// Gets the string value of a given string key.
     ///
     /// Returns `None` if the given key does not exist.
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        if let Some(record) = self.index.get(&key) {
-            self.reader.read_value(record.value())
-        } else {
-            Ok(None)
+    fn get(&self, key: String) -> Result<Option<String>> {
+        if !self.may_contain(&key) {
+            return Ok(None);
+        }
+        // hold the read lock while reading, so compaction cannot remove the file under us
+        let index = self.index.read().unwrap();
+        match index.get(&key) {
+            Some(record) if !record.is_expired() => {
+                record.touch();
+                self.reader.read_value(record)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Gets the value of a key without reading the disk: a key the bloom
+    /// filters rule out, a small value kept in the index, or a cached one.
+    fn get_in_memory(&self, key: &str) -> Option<Result<Option<String>>> {
+        if !self.may_contain(key) {
+            return Some(Ok(None));
+        }
+        let index = self.index.read().unwrap();
+        match index.get(key) {
+            Some(record) if !record.is_expired() => {
+                let value = self.reader.read_bytes_in_memory(record)?;
+                record.touch();
+                Some(String::from_utf8(value).map(Some).map_err(KvError::from))
+            }
+            _ => Some(Ok(None)),
         }
     }
 
+    /// Gets the string values of many keys, reading the log in offset order.
+    fn multi_get(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let index = self.index.read().unwrap();
+        let records: Vec<Option<&RecordInfo>> = keys
+            .iter()
+            .map(|key| index.get(key).filter(|record| !record.is_expired()))
+            .collect();
+        let found: Vec<&RecordInfo> = records.iter().flatten().copied().collect();
+        found.iter().for_each(|record| record.touch());
+        let mut values = self.reader.read_many(&found)?.into_iter();
+        records
+            .iter()
+            .map(|record| match record {
+                Some(_) => values
+                    .next()
+                    .flatten()
+                    .map(String::from_utf8)
+                    .transpose()
+                    .map_err(KvError::from),
+                None => Ok(None),
+            })
+            .collect()
+    }
+
     /// Sets the value of a string key to a string.
     ///
     /// If the key already exists, the previous value will be overwritten.
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.writer.lock().unwrap().set(key, value)
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.writer
+            .lock()
+            .unwrap()
+            .set(Command::Set(key, value, None))
+    }
+
+    /// Sets the value of a string key to a string, which expires after `ttl`.
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.writer
+            .lock()
+            .unwrap()
+            .set(Command::Set(key, value, Some(expire_at(ttl))))
+    }
+
+    /// Sets the value of a string key to bytes.
+    fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        self.writer
+            .lock()
+            .unwrap()
+            .set(Command::SetBytes(key, value, None))
+    }
+
+    /// Gets the value of a given string key as bytes.
+    fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        if !self.may_contain(&key) {
+            return Ok(None);
+        }
+        let index = self.index.read().unwrap();
+        match index.get(&key) {
+            Some(record) if !record.is_expired() => {
+                record.touch();
+                self.reader.read_bytes(record)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the remaining time to live of a given key.
+    fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        match self.index.read().unwrap().get(&key) {
+            Some(record) if !record.is_expired() => Ok(record.ttl()),
+            _ => Err(KvError::KeyNotFound),
+        }
     }
 
     /// Removes a given key.
-    fn remove(&mut self, key: String) -> Result<()> {
+    fn remove(&self, key: String) -> Result<()> {
         self.writer.lock().unwrap().remove(key)
     }
+
+    /// Sets the value of a key only if it does not exist.
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        self.set_if(key, value, false)
+    }
+
+    /// Sets the value of a key only if it already exists.
+    fn set_if_present(&self, key: String, value: String) -> Result<bool> {
+        self.set_if(key, value, true)
+    }
+
+    /// Adds `delta` to the integer value of a key, and returns the new value.
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        // holding the writer lock keeps other writers out between read and write
+        let writer = self.writer.clone();
+        let mut writer = writer.lock().unwrap();
+        let index = self.index.read().unwrap();
+        let (value, expire_at) = match index.get(&key) {
+            Some(record) if !record.is_expired() => {
+                (self.reader.read_bytes(record)?, record.expire_at)
+            }
+            _ => (None, None),
+        };
+        drop(index);
+
+        let value = incr_value(value.as_deref(), delta)?;
+        writer.set(Command::Set(key, value.to_string(), expire_at))?;
+        Ok(value)
+    }
+
+    /// Returns the number of live keys in the index.
+    fn len(&self) -> Result<usize> {
+        let index = self.index.read().unwrap();
+        // expired keys stay in the index until the next compaction
+        Ok(index.values().filter(|record| !record.is_expired()).count())
+    }
+
+    /// Returns the bucket `name`, stored in its own log files under `buckets/<name>`.
+    ///
+    /// The bucket is opened with the options of this store.
+    fn bucket(&self, name: &str) -> Result<KvStore> {
+        check_bucket_name(name)?;
+        let mut buckets = self.buckets.lock().unwrap();
+        if let Some(bucket) = buckets.get(name) {
+            return Ok(bucket.clone());
+        }
+        let dir_path = self.reader.dir_path.join("buckets").join(name);
+        let options = self.writer.lock().unwrap().options.clone();
+        let bucket = KvStore::open_with(dir_path, options)?;
+        buckets.insert(name.to_owned(), bucket.clone());
+        Ok(bucket)
+    }
+
+    /// Returns a receiver of the committed changes, see `KvStore::subscribe`.
+    fn subscribe(&self) -> Result<Receiver<(String, ChangeKind)>> {
+        Ok(KvStore::subscribe(self))
+    }
+
+    fn replication_snapshot(&self) -> Result<KvSnapshot> {
+        Ok(self.snapshot())
+    }
+
+    /// Replaces the records of the store with the segment, holding the
+    /// writer while the segment is read, so other writes wait for it.
+    fn install_snapshot<R: Read>(&self, mut segment: R) -> Result<()> {
+        self.writer.lock().unwrap().install(&mut segment)
+    }
+
+    /// Writes all live key/value pairs as JSON lines, streaming from a snapshot.
+    fn export<W: Write>(&self, writer: W) -> Result<usize> {
+        self.snapshot().export(writer)
+    }
+
+    /// Applies all operations of `batch` with one log append.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.writer.lock().unwrap().write_batch(batch, true)
+    }
+
+    fn write_batch_if(
+        &self,
+        reads: HashMap<String, Option<String>>,
+        batch: WriteBatch,
+    ) -> Result<()> {
+        self.commit(reads, batch)
+    }
+
+    /// Applies all operations of `batch` with one log append, flushed but not synced.
+    fn write_batch_unsynced(&self, batch: WriteBatch) -> Result<()> {
+        self.writer.lock().unwrap().write_batch(batch, false)
+    }
+
+    /// Syncs the logs to the disk, unless the sync policy leaves it to the system.
+    fn sync(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        if writer.options.sync_policy != SyncPolicy::Never {
+            writer.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Syncs the logs to the disk, even if the sync policy leaves it to the system.
+    fn fsync(&self) -> Result<()> {
+        self.writer.lock().unwrap().sync()
+    }
+
+    /// Compacts the logs, see `KvWriter::compact`.
+    ///
+    /// Buckets are stores of their own, compacted separately.
+    fn compact(&self) -> Result<()> {
+        self.writer.lock().unwrap().compact()
+    }
+
+    fn name(&self) -> &'static str {
+        "kvs"
+    }
+
+    /// Returns the number of live keys, the size of the files of the store,
+    /// the size of its stale records and the size of its index in memory.
+    ///
+    /// The sizes do not count the buckets.
+    fn stats(&self) -> Result<EngineStats> {
+        let keys = self.len()?;
+        let mut writer = self.writer.lock().unwrap();
+        // tracked with a memory budget only, counted otherwise
+        let memory_size = match writer.options.max_memory {
+            Some(_) => writer.live_size,
+            None => live_size(&self.index.read().unwrap()),
+        };
+        // files kept for a snapshot may have been removed since the last count
+        writer.sealed_size = writer.sealed_files_size()?;
+        let value_log_size = writer
+            .value_writer
+            .as_ref()
+            .map_or(0, BufWriterWithPosition::get_offset);
+        Ok(EngineStats {
+            keys,
+            disk_size: Some(
+                writer.sealed_size + writer.current_writer.get_offset() + value_log_size,
+            ),
+            stale_size: Some(writer.uncompacted),
+            memory_size: Some(memory_size),
+        })
+    }
+
+    /// Returns all key/value pairs whose key falls in `range`, in key order,
+    /// skipping the binary values.
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>> {
+        let index = self.index.read().unwrap();
+        let mut pairs = Vec::new();
+        for (key, record) in index.range(range) {
+            if record.is_expired() {
+                continue;
+            }
+            if let Some(value) = self.reader.read_string(record)? {
+                pairs.push((key.clone(), value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Returns at most `limit` live keys following `after`, from the index only.
+    fn scan_keys(&self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        let index = self.index.read().unwrap();
+        Ok(index
+            .range((start, Bound::Unbounded))
+            .filter(|(_, record)| !record.is_expired())
+            .take(limit)
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
 }
 
+#[derive(Clone)]
 pub struct KvReader {
     dir_path: Arc<PathBuf>,
-    readers: HashMap<u64, BufReader<File>>,
+    // open log files, shared by all clones; reads are positional, so
+    // concurrent reads of a file share one handle
+    files: Files,
+    // open value logs
+    value_files: Files,
     // generation of the latest compaction file
     safe_point: Arc<AtomicU64>,
+    read_buffer_size: usize,
+    // shared by all clones, values are keyed by position so snapshots can use it too
+    cache: Option<Arc<Mutex<ValueCache>>>,
 }
 
 impl KvReader {
-    fn remove_stale_reader(&mut self) {
-        let readers = &mut self.readers;
+    /// Returns the log file `file_id`, which is opened on first use.
+    fn log_file(&self, file_id: u64) -> Result<Arc<File>> {
+        if let Some(file) = self.files.read().unwrap().get(&file_id) {
+            return Ok(file.clone());
+        }
+        let file = Arc::new(File::open(log_path(&self.dir_path, file_id))?);
+        let mut files = self.files.write().unwrap();
+        // close the files removed by compaction
         let compact_file_id = self.safe_point.load(Ordering::SeqCst);
-        while !readers.is_empty() {
-            let file_id = *readers.keys().next().unwrap();
-            if file_id >= compact_file_id {
-                break;
-            }
-            readers.remove(&file_id);
+        files.retain(|&file_id, _| file_id >= compact_file_id);
+        Ok(files.entry(file_id).or_insert(file).clone())
+    }
+
+    /// Returns the value log `file_id`, which is opened on first use.
+    fn value_log_file(&self, file_id: u64) -> Result<Arc<File>> {
+        if let Some(file) = self.value_files.read().unwrap().get(&file_id) {
+            return Ok(file.clone());
         }
+        let file = Arc::new(File::open(value_log_path(&self.dir_path, file_id))?);
+        let mut files = self.value_files.write().unwrap();
+        Ok(files.entry(file_id).or_insert(file).clone())
     }
 
     /// Read the log file at the given `CommandPos`.
-    pub fn read_and<F, R>(&mut self, record: &RecordInfo, func: F) -> Result<R>
+    ///
+    /// Records in a compressed block are decompressed before being passed to `func`.
+    pub fn read_and<F, R>(&self, record: &RecordInfo, func: F) -> Result<R>
     where
-        F: FnOnce(io::Take<&mut BufReader<File>>) -> Result<R>,
+        F: FnOnce(&mut dyn BufRead) -> Result<R>,
     {
-        self.remove_stale_reader();
+        let corruption = || KvError::Corruption {
+            file_id: record.file_id,
+            offset: record.offset,
+        };
+        let file = self.log_file(record.file_id)?;
+        let block_offset = match record.block_offset {
+            Some(block_offset) => block_offset as usize,
+            None => {
+                let bytes = read_exact_at(&file, record.offset, record.length)
+                    .map_err(|err| read_error(err, record.file_id, record.offset))?;
+                return func(&mut &bytes[..]);
+            }
+        };
+
+        // the length of a block is in its header, so it is read through a buffer
+        let mut reader = BufReader::with_capacity(
+            self.read_buffer_size,
+            FileAt {
+                file: &file,
+                offset: record.offset,
+            },
+        );
+        let block = match read_record(&mut reader)? {
+            ReadRecord::Block(block, _) => block,
+            _ => return Err(corruption()),
+        };
+        let mut slice = block
+            .get(block_offset..block_offset + record.length as usize)
+            .ok_or_else(corruption)?;
+        func(&mut slice)
+    }
 
-        let readers = &mut self.readers;
-        if let Entry::Vacant(entry) = readers.entry(record.file_id) {
-            entry.insert(new_log_reader(&self.dir_path, record.file_id)?);
+    pub fn read_value(&self, record: &RecordInfo) -> Result<Option<String>> {
+        self.read_bytes(record)?
+            .map(String::from_utf8)
+            .transpose()
+            .map_err(KvError::from)
+    }
+
+    /// Reads the value at `record` if it is a string, or `None` for a binary
+    /// value that is not valid UTF-8.
+    pub fn read_string(&self, record: &RecordInfo) -> Result<Option<String>> {
+        Ok(self
+            .read_bytes(record)?
+            .and_then(|value| String::from_utf8(value).ok()))
+    }
+
+    /// Reads the value at `record`, whether it was set as a string or as bytes.
+    pub fn read_bytes(&self, record: &RecordInfo) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = &record.inline_value {
+            return Ok(Some(value.clone()));
+        }
+        let cache_key = (record.file_id, record.offset, record.block_offset);
+        if let Some(cache) = &self.cache {
+            if let Some(value) = cache.lock().unwrap().get(&cache_key) {
+                return Ok(Some(value));
+            }
+        }
+        let value = self.read_bytes_uncached(record)?;
+        if let (Some(cache), Some(value)) = (&self.cache, &value) {
+            cache.lock().unwrap().insert(cache_key, value.clone());
+        }
+        Ok(value)
+    }
+
+    /// Returns the value at `record` if it is kept in the index or cached,
+    /// without reading the disk.
+    pub fn read_bytes_in_memory(&self, record: &RecordInfo) -> Option<Vec<u8>> {
+        if let Some(value) = &record.inline_value {
+            return Some(value.clone());
         }
+        let cache_key = (record.file_id, record.offset, record.block_offset);
+        self.cache.as_ref()?.lock().unwrap().get(&cache_key)
+    }
 
-        let buf_reader = readers.get_mut(&record.file_id).unwrap();
-        buf_reader.seek(SeekFrom::Start(record.offset))?;
-        func(buf_reader.take(record.length))
+    /// Reads the values at `records`, returned in the same order.
+    ///
+    /// The reads are done in file and offset order, so that each file is read
+    /// front to back rather than seeking back and forth.
+    pub fn read_many(&self, records: &[&RecordInfo]) -> Result<Vec<Option<Vec<u8>>>> {
+        let mut order: Vec<usize> = (0..records.len()).collect();
+        order.sort_by_key(|&i| {
+            let record = records[i];
+            (record.file_id, record.offset, record.block_offset)
+        });
+        let mut values = vec![None; records.len()];
+        for i in order {
+            values[i] = self.read_bytes(records[i])?;
+        }
+        Ok(values)
     }
 
-    pub fn read_value(&mut self, record: &RecordInfo) -> Result<Option<String>> {
-        self.read_and(record, |reader| {
+    fn read_bytes_uncached(&self, record: &RecordInfo) -> Result<Option<Vec<u8>>> {
+        let corruption = || KvError::Corruption {
+            file_id: record.file_id,
+            offset: record.offset,
+        };
+        if let Some(pointer) = &record.value {
+            return self.read_value_log(pointer).map_err(|err| match err {
+                KvError::Corruption { .. } => corruption(),
+                err => err,
+            });
+        }
+        self.read_and(record, |reader| match read_record(reader)? {
+            ReadRecord::Command(Command::Set(_, value, _), _) => Ok(Some(value.into_bytes())),
+            ReadRecord::Command(Command::SetBytes(_, value, _), _) => Ok(Some(value)),
             // the command in the log must be a Set cmd, otherwise the log is corrupted
-            if let Command::Set(_, value) = serde_json::from_reader(reader)? {
-                Ok(Some(value))
-            } else {
-                Err(KvError::UnexpectedCommandType)
-            }
+            ReadRecord::Command(..) => Err(KvError::UnexpectedCommandType),
+            _ => Err(corruption()),
         })
     }
 
-    pub fn remove_stale_file(&mut self, compact_file_id: u64) {
-        let readers = &mut self.readers;
-        let file_ids: Vec<u64> = readers
-            .iter()
-            .map(|(&file_id, _)| file_id)
-            .filter(|&file_id| file_id < compact_file_id)
-            .collect();
-
-        for file_id in file_ids {
-            readers.remove(&file_id);
-            if let Err(err) = fs::remove_file(log_path(&self.dir_path, file_id)) {
-                warn!("remove file error: {}", err);
-            }
+    /// Reads the value that `pointer` points to in a value log.
+    fn read_value_log(&self, pointer: &ValuePointer) -> Result<Option<Vec<u8>>> {
+        let corruption = || KvError::Corruption {
+            file_id: pointer.file_id,
+            offset: pointer.offset,
+        };
+        let file = self.value_log_file(pointer.file_id)?;
+        let bytes = read_exact_at(&file, pointer.offset, pointer.length)
+            .map_err(|err| read_error(err, pointer.file_id, pointer.offset))?;
+        match read_record(&mut &bytes[..])? {
+            ReadRecord::Command(Command::SetBytes(_, value, _), _) => Ok(Some(value)),
+            ReadRecord::Command(..) => Err(KvError::UnexpectedCommandType),
+            _ => Err(corruption()),
         }
     }
 }
 
-impl Clone for KvReader {
-    fn clone(&self) -> Self {
-        Self {
-            dir_path: self.dir_path.clone(),
-            readers: HashMap::new(),
-            safe_point: self.safe_point.clone(),
+/// Reads a file from a position, without moving the position of the file.
+///
+/// Any number of them can read one file handle at once.
+struct FileAt<'a> {
+    file: &'a File,
+    offset: u64,
+}
+
+impl Read for FileAt<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(unix)]
+        let read = std::os::unix::fs::FileExt::read_at(self.file, buf, self.offset)?;
+        #[cfg(windows)]
+        let read = std::os::windows::fs::FileExt::seek_read(self.file, buf, self.offset)?;
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
+/// Tells where a read of a record failed, a record cut short being corrupted.
+fn read_error(err: io::Error, file_id: u64, offset: u64) -> KvError {
+    if err.kind() == io::ErrorKind::UnexpectedEof {
+        KvError::Corruption { file_id, offset }
+    } else {
+        KvError::Storage {
+            file_id,
+            offset,
+            source: err,
         }
     }
 }
 
+/// Reads `length` bytes of `file` at `offset`.
+fn read_exact_at(file: &File, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0; length as usize];
+    FileAt { file, offset }.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
 pub struct KvWriter {
     dir_path: Arc<PathBuf>,
-    index: Arc<DashMap<String, RecordInfo>>,
+    index: Index,
+    filters: Filters,
     reader: KvReader,
+    pins: Arc<SnapshotPins>,
+    options: KvStoreOptions,
+    // encodes the records appended to the logs, in the format of the options
+    codec: &'static dyn LogCodec,
     current_writer: BufWriterWithPosition<File>,
     current_file_id: u64,
+    // the active value log, opened once a value is separated from its key
+    value_writer: Option<BufWriterWithPosition<File>>,
+    current_value_file_id: u64,
     uncompacted: u64,
+    last_sync: Instant,
+    // size of the files in the directory, except the active logs
+    sealed_size: u64,
+    subscribers: Subscribers,
+    // log position (file id, offset) covered by the persisted index
+    index_snapshot_at: (u64, u64),
+    // approximate size of the keys in the index, tracked with a memory budget only
+    live_size: u64,
+    // the lock on the directory is released when the file is closed
+    _lock: File,
 }
 
 impl KvWriter {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::Set(key, value);
+    /// Appends a `Set` or `SetBytes` command and applies it to the index.
+    fn set(&mut self, cmd: Command) -> Result<()> {
+        self.check_quota()?;
+        let cmd = self.separate_value(cmd)?;
         let offset = self.current_writer.get_offset();
-        serde_json::to_writer(&mut self.current_writer, &cmd)?;
-        self.current_writer.flush()?;
-        let record = RecordInfo {
-            file_id: self.current_file_id,
+        self.codec.encode(&mut self.current_writer, &cmd)?;
+        self.flush()?;
+        let record = RecordInfo::new(
+            self.current_file_id,
             offset,
-            length: self.current_writer.get_offset() - offset,
+            self.current_writer.get_offset() - offset,
+        );
+        let key = match &cmd {
+            Command::Set(key, ..) | Command::SetBytes(key, ..) | Command::SetRef(key, ..) => {
+                key.clone()
+            }
+            _ => return Err(KvError::UnexpectedCommandType),
         };
-        if let Command::Set(key, _) = cmd {
-            self.uncompacted += self
-                .index
-                .insert(key, record)
-                .map(|record| record.length)
-                .unwrap_or(0);
-        }
+        self.add_to_filter(&[&key]);
+        let index = self.index.clone();
+        self.apply(&mut index.write().unwrap(), cmd, record);
+        self.subscribers.notify(&key, ChangeKind::Set);
+        self.maintain()
+    }
 
-        if self.uncompacted >= COMPACTION_THRESHOLD {
-            self.compact()?;
+    fn remove(&mut self, key: String) -> Result<()> {
+        let old_record = self.index.write().unwrap().remove(&key);
+        if let (Some(old_record), Some(_)) = (&old_record, self.options.max_memory) {
+            self.live_size -= old_record.entry_size(&key);
+        }
+        match old_record {
+            Some(old_record) if old_record.is_expired() => {
+                // the expired set is ignored by recovery, no need for a tombstone
+                self.uncompacted += old_record.stale_size();
+                Err(KvError::KeyNotFound)
+            }
+            Some(old_record) => {
+                let cmd = Command::Remove(key.clone());
+                let offset = self.current_writer.get_offset();
+                self.codec.encode(&mut self.current_writer, &cmd)?;
+                self.flush()?;
+                self.uncompacted += self.current_writer.get_offset() - offset;
+                self.uncompacted += old_record.stale_size();
+                self.subscribers.notify(&key, ChangeKind::Remove);
+                self.maintain()
+            }
+            None => Err(KvError::KeyNotFound),
         }
-        Ok(())
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let (_, old_record) = self.index.remove(&key).expect("key not found");
-            let cmd = Command::Remove(key);
+    /// Appends `batch` to the log and applies it, syncing as the sync policy
+    /// requires only if `sync`.
+    fn write_batch(&mut self, batch: WriteBatch, sync: bool) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.check_batch(&batch)?;
+        if batch.iter().any(|op| matches!(op, BatchOp::Put(..))) {
+            self.check_quota()?;
+        }
+
+        // the batch header makes recovery ignore a partially written batch
+        let offset = self.current_writer.get_offset();
+        let header = Command::Batch(batch.len() as u64);
+        self.codec.encode(&mut self.current_writer, &header)?;
+        self.uncompacted += self.current_writer.get_offset() - offset;
+
+        let mut records = Vec::with_capacity(batch.len());
+        for op in batch {
+            let cmd = match op {
+                BatchOp::Put(key, value) => self.separate_value(Command::Set(key, value, None))?,
+                BatchOp::Delete(key) => Command::Remove(key),
+            };
             let offset = self.current_writer.get_offset();
-            serde_json::to_writer(&mut self.current_writer, &cmd)?;
+            self.codec.encode(&mut self.current_writer, &cmd)?;
+            records.push((cmd, offset, self.current_writer.get_offset() - offset));
+        }
+        if sync {
+            self.flush()?;
+        } else {
             self.current_writer.flush()?;
-            self.uncompacted += self.current_writer.get_offset() - offset;
-            self.uncompacted += old_record.length;
+        }
+
+        let keys: Vec<&str> = records
+            .iter()
+            .filter_map(|(cmd, _, _)| match cmd {
+                Command::Set(key, ..) | Command::SetRef(key, ..) => Some(key.as_str()),
+                _ => None,
+            })
+            .collect();
+        self.add_to_filter(&keys);
+        let changes: Vec<(String, ChangeKind)> = records
+            .iter()
+            .map(|(cmd, _, _)| match cmd {
+                Command::Remove(key) => (key.clone(), ChangeKind::Remove),
+                Command::Set(key, ..) | Command::SetBytes(key, ..) | Command::SetRef(key, ..) => {
+                    (key.clone(), ChangeKind::Set)
+                }
+                Command::Batch(_) => unreachable!("batches are not nested"),
+            })
+            .collect();
+        // apply under one lock, so readers never observe a half applied batch
+        let index = self.index.clone();
+        let mut index = index.write().unwrap();
+        for (cmd, offset, length) in records {
+            let record = RecordInfo::new(self.current_file_id, offset, length);
+            self.apply(&mut index, cmd, record);
+        }
+        drop(index);
+        for (key, kind) in changes {
+            self.subscribers.notify(&key, kind);
+        }
+        self.maintain()
+    }
+
+    /// Applies a command appended to the log at `record` to the index.
+    ///
+    /// With a memory budget, the key counts as just accessed and its size is accounted for.
+    fn apply(
+        &mut self,
+        index: &mut OrdMap<String, RecordInfo>,
+        cmd: Command,
+        mut record: RecordInfo,
+    ) {
+        let inline_value_size = self.options.inline_value_size;
+        if self.options.max_memory.is_none() {
+            self.uncompacted += replay(index, cmd, record, inline_value_size);
+            return;
+        }
+        let key = match &cmd {
+            Command::Set(key, ..)
+            | Command::SetBytes(key, ..)
+            | Command::SetRef(key, ..)
+            | Command::Remove(key) => key.clone(),
+            Command::Batch(_) => String::new(),
+        };
+        let entry_size = |index: &OrdMap<String, RecordInfo>| {
+            index.get(&key).map_or(0, |record| record.entry_size(&key))
+        };
+        let old_size = entry_size(index);
+        record.accessed = Some(Arc::new(AtomicU64::new(next_access())));
+        self.uncompacted += replay(index, cmd, record, inline_value_size);
+        self.live_size = self.live_size + entry_size(index) - old_size;
+    }
+
+    /// Removes the least recently used keys, until the live keys take at most 90% of
+    /// `max_memory`, so that a write over the budget does not evict a key each time.
+    fn evict(&mut self, max_memory: u64) -> Result<()> {
+        let target = max_memory - max_memory / 10;
+        let index = self.index.clone();
+        let index = index.read().unwrap();
+        let mut entries: Vec<(u64, &String, u64)> = index
+            .iter()
+            .filter(|(_, record)| !record.is_expired())
+            .map(|(key, record)| (record.last_access(), key, record.entry_size(key)))
+            .collect();
+        let mut live_size: u64 = entries.iter().map(|&(_, _, size)| size).sum();
+        if live_size <= target {
+            // expired keys count until the compaction that drops them
+            drop(entries);
+            drop(index);
+            return self.compact();
+        }
+        entries.sort_unstable_by_key(|&(accessed, _, _)| accessed);
+        let mut batch = WriteBatch::new();
+        for (_, key, size) in entries {
+            if live_size <= target {
+                break;
+            }
+            batch.delete(key.clone());
+            live_size -= size;
+        }
+        drop(index);
+        self.write_batch(batch, true)
+    }
+
+    /// Moves the value of a large set to the value log, leaving a pointer to it in the command.
+    fn separate_value(&mut self, cmd: Command) -> Result<Command> {
+        let threshold = match self.options.value_log_threshold {
+            Some(threshold) => threshold,
+            None => return Ok(cmd),
+        };
+        let (key, value, expire_at) = match cmd {
+            Command::Set(key, value, expire_at) if value.len() >= threshold => {
+                (key, value.into_bytes(), expire_at)
+            }
+            Command::SetBytes(key, value, expire_at) if value.len() >= threshold => {
+                (key, value, expire_at)
+            }
+            cmd => return Ok(cmd),
+        };
+        let pointer = self.append_value(&key, value)?;
+        Ok(Command::SetRef(key, pointer, expire_at))
+    }
+
+    /// Appends `value` to the active value log and returns where it was written.
+    ///
+    /// The value is flushed before the pointer to it is appended to the log of keys.
+    fn append_value(&mut self, key: &str, value: Vec<u8>) -> Result<ValuePointer> {
+        if self.value_writer.is_none() {
+            let writer = new_value_log_writer(&self.dir_path, self.current_value_file_id)?;
+            self.value_writer = Some(writer);
+        }
+        let writer = self.value_writer.as_mut().unwrap();
+        let offset = writer.get_offset();
+        // the key makes the value log readable on its own
+        let cmd = Command::SetBytes(key.to_owned(), value, None);
+        self.codec.encode(writer, &cmd)?;
+        writer.flush()?;
+        Ok(ValuePointer {
+            file_id: self.current_value_file_id,
+            offset,
+            length: writer.get_offset() - offset,
+        })
+    }
+
+    /// Adds `keys` to the filter of the active log, before they are added to the index.
+    fn add_to_filter(&mut self, keys: &[&str]) {
+        let mut filters = self.filters.write().unwrap();
+        let filter = filters
+            .entry(self.current_file_id)
+            .or_insert_with(|| BloomFilter::with_capacity(INITIAL_FILTER_CAPACITY));
+        if filter.len() + keys.len() > filter.capacity() {
+            // a full filter has too many false positives, rebuild it twice as large, or
+            // larger for a large batch; the index does not hold the keys yet
+            let mut capacity = filter.capacity() * 2;
+            while filter.len() + keys.len() > capacity {
+                capacity *= 2;
+            }
+            let index = self.index.read().unwrap();
+            let mut larger = BloomFilter::with_capacity(capacity);
+            for (key, record) in index.iter() {
+                if record.file_id == self.current_file_id {
+                    larger.insert(key);
+                }
+            }
+            *filter = larger;
+        }
+        for key in keys {
+            filter.insert(key);
+        }
+    }
+
+    /// Flushes the active log, syncing it to the disk as the sync policy requires.
+    fn flush(&mut self) -> Result<()> {
+        self.current_writer.flush()?;
+        let sync = match self.options.sync_policy {
+            SyncPolicy::Never => false,
+            SyncPolicy::Always => true,
+            SyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+        };
+        if sync {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Syncs the active logs to the disk.
+    fn sync(&mut self) -> Result<()> {
+        // values first, so a synced pointer never points past the synced values
+        if let Some(value_writer) = &self.value_writer {
+            value_writer.get_ref().sync_data()?;
+        }
+        self.current_writer.get_ref().sync_data()?;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
 
-            if self.uncompacted >= COMPACTION_THRESHOLD {
-                self.compact()?;
+    /// Fails with `KvError::QuotaExceeded` if the store is over its maximum disk size
+    /// and compaction cannot bring it back under.
+    fn check_quota(&mut self) -> Result<()> {
+        let max_disk_size = match self.options.max_disk_size {
+            Some(max_disk_size) => max_disk_size,
+            None => return Ok(()),
+        };
+        let disk_size = |writer: &KvWriter| {
+            let value_log_size = writer
+                .value_writer
+                .as_ref()
+                .map_or(0, BufWriterWithPosition::get_offset);
+            writer.sealed_size + writer.current_writer.get_offset() + value_log_size
+        };
+        if disk_size(self) < max_disk_size {
+            return Ok(());
+        }
+        // files kept for a snapshot may have been removed since the last count
+        self.sealed_size = self.sealed_files_size()?;
+        if disk_size(self) >= max_disk_size && self.uncompacted > 0 {
+            self.compact()?;
+        }
+        if disk_size(self) >= max_disk_size {
+            return Err(KvError::QuotaExceeded);
+        }
+        Ok(())
+    }
+
+    /// Compacts the log or starts a new log file once the configured limits are reached.
+    fn maintain(&mut self) -> Result<()> {
+        if let Some(max_memory) = self.options.max_memory {
+            if self.live_size > max_memory {
+                self.evict(max_memory)?;
             }
+        }
+        let value_log_full = self
+            .value_writer
+            .as_ref()
+            .is_some_and(|writer| writer.get_offset() >= self.options.max_segment_size);
+        if value_log_full {
+            // the sealed value log is left as is, compaction moves its values once most are stale
+            self.current_value_file_id += 1;
+            let writer = new_value_log_writer(&self.dir_path, self.current_value_file_id)?;
+            self.value_writer = Some(writer);
+            self.sealed_size = self.sealed_files_size()?;
+        }
+        if self.uncompacted >= self.options.compaction_threshold {
+            self.compact()
+        } else if self.current_writer.get_offset() >= self.options.max_segment_size {
+            // the sealed file is left as is, a later compaction cleans it up
+            if let Some(filter) = self.filters.read().unwrap().get(&self.current_file_id) {
+                write_filter(&self.dir_path, self.current_file_id, filter)?;
+            }
+            self.current_file_id += 1;
+            self.current_writer = new_log_writer(&self.dir_path, self.current_file_id)?;
+            self.write_index_snapshot()?;
+            self.sealed_size = self.sealed_files_size()?;
             Ok(())
+        } else if self.index_snapshot_at.0 != self.current_file_id
+            || self.current_writer.get_offset() - self.index_snapshot_at.1
+                >= self.options.index_snapshot_interval
+        {
+            self.write_index_snapshot()
         } else {
-            Err(KvError::KeyNotFound)
+            Ok(())
+        }
+    }
+
+    /// Persists the index as of the end of the active log, so that opening
+    /// the store only replays the log appended after it.
+    fn write_index_snapshot(&mut self) -> Result<()> {
+        let offset = self.current_writer.get_offset();
+        // files before the last compaction may be kept for snapshots, but the index is past them
+        let safe_point = self.reader.safe_point.load(Ordering::SeqCst);
+        let file_ids = sorted_file_ids(&self.dir_path, LOG_EXTENSION)?
+            .into_iter()
+            .filter(|&file_id| file_id >= safe_point && file_id <= self.current_file_id)
+            .collect();
+        let index = self.index.read().unwrap().clone();
+        self.persist_index(&IndexSnapshot {
+            file_ids,
+            offset,
+            uncompacted: self.uncompacted,
+            entries: index.into_iter().collect(),
+        })?;
+        self.index_snapshot_at = (self.current_file_id, offset);
+        Ok(())
+    }
+
+    /// Writes `snapshot` as the persisted index.
+    fn persist_index(&self, snapshot: &IndexSnapshot) -> Result<()> {
+        let mut bytes = bincode::serialize(snapshot)?;
+        let crc = crc32fast::hash(&bytes);
+        bytes.extend_from_slice(&crc.to_le_bytes());
+        // written aside and renamed, so a crash leaves either the old or the new one
+        let path = self.dir_path.join(INDEX_SNAPSHOT_FILE);
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&bytes)?;
+        if self.options.sync_policy != SyncPolicy::Never {
+            file.sync_data()?;
+        }
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Compacts the store if a file has an older format version.
+    fn migrate(&mut self) -> Result<()> {
+        let mut paths: Vec<PathBuf> = sorted_file_ids(&self.dir_path, LOG_EXTENSION)?
+            .into_iter()
+            .map(|file_id| log_path(&self.dir_path, file_id))
+            .collect();
+        for file_id in sorted_file_ids(&self.dir_path, VALUE_LOG_EXTENSION)? {
+            paths.push(value_log_path(&self.dir_path, file_id));
+        }
+        for path in paths {
+            if segment_version(&path)? < FORMAT_VERSION {
+                return self.compact();
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the size of the files in the directory, except the active logs.
+    fn sealed_files_size(&self) -> Result<u64> {
+        let mut active = vec![log_path(&self.dir_path, self.current_file_id)];
+        if self.value_writer.is_some() {
+            active.push(value_log_path(&self.dir_path, self.current_value_file_id));
+        }
+        dir_size(&self.dir_path, &active)
+    }
+
+    /// Returns the sealed value logs less than half of which the index still points
+    /// to, or that have an older format version.
+    ///
+    /// Compaction moves their live values to the active value log, so they can be removed.
+    fn stale_value_logs(&self, index: &OrdMap<String, RecordInfo>) -> Result<HashSet<u64>> {
+        let mut live: HashMap<u64, u64> = HashMap::new();
+        for value in index.values().filter_map(|record| record.value) {
+            *live.entry(value.file_id).or_default() += value.length;
+        }
+        let mut stale = HashSet::new();
+        for file_id in sorted_file_ids(&self.dir_path, VALUE_LOG_EXTENSION)? {
+            let path = value_log_path(&self.dir_path, file_id);
+            let size = fs::metadata(&path)?.len();
+            let live = live.get(&file_id).copied().unwrap_or(0);
+            let outdated = segment_version(&path)? < FORMAT_VERSION;
+            if file_id != self.current_value_file_id && (live * 2 < size || outdated) {
+                stale.insert(file_id);
+            }
+        }
+        Ok(stale)
+    }
+
+    /// Removes the sealed value logs the index no longer points to.
+    ///
+    /// A snapshot may still read from them, so nothing is removed while one
+    /// is alive, and a later compaction removes them instead.
+    fn remove_stale_value_logs(&self) -> Result<()> {
+        let referenced: HashSet<u64> = self
+            .index
+            .read()
+            .unwrap()
+            .values()
+            .filter_map(|record| record.value.map(|value| value.file_id))
+            .collect();
+        // hold the lock, so no snapshot is taken while the files are removed
+        let pins = self.pins.pins.lock().unwrap();
+        if !pins.is_empty() {
+            return Ok(());
+        }
+        for file_id in sorted_file_ids(&self.dir_path, VALUE_LOG_EXTENSION)? {
+            if file_id != self.current_value_file_id && !referenced.contains(&file_id) {
+                fs::remove_file(value_log_path(&self.dir_path, file_id))?;
+                self.reader.value_files.write().unwrap().remove(&file_id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that every delete of the batch targets an existing key.
+    fn check_batch(&self, batch: &WriteBatch) -> Result<()> {
+        let index = self.index.read().unwrap();
+        let mut exists: HashMap<&str, bool> = HashMap::new();
+        for op in batch {
+            match op {
+                BatchOp::Put(key, _) => {
+                    exists.insert(key, true);
+                }
+                BatchOp::Delete(key) => {
+                    let present = exists.get(key.as_str()).copied().unwrap_or_else(|| {
+                        index.get(key).is_some_and(|record| !record.is_expired())
+                    });
+                    if !present {
+                        return Err(KvError::KeyNotFound);
+                    }
+                    exists.insert(key, false);
+                }
+            }
         }
+        Ok(())
     }
 
     /// Clears stale entries in the log.
     fn compact(&mut self) -> Result<()> {
         // compact writer use current_file_id + 1
-        let mut prev_offset = 0;
         let compact_file_id = self.current_file_id + 1;
         let mut compact_writer = new_log_writer(&self.dir_path, compact_file_id)?;
-        let mut new_records = HashMap::with_capacity(self.index.len());
-
-        for entry in self.index.iter_mut() {
-            self.reader.read_and(entry.value(), |mut reader| {
-                io::copy(&mut reader, &mut compact_writer)?;
-                Ok(())
-            })?;
-            let curr_offset = compact_writer.get_offset();
-            new_records.insert(
-                entry.key().clone(),
-                RecordInfo {
-                    file_id: compact_file_id,
-                    offset: prev_offset,
-                    length: curr_offset - prev_offset,
-                },
-            );
-            prev_offset = curr_offset;
+        // only the writer mutates the index, so a copy of it stays up to date while copying
+        let index = self.index.read().unwrap().clone();
+        let stale_value_logs = self.stale_value_logs(&index)?;
+        let mut new_records = Vec::with_capacity(index.len());
+        let mut expired_keys = Vec::new();
+        // uncompressed records of the block being built
+        let mut block = Vec::new();
+
+        for (key, record) in index.iter() {
+            if record.is_expired() {
+                expired_keys.push(key.clone());
+                continue;
+            }
+            // the block is not written yet, so its offset is the current end of the file
+            let mut new_record =
+                RecordInfo::new(compact_file_id, compact_writer.get_offset(), record.length);
+            new_record.expire_at = record.expire_at;
+            new_record.inline_value = record.inline_value.clone();
+            new_record.accessed = record.accessed.clone();
+            if let Some(mut value) = record.value {
+                // the value stays in its value log, unless that one is mostly stale
+                if stale_value_logs.contains(&value.file_id) {
+                    let bytes = self.reader.read_bytes_uncached(record)?.unwrap_or_default();
+                    value = self.append_value(key, bytes)?;
+                }
+                new_record.value = Some(value);
+                let cmd = Command::SetRef(key.clone(), value, record.expire_at);
+                new_record.length = match self.options.compression {
+                    Compression::None => {
+                        self.codec.encode(&mut compact_writer, &cmd)?;
+                        compact_writer.get_offset() - new_record.offset
+                    }
+                    Compression::Lz4 => {
+                        let block_offset = block.len();
+                        new_record.block_offset = Some(block_offset as u32);
+                        self.codec.encode(&mut block, &cmd)?;
+                        (block.len() - block_offset) as u64
+                    }
+                };
+            } else {
+                match self.options.compression {
+                    Compression::None => {
+                        self.reader.read_and(record, |reader| {
+                            io::copy(reader, &mut compact_writer)?;
+                            Ok(())
+                        })?;
+                    }
+                    Compression::Lz4 => {
+                        new_record.block_offset = Some(block.len() as u32);
+                        self.reader.read_and(record, |reader| {
+                            reader.read_to_end(&mut block)?;
+                            Ok(())
+                        })?;
+                    }
+                }
+            }
+            if block.len() >= COMPRESSION_BLOCK_SIZE {
+                write_block(&mut compact_writer, &block)?;
+                block.clear();
+            }
+            new_records.push((key.clone(), new_record));
+        }
+        if !block.is_empty() {
+            write_block(&mut compact_writer, &block)?;
         }
         compact_writer.flush()?;
-        for (key, rec) in new_records {
-            self.index.insert(key, rec);
+        if self.options.sync_policy != SyncPolicy::Never {
+            // the old files are removed below, the compacted one must be durable first
+            if let Some(value_writer) = &self.value_writer {
+                value_writer.get_ref().sync_data()?;
+            }
+            compact_writer.get_ref().sync_data()?;
+        }
+
+        // the new filter covers the compacted keys before the index points to them
+        let mut filter = BloomFilter::with_capacity(new_records.len());
+        for (key, _) in &new_records {
+            filter.insert(key);
         }
+        write_filter(&self.dir_path, compact_file_id, &filter)?;
+        self.filters
+            .write()
+            .unwrap()
+            .insert(compact_file_id, filter);
 
+        let mut index = self.index.write().unwrap();
+        for (key, rec) in new_records {
+            index.insert(key, rec);
+        }
+        for key in expired_keys {
+            index.remove(&key);
+        }
+        if self.options.max_memory.is_some() {
+            self.live_size = live_size(&index);
+        }
         self.reader
             .safe_point
             .store(compact_file_id, Ordering::SeqCst);
-        self.reader.remove_stale_file(compact_file_id);
+        drop(index);
+        self.filters
+            .write()
+            .unwrap()
+            .retain(|&file_id, _| file_id >= compact_file_id);
+        if let Some(cache) = &self.reader.cache {
+            cache.lock().unwrap().remove_before(compact_file_id);
+        }
+
+        self.pins.remove_stale_files();
+        self.remove_stale_value_logs()?;
 
         self.current_file_id += 2;
         self.current_writer = new_log_writer(&self.dir_path, self.current_file_id)?;
         self.uncompacted = 0;
+        self.write_index_snapshot()?;
+        self.sealed_size = self.sealed_files_size()?;
+        Ok(())
+    }
+
+    /// Replaces the records of the store with the compacted segment read
+    /// from `segment`, as written by `KvSnapshot::write_segment`.
+    ///
+    /// The segment is staged next to the logs and checked before it replaces
+    /// them, so a failure leaves the store as is. The persisted index points
+    /// to the segment before it is renamed into place, which a crash either
+    /// happens before, the index then ignored as outdated, or after: the
+    /// store reopens with the old records or the new ones, never a mix.
+    fn install(&mut self, segment: &mut dyn Read) -> Result<()> {
+        let file_id = self.current_file_id + 1;
+        let path = log_path(&self.dir_path, file_id);
+        let staged_path = path.with_extension(STAGED_EXTENSION);
+        let (mut index, uncompacted) = match self.stage(segment, file_id, &staged_path) {
+            Ok(staged) => staged,
+            Err(err) => {
+                let _ = fs::remove_file(&staged_path);
+                return Err(err);
+            }
+        };
+        if self.options.max_memory.is_some() {
+            index = index
+                .into_iter()
+                .map(|(key, mut record)| {
+                    record.accessed = Some(Arc::new(AtomicU64::new(next_access())));
+                    (key, record)
+                })
+                .collect();
+        }
+        let mut filter = BloomFilter::with_capacity(index.len());
+        for key in index.keys() {
+            filter.insert(key);
+        }
+        write_filter(&self.dir_path, file_id, &filter)?;
+        self.persist_index(&IndexSnapshot {
+            file_ids: vec![file_id],
+            offset: fs::metadata(&staged_path)?.len(),
+            uncompacted,
+            entries: index.clone().into_iter().collect(),
+        })?;
+        fs::rename(&staged_path, &path)?;
+
+        let old_index = {
+            let mut current = self.index.write().unwrap();
+            self.reader.safe_point.store(file_id, Ordering::SeqCst);
+            mem::replace(&mut *current, index.clone())
+        };
+        *self.filters.write().unwrap() = BTreeMap::from([(file_id, filter)]);
+        if let Some(cache) = &self.reader.cache {
+            cache.lock().unwrap().remove_before(file_id);
+        }
+        self.current_file_id = file_id + 1;
+        self.current_writer = new_log_writer(&self.dir_path, self.current_file_id)?;
+        self.pins.remove_stale_files();
+        self.remove_stale_value_logs()?;
+        self.uncompacted = uncompacted;
+        if self.options.max_memory.is_some() {
+            self.live_size = live_size(&index);
+        }
+        self.write_index_snapshot()?;
+        self.sealed_size = self.sealed_files_size()?;
+
+        for key in old_index.keys().filter(|key| !index.contains_key(*key)) {
+            self.subscribers.notify(key, ChangeKind::Remove);
+        }
+        for key in index.keys() {
+            self.subscribers.notify(key, ChangeKind::Set);
+        }
         Ok(())
     }
+
+    /// Writes `segment` to `path`, then reads it back as the log file
+    /// `file_id`, returning its index and the size of its stale records.
+    fn stage(
+        &self,
+        segment: &mut dyn Read,
+        file_id: u64,
+        path: &Path,
+    ) -> Result<(OrdMap<String, RecordInfo>, u64)> {
+        let mut file = File::create(path)?;
+        let len = io::copy(segment, &mut file)?;
+        if self.options.sync_policy != SyncPolicy::Never {
+            file.sync_data()?;
+        }
+        if segment_version(path)? != FORMAT_VERSION {
+            return Err(KvError::Corruption { file_id, offset: 0 });
+        }
+
+        let mut index = OrdMap::new();
+        let mut uncompacted = 0;
+        let inline_value_size = self.options.inline_value_size;
+        let mut reader = BufReader::with_capacity(self.options.read_buffer_size, File::open(path)?);
+        reader.seek(SeekFrom::Start(SEGMENT_HEADER_LEN))?;
+        let mut offset = SEGMENT_HEADER_LEN;
+        loop {
+            let corruption = KvError::Corruption { file_id, offset };
+            match read_record(&mut reader)? {
+                ReadRecord::Command(cmd @ (Command::Set(..) | Command::SetBytes(..)), length) => {
+                    let record = RecordInfo::new(file_id, offset, length);
+                    uncompacted += replay(&mut index, cmd, record, inline_value_size);
+                    offset += length;
+                }
+                ReadRecord::Block(block, length) => {
+                    let mut block_reader = &block[..];
+                    let mut block_offset = 0;
+                    loop {
+                        match read_record(&mut block_reader)? {
+                            ReadRecord::Command(
+                                cmd @ (Command::Set(..) | Command::SetBytes(..)),
+                                length,
+                            ) => {
+                                let mut record = RecordInfo::new(file_id, offset, length);
+                                record.block_offset = Some(block_offset);
+                                uncompacted += replay(&mut index, cmd, record, inline_value_size);
+                                block_offset += length as u32;
+                            }
+                            ReadRecord::End => break,
+                            _ => return Err(corruption),
+                        }
+                    }
+                    offset += length;
+                }
+                // a torn record reads as an end before the end of the file
+                ReadRecord::End if offset == len => break,
+                _ => return Err(corruption),
+            }
+        }
+        Ok((index, uncompacted))
+    }
+}
+
+impl Drop for KvWriter {
+    fn drop(&mut self) {
+        // persist the index on a clean shutdown, unless nothing was written since the last time
+        if self.index_snapshot_at != (self.current_file_id, self.current_writer.get_offset()) {
+            if let Err(err) = self.write_index_snapshot() {
+                warn!("failed to persist the index: {}", err);
+            }
+        }
+    }
+}
+
+/// The index as of a position in the log, persisted so that opening the
+/// store does not replay the whole log.
+#[derive(Serialize, Deserialize)]
+struct IndexSnapshot {
+    // log files covered by the index, the last one up to `offset`
+    file_ids: Vec<u64>,
+    offset: u64,
+    uncompacted: u64,
+    entries: Vec<(String, RecordInfo)>,
+}
+
+/// Loads the persisted index, if it is there and the log it covers is unchanged.
+fn load_index_snapshot(dir_path: &Path, file_ids: &[u64]) -> Result<Option<IndexSnapshot>> {
+    let bytes = match fs::read(dir_path.join(INDEX_SNAPSHOT_FILE)) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let snapshot = bytes
+        .split_at_checked(bytes.len().saturating_sub(4))
+        .filter(|(body, crc)| crc32fast::hash(body).to_le_bytes() == **crc)
+        .and_then(|(body, _)| bincode::deserialize::<IndexSnapshot>(body).ok());
+    let snapshot = match snapshot {
+        Some(snapshot) => snapshot,
+        None => {
+            warn!("ignore damaged index snapshot in {}", dir_path.display());
+            return Ok(None);
+        }
+    };
+    // files older than the index are left over from compaction, the others must
+    // all be there, and the last one must still reach the covered position
+    let valid = match (snapshot.file_ids.first(), snapshot.file_ids.last()) {
+        (Some(&first), Some(&last)) => {
+            let position = file_ids.iter().position(|&file_id| file_id == first);
+            position.is_some_and(|position| file_ids[position..].starts_with(&snapshot.file_ids))
+                && fs::metadata(log_path(dir_path, last))?.len() >= snapshot.offset
+        }
+        _ => false,
+    };
+    if !valid {
+        warn!("ignore outdated index snapshot in {}", dir_path.display());
+        return Ok(None);
+    }
+    Ok(Some(snapshot))
+}
+
+/// A read-only point-in-time view of a `KvStore`, created by `KvStore::snapshot`.
+#[derive(Clone)]
+pub struct KvSnapshot {
+    index: OrdMap<String, RecordInfo>,
+    reader: KvReader,
+    // shared by clones, the files are released when the last one is dropped
+    _pin: Arc<SnapshotPin>,
+}
+
+impl KvSnapshot {
+    /// Gets the string value of a given string key as of the snapshot.
+    ///
+    /// Returns `None` if the given key does not exist.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        match self.index.get(&key) {
+            Some(record) if !record.is_expired() => self.reader.read_value(record),
+            _ => Ok(None),
+        }
+    }
+
+    /// Writes all key/value pairs of the snapshot to `writer` as JSON lines, in key order.
+    ///
+    /// Values are read one at a time, so the store does not need to fit in memory.
+    /// The binary values that are not valid UTF-8 are skipped.
+    pub fn export<W: Write>(&self, mut writer: W) -> Result<usize> {
+        let mut count = 0;
+        for (key, record) in self.index.iter() {
+            if record.is_expired() {
+                continue;
+            }
+            if let Some(value) = self.reader.read_string(record)? {
+                write_export_record(&mut writer, key, &value)?;
+                count += 1;
+            }
+        }
+        writer.flush()?;
+        Ok(count)
+    }
+
+    /// Writes the live records of the snapshot to `writer` as one compacted
+    /// log segment, in compressed blocks, which another store installs with
+    /// `KvEngine::install_snapshot`.
+    ///
+    /// Values are read one at a time, so the store does not need to fit in
+    /// memory. Returns the number of records written.
+    pub fn write_segment<W: Write>(&self, mut writer: W) -> Result<usize> {
+        let codec = LogFormat::Bincode.codec();
+        write_segment_header(&mut writer)?;
+        let mut count = 0;
+        // uncompressed records of the block being built
+        let mut block = Vec::new();
+        for (key, record) in self.index.iter() {
+            if record.is_expired() {
+                continue;
+            }
+            if let Some(value) = self.reader.read_bytes(record)? {
+                let cmd = Command::SetBytes(key.clone(), value, record.expire_at);
+                codec.encode(&mut block, &cmd)?;
+                count += 1;
+            }
+            if block.len() >= COMPRESSION_BLOCK_SIZE {
+                write_block(&mut writer, &block)?;
+                block.clear();
+            }
+        }
+        if !block.is_empty() {
+            write_block(&mut writer, &block)?;
+        }
+        writer.flush()?;
+        Ok(count)
+    }
+
+    /// Returns all key/value pairs of the snapshot whose key falls in `range`, in key order,
+    /// skipping the binary values that are not valid UTF-8.
+    pub fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for (key, record) in self.index.range(range) {
+            if record.is_expired() {
+                continue;
+            }
+            if let Some(value) = self.reader.read_string(record)? {
+                pairs.push((key.clone(), value));
+            }
+        }
+        Ok(pairs)
+    }
+}
+
+/// Keeps the log files of a snapshot from being removed while it is alive.
+struct SnapshotPin {
+    // the oldest log file the snapshot may read
+    file_id: u64,
+    pins: Arc<SnapshotPins>,
+}
+
+impl Drop for SnapshotPin {
+    fn drop(&mut self) {
+        self.pins.unpin(self.file_id);
+    }
+}
+
+/// Tracks the log files that live snapshots still read from.
+///
+/// Compaction only removes files older than both the safe point and
+/// the oldest pinned file.
+struct SnapshotPins {
+    dir_path: Arc<PathBuf>,
+    safe_point: Arc<AtomicU64>,
+    // oldest file of a snapshot -> number of live snapshots
+    pins: Mutex<BTreeMap<u64, usize>>,
+}
+
+impl SnapshotPins {
+    /// Pins the files at or after the current safe point.
+    ///
+    /// Must be called with the index lock held, so that the safe point matches the index.
+    fn pin(self: &Arc<Self>) -> SnapshotPin {
+        let file_id = self.safe_point.load(Ordering::SeqCst);
+        *self.pins.lock().unwrap().entry(file_id).or_insert(0) += 1;
+        SnapshotPin {
+            file_id,
+            pins: self.clone(),
+        }
+    }
+
+    fn unpin(&self, file_id: u64) {
+        let mut pins = self.pins.lock().unwrap();
+        if let Some(count) = pins.get_mut(&file_id) {
+            *count -= 1;
+            if *count == 0 {
+                pins.remove(&file_id);
+            }
+        }
+        drop(pins);
+        self.remove_stale_files();
+    }
+
+    /// Removes the log files no longer needed by the store or any snapshot.
+    fn remove_stale_files(&self) {
+        // hold the lock, so no snapshot pins a file while it is being removed
+        let pins = self.pins.lock().unwrap();
+        let safe_point = self.safe_point.load(Ordering::SeqCst);
+        let stale_before = pins
+            .keys()
+            .next()
+            .map_or(safe_point, |&id| id.min(safe_point));
+        let file_ids = match sorted_file_ids(&self.dir_path, LOG_EXTENSION) {
+            Ok(file_ids) => file_ids,
+            Err(err) => {
+                warn!("list log files error: {}", err);
+                return;
+            }
+        };
+        for file_id in file_ids.into_iter().take_while(|&id| id < stale_before) {
+            let paths = [
+                log_path(&self.dir_path, file_id),
+                filter_path(&self.dir_path, file_id),
+            ];
+            for path in paths {
+                match fs::remove_file(path) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                        warn!("remove file error: {}", err)
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// Returns the ids of the files with `extension` in `dir_path` in ascending order.
+fn sorted_file_ids(dir_path: &Path, extension: &str) -> Result<Vec<u64>> {
+    let mut file_ids: Vec<u64> = fs::read_dir(dir_path)?
+        .flat_map(|dir| -> Result<_> { Ok(dir?.path()) })
+        .filter(|path| path.is_file() && path.extension() == Some(extension.as_ref()))
+        .flat_map(|path| {
+            path.file_stem()
+                .and_then(OsStr::to_str)
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+
+    file_ids.sort_unstable();
+    Ok(file_ids)
+}
+
+/// Returns the total size of the files in `dir_path`, except the `active` ones.
+fn dir_size(dir_path: &Path, active: &[PathBuf]) -> Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir_path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        // bucket directories hold stores of their own
+        if metadata.is_file() && !active.contains(&entry.path()) {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
 }
 
 fn log_path(dir: &Path, file_id: u64) -> PathBuf {
     dir.join(format!("{}.log", file_id))
 }
 
+fn value_log_path(dir: &Path, file_id: u64) -> PathBuf {
+    dir.join(format!("{}.vlog", file_id))
+}
+
+fn filter_path(dir: &Path, file_id: u64) -> PathBuf {
+    dir.join(format!("{}.bloom", file_id))
+}
+
+/// Persists the filter of a sealed log file next to it.
+fn write_filter(dir_path: &Path, file_id: u64, filter: &BloomFilter) -> Result<()> {
+    fs::write(filter_path(dir_path, file_id), filter.encode())?;
+    Ok(())
+}
+
+/// Loads the filters of the sealed log files, and builds the missing or damaged ones
+/// and the one of the active log from the index.
+fn load_filters(
+    dir_path: &Path,
+    index: &OrdMap<String, RecordInfo>,
+    current_file_id: u64,
+) -> Result<BTreeMap<u64, BloomFilter>> {
+    let mut keys: BTreeMap<u64, Vec<&str>> = BTreeMap::new();
+    for (key, record) in index.iter() {
+        keys.entry(record.file_id).or_default().push(key);
+    }
+
+    let mut filters = BTreeMap::new();
+    for (file_id, keys) in keys {
+        if file_id == current_file_id {
+            let capacity = INITIAL_FILTER_CAPACITY.max(keys.len() * 2);
+            let mut filter = BloomFilter::with_capacity(capacity);
+            keys.iter().for_each(|key| filter.insert(key));
+            filters.insert(file_id, filter);
+            continue;
+        }
+
+        let persisted = match fs::read(filter_path(dir_path, file_id)) {
+            Ok(bytes) => BloomFilter::decode(&bytes),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+        let filter = match persisted {
+            Some(filter) => filter,
+            None => {
+                let mut filter = BloomFilter::with_capacity(keys.len());
+                keys.iter().for_each(|key| filter.insert(key));
+                write_filter(dir_path, file_id, &filter)?;
+                filter
+            }
+        };
+        filters.insert(file_id, filter);
+    }
+    Ok(filters)
+}
+
 fn new_log_writer(dir_path: &Path, file_id: u64) -> Result<BufWriterWithPosition<File>> {
-    let path = log_path(dir_path, file_id);
-    Ok(BufWriterWithPosition::new(
-        OpenOptions::new().create(true).append(true).open(&path)?,
-    )?)
+    new_segment_writer(&log_path(dir_path, file_id))
 }
 
-fn new_log_reader(dir_path: &Path, file_id: u64) -> Result<BufReader<File>> {
-    let path = log_path(dir_path, file_id);
-    Ok(BufReader::new(File::open(path)?))
+fn new_value_log_writer(dir_path: &Path, file_id: u64) -> Result<BufWriterWithPosition<File>> {
+    new_segment_writer(&value_log_path(dir_path, file_id))
 }
 
-/// Struct representing a command.
-#[derive(Serialize, Deserialize, Debug)]
-enum Command {
-    // set key value
-    Set(String, String),
-    // remove key
-    Remove(String),
+/// Opens a log file for appending, starting it with a header if it is new.
+fn new_segment_writer(path: &Path) -> Result<BufWriterWithPosition<File>> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut writer = BufWriterWithPosition::new(file)?;
+    if writer.get_offset() == 0 {
+        write_segment_header(&mut writer)?;
+        writer.flush()?;
+    }
+    Ok(writer)
 }
 
-/// Represents the position and length of a json-serialized record in the log.
-#[derive(Clone)]
+/// Returns the format version of the log file at `path`.
+///
+/// Fails with `KvError::UnsupportedVersion` if the version is newer than this store knows.
+fn segment_version(path: &Path) -> Result<u32> {
+    let version = read_segment_version(&mut File::open(path)?)?;
+    if version > FORMAT_VERSION {
+        return Err(KvError::UnsupportedVersion(
+            path.display().to_string(),
+            version,
+        ));
+    }
+    Ok(version)
+}
+
+/// Returns the offset of the first record in a log file of `version`.
+fn first_record_offset(version: u32) -> u64 {
+    match version {
+        0 => 0,
+        _ => SEGMENT_HEADER_LEN,
+    }
+}
+
+/// Takes an exclusive lock on the store directory, held as long as the returned file is open.
+///
+/// The lock is advisory, so it keeps out other stores but not other programs.
+fn lock_dir(dir_path: &Path) -> Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir_path.join(LOCK_FILE))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => {
+            Err(KvError::AlreadyLocked(dir_path.display().to_string()))
+        }
+        Err(TryLockError::Error(err)) => Err(err.into()),
+    }
+}
+
+fn next_access() -> u64 {
+    ACCESS_CLOCK.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Returns the approximate size of the keys in `index`.
+fn live_size(index: &OrdMap<String, RecordInfo>) -> u64 {
+    index
+        .iter()
+        .map(|(key, record)| record.entry_size(key))
+        .sum()
+}
+
+/// Represents the position and length of a serialized record in the log.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct RecordInfo {
     file_id: u64,
+    // offset of the record, or of its block in a compressed segment
     offset: u64,
+    // length of the uncompressed record
     length: u64,
+    // expiration time in unix milliseconds
+    expire_at: Option<u64>,
+    // offset of the record within its compressed block
+    block_offset: Option<u32>,
+    // where the value is, if it is stored in a value log
+    value: Option<ValuePointer>,
+    // the value itself, if it is small enough to be kept in the index
+    inline_value: Option<Vec<u8>>,
+    // last access on the access clock, tracked with a memory budget only
+    #[serde(skip)]
+    accessed: Option<Arc<AtomicU64>>,
+}
+
+impl RecordInfo {
+    fn new(file_id: u64, offset: u64, length: u64) -> RecordInfo {
+        RecordInfo {
+            file_id,
+            offset,
+            length,
+            expire_at: None,
+            block_offset: None,
+            value: None,
+            inline_value: None,
+            accessed: None,
+        }
+    }
+
+    /// Returns the approximate bytes the entry of `key` takes in the index and in the log.
+    fn entry_size(&self, key: &str) -> u64 {
+        let inline_value = self.inline_value.as_ref().map_or(0, Vec::len);
+        (key.len() + inline_value + mem::size_of::<RecordInfo>()) as u64 + self.stale_size()
+    }
+
+    /// Records an access to the key, if accesses are tracked.
+    fn touch(&self) {
+        if let Some(accessed) = &self.accessed {
+            accessed.store(next_access(), Ordering::Relaxed);
+        }
+    }
+
+    fn last_access(&self) -> u64 {
+        self.accessed
+            .as_ref()
+            .map_or(0, |accessed| accessed.load(Ordering::Relaxed))
+    }
+
+    /// Returns the bytes that become stale once the record is overwritten or removed.
+    fn stale_size(&self) -> u64 {
+        self.length + self.value.map_or(0, |value| value.length)
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expire_at.is_some_and(is_expired)
+    }
+
+    fn ttl(&self) -> Option<Duration> {
+        self.expire_at
+            .map(|expire_at| Duration::from_millis(expire_at.saturating_sub(now_millis())))
+    }
 }
 
 /// A BufWriter with write position.
@@ -384,6 +2171,10 @@ impl<T: Write + Seek> BufWriterWithPosition<T> {
     fn get_offset(&self) -> u64 {
         self.offset
     }
+
+    fn get_ref(&self) -> &T {
+        self.writer.get_ref()
+    }
 }
 
 impl<T: Write + Seek> Write for BufWriterWithPosition<T> {
@@ -0,0 +1,239 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::{Bound, RangeBounds},
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use super::engine::{check_bucket_name, expire_at, incr_value, is_expired, now_millis};
+use crate::{BatchOp, EngineStats, KvEngine, KvError, Result, WriteBatch};
+
+/// A value and its expiration time in unix milliseconds.
+#[derive(Clone)]
+struct Entry {
+    value: Vec<u8>,
+    expire_at: Option<u64>,
+}
+
+impl Entry {
+    fn is_live(&self) -> bool {
+        !self.expire_at.is_some_and(is_expired)
+    }
+}
+
+/// In-memory KV storage engine.
+///
+/// Nothing is written to disk, so the data is lost when the store is dropped.
+/// Entries are kept in an ordered map behind one lock, which keeps range scans
+/// ordered and lets batches be applied atomically.
+#[derive(Clone, Default)]
+pub struct MemStore {
+    map: Arc<RwLock<BTreeMap<String, Entry>>>,
+    buckets: Arc<Mutex<HashMap<String, MemStore>>>,
+}
+
+impl MemStore {
+    /// Creates an empty `MemStore`.
+    pub fn new() -> MemStore {
+        MemStore::default()
+    }
+
+    fn write(&self, key: String, value: Vec<u8>, expire_at: Option<u64>) {
+        self.map
+            .write()
+            .unwrap()
+            .insert(key, Entry { value, expire_at });
+    }
+
+    /// Sets `key` to `value` if whether the key exists matches `present`.
+    fn set_if(&self, key: String, value: String, present: bool) -> Result<bool> {
+        let mut map = self.map.write().unwrap();
+        let exists = map.get(&key).is_some_and(|entry| entry.is_live());
+        if exists != present {
+            return Ok(false);
+        }
+        let entry = Entry {
+            value: value.into_bytes(),
+            expire_at: None,
+        };
+        map.insert(key, entry);
+        Ok(true)
+    }
+}
+
+impl KvEngine for MemStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.write(key, value.into_bytes(), None);
+        Ok(())
+    }
+
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.write(key, value.into_bytes(), Some(expire_at(ttl)));
+        Ok(())
+    }
+
+    fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        self.write(key, value, None);
+        Ok(())
+    }
+
+    fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .map
+            .read()
+            .unwrap()
+            .get(&key)
+            .filter(|entry| entry.is_live())
+            .map(|entry| entry.value.clone()))
+    }
+
+    fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        match self.map.read().unwrap().get(&key) {
+            Some(entry) if entry.is_live() => Ok(entry
+                .expire_at
+                .map(|expire_at| Duration::from_millis(expire_at.saturating_sub(now_millis())))),
+            _ => Err(KvError::KeyNotFound),
+        }
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.get_bytes(key)?.map(String::from_utf8).transpose()?)
+    }
+
+    fn get_in_memory(&self, key: &str) -> Option<Result<Option<String>>> {
+        Some(self.get(key.to_owned()))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        match self.map.write().unwrap().remove(&key) {
+            Some(entry) if entry.is_live() => Ok(()),
+            _ => Err(KvError::KeyNotFound),
+        }
+    }
+
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        self.set_if(key, value, false)
+    }
+
+    fn set_if_present(&self, key: String, value: String) -> Result<bool> {
+        self.set_if(key, value, true)
+    }
+
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        let mut map = self.map.write().unwrap();
+        let entry = map.get(&key).filter(|entry| entry.is_live());
+        let value = incr_value(entry.map(|entry| entry.value.as_slice()), delta)?;
+        let expire_at = entry.and_then(|entry| entry.expire_at);
+        let entry = Entry {
+            value: value.to_string().into_bytes(),
+            expire_at,
+        };
+        map.insert(key, entry);
+        Ok(value)
+    }
+
+    fn name(&self) -> &'static str {
+        "mem"
+    }
+
+    /// Returns the number of live keys and the bytes of their keys and values.
+    ///
+    /// The sizes do not count the buckets.
+    fn stats(&self) -> Result<EngineStats> {
+        let map = self.map.read().unwrap();
+        let live = map.iter().filter(|(_, entry)| entry.is_live());
+        let (keys, memory_size) = live.fold((0, 0), |(keys, size), (key, entry)| {
+            (keys + 1, size + (key.len() + entry.value.len()) as u64)
+        });
+        Ok(EngineStats {
+            keys,
+            memory_size: Some(memory_size),
+            ..EngineStats::default()
+        })
+    }
+
+    fn bucket(&self, name: &str) -> Result<MemStore> {
+        check_bucket_name(name)?;
+        let mut buckets = self.buckets.lock().unwrap();
+        Ok(buckets.entry(name.to_owned()).or_default().clone())
+    }
+
+    fn len(&self) -> Result<usize> {
+        let map = self.map.read().unwrap();
+        Ok(map.values().filter(|entry| entry.is_live()).count())
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.write_batch_if(HashMap::new(), batch)
+    }
+
+    fn write_batch_if(
+        &self,
+        reads: HashMap<String, Option<String>>,
+        batch: WriteBatch,
+    ) -> Result<()> {
+        let mut map = self.map.write().unwrap();
+        for (key, read) in reads {
+            let entry = map.get(&key).filter(|entry| entry.is_live());
+            if entry.map(|entry| entry.value.as_slice()) != read.as_ref().map(String::as_bytes) {
+                return Err(KvError::TransactionConflict);
+            }
+        }
+        // apply to a copy of the touched entries, so a failing batch changes nothing
+        let mut pending: BTreeMap<String, Option<Entry>> = BTreeMap::new();
+        for op in batch {
+            match op {
+                BatchOp::Put(key, value) => {
+                    let entry = Entry {
+                        value: value.into_bytes(),
+                        expire_at: None,
+                    };
+                    pending.insert(key, Some(entry));
+                }
+                BatchOp::Delete(key) => {
+                    let exists = match pending.get(&key) {
+                        Some(entry) => entry.is_some(),
+                        None => map.get(&key).is_some_and(Entry::is_live),
+                    };
+                    if !exists {
+                        return Err(KvError::KeyNotFound);
+                    }
+                    pending.insert(key, None);
+                }
+            }
+        }
+        for (key, entry) in pending {
+            match entry {
+                Some(entry) => map.insert(key, entry),
+                None => map.remove(&key),
+            };
+        }
+        Ok(())
+    }
+
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>> {
+        let map = self.map.read().unwrap();
+        let mut pairs = Vec::new();
+        for (key, entry) in map.range(range) {
+            if !entry.is_live() {
+                continue;
+            }
+            // a binary value is skipped
+            if let Ok(value) = String::from_utf8(entry.value.clone()) {
+                pairs.push((key.clone(), value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn scan_keys(&self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        let map = self.map.read().unwrap();
+        Ok(map
+            .range((start, Bound::Unbounded))
+            .filter(|(_, entry)| entry.is_live())
+            .take(limit)
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+}
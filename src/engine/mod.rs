@@ -1,7 +1,27 @@
+mod batch;
+mod bloom;
+mod cache;
+mod change;
+#[allow(clippy::module_inception)]
 mod engine;
 mod kv;
+mod mem;
+mod options;
+mod prefixed;
+mod record;
 mod sled;
+mod txn;
+mod typed;
 
 pub use self::sled::SledStore;
-pub use engine::KvEngine;
-pub use kv::KvStore;
+pub use batch::{BatchOp, WriteBatch};
+pub use change::ChangeKind;
+pub(crate) use engine::check_bucket_name;
+pub use engine::{EngineStats, KvEngine};
+pub use kv::{KvSnapshot, KvStore};
+pub use mem::MemStore;
+pub use options::{Compression, FlushMode, KvStoreOptions, SyncPolicy};
+pub use prefixed::PrefixedEngine;
+pub use record::LogFormat;
+pub use txn::Txn;
+pub use typed::TypedStore;
@@ -0,0 +1,199 @@
+use std::time::Duration;
+
+use super::record::LogFormat;
+
+/// Compression applied to log segments sealed by compaction.
+///
+/// The active log is never compressed, so writes are not slowed down.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Segments are stored as written.
+    #[default]
+    None,
+    /// Records are compressed with LZ4 in blocks of a few dozen kilobytes.
+    Lz4,
+}
+
+/// When writes are synced to the disk with `fsync`.
+///
+/// Writes are always flushed to the operating system before returning,
+/// which is enough to survive a crash of the process but not of the machine.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Leaves syncing to the operating system.
+    #[default]
+    Never,
+    /// Syncs after every write.
+    Always,
+    /// Syncs after a write if the last sync is older than the interval.
+    Interval(Duration),
+}
+
+/// When a `SledStore` flushes its writes to the disk.
+///
+/// Writes that are not flushed yet are lost if the process crashes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FlushMode {
+    /// Flushes after every write.
+    #[default]
+    EveryOp,
+    /// Flushes in the background at the interval.
+    Interval(Duration),
+    /// Flushes only when the last handle to the store is dropped.
+    OnDrop,
+}
+
+/// Options for opening a `KvStore` with `KvStore::open_with`.
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    pub(super) format: LogFormat,
+    pub(super) compression: Compression,
+    pub(super) compaction_threshold: u64,
+    pub(super) max_segment_size: u64,
+    pub(super) sync_policy: SyncPolicy,
+    pub(super) read_buffer_size: usize,
+    pub(super) value_cache_size: usize,
+    pub(super) max_disk_size: Option<u64>,
+    pub(super) index_snapshot_interval: u64,
+    pub(super) value_log_threshold: Option<usize>,
+    pub(super) inline_value_size: usize,
+    pub(super) max_memory: Option<u64>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> KvStoreOptions {
+        KvStoreOptions {
+            format: LogFormat::default(),
+            compression: Compression::default(),
+            compaction_threshold: 1024 * 1024,
+            max_segment_size: 64 * 1024 * 1024,
+            sync_policy: SyncPolicy::default(),
+            read_buffer_size: 8 * 1024,
+            value_cache_size: 0,
+            max_disk_size: None,
+            index_snapshot_interval: 16 * 1024 * 1024,
+            value_log_threshold: None,
+            inline_value_size: 0,
+            max_memory: None,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Creates the default options.
+    pub fn new() -> KvStoreOptions {
+        KvStoreOptions::default()
+    }
+
+    /// Sets the encoding of the records appended to the log.
+    pub fn format(mut self, format: LogFormat) -> KvStoreOptions {
+        self.format = format;
+        self
+    }
+
+    /// Sets the compression of the segments written by compaction.
+    pub fn compression(mut self, compression: Compression) -> KvStoreOptions {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets the number of stale bytes in the log that triggers a compaction.
+    ///
+    /// Defaults to 1 MiB.
+    pub fn compaction_threshold(mut self, bytes: u64) -> KvStoreOptions {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// Sets the size after which the active log file is sealed and a new one is started.
+    ///
+    /// Defaults to 64 MiB.
+    pub fn max_segment_size(mut self, bytes: u64) -> KvStoreOptions {
+        self.max_segment_size = bytes;
+        self
+    }
+
+    /// Sets when writes are synced to the disk.
+    ///
+    /// Defaults to `SyncPolicy::Never`.
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> KvStoreOptions {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    /// Sets the buffer size of the log file readers that replay the log on
+    /// open and that read compressed blocks.
+    ///
+    /// Other records are read with exactly one read each. Defaults to 8 KiB.
+    pub fn read_buffer_size(mut self, bytes: usize) -> KvStoreOptions {
+        self.read_buffer_size = bytes;
+        self
+    }
+
+    /// Sets the memory budget of the cache of recently read values.
+    ///
+    /// Repeated reads of a hot key are served from the cache instead of the disk.
+    /// Defaults to 0, which disables the cache.
+    pub fn value_cache_size(mut self, bytes: usize) -> KvStoreOptions {
+        self.value_cache_size = bytes;
+        self
+    }
+
+    /// Sets the maximum size of the files in the store directory.
+    ///
+    /// Once it is reached, the store compacts, and writes fail with
+    /// `KvError::QuotaExceeded` if that does not free enough space. Removes are
+    /// always accepted. Compaction temporarily needs space on top of the limit.
+    /// Defaults to no limit.
+    pub fn max_disk_size(mut self, bytes: u64) -> KvStoreOptions {
+        self.max_disk_size = Some(bytes);
+        self
+    }
+
+    /// Sets the number of bytes appended to the log after which the index is persisted.
+    ///
+    /// Opening the store loads the persisted index and replays only the log
+    /// after it. The index is also persisted when the active log changes and
+    /// when the store is dropped. Defaults to 16 MiB.
+    pub fn index_snapshot_interval(mut self, bytes: u64) -> KvStoreOptions {
+        self.index_snapshot_interval = bytes;
+        self
+    }
+
+    /// Sets the size from which values are stored in a separate value log.
+    ///
+    /// The log of keys then only holds a pointer to each large value, so
+    /// compaction does not copy large values, except out of a value log that
+    /// is mostly stale. Defaults to no threshold, which keeps every value in
+    /// the log of keys.
+    pub fn value_log_threshold(mut self, bytes: usize) -> KvStoreOptions {
+        self.value_log_threshold = Some(bytes);
+        self
+    }
+
+    /// Sets the size below which values are kept in the in-memory index.
+    ///
+    /// Reading such a value never touches the disk, which suits counters and
+    /// flags, at the cost of memory for every small value. Values are only
+    /// moved in or out of the index when they are written or when the store
+    /// replays the log. Defaults to 0, which keeps no value in the index.
+    pub fn inline_value_size(mut self, bytes: usize) -> KvStoreOptions {
+        self.inline_value_size = bytes;
+        self
+    }
+
+    /// Sets the approximate budget of the live keys, over which the least
+    /// recently used keys are evicted.
+    ///
+    /// This turns the store into a persistent cache. A key counts its index
+    /// entry and its record in the log, so the budget bounds both the memory of
+    /// the index and the live data on disk. Once over the budget, keys are
+    /// removed until 90% of it is used, and subscribers see the evictions as
+    /// removes. Reads with `get`, `get_bytes` and `multi_get` count as accesses,
+    /// and every key counts as accessed when the store is opened. Defaults to
+    /// no budget.
+    pub fn max_memory(mut self, bytes: u64) -> KvStoreOptions {
+        self.max_memory = Some(bytes);
+        self
+    }
+}
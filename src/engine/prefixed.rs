@@ -0,0 +1,231 @@
+use std::{
+    collections::HashMap,
+    ops::{Bound, RangeBounds},
+    time::Duration,
+};
+
+use crate::{BatchOp, EngineStats, KvEngine, Result, WriteBatch};
+
+/// An engine that keeps its keys under a namespace prefix of another engine.
+///
+/// Every key is stored as the prefix followed by the key, and scans only
+/// return the keys under the prefix, with the prefix stripped. Components
+/// given engines with different prefixes, none a prefix of another, can share
+/// one store without seeing each other's keys.
+#[derive(Clone)]
+pub struct PrefixedEngine<E: KvEngine> {
+    engine: E,
+    prefix: String,
+}
+
+impl<E: KvEngine> PrefixedEngine<E> {
+    /// Wraps `engine`, whose keys are then read and written under `prefix`.
+    pub fn new(engine: E, prefix: impl Into<String>) -> PrefixedEngine<E> {
+        PrefixedEngine {
+            engine,
+            prefix: prefix.into(),
+        }
+    }
+
+    /// Returns the prefix of the keys.
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Returns the wrapped engine.
+    pub fn engine(&self) -> &E {
+        &self.engine
+    }
+
+    /// Unwraps the engine.
+    pub fn into_inner(self) -> E {
+        self.engine
+    }
+
+    /// Returns the key of the wrapped engine under which `key` is stored.
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    /// Returns `batch` with the keys of the wrapped engine.
+    fn batch(&self, batch: WriteBatch) -> WriteBatch {
+        let mut prefixed = WriteBatch::new();
+        for op in batch {
+            match op {
+                BatchOp::Put(key, value) => prefixed.put(self.key(&key), value),
+                BatchOp::Delete(key) => prefixed.delete(self.key(&key)),
+            };
+        }
+        prefixed
+    }
+
+    fn map_bound(&self, bound: Bound<&String>) -> Bound<String> {
+        match bound {
+            Bound::Included(key) => Bound::Included(self.key(key)),
+            Bound::Excluded(key) => Bound::Excluded(self.key(key)),
+            Bound::Unbounded => Bound::Unbounded,
+        }
+    }
+}
+
+impl<E: KvEngine> KvEngine for PrefixedEngine<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.engine.set(self.key(&key), value)
+    }
+
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.engine.set_with_ttl(self.key(&key), value, ttl)
+    }
+
+    fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        self.engine.ttl(self.key(&key))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.engine.get(self.key(&key))
+    }
+
+    fn get_in_memory(&self, key: &str) -> Option<Result<Option<String>>> {
+        self.engine.get_in_memory(&self.key(key))
+    }
+
+    fn multi_get(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let keys = keys.iter().map(|key| self.key(key)).collect();
+        self.engine.multi_get(keys)
+    }
+
+    fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        self.engine.set_bytes(self.key(&key), value)
+    }
+
+    fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        self.engine.get_bytes(self.key(&key))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.engine.remove(self.key(&key))
+    }
+
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        self.engine.set_if_absent(self.key(&key), value)
+    }
+
+    fn set_if_present(&self, key: String, value: String) -> Result<bool> {
+        self.engine.set_if_present(self.key(&key), value)
+    }
+
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        self.engine.incr(self.key(&key), delta)
+    }
+
+    fn name(&self) -> &'static str {
+        self.engine.name()
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.engine.sync()
+    }
+
+    fn fsync(&self) -> Result<()> {
+        self.engine.fsync()
+    }
+
+    /// Compacts the whole wrapped engine, the keys of other prefixes too.
+    fn compact(&self) -> Result<()> {
+        self.engine.compact()
+    }
+
+    /// Returns the statistics of the wrapped engine, with the number of live
+    /// keys under the prefix.
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            keys: self.len()?,
+            ..self.engine.stats()?
+        })
+    }
+
+    /// Returns the number of live keys under the prefix, counted with a scan
+    /// of the keys.
+    fn len(&self) -> Result<usize> {
+        Ok(self.scan_keys(None, usize::MAX)?.len())
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.engine.write_batch(self.batch(batch))
+    }
+
+    fn write_batch_if(
+        &self,
+        reads: HashMap<String, Option<String>>,
+        batch: WriteBatch,
+    ) -> Result<()> {
+        let reads = reads
+            .into_iter()
+            .map(|(key, value)| (self.key(&key), value))
+            .collect();
+        self.engine.write_batch_if(reads, self.batch(batch))
+    }
+
+    /// Returns the key/value pairs under the prefix whose key falls in `range`.
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>> {
+        let start = match self.map_bound(range.start_bound()) {
+            Bound::Unbounded => Bound::Included(self.prefix.clone()),
+            bound => bound,
+        };
+        let end = match self.map_bound(range.end_bound()) {
+            Bound::Unbounded => prefix_end(&self.prefix).map_or(Bound::Unbounded, Bound::Excluded),
+            bound => bound,
+        };
+        let pairs = self.engine.scan((start, end))?;
+        Ok(pairs
+            .into_iter()
+            .map(|(key, value)| (key[self.prefix.len()..].to_owned(), value))
+            .collect())
+    }
+
+    /// Lists the keys under the prefix with the keys of the wrapped engine, so
+    /// the keys with a binary value are listed too.
+    fn scan_keys(&self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let after = match after {
+            Some(after) => self.key(&after),
+            None => {
+                // the empty key is the prefix itself, which the keys after it miss
+                if limit > 0 && self.engine.get_bytes(self.prefix.clone())?.is_some() {
+                    keys.push(String::new());
+                }
+                self.prefix.clone()
+            }
+        };
+        // the keys under the prefix follow each other
+        let page = self.engine.scan_keys(Some(after), limit - keys.len())?;
+        keys.extend(
+            page.into_iter()
+                .take_while(|key| key.starts_with(&self.prefix))
+                .map(|key| key[self.prefix.len()..].to_owned()),
+        );
+        Ok(keys)
+    }
+
+    /// Returns the bucket `name` of the wrapped engine, with its keys under the same prefix.
+    fn bucket(&self, name: &str) -> Result<Self> {
+        Ok(PrefixedEngine {
+            engine: self.engine.bucket(name)?,
+            prefix: self.prefix.clone(),
+        })
+    }
+}
+
+/// Returns the smallest string greater than every string starting with
+/// `prefix`, or `None` if there is no such string.
+fn prefix_end(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        // strings compare by their UTF-8 bytes, which order like the code points
+        if let Some(next) = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32) {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    None
+}
@@ -0,0 +1,286 @@
+use std::io::{self, BufRead, Read, Write};
+
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+
+use crate::Result;
+
+/// First byte of a bincode record, json records always start with `{`.
+const BINCODE_TAG: u8 = 0xB1;
+
+/// Length of the bincode record header: tag, payload length and CRC32.
+const BINCODE_HEADER_LEN: usize = 9;
+
+/// First byte of a compressed block of records.
+const BLOCK_TAG: u8 = 0xC1;
+
+/// Length of the block header: tag, uncompressed length, compressed length and CRC32.
+const BLOCK_HEADER_LEN: usize = 13;
+
+/// Magic bytes at the start of every log file, followed by its format version.
+const SEGMENT_MAGIC: &[u8; 4] = b"RKVL";
+
+/// Length of the header of a log file: magic bytes and format version.
+pub(super) const SEGMENT_HEADER_LEN: u64 = 8;
+
+/// Version of the layout of the log files written by this version of the store.
+///
+/// Files written before log files had a header are version 0.
+pub(super) const FORMAT_VERSION: u32 = 1;
+
+/// The encoding of the records appended to the log.
+///
+/// Records of both formats may be mixed in one log, so the format of
+/// a store can be changed without rewriting existing data.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Checksummed json records, readable with standard tools.
+    Json,
+    /// Length-prefixed, checksummed bincode records.
+    #[default]
+    Bincode,
+}
+
+impl LogFormat {
+    /// Returns the codec that writes records in this format.
+    pub(super) fn codec(self) -> &'static dyn LogCodec {
+        match self {
+            LogFormat::Json => &JsonCodec,
+            LogFormat::Bincode => &BincodeCodec,
+        }
+    }
+}
+
+/// Struct representing a command.
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) enum Command {
+    // set key value, with an optional expiration time in unix milliseconds
+    Set(String, String, #[serde(default)] Option<u64>),
+    // remove key
+    Remove(String),
+    // the following number of commands are written atomically
+    Batch(u64),
+    // set key to a binary value, with an optional expiration time in unix milliseconds
+    SetBytes(
+        String,
+        #[serde(with = "serde_bytes")] Vec<u8>,
+        #[serde(default)] Option<u64>,
+    ),
+    // set key to a value stored in a value log, with an optional expiration time in unix milliseconds
+    SetRef(String, ValuePointer, #[serde(default)] Option<u64>),
+}
+
+/// Position and length of a value stored in a value log.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct ValuePointer {
+    pub(super) file_id: u64,
+    pub(super) offset: u64,
+    pub(super) length: u64,
+}
+
+/// Outcome of reading a record from the log.
+pub(super) enum ReadRecord {
+    /// A valid command and the length of its record in bytes.
+    Command(Command, u64),
+    /// The decompressed records of a block, and the length of the block in bytes.
+    Block(Vec<u8>, u64),
+    /// The log ends here, possibly in the middle of a torn record.
+    End,
+    /// The record fails its checksum or cannot be decoded.
+    Corrupted,
+}
+
+/// Encoding of commands in the log, with the framing that delimits and checksums them.
+///
+/// The first byte of a record tells which codec wrote it, so records of
+/// every codec can be read back from one log.
+pub(super) trait LogCodec: Sync {
+    /// Returns the first byte of every record written by the codec.
+    fn tag(&self) -> u8;
+
+    /// Appends `cmd` with its checksum to `writer`.
+    fn encode(&self, writer: &mut dyn Write, cmd: &Command) -> Result<()>;
+
+    /// Reads the record at the current position of `reader`, which starts with the tag.
+    fn decode(&self, reader: &mut dyn BufRead) -> io::Result<ReadRecord>;
+}
+
+/// Codecs that records in the log may have been written with.
+const CODECS: [&dyn LogCodec; 2] = [&JsonCodec, &BincodeCodec];
+
+/// Reads the record at the current position of `reader`, whatever codec wrote it.
+pub(super) fn read_record(reader: &mut dyn BufRead) -> io::Result<ReadRecord> {
+    let tag = match reader.fill_buf()?.first() {
+        None => return Ok(ReadRecord::End),
+        Some(&BLOCK_TAG) => return read_block(reader),
+        Some(&tag) => tag,
+    };
+    match CODECS.iter().find(|codec| codec.tag() == tag) {
+        Some(codec) => codec.decode(reader),
+        None => Ok(ReadRecord::Corrupted),
+    }
+}
+
+/// Writes each command as a json object along with the CRC32 of its serialization.
+pub(super) struct JsonCodec;
+
+/// A command with the CRC32 of its json serialization, as written to the log.
+#[derive(Deserialize)]
+struct JsonRecord<'a> {
+    crc: u32,
+    #[serde(borrow)]
+    cmd: &'a RawValue,
+}
+
+impl LogCodec for JsonCodec {
+    fn tag(&self) -> u8 {
+        b'{'
+    }
+
+    fn encode(&self, writer: &mut dyn Write, cmd: &Command) -> Result<()> {
+        let cmd = serde_json::to_vec(cmd)?;
+        write!(writer, "{{\"crc\":{},\"cmd\":", crc32fast::hash(&cmd))?;
+        writer.write_all(&cmd)?;
+        writer.write_all(b"}")?;
+        Ok(())
+    }
+
+    fn decode(&self, reader: &mut dyn BufRead) -> io::Result<ReadRecord> {
+        // deserializing a single value does not read past its end
+        let mut de = serde_json::Deserializer::from_reader(reader);
+        let raw = match Box::<RawValue>::deserialize(&mut de) {
+            Ok(raw) => raw,
+            Err(err) if err.is_eof() => return Ok(ReadRecord::End),
+            Err(err) if err.is_io() => return Err(err.into()),
+            Err(_) => return Ok(ReadRecord::Corrupted),
+        };
+        let raw = raw.get();
+        Ok(match decode_json(raw) {
+            Some(cmd) => ReadRecord::Command(cmd, raw.len() as u64),
+            None => ReadRecord::Corrupted,
+        })
+    }
+}
+
+/// Decodes a json record, verifying its checksum.
+fn decode_json(raw: &str) -> Option<Command> {
+    if raw.starts_with("{\"crc\":") {
+        let record: JsonRecord = serde_json::from_str(raw).ok()?;
+        if crc32fast::hash(record.cmd.get().as_bytes()) != record.crc {
+            return None;
+        }
+        serde_json::from_str(record.cmd.get()).ok()
+    } else {
+        // written before records carried a checksum
+        serde_json::from_str(raw).ok()
+    }
+}
+
+/// Writes each command in bincode, after a header with its length and CRC32.
+pub(super) struct BincodeCodec;
+
+impl LogCodec for BincodeCodec {
+    fn tag(&self) -> u8 {
+        BINCODE_TAG
+    }
+
+    fn encode(&self, writer: &mut dyn Write, cmd: &Command) -> Result<()> {
+        let payload = bincode::serialize(cmd)?;
+        writer.write_all(&[BINCODE_TAG])?;
+        writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+        writer.write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+        writer.write_all(&payload)?;
+        Ok(())
+    }
+
+    fn decode(&self, reader: &mut dyn BufRead) -> io::Result<ReadRecord> {
+        let mut header = [0; BINCODE_HEADER_LEN];
+        if !read_full(reader, &mut header)? {
+            return Ok(ReadRecord::End);
+        }
+        let len = u32::from_le_bytes(header[1..5].try_into().unwrap()) as u64;
+        let crc = u32::from_le_bytes(header[5..9].try_into().unwrap());
+
+        // a corrupted length must not cause a huge allocation up front
+        let mut payload = Vec::new();
+        reader.take(len).read_to_end(&mut payload)?;
+        if (payload.len() as u64) < len {
+            return Ok(ReadRecord::End);
+        }
+        if crc32fast::hash(&payload) != crc {
+            return Ok(ReadRecord::Corrupted);
+        }
+        Ok(match bincode::deserialize(&payload) {
+            Ok(cmd) => ReadRecord::Command(cmd, BINCODE_HEADER_LEN as u64 + len),
+            Err(_) => ReadRecord::Corrupted,
+        })
+    }
+}
+
+/// Writes the header that starts a new log file.
+pub(super) fn write_segment_header<W: Write>(writer: &mut W) -> Result<()> {
+    writer.write_all(SEGMENT_MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    Ok(())
+}
+
+/// Reads the format version from the header at the start of `reader`.
+///
+/// An empty file has no version yet, and gets the current one.
+pub(super) fn read_segment_version<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut header = Vec::with_capacity(SEGMENT_HEADER_LEN as usize);
+    reader.take(SEGMENT_HEADER_LEN).read_to_end(&mut header)?;
+    Ok(match header.split_first_chunk::<4>() {
+        _ if header.is_empty() => FORMAT_VERSION,
+        Some((magic, version)) if magic == SEGMENT_MAGIC && version.len() == 4 => {
+            u32::from_le_bytes(version.try_into().unwrap())
+        }
+        // records start with their own tag, never with the magic bytes
+        _ => 0,
+    })
+}
+
+/// Appends the records in `block` compressed as one block.
+pub(super) fn write_block<W: Write>(writer: &mut W, block: &[u8]) -> Result<()> {
+    let compressed = lz4_flex::compress(block);
+    writer.write_all(&[BLOCK_TAG])?;
+    writer.write_all(&(block.len() as u32).to_le_bytes())?;
+    writer.write_all(&(compressed.len() as u32).to_le_bytes())?;
+    writer.write_all(&crc32fast::hash(&compressed).to_le_bytes())?;
+    writer.write_all(&compressed)?;
+    Ok(())
+}
+
+fn read_block<R: BufRead + ?Sized>(reader: &mut R) -> io::Result<ReadRecord> {
+    let mut header = [0; BLOCK_HEADER_LEN];
+    if !read_full(reader, &mut header)? {
+        return Ok(ReadRecord::End);
+    }
+    let raw_len = u32::from_le_bytes(header[1..5].try_into().unwrap()) as usize;
+    let len = u32::from_le_bytes(header[5..9].try_into().unwrap()) as u64;
+    let crc = u32::from_le_bytes(header[9..13].try_into().unwrap());
+
+    let mut compressed = Vec::new();
+    (&mut *reader).take(len).read_to_end(&mut compressed)?;
+    if (compressed.len() as u64) < len {
+        return Ok(ReadRecord::End);
+    }
+    if crc32fast::hash(&compressed) != crc {
+        return Ok(ReadRecord::Corrupted);
+    }
+    Ok(match lz4_flex::decompress(&compressed, raw_len) {
+        Ok(block) if block.len() == raw_len => {
+            ReadRecord::Block(block, BLOCK_HEADER_LEN as u64 + len)
+        }
+        _ => ReadRecord::Corrupted,
+    })
+}
+
+/// Fills `buf` completely, returns `false` if the reader ends before.
+fn read_full<R: Read + ?Sized>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
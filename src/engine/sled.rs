@@ -1,41 +1,417 @@
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    ops::{Bound, RangeBounds},
+    path::PathBuf,
+    sync::{
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
 
-use crate::{KvEngine, KvError, Result};
-use sled::Db;
+use log::error;
+
+use super::engine::{check_bucket_name, expire_at, incr_value, is_expired, now_millis};
+use super::options::FlushMode;
+use crate::{BatchOp, EngineStats, KvEngine, KvError, Result, WriteBatch};
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionError},
+    Db, Transactional, Tree,
+};
+
+/// Name of the tree holding the expiration time of keys set with a ttl.
+///
+/// The expirations of the keys of a named tree are in the tree of this name
+/// followed by `/` and the tree name.
+const EXPIRATIONS_TREE: &str = "__rust_kv_expirations";
+
+/// Prefix of the tree names used by sled and by this store.
+const RESERVED_TREE_PREFIX: &str = "__";
 
 /// Sled KV storage engine
 #[derive(Clone)]
 pub struct SledStore {
-    db: Db,
+    // the default tree of the db, or the named tree of `open_tree`
+    tree: Tree,
+    // key -> expiration time in unix milliseconds (big endian)
+    expirations: Tree,
+    // shared by all clones, flushes once more when the last one is dropped
+    flusher: Arc<Flusher>,
 }
 
 impl SledStore {
+    /// Opens a `SledStore` with the given dir_path, flushing after every write.
     pub fn open(dir_path: impl Into<PathBuf>) -> Result<SledStore> {
+        Self::open_with(dir_path, FlushMode::default())
+    }
+
+    /// Opens a `SledStore` with the given dir_path, flushing as set by `flush_mode`.
+    pub fn open_with(dir_path: impl Into<PathBuf>, flush_mode: FlushMode) -> Result<SledStore> {
+        let db = sled::open(dir_path.into())?;
+        let expirations = db.open_tree(EXPIRATIONS_TREE)?;
+        let flusher = Arc::new(Flusher::new(db.clone(), flush_mode)?);
+        Ok(SledStore {
+            tree: Tree::clone(&db),
+            expirations,
+            flusher,
+        })
+    }
+
+    /// Returns a `SledStore` bound to the tree `name` of the same sled db.
+    ///
+    /// The tree is created if it does not exist, and its keys are apart from
+    /// those of every other tree. Trees are not nested: opening a tree from a
+    /// handle to another tree opens it in the db all the same. Names follow
+    /// the rules of bucket names, and names starting with `__` are reserved.
+    pub fn open_tree(&self, name: &str) -> Result<SledStore> {
+        check_bucket_name(name)?;
+        if name.starts_with(RESERVED_TREE_PREFIX) {
+            return Err(KvError::InvalidBucketName(name.to_owned()));
+        }
+        let db = &self.flusher.db;
         Ok(SledStore {
-            db: sled::open(dir_path.into())?,
+            tree: db.open_tree(name)?,
+            expirations: db.open_tree(format!("{}/{}", EXPIRATIONS_TREE, name))?,
+            flusher: self.flusher.clone(),
         })
     }
+
+    /// Flushes the writes to the disk if the flush mode asks for it after every write.
+    fn flush(&self) -> Result<()> {
+        if self.flusher.mode == FlushMode::EveryOp {
+            self.flusher.db.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the expiration time of a given key, if it has one.
+    fn expire_at(&self, key: &str) -> Result<Option<u64>> {
+        Ok(self
+            .expirations
+            .get(key)?
+            .map(|ivec| decode_expire_at(&ivec)))
+    }
+
+    fn is_expired(&self, key: &str) -> Result<bool> {
+        Ok(self.expire_at(key)?.is_some_and(is_expired))
+    }
+
+    /// Writes `value` and its expiration time in one transaction.
+    fn write(&self, key: &str, value: &[u8], expire_at: Option<u64>) -> Result<()> {
+        (&self.tree, &self.expirations)
+            .transaction(|(db, expirations)| {
+                db.insert(key.as_bytes(), value)?;
+                match expire_at {
+                    Some(expire_at) => {
+                        expirations.insert(key.as_bytes(), &expire_at.to_be_bytes())?
+                    }
+                    None => expirations.remove(key.as_bytes())?,
+                };
+                Ok(())
+            })
+            .map_err(|err: TransactionError| match err {
+                TransactionError::Abort(err) | TransactionError::Storage(err) => KvError::Sled(err),
+            })?;
+        self.flush()?;
+        Ok(())
+    }
+
+    /// Sets `key` to `value` if whether the key exists matches `present`.
+    fn set_if(&self, key: String, value: String, present: bool) -> Result<bool> {
+        let set = (&self.tree, &self.expirations)
+            .transaction(|(db, expirations)| {
+                let expired = expirations
+                    .get(key.as_bytes())?
+                    .is_some_and(|ivec| is_expired(decode_expire_at(&ivec)));
+                let exists = !expired && db.get(key.as_bytes())?.is_some();
+                if exists != present {
+                    return Ok(false);
+                }
+                db.insert(key.as_bytes(), value.as_bytes())?;
+                expirations.remove(key.as_bytes())?;
+                Ok(true)
+            })
+            .map_err(|err: TransactionError| match err {
+                TransactionError::Abort(err) | TransactionError::Storage(err) => KvError::Sled(err),
+            })?;
+        self.flush()?;
+        Ok(set)
+    }
 }
 
 impl KvEngine for SledStore {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.db.insert(key.as_str(), value.as_str())?;
-        self.db.flush()?;
-        Ok(())
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.write(&key, value.as_bytes(), None)
+    }
+
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.write(&key, value.as_bytes(), Some(expire_at(ttl)))
+    }
+
+    fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        self.write(&key, &value, None)
     }
 
-    fn get(&mut self, key: String) -> Result<Option<String>> {
+    fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        if self.is_expired(&key)? {
+            return Ok(None);
+        }
+        Ok(self.tree.get(key.as_str())?.map(|ivec| ivec.to_vec()))
+    }
+
+    fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        if !self.tree.contains_key(key.as_str())? || self.is_expired(&key)? {
+            return Err(KvError::KeyNotFound);
+        }
+        Ok(self
+            .expire_at(&key)?
+            .map(|expire_at| Duration::from_millis(expire_at.saturating_sub(now_millis()))))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        if self.is_expired(&key)? {
+            return Ok(None);
+        }
         let value = self
-            .db
+            .tree
             .get(key.as_str())?
             .map(|ivec| String::from_utf8(ivec.to_vec()))
             .transpose()?;
         Ok(value)
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        self.db.remove(&key)?.ok_or(KvError::KeyNotFound)?;
-        self.db.flush()?;
+    fn remove(&self, key: String) -> Result<()> {
+        let expired = self.is_expired(&key)?;
+        self.expirations.remove(key.as_str())?;
+        self.tree.remove(&key)?.ok_or(KvError::KeyNotFound)?;
+        self.flush()?;
+        if expired {
+            return Err(KvError::KeyNotFound);
+        }
         Ok(())
     }
+
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        self.set_if(key, value, false)
+    }
+
+    fn set_if_present(&self, key: String, value: String) -> Result<bool> {
+        self.set_if(key, value, true)
+    }
+
+    fn incr(&self, key: String, delta: i64) -> Result<i64> {
+        let value = (&self.tree, &self.expirations)
+            .transaction(|(db, expirations)| {
+                let expired = expirations
+                    .get(key.as_bytes())?
+                    .is_some_and(|ivec| is_expired(decode_expire_at(&ivec)));
+                let current = if expired {
+                    expirations.remove(key.as_bytes())?;
+                    None
+                } else {
+                    db.get(key.as_bytes())?
+                };
+                let value = incr_value(current.as_deref(), delta)
+                    .map_err(ConflictableTransactionError::Abort)?;
+                db.insert(key.as_bytes(), value.to_string().as_bytes())?;
+                Ok(value)
+            })
+            .map_err(|err| match err {
+                TransactionError::Abort(err) => err,
+                TransactionError::Storage(err) => KvError::Sled(err),
+            })?;
+        self.flush()?;
+        Ok(value)
+    }
+
+    fn bucket(&self, name: &str) -> Result<SledStore> {
+        self.open_tree(name)
+    }
+
+    fn len(&self) -> Result<usize> {
+        // expired keys are only dropped when removed, so leave them out of the count
+        let mut expired = 0;
+        for pair in self.expirations.iter() {
+            let (_, ivec) = pair?;
+            if is_expired(decode_expire_at(&ivec)) {
+                expired += 1;
+            }
+        }
+        Ok(self.tree.len().saturating_sub(expired))
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.write_batch_if(HashMap::new(), batch)
+    }
+
+    fn write_batch_if(
+        &self,
+        reads: HashMap<String, Option<String>>,
+        batch: WriteBatch,
+    ) -> Result<()> {
+        (&self.tree, &self.expirations)
+            .transaction(|(db, expirations)| {
+                for (key, read) in &reads {
+                    let expired = expirations
+                        .get(key.as_bytes())?
+                        .is_some_and(|ivec| is_expired(decode_expire_at(&ivec)));
+                    let value = if expired {
+                        None
+                    } else {
+                        db.get(key.as_bytes())?
+                    };
+                    if value.as_deref() != read.as_ref().map(String::as_bytes) {
+                        return Err(ConflictableTransactionError::Abort(
+                            KvError::TransactionConflict,
+                        ));
+                    }
+                }
+                for op in &batch {
+                    match op {
+                        BatchOp::Put(key, value) => {
+                            db.insert(key.as_bytes(), value.as_bytes())?;
+                            expirations.remove(key.as_bytes())?;
+                        }
+                        BatchOp::Delete(key) => {
+                            let expired = expirations
+                                .remove(key.as_bytes())?
+                                .is_some_and(|ivec| is_expired(decode_expire_at(&ivec)));
+                            if db.remove(key.as_bytes())?.is_none() || expired {
+                                return Err(ConflictableTransactionError::Abort(
+                                    KvError::KeyNotFound,
+                                ));
+                            }
+                        }
+                    }
+                }
+                Ok(())
+            })
+            .map_err(|err| match err {
+                TransactionError::Abort(err) => err,
+                TransactionError::Storage(err) => KvError::Sled(err),
+            })?;
+        self.flush()?;
+        Ok(())
+    }
+
+    /// Flushes the writes to the disk, whatever the flush mode.
+    fn fsync(&self) -> Result<()> {
+        self.flusher.db.flush()?;
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        "sled"
+    }
+
+    /// Returns the number of live keys and the size of the database on the disk.
+    ///
+    /// Sled reclaims space on its own, so the stale size is not tracked.
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            keys: self.len()?,
+            disk_size: Some(self.flusher.db.size_on_disk()?),
+            stale_size: None,
+            memory_size: None,
+        })
+    }
+
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>> {
+        let bounds = (
+            range.start_bound().map(String::as_bytes),
+            range.end_bound().map(String::as_bytes),
+        );
+        let mut pairs = Vec::new();
+        for pair in self.tree.range::<&[u8], _>(bounds) {
+            let (key, value) = pair?;
+            let key = String::from_utf8(key.to_vec())?;
+            if self.is_expired(&key)? {
+                continue;
+            }
+            // a binary value is skipped
+            if let Ok(value) = String::from_utf8(value.to_vec()) {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Lists the keys without reading their values, so the keys with a binary
+    /// value are listed too.
+    fn scan_keys(&self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        let start = after.map_or(Bound::Unbounded, |after| {
+            Bound::Excluded(after.into_bytes())
+        });
+        let mut keys = Vec::new();
+        for key in self
+            .tree
+            .range::<Vec<u8>, _>((start, Bound::Unbounded))
+            .keys()
+        {
+            if keys.len() == limit {
+                break;
+            }
+            let key = String::from_utf8(key?.to_vec())?;
+            if !self.is_expired(&key)? {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+}
+
+/// Flushes a db in the background or on drop, as set by its `FlushMode`.
+struct Flusher {
+    db: Db,
+    mode: FlushMode,
+    // dropping it stops the background flusher
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Flusher {
+    fn new(db: Db, mode: FlushMode) -> Result<Flusher> {
+        let (stop, handle) = match mode {
+            FlushMode::Interval(interval) => {
+                let (stop, stopped) = mpsc::channel::<()>();
+                let db = db.clone();
+                let handle = thread::Builder::new()
+                    .name("sled-flusher".to_owned())
+                    .spawn(move || {
+                        while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                            if let Err(err) = db.flush() {
+                                error!("failed to flush sled: {}", err);
+                            }
+                        }
+                    })?;
+                (Some(stop), Some(handle))
+            }
+            FlushMode::EveryOp | FlushMode::OnDrop => (None, None),
+        };
+        Ok(Flusher {
+            db,
+            mode,
+            stop,
+            handle,
+        })
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+        if let Err(err) = self.db.flush() {
+            error!("failed to flush sled: {}", err);
+        }
+    }
+}
+
+fn decode_expire_at(bytes: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(bytes);
+    u64::from_be_bytes(buf)
 }
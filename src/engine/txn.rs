@@ -0,0 +1,79 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{KvEngine, KvError, KvStore, Result, WriteBatch};
+
+/// A transaction over a `KvStore`, created by `KvStore::transaction`.
+///
+/// Writes are buffered in the transaction and committed atomically.
+/// The commit fails with `KvError::TransactionConflict` if any key read by
+/// the transaction has been changed by another writer in the meantime.
+pub struct Txn<'a> {
+    store: &'a KvStore,
+    // key -> value observed by the first read of the key
+    reads: HashMap<String, Option<String>>,
+    // key -> buffered value, `None` for a removal
+    writes: BTreeMap<String, Option<String>>,
+}
+
+impl<'a> Txn<'a> {
+    pub(super) fn new(store: &'a KvStore) -> Txn<'a> {
+        Txn {
+            store,
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Sees the writes buffered by this transaction.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        self.read(key)
+    }
+
+    /// Gets the value of a key in the store, as first read by this transaction.
+    fn read(&mut self, key: String) -> Result<Option<String>> {
+        if let Some(value) = self.reads.get(&key) {
+            return Ok(value.clone());
+        }
+        let value = self.store.get(key.clone())?;
+        self.reads.insert(key, value.clone());
+        Ok(value)
+    }
+
+    /// Sets the value of a string key to a string.
+    pub fn set(&mut self, key: String, value: String) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Removes a given key.
+    ///
+    /// Returns `KvError::KeyNotFound` if the given key is not found.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        if self.get(key.clone())?.is_none() {
+            return Err(KvError::KeyNotFound);
+        }
+        // a key only this transaction set is not in the store to remove
+        if self.read(key.clone())?.is_none() {
+            self.writes.remove(&key);
+        } else {
+            self.writes.insert(key, None);
+        }
+        Ok(())
+    }
+
+    /// Validates the reads and writes the buffered writes atomically.
+    pub(super) fn commit(self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes {
+            match value {
+                Some(value) => batch.put(key, value),
+                None => batch.delete(key),
+            };
+        }
+        self.store.commit(self.reads, batch)
+    }
+}
@@ -0,0 +1,100 @@
+use std::{marker::PhantomData, ops::RangeBounds, time::Duration};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{KvEngine, Result};
+
+/// A store of values of type `V` on top of any engine.
+///
+/// Values are stored as JSON strings, so they stay readable through the
+/// engine itself and through `export`. Reading a value that is not valid JSON
+/// for `V` fails with `KvError::Serde`.
+pub struct TypedStore<E: KvEngine, V> {
+    engine: E,
+    _value: PhantomData<fn() -> V>,
+}
+
+impl<E: KvEngine, V> Clone for TypedStore<E, V> {
+    fn clone(&self) -> Self {
+        TypedStore {
+            engine: self.engine.clone(),
+            _value: PhantomData,
+        }
+    }
+}
+
+impl<E: KvEngine, V: Serialize + DeserializeOwned> TypedStore<E, V> {
+    /// Wraps `engine`, whose keys are then read and written as `V` values.
+    pub fn new(engine: E) -> TypedStore<E, V> {
+        TypedStore {
+            engine,
+            _value: PhantomData,
+        }
+    }
+
+    /// Returns the wrapped engine.
+    pub fn engine(&self) -> &E {
+        &self.engine
+    }
+
+    /// Unwraps the engine.
+    pub fn into_inner(self) -> E {
+        self.engine
+    }
+
+    /// Sets the value of a key.
+    pub fn set(&self, key: String, value: &V) -> Result<()> {
+        self.engine.set(key, serde_json::to_string(value)?)
+    }
+
+    /// Sets the value of a key, which expires after `ttl`.
+    pub fn set_with_ttl(&self, key: String, value: &V, ttl: Duration) -> Result<()> {
+        self.engine
+            .set_with_ttl(key, serde_json::to_string(value)?, ttl)
+    }
+
+    /// Sets the value of a key only if it does not exist, and returns whether it was set.
+    pub fn set_if_absent(&self, key: String, value: &V) -> Result<bool> {
+        self.engine
+            .set_if_absent(key, serde_json::to_string(value)?)
+    }
+
+    /// Gets the value of a key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    pub fn get(&self, key: String) -> Result<Option<V>> {
+        self.engine
+            .get(key)?
+            .map(|value| decode(&value))
+            .transpose()
+    }
+
+    /// Gets the values of many keys, in the order of `keys`.
+    pub fn multi_get(&self, keys: Vec<String>) -> Result<Vec<Option<V>>> {
+        self.engine
+            .multi_get(keys)?
+            .into_iter()
+            .map(|value| value.map(|value| decode(&value)).transpose())
+            .collect()
+    }
+
+    /// Removes a given key.
+    ///
+    /// Returns `KvError::KeyNotFound` if the given key is not found.
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.engine.remove(key)
+    }
+
+    /// Returns the key/value pairs whose key falls in `range`, ordered by key.
+    pub fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, V)>> {
+        self.engine
+            .scan(range)?
+            .into_iter()
+            .map(|(key, value)| Ok((key, decode(&value)?)))
+            .collect()
+    }
+}
+
+fn decode<V: DeserializeOwned>(value: &str) -> Result<V> {
+    Ok(serde_json::from_str(value)?)
+}
@@ -1,73 +1,202 @@
-use std::{io, string};
+use std::{io, string, time::Duration};
 
-use failure::Fail;
+use thiserror::Error;
 
 /// Result type for kvs.
 pub type Result<T> = std::result::Result<T, KvError>;
 
 /// Error type for kvs.
-#[derive(Fail, Debug)]
+#[derive(Error, Debug)]
 pub enum KvError {
     /// IO error.
-    #[fail(display = "{}", _0)]
-    Io(#[cause] io::Error),
+    #[error("{0}")]
+    Io(#[from] io::Error),
 
     /// Serialization or deserialization error.
-    #[fail(display = "{}", _0)]
-    Serde(#[cause] serde_json::Error),
+    #[error("{0}")]
+    Serde(#[from] serde_json::Error),
+
+    /// Binary serialization or deserialization error.
+    #[error("{0}")]
+    Bincode(#[from] bincode::Error),
 
     /// Removing non-existent key error.
-    #[fail(display = "Key not found")]
+    #[error("Key not found")]
     KeyNotFound,
 
+    /// The value is not a 64-bit integer, or the arithmetic overflowed.
+    #[error("Value is not an integer or out of range")]
+    NotAnInteger,
+
+    /// The store reached its maximum disk size, even after compaction.
+    /// Removing keys frees space again.
+    #[error("Disk quota exceeded")]
+    QuotaExceeded,
+
+    /// The store directory is used by another open store, in this process or another.
+    #[error("Directory {0} is locked by another store")]
+    AlreadyLocked(String),
+
+    /// Bucket names must be 1 to 64 ASCII letters, digits, `-` or `_`.
+    #[error("Invalid bucket name: {0}")]
+    InvalidBucketName(String),
+
+    /// The engine does not support the operation.
+    #[error("Unsupported operation: {0}")]
+    Unsupported(String),
+
+    /// A key read by a transaction was changed by another writer
+    /// before the transaction committed. The transaction can be retried.
+    #[error("Transaction conflict")]
+    TransactionConflict,
+
+    /// A record in the log failed its checksum or could not be decoded.
+    #[error("Corrupted record in log {file_id} at offset {offset}")]
+    Corruption {
+        /// Id of the log file holding the record.
+        file_id: u64,
+        /// Offset of the record in the log file.
+        offset: u64,
+    },
+
+    /// Reading a record from a log file failed, like when the disk fails.
+    #[error("Failed to read log {file_id} at offset {offset}: {source}")]
+    Storage {
+        /// Id of the log file holding the record.
+        file_id: u64,
+        /// Offset of the record in the log file.
+        offset: u64,
+        /// The error of the read.
+        #[source]
+        source: io::Error,
+    },
+
+    /// A log file has a format version this version of the store cannot read.
+    /// It was written by a newer version.
+    #[error("Unsupported format version {1} of log file {0}")]
+    UnsupportedVersion(String, u32),
+
+    /// A TLS certificate, key or configuration is invalid.
+    #[error("Invalid TLS configuration: {0}")]
+    Tls(String),
+
+    /// A request is larger than the maximum frame size of the server, or of
+    /// the client sending it.
+    #[error("Request larger than the maximum frame size of {0} bytes")]
+    FrameTooLarge(usize),
+
+    /// A response is larger than the maximum frame size of the client.
+    #[error("Response larger than the maximum frame size of {0} bytes")]
+    ResponseTooLarge(usize),
+
+    /// The authorization hook of the server refused the request.
+    #[error("Permission denied")]
+    PermissionDenied,
+
+    /// A read-only server, like a replica, refused a write.
+    #[error("Server is read-only")]
+    ReadOnly,
+
+    /// A sharded client has no shard to send a request to.
+    #[error("No shard in the hash ring")]
+    NoShards,
+
+    /// A client sent input that does not follow the protocol.
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+
+    /// A request did not complete within the request timeout of the server,
+    /// or of the client.
+    #[error("Request timed out after {0:?}")]
+    RequestTimeout(Duration),
+
+    /// The server had no room left to queue the request, which may be retried later.
+    #[error("Server is busy")]
+    Busy,
+
+    /// The server is shutting down, so it no longer reads the requests of the
+    /// connection. The request may be retried on another server.
+    #[error("Server is shutting down")]
+    ShuttingDown,
+
+    /// The circuit breaker of the client is open after too many failures in
+    /// a row, so the request failed without being sent. It may be retried
+    /// once the cooldown passed.
+    #[error("Circuit breaker open")]
+    CircuitOpen,
+
+    /// An operation failed, with the operation and its key for context, like
+    /// `set key 'user:42' failed: No space left on device`.
+    ///
+    /// Only errors which do not tell what failed on their own are wrapped, see
+    /// `KvError::with_op`.
+    #[error("{op}{} failed: {source}", key.as_ref().map(|key| format!(" key '{}'", key)).unwrap_or_default())]
+    Op {
+        /// The name of the operation, like `set`.
+        op: &'static str,
+        /// The key of the operation, if it has one.
+        key: Option<String>,
+        /// The error of the operation.
+        #[source]
+        source: Box<KvError>,
+    },
+
     /// Unexpected command type error in log.
     /// It indicated a corrupted log or a program bug.
-    #[fail(display = "Unexpected command type")]
+    #[error("Unexpected command type")]
     UnexpectedCommandType,
 
+    /// Unexpected response type from the server.
+    /// It indicated a protocol mismatch between client and server.
+    #[error("Unexpected response type")]
+    UnexpectedResponse,
+
     /// Error with a string message
-    #[fail(display = "{}", _0)]
+    #[error("{0}")]
     StringError(String),
 
     /// Sled store error.
-    #[fail(display = "{}", _0)]
-    Sled(#[cause] sled::Error),
+    #[error("{0}")]
+    Sled(#[from] sled::Error),
 
     /// Key or value is invalid UTF-8 sequence
-    #[fail(display = "{}", _0)]
-    Utf8(#[cause] string::FromUtf8Error),
+    #[error("{0}")]
+    Utf8(#[from] string::FromUtf8Error),
 
     /// rayon ThreadPool build error
-    #[fail(display = "{}", _0)]
-    ThreadPool(#[cause] rayon::ThreadPoolBuildError),
-}
-
-impl From<io::Error> for KvError {
-    fn from(error: io::Error) -> Self {
-        KvError::Io(error)
-    }
-}
-
-impl From<serde_json::Error> for KvError {
-    fn from(error: serde_json::Error) -> Self {
-        KvError::Serde(error)
-    }
+    #[error("{0}")]
+    ThreadPool(#[from] rayon::ThreadPoolBuildError),
 }
 
-impl From<sled::Error> for KvError {
-    fn from(error: sled::Error) -> Self {
-        KvError::Sled(error)
+impl KvError {
+    /// Wraps the error with the operation `op` which failed, and its key if any.
+    ///
+    /// Errors which already tell what went wrong, like `KeyNotFound` or
+    /// `TransactionConflict`, are returned as they are, so they can still be
+    /// matched, and an error is never wrapped twice.
+    pub fn with_op(self, op: &'static str, key: Option<&str>) -> KvError {
+        match self {
+            KvError::Io(_)
+            | KvError::Serde(_)
+            | KvError::Bincode(_)
+            | KvError::Sled(_)
+            | KvError::Utf8(_)
+            | KvError::Corruption { .. }
+            | KvError::Storage { .. }
+            | KvError::UnexpectedCommandType => KvError::Op {
+                op,
+                key: key.map(str::to_owned),
+                source: Box::new(self),
+            },
+            err => err,
+        }
     }
-}
-
-impl From<string::FromUtf8Error> for KvError {
-    fn from(error: string::FromUtf8Error) -> Self {
-        KvError::Utf8(error)
-    }
-}
 
-impl From<rayon::ThreadPoolBuildError> for KvError {
-    fn from(error: rayon::ThreadPoolBuildError) -> Self {
-        KvError::ThreadPool(error)
+    /// Returns the error without the context of `with_op`.
+    pub fn root(&self) -> &KvError {
+        match self {
+            KvError::Op { source, .. } => source.root(),
+            err => err,
+        }
     }
 }
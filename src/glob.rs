@@ -0,0 +1,70 @@
+//! Glob-style patterns matching keys, like the patterns of Redis.
+
+/// Returns whether `key` matches `pattern`.
+///
+/// In a pattern, `*` matches any characters, `?` any one character, `[abc]`
+/// or `[a-z]` one character of the set, `[^abc]` one character out of it,
+/// and `\` makes the next character match itself.
+pub(crate) fn matches(pattern: &str, key: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let key: Vec<char> = key.chars().collect();
+    let (mut p, mut k) = (0, 0);
+    // the pattern after the last `*`, and the key from which it is retried
+    let mut star = None;
+    while k < key.len() {
+        if pattern.get(p) == Some(&'*') {
+            p += 1;
+            star = Some((p, k));
+            continue;
+        }
+        if let Some(len) = match_char(&pattern[p..], key[k]) {
+            p += len;
+            k += 1;
+            continue;
+        }
+        // the last `*` takes one more character
+        match star {
+            Some((star_p, star_k)) => {
+                p = star_p;
+                k = star_k + 1;
+                star = Some((star_p, k));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Returns the length of the token starting `pattern` if it matches `c`.
+fn match_char(pattern: &[char], c: char) -> Option<usize> {
+    match *pattern.first()? {
+        '?' => Some(1),
+        '\\' => (*pattern.get(1)? == c).then_some(2),
+        '[' => {
+            let negated = pattern.get(1) == Some(&'^');
+            let mut i = if negated { 2 } else { 1 };
+            let mut found = false;
+            loop {
+                match *pattern.get(i)? {
+                    ']' => break,
+                    '\\' => {
+                        found |= *pattern.get(i + 1)? == c;
+                        i += 2;
+                    }
+                    low if pattern.get(i + 1) == Some(&'-')
+                        && pattern.get(i + 2).is_some_and(|&high| high != ']') =>
+                    {
+                        found |= (low..=pattern[i + 2]).contains(&c);
+                        i += 3;
+                    }
+                    other => {
+                        found |= other == c;
+                        i += 1;
+                    }
+                }
+            }
+            (found != negated).then_some(i + 1)
+        }
+        other => (other == c).then_some(1),
+    }
+}
@@ -1,15 +1,44 @@
 //! A simple key/value store.
 
+mod change_log;
 mod client;
+mod client_cache;
+mod clients;
+mod codec;
 mod common;
+mod connection;
 mod engine;
 mod error;
+mod glob;
+mod monitor;
+mod pubsub;
+mod replication;
+mod resp;
 mod server;
+mod sharded;
 mod thread_pool;
+mod tls;
+mod watch;
 
-pub use client::KvClient;
-pub use common::{Request, Response};
-pub use engine::{KvEngine, KvStore, SledStore};
+pub use client::{CircuitState, KvClient, KvClientBuilder, Pipeline, RequestEvent, Transaction};
+pub use codec::Codec;
+pub use common::{
+    AdminCommand, BulkFrame, ChangeEvent, ClientInfo, LatencyHistogram, LoadSummary, MonitorEvent,
+    OpStats, Request, RequestFrame, Response, ResponseFrame, ServerCounts, ServerInfo, WatchEvent,
+};
+pub use engine::{
+    BatchOp, ChangeKind, Compression, EngineStats, FlushMode, KvEngine, KvSnapshot, KvStore,
+    KvStoreOptions, LogFormat, MemStore, PrefixedEngine, SledStore, SyncPolicy, Txn, TypedStore,
+    WriteBatch,
+};
 pub use error::{KvError, Result};
-pub use server::KvServer;
-pub use thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+pub use replication::{Replica, ReplicaHandle};
+pub use server::{
+    KvServer, KvServerBuilder, NamespaceMetrics, ReloadHandle, RunningServer, ServerMetrics,
+    ServerTask, ShutdownHandle,
+};
+pub use sharded::{HashRing, KeyMove, ShardedKvClient};
+pub use thread_pool::{
+    JobHandle, NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool,
+};
+pub use tls::{client_tls_config, server_tls_config, ClientIdentity};
@@ -0,0 +1,131 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::mpsc::{self, error::TrySendError},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+use crate::{
+    replication::{forward_messages, write_response},
+    Codec, MonitorEvent, Response, Result,
+};
+
+/// Events buffered for a monitor before the next ones are dropped.
+const MONITOR_BUFFER: usize = 1024;
+
+/// The monitors of a server, the connections it sends an event to for each
+/// request it reads.
+pub(crate) struct Monitors {
+    // the events sent to each monitor a second at most
+    rate: u32,
+    monitors: Mutex<Vec<Monitor>>,
+    // the number of monitors, so the requests skip the lock when there are none
+    count: AtomicUsize,
+}
+
+/// A monitor, with the events sent to it in the current second.
+struct Monitor {
+    tx: mpsc::Sender<MonitorEvent>,
+    window: Instant,
+    sent: u32,
+    // the events dropped since the last one sent
+    dropped: u64,
+}
+
+impl Monitors {
+    pub(crate) fn new(rate: u32) -> Monitors {
+        Monitors {
+            rate: rate.max(1),
+            monitors: Mutex::default(),
+            count: AtomicUsize::new(0),
+        }
+    }
+
+    /// Sends the request of operation `op` on `key` from `addr` to the monitors.
+    ///
+    /// A monitor over its rate, or whose buffer is full, misses the event and
+    /// learns of it with the next one, so a monitor does not hold up the
+    /// server.
+    pub(crate) fn record(&self, addr: &impl fmt::Display, op: &str, key: Option<&str>) {
+        if self.count.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let time = SystemTime::now();
+        let addr = addr.to_string();
+        let mut monitors = self.monitors.lock().unwrap();
+        // the monitors disconnected since are forgotten
+        monitors.retain_mut(|monitor| {
+            if now.duration_since(monitor.window) >= Duration::from_secs(1) {
+                monitor.window = now;
+                monitor.sent = 0;
+            }
+            if monitor.sent == self.rate {
+                monitor.dropped += 1;
+                return true;
+            }
+            let event = MonitorEvent {
+                time,
+                addr: addr.clone(),
+                op: op.to_owned(),
+                key: key.map(str::to_owned),
+                dropped: monitor.dropped,
+            };
+            match monitor.tx.try_send(event) {
+                Ok(()) => {
+                    monitor.sent += 1;
+                    monitor.dropped = 0;
+                }
+                Err(TrySendError::Full(_)) => monitor.dropped += 1,
+                Err(TrySendError::Closed(_)) => return false,
+            }
+            true
+        });
+        self.count.store(monitors.len(), Ordering::Relaxed);
+    }
+
+    /// Adds a monitor, returning the events sent to it.
+    fn add(&self) -> mpsc::Receiver<MonitorEvent> {
+        let (tx, rx) = mpsc::channel(MONITOR_BUFFER);
+        let mut monitors = self.monitors.lock().unwrap();
+        monitors.push(Monitor {
+            tx,
+            window: Instant::now(),
+            sent: 0,
+            dropped: 0,
+        });
+        self.count.store(monitors.len(), Ordering::Relaxed);
+        rx
+    }
+}
+
+/// Streams the requests the server reads to a client connected over
+/// `stream`, whose request numbered `id` asked for them, until the server
+/// stops.
+pub(crate) async fn serve_monitor<S>(
+    monitors: &Monitors,
+    mut stream: S,
+    codec: Codec,
+    id: u64,
+    stop: CancellationToken,
+) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let rx = monitors.add();
+    write_response(&mut stream, codec, id, Response::Ok(None)).await?;
+    info!("monitoring the requests of the server");
+    forward_messages(&mut stream, codec, rx, &stop).await?;
+    stream.shutdown().await?;
+    info!("monitor disconnected");
+    Ok(())
+}
@@ -0,0 +1,82 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::warn;
+
+/// Messages buffered for a connection before the next ones are dropped.
+const SUBSCRIBER_BUFFER: usize = 1024;
+
+/// A message published on a channel: the channel, then the message.
+pub(crate) type Message = (String, String);
+
+/// The channels of a server, fanning out each message published on a
+/// channel to the connections subscribed to it.
+#[derive(Default)]
+pub(crate) struct Broker {
+    channels: Mutex<HashMap<String, Vec<mpsc::Sender<Message>>>>,
+}
+
+impl Broker {
+    /// Subscribes `subscription` to `channel`, returning whether it was not yet.
+    pub(crate) fn subscribe(&self, channel: String, subscription: &Subscription) -> bool {
+        let mut channels = self.channels.lock().unwrap();
+        let subscribers = channels.entry(channel).or_default();
+        if subscribers
+            .iter()
+            .any(|tx| tx.same_channel(&subscription.tx))
+        {
+            return false;
+        }
+        subscribers.push(subscription.tx.clone());
+        true
+    }
+
+    /// Publishes `message` on `channel`, returning the number of connections
+    /// it was sent to.
+    ///
+    /// A connection whose buffer is full misses the message, so a slow
+    /// subscriber does not hold up the publisher.
+    pub(crate) fn publish(&self, channel: &str, message: String) -> usize {
+        let mut channels = self.channels.lock().unwrap();
+        let Some(subscribers) = channels.get_mut(channel) else {
+            return 0;
+        };
+        let mut sent = 0;
+        // the connections closed since are forgotten
+        subscribers.retain(|tx| {
+            match tx.try_send((channel.to_owned(), message.clone())) {
+                Ok(()) => sent += 1,
+                Err(TrySendError::Full(_)) => {
+                    warn!("dropping a message on {} for a slow subscriber", channel)
+                }
+                Err(TrySendError::Closed(_)) => return false,
+            }
+            true
+        });
+        if subscribers.is_empty() {
+            channels.remove(channel);
+        }
+        sent
+    }
+}
+
+/// The messages of the channels a connection subscribed to.
+pub(crate) struct Subscription {
+    tx: mpsc::Sender<Message>,
+    rx: mpsc::Receiver<Message>,
+}
+
+impl Subscription {
+    pub(crate) fn new() -> Subscription {
+        let (tx, rx) = mpsc::channel(SUBSCRIBER_BUFFER);
+        Subscription { tx, rx }
+    }
+
+    /// Waits for the next message.
+    pub(crate) async fn next(&mut self) -> Message {
+        self.rx
+            .recv()
+            .await
+            .expect("the subscription holds a sender")
+    }
+}
@@ -0,0 +1,576 @@
+use std::{
+    collections::HashSet,
+    io::{self, Read, Write},
+    iter,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::Receiver,
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    select,
+    sync::mpsc,
+};
+use tokio_rustls::rustls::ClientConfig;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use crate::{
+    ChangeKind, Codec, KvClient, KvEngine, KvError, KvSnapshot, Response, ResponseFrame, Result,
+};
+
+/// How long a replica waits before reconnecting to its primary by default.
+const DEFAULT_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Messages buffered for a replica before the primary waits for it.
+const REPLICA_BUFFER: usize = 1024;
+/// Bytes of messages written to a replica at once.
+const WRITE_BUFFER_SIZE: usize = 64 * 1024;
+/// Bytes of the snapshot of a primary sent in one message.
+const SNAPSHOT_CHUNK_SIZE: usize = 256 * 1024;
+/// Keys read from an engine at once while paging through its keys.
+const KEYS_PAGE_SIZE: usize = 1024;
+
+/// A message of a primary to its replicas.
+#[derive(Serialize, Deserialize)]
+pub(crate) enum Replication {
+    // the key has the value, string or binary, expiring after the duration if any
+    Set(
+        String,
+        #[serde(with = "serde_bytes")] Vec<u8>,
+        Option<Duration>,
+    ),
+    // the key was removed
+    Remove(String),
+    // every key of the primary was sent, the following messages are changes
+    Synced,
+    // the next bytes of the snapshot of the primary, with their crc32
+    Chunk(#[serde(with = "serde_bytes")] Vec<u8>, u32),
+    // the snapshot of the primary was sent, in chunks of this many bytes in total
+    SnapshotEnd(u64),
+}
+
+/// Streams the keys of `engine`, or a snapshot of it if `snapshot`, then its
+/// changes, to a replica connected over `stream`, whose request numbered
+/// `id` asked for them, until the server stops.
+pub(crate) async fn serve_replica<E, S>(
+    engine: Arc<E>,
+    mut stream: S,
+    codec: Codec,
+    id: u64,
+    snapshot: bool,
+    stop: CancellationToken,
+) -> Result<()>
+where
+    E: KvEngine,
+    S: AsyncWrite + Unpin,
+{
+    // subscribed before the snapshot is taken, so no change comes unnoticed
+    let started = engine.subscribe().and_then(|changes| {
+        let snapshot = match snapshot {
+            true => Some(engine.replication_snapshot()?),
+            false => None,
+        };
+        Ok((changes, snapshot))
+    });
+    let (changes, snapshot) = match started {
+        Ok(started) => started,
+        Err(err) => {
+            write_response(&mut stream, codec, id, Response::Err(format!("{}", err))).await?;
+            return Ok(());
+        }
+    };
+    write_response(&mut stream, codec, id, Response::Ok(None)).await?;
+    info!("replica connected");
+
+    let (tx, rx) = mpsc::channel(REPLICA_BUFFER);
+    // reading the engine blocks, so it runs on a thread of its own
+    thread::spawn(move || send_changes(&*engine, snapshot, changes, tx));
+    forward_messages(&mut stream, codec, rx, &stop).await?;
+    stream.shutdown().await?;
+    info!("replica disconnected");
+    Ok(())
+}
+
+/// Writes the messages of `rx` to `stream`, encoded with `codec`, until the sender is gone or the
+/// server stops.
+pub(crate) async fn forward_messages<S, M>(
+    stream: &mut S,
+    codec: Codec,
+    mut rx: mpsc::Receiver<M>,
+    stop: &CancellationToken,
+) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+    M: Serialize,
+{
+    loop {
+        let message = select! {
+            biased;
+            _ = stop.cancelled() => break,
+            message = rx.recv() => message,
+        };
+        let Some(message) = message else {
+            break;
+        };
+        let mut data = codec.encode(&message)?;
+        while data.len() < WRITE_BUFFER_SIZE {
+            match rx.try_recv() {
+                Ok(message) => codec.encode_into(&mut data, &message)?,
+                Err(_) => break,
+            }
+        }
+        stream.write_all(&data).await?;
+        stream.flush().await?;
+    }
+    Ok(())
+}
+
+/// Writes the `response` to the request numbered `id`, which turned the
+/// connection into a stream of messages.
+pub(crate) async fn write_response<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    codec: Codec,
+    id: u64,
+    response: Response,
+) -> Result<()> {
+    let frame = ResponseFrame {
+        id: Some(id),
+        response,
+    };
+    stream.write_all(&codec.encode(&frame)?).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Sends every key of `engine`, or the `snapshot` of it if any, then the
+/// keys of its `changes`, to `tx` until the replica is gone.
+fn send_changes<E: KvEngine>(
+    engine: &E,
+    snapshot: Option<KvSnapshot>,
+    changes: Receiver<(String, ChangeKind)>,
+    tx: mpsc::Sender<Replication>,
+) {
+    // subscribed first, so a key changed while copying is sent again
+    let copied = match snapshot {
+        Some(snapshot) => send_snapshot(&snapshot, &tx),
+        None => send_keys(engine, &tx),
+    };
+    if !copied || tx.blocking_send(Replication::Synced).is_err() {
+        return;
+    }
+    // waits for the next change, even once the replica is gone
+    for (key, kind) in changes {
+        let message = match kind {
+            ChangeKind::Set => current_value(engine, key),
+            ChangeKind::Remove => Some(Replication::Remove(key)),
+        };
+        if let Some(message) = message {
+            if tx.blocking_send(message).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Sends every key of `engine` to `tx`, returning whether the replica got them.
+fn send_keys<E: KvEngine>(engine: &E, tx: &mpsc::Sender<Replication>) -> bool {
+    for key in keys(engine) {
+        let key = match key {
+            Ok(key) => key,
+            Err(err) => {
+                error!("failed to copy the keys to a replica: {}", err);
+                return false;
+            }
+        };
+        if let Some(message) = current_value(engine, key) {
+            if tx.blocking_send(message).is_err() {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Pages through the keys of `engine` in key order, without reading their
+/// values nor holding the engine between pages.
+fn keys<E: KvEngine>(engine: &E) -> impl Iterator<Item = Result<String>> + '_ {
+    let mut page = Vec::new().into_iter();
+    let mut after = None;
+    let mut done = false;
+    iter::from_fn(move || loop {
+        if let Some(key) = page.next() {
+            return Some(Ok(key));
+        }
+        if done {
+            return None;
+        }
+        match engine.scan_keys(after.take(), KEYS_PAGE_SIZE) {
+            Ok(keys) => {
+                done = keys.len() < KEYS_PAGE_SIZE;
+                after = keys.last().cloned();
+                page = keys.into_iter();
+            }
+            Err(err) => {
+                done = true;
+                return Some(Err(err));
+            }
+        }
+    })
+}
+
+/// Sends `snapshot` to `tx` as a compacted segment in chunks, returning
+/// whether the replica got all of it.
+fn send_snapshot(snapshot: &KvSnapshot, tx: &mpsc::Sender<Replication>) -> bool {
+    let mut chunks = ChunkWriter {
+        tx,
+        chunk: Vec::with_capacity(SNAPSHOT_CHUNK_SIZE),
+        len: 0,
+    };
+    let sent = snapshot
+        .write_segment(&mut chunks)
+        .and_then(|count| chunks.finish().map(|len| (count, len)));
+    match sent {
+        Ok((count, len)) => {
+            info!(
+                "sent a snapshot of {} keys, {} bytes, to a replica",
+                count, len
+            );
+            true
+        }
+        Err(err) => {
+            warn!("failed to send a snapshot to a replica: {}", err);
+            false
+        }
+    }
+}
+
+/// Cuts the bytes written to it into `Replication::Chunk` messages.
+struct ChunkWriter<'a> {
+    tx: &'a mpsc::Sender<Replication>,
+    chunk: Vec<u8>,
+    // bytes sent so far
+    len: u64,
+}
+
+impl ChunkWriter<'_> {
+    /// Sends the chunk being built.
+    fn send(&mut self) -> io::Result<()> {
+        let chunk = std::mem::replace(&mut self.chunk, Vec::with_capacity(SNAPSHOT_CHUNK_SIZE));
+        let crc = crc32fast::hash(&chunk);
+        self.len += chunk.len() as u64;
+        self.tx
+            .blocking_send(Replication::Chunk(chunk, crc))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the replica is gone"))
+    }
+
+    /// Sends the last chunk, then the end of the snapshot, returning its length.
+    fn finish(mut self) -> Result<u64> {
+        if !self.chunk.is_empty() {
+            self.send()?;
+        }
+        if self
+            .tx
+            .blocking_send(Replication::SnapshotEnd(self.len))
+            .is_err()
+        {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "the replica is gone").into());
+        }
+        Ok(self.len)
+    }
+}
+
+impl Write for ChunkWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(SNAPSHOT_CHUNK_SIZE - self.chunk.len());
+        self.chunk.extend_from_slice(&buf[..n]);
+        if self.chunk.len() == SNAPSHOT_CHUNK_SIZE {
+            self.send()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads the snapshot a primary sends in `Replication::Chunk` messages,
+/// checking the crc32 of each chunk and the length of the whole snapshot.
+struct SnapshotReader<'a, I> {
+    messages: &'a mut I,
+    chunk: Vec<u8>,
+    // bytes of the chunk already read
+    pos: usize,
+    // bytes received so far
+    len: u64,
+    ended: bool,
+}
+
+impl<'a, I: Iterator<Item = Result<Replication>>> SnapshotReader<'a, I> {
+    /// Starts reading with the first `chunk`, of the `messages` that follow it.
+    fn new(messages: &'a mut I, chunk: Vec<u8>, crc: u32) -> io::Result<SnapshotReader<'a, I>> {
+        let mut reader = SnapshotReader {
+            messages,
+            chunk: Vec::new(),
+            pos: 0,
+            len: 0,
+            ended: false,
+        };
+        reader.receive(chunk, crc)?;
+        Ok(reader)
+    }
+
+    fn receive(&mut self, chunk: Vec<u8>, crc: u32) -> io::Result<()> {
+        if crc32fast::hash(&chunk) != crc {
+            return Err(invalid_snapshot(format!(
+                "the chunk at byte {} has a wrong checksum",
+                self.len
+            )));
+        }
+        self.len += chunk.len() as u64;
+        self.chunk = chunk;
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+impl<I: Iterator<Item = Result<Replication>>> Read for SnapshotReader<'_, I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() && !self.ended {
+            match self.messages.next() {
+                Some(Ok(Replication::Chunk(chunk, crc))) => self.receive(chunk, crc)?,
+                Some(Ok(Replication::SnapshotEnd(len))) if len == self.len => self.ended = true,
+                Some(Ok(Replication::SnapshotEnd(len))) => {
+                    return Err(invalid_snapshot(format!(
+                        "{} bytes received of {}",
+                        self.len, len
+                    )));
+                }
+                Some(Ok(_)) => return Err(invalid_snapshot("a change in the middle".to_owned())),
+                Some(Err(err)) => return Err(io::Error::other(err.to_string())),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "the snapshot ended early",
+                    ));
+                }
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+fn invalid_snapshot(reason: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid snapshot: {}", reason),
+    )
+}
+
+/// Returns the message setting `key` to its current value in `engine`.
+///
+/// Sending the current value rather than the change itself, the replica
+/// ends up with the last value of the key whatever changes it missed.
+fn current_value<E: KvEngine>(engine: &E, key: String) -> Option<Replication> {
+    let value = match engine.get_bytes(key.clone()) {
+        Ok(Some(value)) => value,
+        Ok(None) => return Some(Replication::Remove(key)),
+        Err(err) => {
+            warn!("cannot replicate key {}: {}", key, err);
+            return None;
+        }
+    };
+    match engine.ttl(key.clone()) {
+        Ok(ttl) => Some(Replication::Set(key, value, ttl)),
+        // expired in the meantime
+        Err(KvError::KeyNotFound) => Some(Replication::Remove(key)),
+        Err(err) => {
+            warn!("cannot replicate key {}: {}", key, err);
+            None
+        }
+    }
+}
+
+/// Keeps an engine a copy of the engine of a primary server.
+///
+/// Once started, a replica connects to its primary, copies every key of it
+/// and then applies each change committed on the primary. Keys it has but
+/// the primary has not are removed once copied. When the connection breaks,
+/// the replica reconnects and copies the keys again. The engine of the
+/// primary must support `KvEngine::subscribe`.
+///
+/// With `Replica::snapshots`, the replica copies the primary from a snapshot
+/// of its engine instead, which it installs in one go.
+pub struct Replica<E: KvEngine> {
+    engine: E,
+    primary: String,
+    tls: Option<Arc<ClientConfig>>,
+    reconnect_delay: Duration,
+    snapshots: bool,
+}
+
+impl<E: KvEngine> Replica<E> {
+    /// Creates a replica copying the server at `primary` into `engine`.
+    pub fn new(engine: E, primary: impl Into<String>) -> Replica<E> {
+        Replica {
+            engine,
+            primary: primary.into(),
+            tls: None,
+            reconnect_delay: DEFAULT_RECONNECT_DELAY,
+            snapshots: false,
+        }
+    }
+
+    /// Connects to the primary over TLS with `config`, built by `client_tls_config`.
+    pub fn tls(mut self, config: Arc<ClientConfig>) -> Replica<E> {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Sets how long the replica waits before reconnecting, 1 second by default.
+    pub fn reconnect_delay(mut self, delay: Duration) -> Replica<E> {
+        self.reconnect_delay = delay;
+        self
+    }
+
+    /// Copies the primary from a snapshot of its engine, streamed as a
+    /// compacted segment, rather than key by key, false by default.
+    ///
+    /// The engine of the replica must support `KvEngine::install_snapshot`.
+    /// The replica falls back to copying the keys from a primary whose engine
+    /// takes no snapshot.
+    pub fn snapshots(mut self, snapshots: bool) -> Replica<E> {
+        self.snapshots = snapshots;
+        self
+    }
+
+    /// Starts replicating on a thread of its own.
+    pub fn start(self) -> ReplicaHandle {
+        let handle = ReplicaHandle {
+            synced: Arc::default(),
+            stopped: Arc::default(),
+        };
+        let synced = handle.synced.clone();
+        let stopped = handle.stopped.clone();
+        thread::spawn(move || {
+            while !stopped.load(Ordering::SeqCst) {
+                match self.sync(&synced, &stopped) {
+                    Ok(()) => info!("primary {} closed the replication", self.primary),
+                    Err(err) => warn!("replication from {} failed: {}", self.primary, err),
+                }
+                synced.store(false, Ordering::SeqCst);
+                thread::sleep(self.reconnect_delay);
+            }
+        });
+        handle
+    }
+
+    /// Copies the primary until the connection breaks or the replica is stopped.
+    fn sync(&self, synced: &AtomicBool, stopped: &AtomicBool) -> Result<()> {
+        let mut messages = match self.connect()?.replicate(self.snapshots) {
+            Ok(messages) => messages,
+            Err(err) if self.snapshots => {
+                warn!("no snapshot of {}, copying its keys: {}", self.primary, err);
+                self.connect()?.replicate(false)?
+            }
+            Err(err) => return Err(err),
+        };
+        info!("replicating {}", self.primary);
+        // the keys copied, until every key of the primary is
+        let mut copied = Some(HashSet::new());
+        while let Some(message) = messages.next() {
+            if stopped.load(Ordering::SeqCst) {
+                break;
+            }
+            match message? {
+                Replication::Set(key, value, ttl) => {
+                    if let Some(copied) = &mut copied {
+                        copied.insert(key.clone());
+                    }
+                    set(&self.engine, key, value, ttl)?;
+                }
+                Replication::Remove(key) => remove(&self.engine, key)?,
+                Replication::Chunk(chunk, crc) => {
+                    let snapshot = SnapshotReader::new(&mut messages, chunk, crc)?;
+                    self.engine.install_snapshot(snapshot)?;
+                    // the keys missing from the snapshot went with the old records
+                    copied = None;
+                    info!("installed a snapshot of {}", self.primary);
+                }
+                Replication::SnapshotEnd(_) => {
+                    return Err(invalid_snapshot("an end without chunks".to_owned()).into());
+                }
+                Replication::Synced => {
+                    if let Some(copied) = copied.take() {
+                        for key in keys(&self.engine) {
+                            let key = key?;
+                            if !copied.contains(&key) {
+                                remove(&self.engine, key)?;
+                            }
+                        }
+                    }
+                    synced.store(true, Ordering::SeqCst);
+                    info!("in sync with {}", self.primary);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Connects to the primary.
+    fn connect(&self) -> Result<KvClient> {
+        match &self.tls {
+            Some(config) => KvClient::connect_tls(&self.primary, config.clone()),
+            None => KvClient::new(&self.primary),
+        }
+    }
+}
+
+/// Sets `key` to `value`, as a string if it is one, which expires after `ttl`
+/// if any, or else as bytes, which never expire.
+fn set<E: KvEngine>(engine: &E, key: String, value: Vec<u8>, ttl: Option<Duration>) -> Result<()> {
+    match (String::from_utf8(value), ttl) {
+        (Ok(value), Some(ttl)) => engine.set_with_ttl(key, value, ttl),
+        (Ok(value), None) => engine.set(key, value),
+        (Err(err), _) => engine.set_bytes(key, err.into_bytes()),
+    }
+}
+
+/// Removes `key`, which may already be missing.
+fn remove<E: KvEngine>(engine: &E, key: String) -> Result<()> {
+    match engine.remove(key) {
+        Ok(()) | Err(KvError::KeyNotFound) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// A handle to a started `Replica`.
+#[derive(Clone)]
+pub struct ReplicaHandle {
+    synced: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl ReplicaHandle {
+    /// Returns whether the replica copied every key of its primary, and
+    /// applies its changes since.
+    pub fn is_synced(&self) -> bool {
+        self.synced.load(Ordering::SeqCst)
+    }
+
+    /// Stops the replica, which leaves its engine as is.
+    ///
+    /// The replica stops at the next change it receives, or before reconnecting.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,235 @@
+//! The RESP2 protocol of Redis, for Redis clients to talk to the server.
+
+use crate::{KvEngine, KvError, Request, Result};
+
+/// A command of a Redis client.
+pub(crate) enum Command {
+    Ping(Option<Vec<u8>>),
+    Get(String),
+    Set(String, String),
+    Del(Vec<String>),
+    Exists(Vec<String>),
+}
+
+impl Command {
+    /// Returns the command of the arguments sent by a client, or the error to
+    /// reply when the server does not understand it.
+    fn parse(args: Vec<Vec<u8>>) -> std::result::Result<Command, String> {
+        let name = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+        let arity = match name.as_str() {
+            "ping" => 1..=2,
+            "get" => 2..=2,
+            "set" => 3..=3,
+            "del" | "exists" => 2..=usize::MAX,
+            _ => {
+                return Err(format!(
+                    "ERR unknown command '{}'",
+                    String::from_utf8_lossy(&args[0])
+                ))
+            }
+        };
+        if !arity.contains(&args.len()) {
+            return Err(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name
+            ));
+        }
+        let mut args = args.into_iter().skip(1);
+        if name == "ping" {
+            return Ok(Command::Ping(args.next()));
+        }
+        let mut args = args
+            .map(String::from_utf8)
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|_| "ERR keys and values must be UTF-8".to_owned())?;
+        Ok(match name.as_str() {
+            "get" => Command::Get(args.remove(0)),
+            "set" => {
+                let value = args.pop().expect("set has a value");
+                Command::Set(args.remove(0), value)
+            }
+            "del" => Command::Del(args),
+            _ => Command::Exists(args),
+        })
+    }
+
+    /// Returns the name of the command, for logs.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Command::Ping(_) => "ping",
+            Command::Get(_) => "get",
+            Command::Set(..) => "set",
+            Command::Del(_) => "del",
+            Command::Exists(_) => "exists",
+        }
+    }
+
+    /// Returns the key of the command, if it is about a single key.
+    pub(crate) fn key(&self) -> Option<&str> {
+        match self {
+            Command::Get(key) | Command::Set(key, _) => Some(key),
+            Command::Del(keys) | Command::Exists(keys) if keys.len() == 1 => Some(&keys[0]),
+            _ => None,
+        }
+    }
+
+    /// Returns the requests of the JSON protocol doing the same, which the
+    /// authorization hook of the server checks.
+    pub(crate) fn requests(&self) -> Vec<Request> {
+        match self {
+            Command::Ping(_) => Vec::new(),
+            Command::Get(key) => vec![Request::Get(key.clone())],
+            Command::Set(key, value) => vec![Request::Set(key.clone(), value.clone())],
+            Command::Del(keys) => keys.iter().cloned().map(Request::Remove).collect(),
+            Command::Exists(keys) => keys.iter().cloned().map(Request::Get).collect(),
+        }
+    }
+
+    /// Runs the command against `engine`.
+    pub(crate) fn execute<E: KvEngine>(self, engine: &E) -> Reply {
+        let reply = match self {
+            Command::Ping(None) => Ok(Reply::Status("PONG")),
+            Command::Ping(Some(message)) => Ok(Reply::Bulk(Some(message))),
+            Command::Get(key) => engine
+                .get(key)
+                .map(|value| Reply::Bulk(value.map(String::into_bytes))),
+            Command::Set(key, value) => engine.set(key, value).map(|_| Reply::Status("OK")),
+            Command::Del(keys) => keys
+                .into_iter()
+                .try_fold(0, |removed, key| match engine.remove(key) {
+                    Ok(()) => Ok(removed + 1),
+                    Err(KvError::KeyNotFound) => Ok(removed),
+                    Err(err) => Err(err),
+                })
+                .map(Reply::Integer),
+            Command::Exists(keys) => keys
+                .into_iter()
+                .try_fold(
+                    0,
+                    |found, key| Ok(found + engine.get(key)?.map_or(0, |_| 1)),
+                )
+                .map(Reply::Integer),
+        };
+        reply.unwrap_or_else(|err| Reply::error(&err))
+    }
+}
+
+/// A reply to a Redis client.
+pub(crate) enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+}
+
+impl Reply {
+    /// Returns the generic error reply of `err`.
+    pub(crate) fn error(err: &KvError) -> Reply {
+        Reply::Error(format!("ERR {}", err))
+    }
+
+    /// Returns the reply encoded as RESP.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        match self {
+            Reply::Status(status) => format!("+{}\r\n", status).into_bytes(),
+            // a line break would end the error early
+            Reply::Error(message) => {
+                format!("-{}\r\n", message.replace(['\r', '\n'], " ")).into_bytes()
+            }
+            Reply::Integer(value) => format!(":{}\r\n", value).into_bytes(),
+            Reply::Bulk(None) => b"$-1\r\n".to_vec(),
+            Reply::Bulk(Some(value)) => {
+                let mut data = format!("${}\r\n", value.len()).into_bytes();
+                data.extend_from_slice(value);
+                data.extend_from_slice(b"\r\n");
+                data
+            }
+        }
+    }
+}
+
+/// Takes the first whole command out of `buf`, or returns `None` if more
+/// bytes are needed.
+///
+/// Commands come as arrays of bulk strings, like Redis clients send them, or
+/// inline as a line of words, like typed in telnet. A command the server does
+/// not understand comes as the error to reply, while malformed input fails.
+pub(crate) fn next_command(
+    buf: &mut Vec<u8>,
+) -> Result<Option<std::result::Result<Command, String>>> {
+    loop {
+        let (args, offset) = match buf.first() {
+            None => return Ok(None),
+            Some(b'*') => match parse_array(buf)? {
+                Some(parsed) => parsed,
+                None => return Ok(None),
+            },
+            Some(_) => match line(buf, 0) {
+                Some((line, offset)) => {
+                    let args = line
+                        .split(|byte| byte.is_ascii_whitespace())
+                        .filter(|arg| !arg.is_empty())
+                        .map(<[u8]>::to_vec)
+                        .collect();
+                    (args, offset)
+                }
+                None => return Ok(None),
+            },
+        };
+        buf.drain(..offset);
+        // empty commands are skipped
+        if !args.is_empty() {
+            return Ok(Some(Command::parse(args)));
+        }
+    }
+}
+
+/// Parses the array of bulk strings at the start of `buf`, returning its
+/// items and the offset after it, or `None` if it is not whole yet.
+fn parse_array(buf: &[u8]) -> Result<Option<(Vec<Vec<u8>>, usize)>> {
+    let Some((header, mut offset)) = line(buf, 0) else {
+        return Ok(None);
+    };
+    let count = parse_length(&header[1..])?;
+    let mut args = Vec::new();
+    for _ in 0..count {
+        let Some((header, start)) = line(buf, offset) else {
+            return Ok(None);
+        };
+        if header.first() != Some(&b'$') {
+            return Err(protocol_error("expected '$'"));
+        }
+        let len = parse_length(&header[1..])?;
+        let end = start + len;
+        if buf.len() < end + 2 {
+            return Ok(None);
+        }
+        if &buf[end..end + 2] != b"\r\n" {
+            return Err(protocol_error("bulk string not followed by CRLF"));
+        }
+        args.push(buf[start..end].to_vec());
+        offset = end + 2;
+    }
+    Ok(Some((args, offset)))
+}
+
+/// Returns the line of `buf` starting at `start` without its line break, and
+/// the offset after it, or `None` if it is not whole yet.
+fn line(buf: &[u8], start: usize) -> Option<(&[u8], usize)> {
+    let end = buf[start..].iter().position(|&byte| byte == b'\n')?;
+    let line = &buf[start..start + end];
+    Some((line.strip_suffix(b"\r").unwrap_or(line), start + end + 1))
+}
+
+fn parse_length(digits: &[u8]) -> Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse::<i64>().ok())
+        // a null or empty array has no items
+        .map(|len| len.max(0) as usize)
+        .ok_or_else(|| protocol_error("invalid length"))
+}
+
+fn protocol_error(message: &str) -> KvError {
+    KvError::Protocol(message.to_owned())
+}
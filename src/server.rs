@@ -1,112 +1,2560 @@
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs,
+    future::Future,
+    io, mem,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use crate::{KvEngine, KvError, Request, Response, Result, ThreadPool};
-use log::{error, info};
+use crate::{
+    change_log::{self, ChangeLog},
+    clients::{ClientState, Clients, Metered},
+    codec::PREAMBLE_LEN,
+    common::{
+        AdminCommand, BulkFrame, LoadSummary, OpStats, RequestFrame, ResponseFrame, ServerCounts,
+        ServerInfo,
+    },
+    engine::check_bucket_name,
+    glob,
+    monitor::{self, Monitors},
+    pubsub::{Broker, Message, Subscription},
+    replication, resp, watch, ClientIdentity, Codec, KvEngine, KvError, Request, Response, Result,
+    ThreadPool, WriteBatch,
+};
+use futures_util::{
+    future::{self, Either},
+    stream::{FuturesUnordered, StreamExt},
+};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
     select, signal,
-    sync::oneshot,
+    sync::{mpsc, OwnedSemaphorePermit, Semaphore},
+    time::timeout,
 };
+use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, debug_span, error, field, info, info_span, warn, Instrument, Span};
+
+/// The address a server listens on by default.
+const DEFAULT_ADDRESS: &str = "127.0.0.1:4000";
+/// The prefix of an address which is the path of a unix socket.
+const UNIX_PREFIX: &str = "unix:";
+/// How long a shutdown waits for in-flight requests by default.
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a shutdown waits past the shutdown timeout for the connections to close.
+const SHUTDOWN_GRACE: Duration = Duration::from_millis(500);
+/// The largest request a server reads by default.
+const DEFAULT_MAX_FRAME_SIZE: usize = 8 * 1024 * 1024;
+/// How many events a second are sent to a monitor by default.
+const DEFAULT_MONITOR_RATE: u32 = 1000;
+/// How many requests of a connection run at once by default.
+const DEFAULT_MAX_IN_FLIGHT: usize = 16;
+/// The connections waiting to be accepted by a listener bound with SO_REUSEPORT.
+#[cfg(unix)]
+const LISTEN_BACKLOG: i32 = 1024;
+/// Bytes read from a connection at once.
+const READ_BUFFER_SIZE: usize = 4096;
+
+/// A hook deciding whether a client may run a request.
+type Authorizer = Arc<dyn Fn(Option<&ClientIdentity>, &Request) -> bool + Send + Sync>;
+/// A future stopping a server once it resolves.
+type ShutdownSignal = Pin<Box<dyn Future<Output = ()> + Send>>;
+/// A hook reloading the settings of a server.
+type Reloader = Arc<dyn Fn(&ReloadHandle) -> Result<()> + Send + Sync>;
+/// A handler running a custom command against an engine, with the payload of the request.
+type CustomHandler<E> = Arc<dyn Fn(&E, String) -> Result<Option<String>> + Send + Sync>;
+/// The custom commands of a server, by name.
+type CustomCommands<E> = Arc<HashMap<String, CustomHandler<E>>>;
 
-/// The server of a key value store.
+/// The server of a key value store, built by `KvServer::builder`.
 pub struct KvServer<E: KvEngine, T: ThreadPool> {
+    engine: Arc<E>,
+    pool: T,
+    addrs: Vec<String>,
+    // bounds the number of open connections, if limited
+    connections: Option<Arc<Semaphore>>,
+    shutdown: CancellationToken,
+    // stops the server once it resolves, if any
+    shutdown_signal: Option<ShutdownSignal>,
+    settings: Arc<ConnectionSettings>,
+    commands: CustomCommands<E>,
+    // the listeners accepting connections, each on a task of its own
+    acceptors: usize,
+}
+
+/// The settings each connection of a server uses.
+struct ConnectionSettings {
+    tls: Option<TlsAcceptor>,
+    // the settings a reload changes, shared with the reload handles
+    limits: Arc<RwLock<Limits>>,
+    reloader: Option<Reloader>,
+    read_only: bool,
+    // the codecs clients may negotiate besides JSON
+    codecs: Vec<Codec>,
+    metrics: Option<Arc<ServerMetrics>>,
+    // the channels of the server, shared by its connections
+    broker: Broker,
+    // the connections open from each peer IP
+    peers: Arc<Peers>,
+    // the last changes of the engine, if recorded
+    change_log: Option<Arc<ChangeLog>>,
+    // when the server was built, for its uptime
+    started: Instant,
+    // how long a stopping connection waits for its requests in flight
+    shutdown_timeout: Duration,
+    // the connections open, for the client list and kill admin commands
+    clients: Arc<Clients>,
+    // the connections monitoring the requests of the server
+    monitors: Monitors,
+}
+
+/// The settings of a server which a reload changes.
+///
+/// Connections read them as they go, so the open ones get the new settings too.
+#[derive(Clone)]
+struct Limits {
+    authorizer: Option<Authorizer>,
+    idle_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    max_frame_size: usize,
+    max_in_flight: usize,
+}
+
+impl ConnectionSettings {
+    /// Returns the current settings a reload changes.
+    fn limits(&self) -> Limits {
+        self.limits.read().unwrap().clone()
+    }
+
+    /// Returns the error refusing `request` of `client`, if the server does not run it.
+    fn refusal(&self, client: &Client, request: &Request) -> Option<KvError> {
+        if self.read_only && request.is_write() {
+            return Some(KvError::ReadOnly);
+        }
+        let authorizer = self.limits.read().unwrap().authorizer.clone();
+        let allowed =
+            authorizer.is_none_or(|authorizer| authorizer(client.identity.as_ref(), request));
+        (!allowed).then_some(KvError::PermissionDenied)
+    }
+
+    /// Returns the job answering `Request::Info` with the state of the server
+    /// now and the statistics of the engine it gets.
+    fn info_job<E: KvEngine>(&self) -> impl FnOnce(&E) -> Result<ServerInfo> + Send + 'static {
+        let uptime = self.started.elapsed();
+        let counts = self.metrics.as_ref().map(|metrics| metrics.counts());
+        let ops = self
+            .metrics
+            .as_ref()
+            .map_or_else(BTreeMap::new, |metrics| metrics.ops());
+        move |engine| {
+            Ok(ServerInfo {
+                version: env!("CARGO_PKG_VERSION").to_owned(),
+                uptime,
+                engine: engine.name().to_owned(),
+                stats: engine.stats()?,
+                counts,
+                ops,
+            })
+        }
+    }
+
+    /// Returns the job running the reload hook of the server, if it has one.
+    fn reload_job(&self) -> Option<impl FnOnce() -> Result<()> + Send + 'static> {
+        let reloader = self.reloader.clone()?;
+        let handle = ReloadHandle {
+            limits: self.limits.clone(),
+        };
+        Some(move || reloader(&handle))
+    }
+}
+
+/// A builder of `KvServer`.
+///
+/// Every setting has a default, so a server only needs its engine and thread pool.
+pub struct KvServerBuilder<E: KvEngine, T: ThreadPool> {
     engine: E,
     pool: T,
+    addrs: Vec<String>,
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    acceptors: usize,
+    idle_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    shutdown_timeout: Duration,
+    shutdown_signal: Option<ShutdownSignal>,
+    max_frame_size: usize,
+    max_in_flight: usize,
+    tls: Option<TlsAcceptor>,
+    authorizer: Option<Authorizer>,
+    reloader: Option<Reloader>,
+    read_only: bool,
+    codecs: Vec<Codec>,
+    metrics: bool,
+    change_log: Option<usize>,
+    monitor_rate: u32,
+    commands: HashMap<String, CustomHandler<E>>,
+}
+
+impl<E: KvEngine, T: ThreadPool> KvServerBuilder<E, T> {
+    /// Sets the address the server listens on, `127.0.0.1:4000` by default.
+    ///
+    /// An address `unix:<path>` is a unix socket at `path`, on unix only.
+    pub fn addr(self, addr: impl Into<String>) -> KvServerBuilder<E, T> {
+        self.addrs([addr])
+    }
+
+    /// Sets the addresses the server listens on, each like `addr`.
+    ///
+    /// The connections of every address are served alike, by the same
+    /// engine and with the same settings.
+    pub fn addrs<I>(mut self, addrs: I) -> KvServerBuilder<E, T>
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.addrs = addrs.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets how many connections may be open at once, unlimited by default.
+    ///
+    /// Connections over the limit wait to be accepted until another one closes.
+    pub fn max_connections(mut self, max_connections: usize) -> KvServerBuilder<E, T> {
+        self.max_connections = Some(max_connections);
+        self
+    }
+
+    /// Sets how many connections may be open at once from one IP, unlimited by default.
+    ///
+    /// Unlike the connections over `max_connections`, which wait, the
+    /// connections of an IP over the limit are closed as soon as they are
+    /// accepted, so one host cannot take every connection of the server.
+    pub fn max_connections_per_ip(mut self, max_connections: usize) -> KvServerBuilder<E, T> {
+        self.max_connections_per_ip = Some(max_connections);
+        self
+    }
+
+    /// Sets how many tasks accept connections, each with a listener of its own, 1 by default.
+    ///
+    /// The listeners share the address with SO_REUSEPORT, the kernel
+    /// spreading the connections among them, so a server accepting many
+    /// connections a second accepts them on several cores. Several acceptors
+    /// are only supported on unix.
+    pub fn acceptors(mut self, acceptors: usize) -> KvServerBuilder<E, T> {
+        self.acceptors = acceptors.max(1);
+        self
+    }
+
+    /// Closes connections that send no request for `timeout`, none by default.
+    pub fn idle_timeout(mut self, timeout: Duration) -> KvServerBuilder<E, T> {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Fails requests that take longer than `timeout` to run with
+    /// `KvError::RequestTimeout`, none by default.
+    ///
+    /// The connection moves on to its next request, while the job of the
+    /// timed out one keeps its thread of the pool until it returns.
+    pub fn request_timeout(mut self, timeout: Duration) -> KvServerBuilder<E, T> {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how long a shutdown waits for in-flight requests before closing
+    /// their connections, 5 seconds by default.
+    ///
+    /// Once the server stops accepting connections, each connection tells its
+    /// client with a `Response::ShuttingDown` frame, stops reading requests,
+    /// and closes once it answered the requests in flight or the timeout
+    /// passed, whichever comes first.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> KvServerBuilder<E, T> {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Stops the server once `signal` resolves, like a `ShutdownHandle`.
+    ///
+    /// The server stops on ctrl-c and SIGTERM too, so an embedding program
+    /// only needs this for other signals, like the end of a test or of its
+    /// own work. The future is polled by the first `run`.
+    pub fn shutdown_signal<F>(mut self, signal: F) -> KvServerBuilder<E, T>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.shutdown_signal = Some(Box::pin(signal));
+        self
+    }
+
+    /// Sets the size in bytes of the largest request the server reads, 8 MiB by default.
+    ///
+    /// A larger request fails with `KvError::FrameTooLarge`, and its
+    /// connection is closed.
+    pub fn max_frame_size(mut self, max_frame_size: usize) -> KvServerBuilder<E, T> {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Sets how many requests of a connection run at once, 16 by default.
+    ///
+    /// A client may send requests without waiting for their responses, which
+    /// are written back in the order of the requests, but which may run in any
+    /// order. Over the limit, the server stops reading the connection until
+    /// its oldest request completes.
+    pub fn max_in_flight(mut self, max_in_flight: usize) -> KvServerBuilder<E, T> {
+        self.max_in_flight = max_in_flight.max(1);
+        self
+    }
+
+    /// Serves clients over TLS with `config`, built by `server_tls_config`.
+    pub fn tls(mut self, config: Arc<ServerConfig>) -> KvServerBuilder<E, T> {
+        self.tls = Some(TlsAcceptor::from(config));
+        self
+    }
+
+    /// Runs only the requests `authorizer` allows, failing the others with
+    /// `KvError::PermissionDenied`.
+    ///
+    /// The hook gets the identity of the client when it authenticated with a
+    /// certificate, and the whole request, a bucket request included. It runs
+    /// on the network threads, so it should not block.
+    pub fn authorize<F>(mut self, authorizer: F) -> KvServerBuilder<E, T>
+    where
+        F: Fn(Option<&ClientIdentity>, &Request) -> bool + Send + Sync + 'static,
+    {
+        self.authorizer = Some(Arc::new(authorizer));
+        self
+    }
+
+    /// Sets the hook reloading the settings of the running server, on SIGHUP
+    /// or with the reload admin command.
+    ///
+    /// The hook changes the settings through the handle it gets, like a
+    /// `ReloadHandle`, and its error fails the reload. The open connections
+    /// keep going, with the new settings from their next request. It runs on
+    /// the thread pool, so it may read a config file.
+    pub fn on_reload<F>(mut self, reloader: F) -> KvServerBuilder<E, T>
+    where
+        F: Fn(&ReloadHandle) -> Result<()> + Send + Sync + 'static,
+    {
+        self.reloader = Some(Arc::new(reloader));
+        self
+    }
+
+    /// Sets whether the server refuses writes with `KvError::ReadOnly`, off by default.
+    ///
+    /// A replica is read-only, its engine only written by the replication.
+    pub fn read_only(mut self, read_only: bool) -> KvServerBuilder<E, T> {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Sets the codecs clients may negotiate when they connect, every codec by default.
+    ///
+    /// JSON is always accepted, for the clients which do not negotiate one.
+    pub fn codecs(mut self, codecs: &[Codec]) -> KvServerBuilder<E, T> {
+        self.codecs = codecs.to_vec();
+        self
+    }
+
+    /// Registers `handler` as the custom command `name`, which clients run
+    /// with `Request::Custom`, replacing the handler registered before if any.
+    ///
+    /// The handler gets the engine, or the bucket of a bucket request, and the
+    /// payload of the request, and its value answers the request. It runs on
+    /// the thread pool next to the other requests, so it may block, but it is
+    /// not atomic with them unless it takes a transaction of its own. As it
+    /// may write, a read-only server refuses custom commands.
+    pub fn custom_command<F>(mut self, name: impl Into<String>, handler: F) -> KvServerBuilder<E, T>
+    where
+        F: Fn(&E, String) -> Result<Option<String>> + Send + Sync + 'static,
+    {
+        self.commands.insert(name.into(), Arc::new(handler));
+        self
+    }
+
+    /// Sets whether the server counts connections and requests, off by default.
+    ///
+    /// The counts are read from `KvServer::metrics`.
+    pub fn metrics(mut self, enabled: bool) -> KvServerBuilder<E, T> {
+        self.metrics = enabled;
+        self
+    }
+
+    /// Sets how many of the last changes of the engine the server keeps in
+    /// its change log, which clients tail with `KvClient::changes`; none by
+    /// default.
+    ///
+    /// The engine must report its changes, like the kvs engine. Each change
+    /// records the value of its key, read from the engine once committed.
+    pub fn change_log(mut self, capacity: usize) -> KvServerBuilder<E, T> {
+        self.change_log = Some(capacity);
+        self
+    }
+
+    /// Sets how many requests a second the server sends to each client
+    /// monitoring it with `KvClient::monitor`, 1000 by default.
+    ///
+    /// A monitor misses the requests over the rate, and learns how many with
+    /// the next one, so monitoring a busy server does not slow it down.
+    pub fn monitor_rate(mut self, rate: u32) -> KvServerBuilder<E, T> {
+        self.monitor_rate = rate;
+        self
+    }
+
+    /// Builds the server.
+    pub fn build(self) -> KvServer<E, T> {
+        let engine = Arc::new(self.engine);
+        let change_log = self.change_log.and_then(|capacity| {
+            ChangeLog::start(&engine, capacity)
+                .map_err(|err| error!("cannot record the changes of the engine: {}", err))
+                .ok()
+        });
+        KvServer {
+            engine,
+            pool: self.pool,
+            addrs: self.addrs,
+            connections: self
+                .max_connections
+                .map(|max_connections| Arc::new(Semaphore::new(max_connections))),
+            shutdown: CancellationToken::new(),
+            shutdown_signal: self.shutdown_signal,
+            settings: Arc::new(ConnectionSettings {
+                tls: self.tls,
+                limits: Arc::new(RwLock::new(Limits {
+                    authorizer: self.authorizer,
+                    idle_timeout: self.idle_timeout,
+                    request_timeout: self.request_timeout,
+                    max_frame_size: self.max_frame_size,
+                    max_in_flight: self.max_in_flight,
+                })),
+                reloader: self.reloader,
+                read_only: self.read_only,
+                codecs: self.codecs,
+                metrics: self.metrics.then(Arc::default),
+                broker: Broker::default(),
+                peers: Arc::new(Peers {
+                    max_per_ip: self.max_connections_per_ip,
+                    counts: Mutex::default(),
+                }),
+                change_log,
+                started: Instant::now(),
+                shutdown_timeout: self.shutdown_timeout,
+                clients: Arc::default(),
+                monitors: Monitors::new(self.monitor_rate),
+            }),
+            commands: Arc::new(self.commands),
+            acceptors: self.acceptors,
+        }
+    }
+}
+
+/// Counts of the connections and requests of a server.
+#[derive(Default, Debug)]
+pub struct ServerMetrics {
+    connections: AtomicU64,
+    active_connections: AtomicU64,
+    requests: AtomicU64,
+    failed_requests: AtomicU64,
+    busy_requests: AtomicU64,
+    refused_connections: AtomicU64,
+    namespaces: Mutex<BTreeMap<String, NamespaceMetrics>>,
+    ops: Mutex<BTreeMap<&'static str, OpStats>>,
+}
+
+/// Counts of the requests run in a namespace of a server, see `ServerMetrics::namespaces`.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NamespaceMetrics {
+    /// The number of requests answered.
+    pub requests: u64,
+    /// The number of requests answered with an error, the refused ones included.
+    pub failed_requests: u64,
+}
+
+impl ServerMetrics {
+    /// Returns the number of connections accepted.
+    pub fn connections(&self) -> u64 {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of connections currently open.
+    pub fn active_connections(&self) -> u64 {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of connections closed right away, as their IP had
+    /// as many open as allowed.
+    pub fn refused_connections(&self) -> u64 {
+        self.refused_connections.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of requests answered.
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of requests answered with an error.
+    pub fn failed_requests(&self) -> u64 {
+        self.failed_requests.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of requests refused as the pool had no room to queue them,
+    /// counted among the failed ones.
+    pub fn busy_requests(&self) -> u64 {
+        self.busy_requests.load(Ordering::Relaxed)
+    }
+
+    /// Returns all the counts of connections and requests at once.
+    pub fn counts(&self) -> ServerCounts {
+        ServerCounts {
+            connections: self.connections(),
+            active_connections: self.active_connections(),
+            refused_connections: self.refused_connections(),
+            requests: self.requests(),
+            failed_requests: self.failed_requests(),
+            busy_requests: self.busy_requests(),
+        }
+    }
+
+    /// Returns the counts of the requests run in each namespace, the bucket
+    /// of a bucket request or of a connection after `Request::Use`, by name.
+    ///
+    /// A request running in several namespaces, like a batch, counts in each.
+    /// The requests of the default keyspace count in none.
+    pub fn namespaces(&self) -> BTreeMap<String, NamespaceMetrics> {
+        self.namespaces.lock().unwrap().clone()
+    }
+
+    /// Returns the statistics of the requests of each operation, like get,
+    /// by name: their counts, hits and misses, and latencies.
+    ///
+    /// The requests in a bucket count as their operation, and a batch as one
+    /// batch request.
+    pub fn ops(&self) -> BTreeMap<String, OpStats> {
+        let ops = self.ops.lock().unwrap();
+        ops.iter()
+            .map(|(op, stats)| (op.to_string(), stats.clone()))
+            .collect()
+    }
 }
 
 impl<E: KvEngine, T: ThreadPool> KvServer<E, T> {
-    /// create a `KvServer` with a given storage engine.
-    pub fn new(engine: E, pool: T) -> KvServer<E, T> {
-        KvServer { engine, pool }
+    /// Returns a builder of a server using `engine`, running requests on `pool`.
+    pub fn builder(engine: E, pool: T) -> KvServerBuilder<E, T> {
+        KvServerBuilder {
+            engine,
+            pool,
+            addrs: vec![DEFAULT_ADDRESS.to_owned()],
+            max_connections: None,
+            max_connections_per_ip: None,
+            acceptors: 1,
+            idle_timeout: None,
+            request_timeout: None,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            shutdown_signal: None,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            tls: None,
+            authorizer: None,
+            reloader: None,
+            read_only: false,
+            codecs: vec![Codec::Json, Codec::Bincode],
+            metrics: false,
+            change_log: None,
+            monitor_rate: DEFAULT_MONITOR_RATE,
+            commands: HashMap::new(),
+        }
+    }
+
+    /// Returns a handle which shuts down the running server.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            token: self.shutdown.clone(),
+        }
+    }
+
+    /// Returns a handle which changes the settings of the running server.
+    pub fn reload_handle(&self) -> ReloadHandle {
+        ReloadHandle {
+            limits: self.settings.limits.clone(),
+        }
+    }
+
+    /// Returns the counts of connections and requests, if enabled with `KvServerBuilder::metrics`.
+    pub fn metrics(&self) -> Option<Arc<ServerMetrics>> {
+        self.settings.metrics.clone()
+    }
+
+    /// Run the server listening on its addresses
+    ///
+    /// Runs until a `ShutdownHandle`, the shutdown signal of the builder,
+    /// ctrl-c or SIGTERM stops it. The server then stops accepting
+    /// connections, lets each connection finish the requests it has read and
+    /// closes it, waiting at most the shutdown timeout.
+    pub fn run(&mut self) -> Result<()> {
+        let listeners = bind(&self.addrs, self.acceptors)?;
+        self.serve_blocking(listeners, Protocol::Json)
+    }
+
+    /// Binds the addresses of the server, then runs it on a thread of its own.
+    ///
+    /// The server is listening once this returns, and the handle tells the
+    /// address it got, so a server bound to port 0 gets a free port.
+    pub fn start(mut self) -> Result<RunningServer> {
+        let listeners = bind(&self.addrs, self.acceptors)?;
+        let addr = listeners
+            .iter()
+            .find_map(|listener| match listener {
+                Bound::Tcp(listener) => Some(listener.local_addr()),
+                #[cfg(unix)]
+                Bound::Unix(..) => None,
+            })
+            .transpose()?;
+        let shutdown = self.shutdown_handle();
+        let thread = thread::Builder::new()
+            .name("kv-server".to_owned())
+            .spawn(move || self.serve_blocking(listeners, Protocol::Json))?;
+        Ok(RunningServer {
+            addr,
+            shutdown,
+            thread,
+        })
+    }
+
+    /// Run the server listening on `addr` for Redis clients, speaking RESP2
+    /// instead of the JSON protocol.
+    ///
+    /// The server understands the GET, SET, DEL, EXISTS and PING commands,
+    /// with its settings, TLS and the authorization hook included: each
+    /// command is authorized as the requests doing the same. The commands of
+    /// a connection run one at a time, in order, as Redis clients expect.
+    /// Runs until stopped like `run`. To serve both protocols, run two
+    /// servers over clones of one engine.
+    pub fn run_resp(&mut self, addr: &str) -> Result<()> {
+        let listeners = bind(&[addr.to_owned()], self.acceptors)?;
+        self.serve_blocking(listeners, Protocol::Resp)
+    }
+
+    /// Runs the server on `listener`, on the tokio runtime of the caller.
+    ///
+    /// Unlike `run`, which blocks its thread on a runtime of its own, this
+    /// embeds the server in an async program. It leaves the signals of the
+    /// process to the program: a `ShutdownHandle` or the shutdown signal of
+    /// the builder stops it, as `run` stops. The addresses and acceptors of
+    /// the builder do not apply, the server accepting on the listener given.
+    pub async fn serve(mut self, listener: TcpListener) -> Result<()> {
+        self.serve_listeners(vec![Listener::Tcp(listener)], Protocol::Json, false)
+            .await?;
+        info!("server exited");
+        Ok(())
     }
 
-    /// Run the server listening on the given address
-    pub fn run(&mut self, addr: String, is_stop: Arc<AtomicBool>) -> Result<()> {
+    /// Runs the server on `listener` as a task of the tokio runtime of the
+    /// caller, like `serve`, returning a handle on the task.
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn spawn(self, listener: TcpListener) -> Result<ServerTask> {
+        let addr = listener.local_addr()?;
+        let shutdown = self.shutdown_handle();
+        let task = tokio::spawn(self.serve(listener));
+        Ok(ServerTask {
+            addr,
+            shutdown,
+            task,
+        })
+    }
+
+    fn serve_blocking(&mut self, listeners: Vec<Bound>, protocol: Protocol) -> Result<()> {
         let rt = tokio::runtime::Runtime::new()?;
         rt.block_on(async {
+            let listeners = listeners
+                .into_iter()
+                .map(Bound::listen)
+                .collect::<io::Result<Vec<_>>>()?;
+            self.serve_listeners(listeners, protocol, true).await
+        })?;
+        info!("server exited");
+        Ok(())
+    }
+
+    /// Serves `listeners` until the server stops, listening to ctrl-c and the
+    /// unix signals if `signals`.
+    async fn serve_listeners(
+        &mut self,
+        listeners: Vec<Listener>,
+        protocol: Protocol,
+        signals: bool,
+    ) -> Result<()> {
+        // cancelled once the server stops, closing the connections
+        let stop = self.shutdown.child_token();
+        // each connection holds a sender, so `recv` returns once all are closed
+        let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+        // an acceptor failing to accept stops the server
+        let (failed_tx, mut failed_rx) = mpsc::channel::<io::Error>(1);
+        let acceptor = Acceptor {
+            engine: self.engine.clone(),
+            commands: self.commands.clone(),
+            pool: self.pool.clone(),
+            settings: self.settings.clone(),
+            connections: self.connections.clone(),
+            stop: stop.clone(),
+            done_tx: done_tx.clone(),
+            protocol,
+        };
+        let mut acceptors = Vec::with_capacity(listeners.len());
+        for listener in listeners {
+            let acceptor = acceptor.clone();
+            let failed_tx = failed_tx.clone();
+            acceptors.push(tokio::spawn(async move {
+                if let Err(err) = acceptor.run(listener).await {
+                    let _ = failed_tx.send(err).await;
+                }
+            }));
+        }
+        drop(acceptor);
+        let ctrl_c = async {
+            if signals {
+                let _ = signal::ctrl_c().await;
+            } else {
+                std::future::pending::<()>().await
+            }
+        };
+        tokio::pin!(ctrl_c);
+        // without a reload hook, SIGHUP keeps its default action
+        let (mut hangups, mut terminations) = if signals {
+            unix_signals(self.settings.reloader.is_some())?
+        } else {
+            Default::default()
+        };
+        let shutdown_signal = self.shutdown_signal.take();
+        let shutdown_signal = async move {
+            match shutdown_signal {
+                Some(shutdown_signal) => shutdown_signal.await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(shutdown_signal);
+        loop {
             select! {
-                res = async {
-                    let listener = TcpListener::bind(addr).await?;
-                    loop {
-                        let (client, client_addr) = listener.accept().await?;
-                        if is_stop.load(Ordering::SeqCst) {
-                            break;
-                        }
-                        let engine = self.engine.clone();
-                        let pool = self.pool.clone();
-                        tokio::spawn(async move {
-                            if let Err(err) = handle_request(engine, client, pool).await {
-                                error!("failed to handle request from {}: {}", client_addr, err);
-                            }
-                        });
-                    }
+                Some(err) = failed_rx.recv() => {
+                    error!("server error: {}", err);
+                    break;
+                }
+                _ = stop.cancelled() => {
                     info!("server is stopping...");
-                    Ok::<_, std::io::Error>(())
-                } => {
-                    if let Err(err) = res {
-                        error!("server error: {}", err);
-                    }
+                    break;
                 }
-                _ = signal::ctrl_c() => {
+                _ = &mut ctrl_c => {
                     info!("receive ctrl-c, server is stopping...");
+                    break;
                 }
-            };
-        });
-        info!("server exited");
+                _ = next_signal(&mut terminations) => {
+                    info!("receive SIGTERM, server is stopping...");
+                    break;
+                }
+                _ = &mut shutdown_signal => {
+                    info!("shutdown signal resolved, server is stopping...");
+                    break;
+                }
+                _ = next_signal(&mut hangups) => {
+                    info!("receive SIGHUP, reloading the settings...");
+                    let reload = self.settings.reload_job();
+                    tokio::task::spawn_blocking(move || run_reload(reload));
+                }
+            }
+        }
+        stop.cancel();
+        // the acceptors drop their listeners as they stop
+        for acceptor in acceptors {
+            let _ = acceptor.await;
+        }
+        drop(done_tx);
+        // the connections close on their own once their deadline passes
+        let deadline = self.settings.shutdown_timeout + SHUTDOWN_GRACE;
+        if timeout(deadline, done_rx.recv()).await.is_err() {
+            warn!("dropping connections still open after {:?}", deadline);
+        }
         Ok(())
     }
 }
 
-async fn handle_request<E: KvEngine, T: ThreadPool>(
-    engine: E,
-    mut stream: TcpStream,
+/// Accepts the connections of a listener of a server, each served on a task of its own.
+#[derive(Clone)]
+struct Acceptor<E: KvEngine, T: ThreadPool> {
+    engine: Arc<E>,
+    commands: CustomCommands<E>,
+    pool: T,
+    settings: Arc<ConnectionSettings>,
+    connections: Option<Arc<Semaphore>>,
+    stop: CancellationToken,
+    done_tx: mpsc::Sender<()>,
+    protocol: Protocol,
+}
+
+impl<E: KvEngine, T: ThreadPool> Acceptor<E, T> {
+    /// Accepts the connections of `listener` until the server stops, or until
+    /// accepting one fails.
+    async fn run(mut self, listener: Listener) -> io::Result<()> {
+        let res = self.accept(&listener).await;
+        listener.close();
+        res
+    }
+
+    // by `&mut self`, as the pool of the server may not be `Sync`
+    async fn accept(&mut self, listener: &Listener) -> io::Result<()> {
+        loop {
+            let accept = async {
+                let permit = acquire(&self.connections).await;
+                let accepted = listener.accept().await?;
+                Ok::<_, io::Error>((accepted, permit))
+            };
+            let (accepted, permit) = select! {
+                biased;
+                _ = self.stop.cancelled() => return Ok(()),
+                res = accept => res?,
+            };
+            match accepted {
+                Accepted::Tcp(stream, client_addr) => {
+                    let Some(peer) = self.settings.peers.open(client_addr.ip()) else {
+                        warn!(
+                            "too many connections from {}, closing the connection",
+                            client_addr.ip()
+                        );
+                        if let Some(metrics) = &self.settings.metrics {
+                            metrics.refused_connections.fetch_add(1, Ordering::Relaxed);
+                        }
+                        continue;
+                    };
+                    self.spawn(stream, PeerAddr::Tcp(client_addr), permit, Some(peer));
+                }
+                #[cfg(unix)]
+                Accepted::Unix(stream) => self.spawn(stream, PeerAddr::Unix, permit, None),
+            }
+        }
+    }
+
+    /// Serves the connection of a client on a task of its own, holding its
+    /// connection slot and the count of its IP until it closes.
+    fn spawn<S>(
+        &self,
+        stream: S,
+        addr: PeerAddr,
+        permit: Option<OwnedSemaphorePermit>,
+        peer: Option<PeerGuard>,
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let engine = self.engine.clone();
+        let commands = self.commands.clone();
+        let pool = self.pool.clone();
+        let settings = self.settings.clone();
+        let stop = self.stop.child_token();
+        let registered = settings.clients.open(addr.to_string(), stop.clone());
+        let stream = Metered::new(stream, registered.state().clone());
+        let done_tx = self.done_tx.clone();
+        let protocol = self.protocol;
+        let span = info_span!("connection", peer = %addr, identity = field::Empty);
+        tokio::spawn(
+            async move {
+                let metrics = settings.metrics.clone();
+                if let Some(metrics) = &metrics {
+                    metrics.connections.fetch_add(1, Ordering::Relaxed);
+                    metrics.active_connections.fetch_add(1, Ordering::Relaxed);
+                }
+                let res = match &settings.tls {
+                    Some(tls) => match tls.accept(stream).await {
+                        Ok(stream) => {
+                            let identity = stream
+                                .get_ref()
+                                .1
+                                .peer_certificates()
+                                .and_then(ClientIdentity::from_certificates);
+                            if let Some(name) = identity.as_ref().and_then(|i| i.common_name()) {
+                                Span::current().record("identity", name);
+                                registered.state().set_name(name);
+                            }
+                            let client = Client {
+                                addr,
+                                identity,
+                                state: registered.state().clone(),
+                            };
+                            handle_connection(
+                                engine, commands, stream, pool, client, &settings, stop, protocol,
+                            )
+                            .await
+                        }
+                        Err(err) => Err(err.into()),
+                    },
+                    None => {
+                        let client = Client {
+                            addr,
+                            identity: None,
+                            state: registered.state().clone(),
+                        };
+                        handle_connection(
+                            engine, commands, stream, pool, client, &settings, stop, protocol,
+                        )
+                        .await
+                    }
+                };
+                if let Err(err) = res {
+                    error!("failed to handle request from {}: {}", addr, err);
+                }
+                if let Some(metrics) = &metrics {
+                    metrics.active_connections.fetch_sub(1, Ordering::Relaxed);
+                }
+                drop(registered);
+                drop(permit);
+                drop(peer);
+                drop(done_tx);
+            }
+            .instrument(span),
+        );
+    }
+}
+
+/// Binds `addrs`, for listeners served by tokio.
+fn bind(addrs: &[String], acceptors: usize) -> Result<Vec<Bound>> {
+    if addrs.is_empty() {
+        return Err(KvError::StringError("no address to listen on".to_owned()));
+    }
+    let mut listeners = Vec::new();
+    for addr in addrs {
+        match addr.strip_prefix(UNIX_PREFIX) {
+            Some(path) => listeners.push(bind_unix(Path::new(path))?),
+            None => listeners.extend(bind_tcp(addr, acceptors)?.into_iter().map(Bound::Tcp)),
+        }
+    }
+    Ok(listeners)
+}
+
+/// Binds `addr` with `acceptors` listeners.
+///
+/// Several listeners share the address with SO_REUSEPORT, the kernel
+/// spreading the connections among them, so the first one picks the port
+/// when `addr` has port 0.
+fn bind_tcp(addr: &str, acceptors: usize) -> Result<Vec<std::net::TcpListener>> {
+    let listener = if acceptors == 1 {
+        std::net::TcpListener::bind(addr)?
+    } else {
+        let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("no address for {}", addr),
+            )
+        })?;
+        bind_reuse_port(addr)?
+    };
+    let addr = listener.local_addr()?;
+    let mut listeners = vec![listener];
+    for _ in 1..acceptors {
+        listeners.push(bind_reuse_port(addr)?);
+    }
+    for listener in &listeners {
+        listener.set_nonblocking(true)?;
+    }
+    Ok(listeners)
+}
+
+/// Binds a unix socket at `path`, replacing a socket left there by a server
+/// which did not stop cleanly.
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> Result<Bound> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        fs::remove_file(path)?;
+    }
+    let listener = std::os::unix::net::UnixListener::bind(path)?;
+    listener.set_nonblocking(true)?;
+    Ok(Bound::Unix(listener, path.to_owned()))
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_path: &Path) -> Result<Bound> {
+    Err(KvError::Unsupported("unix sockets".to_owned()))
+}
+
+/// A listener bound by `bind`, served by tokio once a runtime runs.
+enum Bound {
+    Tcp(std::net::TcpListener),
+    // with the path of the socket
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener, PathBuf),
+}
+
+impl Bound {
+    /// Registers the listener with the runtime of the caller.
+    fn listen(self) -> io::Result<Listener> {
+        match self {
+            Bound::Tcp(listener) => Ok(Listener::Tcp(TcpListener::from_std(listener)?)),
+            #[cfg(unix)]
+            Bound::Unix(listener, path) => Ok(Listener::Unix(
+                tokio::net::UnixListener::from_std(listener)?,
+                path,
+            )),
+        }
+    }
+}
+
+/// A listener a server accepts the connections of its clients on.
+enum Listener {
+    Tcp(TcpListener),
+    // with the path of the socket, removed once the server stops
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, PathBuf),
+}
+
+impl Listener {
+    async fn accept(&self) -> io::Result<Accepted> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok(Accepted::Tcp(stream, addr))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                Ok(Accepted::Unix(stream))
+            }
+        }
+    }
+
+    /// Closes the listener, removing its unix socket if any.
+    fn close(self) {
+        #[cfg(unix)]
+        if let Listener::Unix(listener, path) = self {
+            drop(listener);
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// A connection accepted by a `Listener`.
+enum Accepted {
+    Tcp(tokio::net::TcpStream, SocketAddr),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+/// The address of a connected client.
+#[derive(Clone, Copy)]
+enum PeerAddr {
+    Tcp(SocketAddr),
+    // a unix socket, whose clients have no address
+    #[cfg(unix)]
+    Unix,
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Tcp(addr) => addr.fmt(f),
+            #[cfg(unix)]
+            PeerAddr::Unix => f.write_str("unix socket"),
+        }
+    }
+}
+
+/// Binds `addr` with SO_REUSEPORT, so other listeners may bind it too.
+#[cfg(unix)]
+fn bind_reuse_port(addr: SocketAddr) -> Result<std::net::TcpListener> {
+    use socket2::{Domain, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    Ok(socket.into())
+}
+
+#[cfg(not(unix))]
+fn bind_reuse_port(_addr: SocketAddr) -> Result<std::net::TcpListener> {
+    Err(KvError::Unsupported(
+        "several acceptors without SO_REUSEPORT".to_owned(),
+    ))
+}
+
+/// A unix signal a server listens to, if any.
+#[cfg(unix)]
+type UnixSignal = Option<signal::unix::Signal>;
+#[cfg(not(unix))]
+type UnixSignal = ();
+
+/// Listens to SIGHUP if `hangup`, and to SIGTERM, on unix only.
+#[cfg(unix)]
+fn unix_signals(hangup: bool) -> io::Result<(UnixSignal, UnixSignal)> {
+    use signal::unix::{signal, SignalKind};
+
+    let hangups = hangup.then(|| signal(SignalKind::hangup())).transpose()?;
+    let terminations = Some(signal(SignalKind::terminate())?);
+    Ok((hangups, terminations))
+}
+
+#[cfg(not(unix))]
+fn unix_signals(_hangup: bool) -> io::Result<(UnixSignal, UnixSignal)> {
+    Ok(((), ()))
+}
+
+/// Waits for the next `signal`, forever if the server does not listen to it.
+async fn next_signal(signal: &mut UnixSignal) {
+    #[cfg(unix)]
+    if let Some(signal) = signal {
+        if signal.recv().await.is_some() {
+            return;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = signal;
+    std::future::pending().await
+}
+
+/// Waits for a free connection slot, if connections are limited.
+async fn acquire(connections: &Option<Arc<Semaphore>>) -> Option<OwnedSemaphorePermit> {
+    match connections {
+        Some(connections) => Some(
+            connections
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("the semaphore is never closed"),
+        ),
+        None => None,
+    }
+}
+
+/// The connections open from each peer IP of a server.
+struct Peers {
+    // the most connections an IP may open, if limited
+    max_per_ip: Option<usize>,
+    counts: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl Peers {
+    /// Counts a new connection of `ip` until the returned guard drops, or
+    /// returns `None` if `ip` already has as many open as allowed.
+    fn open(self: &Arc<Self>, ip: IpAddr) -> Option<PeerGuard> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(ip).or_default();
+        if self.max_per_ip.is_some_and(|max| *count >= max) {
+            return None;
+        }
+        *count += 1;
+        Some(PeerGuard {
+            peers: self.clone(),
+            ip,
+        })
+    }
+
+    /// Returns the number of connections open from each IP, ordered by IP.
+    fn counts(&self) -> Vec<(IpAddr, usize)> {
+        let mut counts: Vec<_> = self
+            .counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(ip, count)| (*ip, *count))
+            .collect();
+        counts.sort();
+        counts
+    }
+}
+
+/// A connection counted among the ones of its IP, until dropped.
+struct PeerGuard {
+    peers: Arc<Peers>,
+    ip: IpAddr,
+}
+
+impl Drop for PeerGuard {
+    fn drop(&mut self) {
+        let mut counts = self.peers.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// A handle shutting down a `KvServer`, from any thread.
+///
+/// Shutting down before the server runs makes `run` return right away.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    token: CancellationToken,
+}
+
+impl ShutdownHandle {
+    /// Stops the server, whose `run` returns once its connections are closed.
+    pub fn shutdown(&self) {
+        self.token.cancel();
+    }
+}
+
+/// A `KvServer` running on a thread of its own, from `KvServer::start`.
+pub struct RunningServer {
+    // the address of the first TCP listener, if any
+    addr: Option<SocketAddr>,
+    shutdown: ShutdownHandle,
+    thread: thread::JoinHandle<Result<()>>,
+}
+
+impl RunningServer {
+    /// Returns the address the server listens on, with the port it got if bound to port 0.
+    ///
+    /// With several addresses, this is the first TCP one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the server only listens on unix sockets.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr.expect("the server only listens on unix sockets")
+    }
+
+    /// Returns a handle which shuts down the server.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Shuts down the server, returning once its connections are closed.
+    pub fn shutdown(self) -> Result<()> {
+        self.shutdown.shutdown();
+        self.join()
+    }
+
+    /// Waits for the server to stop, returning the error it stopped with if any.
+    pub fn join(self) -> Result<()> {
+        self.thread
+            .join()
+            .unwrap_or_else(|_| Err(KvError::StringError("the server panicked".to_owned())))
+    }
+}
+
+/// A `KvServer` running as a task of a tokio runtime, from `KvServer::spawn`.
+pub struct ServerTask {
+    addr: SocketAddr,
+    shutdown: ShutdownHandle,
+    task: tokio::task::JoinHandle<Result<()>>,
+}
+
+impl ServerTask {
+    /// Returns the address the server listens on, with the port it got if bound to port 0.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns a handle which shuts down the server.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Shuts down the server, returning once its connections are closed.
+    pub async fn shutdown(self) -> Result<()> {
+        self.shutdown.shutdown();
+        self.join().await
+    }
+
+    /// Waits for the server to stop, returning the error it stopped with if any.
+    pub async fn join(self) -> Result<()> {
+        self.task
+            .await
+            .unwrap_or_else(|_| Err(KvError::StringError("the server panicked".to_owned())))
+    }
+}
+
+/// A handle changing the settings of a `KvServer` as it runs, from any thread.
+///
+/// The open connections get the new settings too, each from its next
+/// request. The other settings of the builder only change with a restart.
+#[derive(Clone)]
+pub struct ReloadHandle {
+    limits: Arc<RwLock<Limits>>,
+}
+
+impl ReloadHandle {
+    /// Sets the idle timeout of the connections, like `KvServerBuilder::idle_timeout`,
+    /// removing it if `None`.
+    pub fn set_idle_timeout(&self, timeout: Option<Duration>) {
+        self.limits.write().unwrap().idle_timeout = timeout;
+    }
+
+    /// Sets the timeout of the requests, like `KvServerBuilder::request_timeout`,
+    /// removing it if `None`.
+    pub fn set_request_timeout(&self, timeout: Option<Duration>) {
+        self.limits.write().unwrap().request_timeout = timeout;
+    }
+
+    /// Sets the size of the largest request, like `KvServerBuilder::max_frame_size`.
+    pub fn set_max_frame_size(&self, max_frame_size: usize) {
+        self.limits.write().unwrap().max_frame_size = max_frame_size;
+    }
+
+    /// Sets how many requests of a connection run at once, like
+    /// `KvServerBuilder::max_in_flight`.
+    pub fn set_max_in_flight(&self, max_in_flight: usize) {
+        self.limits.write().unwrap().max_in_flight = max_in_flight.max(1);
+    }
+
+    /// Replaces the authorization hook, like `KvServerBuilder::authorize`.
+    pub fn authorize<F>(&self, authorizer: F)
+    where
+        F: Fn(Option<&ClientIdentity>, &Request) -> bool + Send + Sync + 'static,
+    {
+        self.limits.write().unwrap().authorizer = Some(Arc::new(authorizer));
+    }
+
+    /// Removes the authorization hook, so clients may run every request.
+    pub fn allow_all(&self) {
+        self.limits.write().unwrap().authorizer = None;
+    }
+}
+
+/// A connected client.
+struct Client {
+    addr: PeerAddr,
+    // the identity of the client, if it authenticated with a certificate
+    identity: Option<ClientIdentity>,
+    // what the client list admin command reports of the connection
+    state: Arc<ClientState>,
+}
+
+impl Client {
+    /// Records a request of operation `op` on `key` read from the client, for
+    /// the client list and the monitors of the server.
+    fn record(&self, op: &'static str, key: Option<&str>, settings: &ConnectionSettings) {
+        self.state.record(op);
+        settings.monitors.record(&self.addr, op, key);
+    }
+}
+
+/// The protocol a server speaks with its clients.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    // the JSON encoded `Request` and `Response`
+    Json,
+    // RESP2, for Redis clients
+    Resp,
+}
+
+impl Protocol {
+    /// Returns the frame telling a client speaking `codec` about `err`.
+    fn error_frame(self, codec: Codec, err: &KvError) -> Result<Vec<u8>> {
+        Ok(match self {
+            Protocol::Json => codec.encode(&ResponseFrame {
+                id: None,
+                response: Response::Err(format!("{}", err)),
+            })?,
+            Protocol::Resp => resp::Reply::error(err).encode(),
+        })
+    }
+}
+
+/// The frame answering a request, once the request completes.
+type Answer<'a> = Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send + 'a>>;
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_connection<E, T, S>(
+    engine: Arc<E>,
+    commands: CustomCommands<E>,
+    mut stream: S,
     pool: T,
-) -> Result<()> {
-    let client_addr = stream.peer_addr()?;
+    client: Client,
+    settings: &ConnectionSettings,
+    stop: CancellationToken,
+    protocol: Protocol,
+) -> Result<()>
+where
+    E: KvEngine,
+    T: ThreadPool,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let client_addr = client.addr;
     info!("handle request from {}", client_addr);
 
+    // bytes read but not parsed yet, which may hold the start of the next request
+    let mut buf = Vec::new();
+    // the requests running, whose answers are written as they complete
+    let mut in_flight = FuturesUnordered::<Answer>::new();
+    // the id of the next command of a Redis client, which does not number its commands
+    let mut next_id: u64 = 0;
+    // the messages of the channels the connection subscribed to, if any
+    let mut subscription = None;
+    // the bucket the requests of the connection run in, after `Request::Use`
+    let mut namespace = None;
+    let codec = match protocol {
+        Protocol::Json => match negotiate(&mut stream, &mut buf, settings, &stop).await? {
+            Some(codec) => codec,
+            None => return Ok(()),
+        },
+        Protocol::Resp => Codec::Json,
+    };
     loop {
-        let mut buf = Vec::new();
-        let n = stream.read_buf(&mut buf).await?;
-        if n == 0 {
-            info!("client {} closed", client_addr);
-            break;
+        // read again each time, as a reload may change them
+        let limits = settings.limits();
+        let max_in_flight = match protocol {
+            Protocol::Json => limits.max_in_flight,
+            // Redis clients expect the commands of a connection to run in order
+            // so one in flight at a time
+            Protocol::Resp => 1,
+        };
+        if in_flight.len() < max_in_flight {
+            let next = match protocol {
+                Protocol::Json => match codec.decode(&mut buf).map(|frame| {
+                    frame.map(|frame: RequestFrame| {
+                        client.record(frame.request.op(), frame.request.key(), settings);
+                        in_namespace(namespace.as_deref(), frame)
+                    })
+                }) {
+                    Ok(Some(RequestFrame {
+                        id,
+                        request: request @ (Request::Replicate | Request::ReplicateSnapshot),
+                    })) if settings.refusal(&client, &request).is_none() => {
+                        write_answers(&mut stream, &mut in_flight).await?;
+                        let snapshot = matches!(request, Request::ReplicateSnapshot);
+                        return replication::serve_replica(
+                            engine, stream, codec, id, snapshot, stop,
+                        )
+                        .await;
+                    }
+                    Ok(Some(RequestFrame {
+                        id,
+                        request: Request::Watch(prefix),
+                    })) if settings
+                        .refusal(&client, &Request::Watch(prefix.clone()))
+                        .is_none() =>
+                    {
+                        write_answers(&mut stream, &mut in_flight).await?;
+                        return watch::serve_watcher(engine, stream, codec, id, prefix, stop).await;
+                    }
+                    Ok(Some(RequestFrame {
+                        id,
+                        request: Request::Bucket(name, request),
+                    })) if matches!(*request, Request::Watch(_))
+                        && settings
+                            .refusal(&client, &Request::Bucket(name.clone(), request.clone()))
+                            .is_none() =>
+                    {
+                        let Request::Watch(prefix) = *request else {
+                            unreachable!("matched a watch");
+                        };
+                        match engine.bucket(&name) {
+                            Ok(bucket) => {
+                                write_answers(&mut stream, &mut in_flight).await?;
+                                let bucket = Arc::new(bucket);
+                                return watch::serve_watcher(
+                                    bucket, stream, codec, id, prefix, stop,
+                                )
+                                .await;
+                            }
+                            Err(err) => {
+                                let answer =
+                                    response_frame(codec, id, Response::Err(format!("{}", err)));
+                                Ok(Some(Box::pin(async move { answer }) as Answer))
+                            }
+                        }
+                    }
+                    Ok(Some(RequestFrame {
+                        id,
+                        request: Request::Changes(offset),
+                    })) if settings
+                        .refusal(&client, &Request::Changes(offset))
+                        .is_none() =>
+                    {
+                        write_answers(&mut stream, &mut in_flight).await?;
+                        let log = settings.change_log.clone();
+                        return change_log::serve_consumer(log, stream, codec, id, offset, stop)
+                            .await;
+                    }
+                    Ok(Some(RequestFrame {
+                        id,
+                        request: Request::Monitor,
+                    })) if settings.refusal(&client, &Request::Monitor).is_none() => {
+                        write_answers(&mut stream, &mut in_flight).await?;
+                        return monitor::serve_monitor(&settings.monitors, stream, codec, id, stop)
+                            .await;
+                    }
+                    Ok(Some(RequestFrame {
+                        id,
+                        request: Request::BulkLoad,
+                    })) if settings.refusal(&client, &Request::BulkLoad).is_none() => {
+                        write_answers(&mut stream, &mut in_flight).await?;
+                        let loaded = run_bulk_load(
+                            codec,
+                            id,
+                            &engine,
+                            &mut stream,
+                            &mut buf,
+                            pool.clone(),
+                            settings,
+                            &stop,
+                        )
+                        .await;
+                        match loaded {
+                            Ok(true) => continue,
+                            Ok(false) => return Ok(()),
+                            Err(err) => Err(err),
+                        }
+                    }
+                    Ok(Some(RequestFrame {
+                        id,
+                        request: Request::Bucket(name, request),
+                    })) if matches!(*request, Request::BulkLoad)
+                        && settings
+                            .refusal(&client, &Request::Bucket(name.clone(), request.clone()))
+                            .is_none() =>
+                    {
+                        match engine.bucket(&name) {
+                            Ok(bucket) => {
+                                write_answers(&mut stream, &mut in_flight).await?;
+                                let loaded = run_bulk_load(
+                                    codec,
+                                    id,
+                                    &Arc::new(bucket),
+                                    &mut stream,
+                                    &mut buf,
+                                    pool.clone(),
+                                    settings,
+                                    &stop,
+                                )
+                                .await;
+                                match loaded {
+                                    Ok(true) => continue,
+                                    Ok(false) => return Ok(()),
+                                    Err(err) => Err(err),
+                                }
+                            }
+                            Err(err) => {
+                                let answer =
+                                    response_frame(codec, id, Response::Err(format!("{}", err)));
+                                Ok(Some(Box::pin(async move { answer }) as Answer))
+                            }
+                        }
+                    }
+                    Ok(Some(RequestFrame {
+                        id,
+                        request: request @ (Request::Subscribe(_) | Request::Publish(..)),
+                    })) => {
+                        let answer =
+                            run_pubsub(codec, id, request, &client, settings, &mut subscription);
+                        Ok(Some(Box::pin(async move { answer }) as Answer))
+                    }
+                    Ok(Some(RequestFrame {
+                        id,
+                        request: Request::Use(name),
+                    })) => {
+                        let answer = run_use(codec, id, name, &client, settings, &mut namespace);
+                        Ok(Some(Box::pin(async move { answer }) as Answer))
+                    }
+                    next => next.map(|frame| {
+                        frame.map(|RequestFrame { id, request }| {
+                            let answer = run_request(
+                                codec,
+                                id,
+                                request,
+                                engine.clone(),
+                                commands.clone(),
+                                &pool,
+                                &client,
+                                settings,
+                            );
+                            Box::pin(answer) as Answer
+                        })
+                    }),
+                },
+                Protocol::Resp => resp::next_command(&mut buf).map(|command| {
+                    command.map(|command| match command {
+                        Ok(command) => {
+                            client.record(command.name(), command.key(), settings);
+                            let answer = run_command(
+                                next_id,
+                                command,
+                                engine.clone(),
+                                &pool,
+                                &client,
+                                settings,
+                            );
+                            Box::pin(answer) as Answer
+                        }
+                        Err(message) => {
+                            let reply = resp::Reply::Error(message).encode();
+                            Box::pin(async move { Ok(reply) }) as Answer
+                        }
+                    })
+                }),
+            };
+            let err = match next {
+                Ok(Some(answer)) => {
+                    next_id += 1;
+                    in_flight.push(answer);
+                    continue;
+                }
+                Ok(None) if buf.len() > limits.max_frame_size => {
+                    Some(KvError::FrameTooLarge(limits.max_frame_size))
+                }
+                Ok(None) => None,
+                Err(err) => Some(err),
+            };
+            if let Some(err) = err {
+                write_answers(&mut stream, &mut in_flight).await?;
+                write_frame(&mut stream, &protocol.error_frame(codec, &err)?).await?;
+                return Err(err);
+            }
         }
-        let request: Request = serde_json::from_slice(&buf[..n])?;
 
-        let (tx, rx) = oneshot::channel();
+        let reading = in_flight.len() < max_in_flight;
+        if reading {
+            buf.reserve(READ_BUFFER_SIZE);
+        }
+        // a connection waiting on its requests or messages is not idle
+        let idle_timeout = limits
+            .idle_timeout
+            .filter(|_| in_flight.is_empty() && subscription.is_none());
+        let read = read_some(&mut stream, &mut buf, idle_timeout);
+        select! {
+            biased;
+            _ = stop.cancelled() => {
+                if client.state.is_killed() {
+                    info!("killed connection of {}", client_addr);
+                    break;
+                }
+                info!("closing connection of {}", client_addr);
+                drain(&mut stream, &mut in_flight, codec, protocol, settings).await?;
+                stream.shutdown().await?;
+                break;
+            }
+            Some(answer) = in_flight.next() => {
+                write_frame(&mut stream, &answer?).await?;
+            }
+            (channel, message) = next_message(&mut subscription) => {
+                let frame = codec.encode(&ResponseFrame {
+                    id: None,
+                    response: Response::Message(channel, message),
+                })?;
+                write_frame(&mut stream, &frame).await?;
+            }
+            n = read, if reading => {
+                if n? == 0 {
+                    info!("client {} closed", client_addr);
+                    write_answers(&mut stream, &mut in_flight).await?;
+                    break;
+                }
+            }
+        }
+    }
 
-        let mut engine = engine.clone();
-        pool.spawn(move || {
-            let resp = match request {
-                Request::Get(key) => match engine.get(key) {
-                    Ok(value) => Response::Ok(value),
-                    Err(err) => Response::Err(format!("{}", err)),
-                },
-                Request::Set(key, value) => match engine.set(key, value) {
-                    Ok(_) => Response::Ok(None),
-                    Err(err) => Response::Err(format!("{}", err)),
-                },
-                Request::Remove(key) => match engine.remove(key) {
-                    Ok(_) => Response::Ok(None),
-                    Err(err) => Response::Err(format!("{}", err)),
+    Ok(())
+}
+
+/// Spawns `request`, the request numbered `id` of the connection, returning
+/// a future of its response encoded with `codec`.
+#[allow(clippy::too_many_arguments)]
+fn run_request<'a, E, T>(
+    codec: Codec,
+    id: u64,
+    request: Request,
+    engine: Arc<E>,
+    commands: CustomCommands<E>,
+    pool: &T,
+    client: &Client,
+    settings: &'a ConnectionSettings,
+) -> impl Future<Output = Result<Vec<u8>>> + 'a
+where
+    E: KvEngine,
+    T: ThreadPool,
+{
+    let op = request.op();
+    let span = debug_span!(
+        "request",
+        id,
+        op,
+        key = request.key(),
+        latency_us = field::Empty,
+        outcome = field::Empty,
+    );
+    let started = Instant::now();
+    // a reload runs the hook of the server rather than a command of the engine,
+    // and the connections and the info are the ones of the server
+    let reload = match request {
+        Request::Admin(AdminCommand::Reload) => Some(settings.reload_job()),
+        _ => None,
+    };
+    let peers = match request {
+        Request::Admin(AdminCommand::Connections) => Some(settings.peers.clone()),
+        _ => None,
+    };
+    let clients = match request {
+        Request::Admin(command @ (AdminCommand::ClientList | AdminCommand::ClientKill(_))) => {
+            Some((settings.clients.clone(), command))
+        }
+        _ => None,
+    };
+    let info = match request {
+        Request::Info => Some(settings.info_job()),
+        _ => None,
+    };
+    let refusal = settings.refusal(client, &request);
+    let namespaces: Vec<String> = request
+        .namespaces()
+        .into_iter()
+        .map(str::to_owned)
+        .collect();
+    // a ping, and a get the engine answers from memory, skip the round trip to the thread pool
+    let answered = match &request {
+        _ if refusal.is_some() => None,
+        Request::Ping(payload) => Some(Response::Pong(payload.clone())),
+        Request::Get(key) => engine.get_in_memory(key).map(|value| match value {
+            Ok(value) => Response::Ok(value),
+            Err(err) => Response::Err(format!("{}", err.with_op("get", Some(key)))),
+        }),
+        _ => None,
+    };
+    // spawned right away, so the requests of a connection run concurrently
+    let job = match (refusal, answered) {
+        (Some(err), _) => Err(err),
+        (None, Some(resp)) => Ok(Either::Left(future::ready(Ok(Ok(resp))))),
+        (None, None) => spawn_job(
+            pool,
+            &span,
+            settings.limits().request_timeout,
+            move || match (reload, peers, clients, info) {
+                (Some(reload), ..) => run_reload(reload),
+                (_, Some(peers), ..) => Response::Connections(peers.counts()),
+                (_, _, Some((clients, command)), _) => run_clients(&clients, command),
+                (_, _, _, Some(info)) => match info(&*engine) {
+                    Ok(info) => Response::Info(info),
+                    Err(err) => Response::Err(format!("{}", err.with_op("info", None))),
                 },
+                (None, None, None, None) => execute(&*engine, &commands, request),
+            },
+        )
+        .map(Either::Right),
+    };
+
+    async move {
+        let (resp, outcome) = match job {
+            Ok(job) => match job.await? {
+                Ok(resp @ Response::Err(_)) => (resp, "error"),
+                Ok(resp) => (resp, "ok"),
+                Err(elapsed) => (Response::Timeout(elapsed), "timeout"),
+            },
+            Err(KvError::Busy) => (Response::Busy, "busy"),
+            Err(err) => (Response::Err(format!("{}", err)), "denied"),
+        };
+        record_outcome(
+            &span,
+            started,
+            op,
+            outcome,
+            found(op, &resp),
+            &namespaces,
+            settings,
+        );
+        response_frame(codec, id, resp)
+    }
+}
+
+/// Runs `Request::Use(name)`, the request numbered `id` of the connection,
+/// which then runs its requests in the namespace `name` until the next one.
+///
+/// The namespace belongs to the connection, so the request runs right away
+/// instead of on the pool. A request read before it keeps its namespace.
+fn run_use(
+    codec: Codec,
+    id: u64,
+    name: Option<String>,
+    client: &Client,
+    settings: &ConnectionSettings,
+    namespace: &mut Option<String>,
+) -> Result<Vec<u8>> {
+    let request = Request::Use(name);
+    let span = debug_span!(
+        "request",
+        id,
+        op = request.op(),
+        key = request.key(),
+        latency_us = field::Empty,
+        outcome = field::Empty,
+    );
+    let started = Instant::now();
+    let namespaces: Vec<String> = request
+        .namespaces()
+        .into_iter()
+        .map(str::to_owned)
+        .collect();
+    let refusal = settings
+        .refusal(client, &request)
+        .or_else(|| match &request {
+            Request::Use(Some(name)) => check_bucket_name(name).err(),
+            _ => None,
+        });
+    let (resp, outcome) = match refusal {
+        Some(err) => (Response::Err(format!("{}", err)), "denied"),
+        None => {
+            let Request::Use(name) = request else {
+                unreachable!("not a use request")
             };
-            if tx.send(resp).is_err() {
-                error!("Receiving end is dropped");
+            *namespace = name;
+            (Response::Ok(None), "ok")
+        }
+    };
+    record_outcome(&span, started, "use", outcome, None, &namespaces, settings);
+    response_frame(codec, id, resp)
+}
+
+/// Returns `frame` with its request wrapped into a bucket request of
+/// `namespace`, the namespace of the connection, unless the request belongs
+/// to the server rather than a keyspace.
+fn in_namespace(namespace: Option<&str>, frame: RequestFrame) -> RequestFrame {
+    let RequestFrame { id, request } = frame;
+    let request = match (namespace, request) {
+        (
+            _,
+            request @ (Request::Ping(_)
+            | Request::Use(_)
+            | Request::Info
+            | Request::Monitor
+            | Request::Replicate
+            | Request::ReplicateSnapshot
+            | Request::Changes(_)
+            | Request::Subscribe(_)
+            | Request::Publish(..)
+            | Request::Admin(
+                AdminCommand::Reload
+                | AdminCommand::Connections
+                | AdminCommand::ClientList
+                | AdminCommand::ClientKill(_),
+            )),
+        )
+        | (None, request) => request,
+        (Some(namespace), request) => Request::Bucket(namespace.to_owned(), Box::new(request)),
+    };
+    RequestFrame { id, request }
+}
+
+/// Runs `request`, the pub/sub request numbered `id` of the connection,
+/// whose messages then come from `subscription`.
+///
+/// Channels belong to the server rather than the engine, so the request
+/// runs right away instead of on the pool.
+fn run_pubsub(
+    codec: Codec,
+    id: u64,
+    request: Request,
+    client: &Client,
+    settings: &ConnectionSettings,
+    subscription: &mut Option<Subscription>,
+) -> Result<Vec<u8>> {
+    let op = request.op();
+    let span = debug_span!(
+        "request",
+        id,
+        op,
+        key = request.key(),
+        latency_us = field::Empty,
+        outcome = field::Empty,
+    );
+    let started = Instant::now();
+    let (resp, outcome) = match settings.refusal(client, &request) {
+        Some(err) => (Response::Err(format!("{}", err)), "denied"),
+        None => match request {
+            Request::Subscribe(channel) => {
+                let subscription = subscription.get_or_insert_with(Subscription::new);
+                settings.broker.subscribe(channel, subscription);
+                (Response::Ok(None), "ok")
             }
-        });
+            Request::Publish(channel, message) => {
+                let receivers = settings.broker.publish(&channel, message);
+                (Response::Receivers(receivers), "ok")
+            }
+            _ => unreachable!("not a pub/sub request"),
+        },
+    };
+    record_outcome(&span, started, op, outcome, None, &[], settings);
+    response_frame(codec, id, resp)
+}
+
+/// Runs the bulk load started by the request numbered `id` of the connection:
+/// acknowledges the request, then applies the pairs of each frame as a batch
+/// until the end frame, answered with the summary of the load.
+///
+/// Batches are synced to the disk once at the end rather than one by one.
+/// Once a batch fails, the following frames are read but not applied. Returns
+/// whether the connection goes on, which it does not once the client closes
+/// it or the server stops.
+#[allow(clippy::too_many_arguments)]
+async fn run_bulk_load<E, T, S>(
+    codec: Codec,
+    id: u64,
+    engine: &Arc<E>,
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    pool: T,
+    settings: &ConnectionSettings,
+    stop: &CancellationToken,
+) -> Result<bool>
+where
+    E: KvEngine,
+    T: ThreadPool,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let span = debug_span!(
+        "request",
+        id,
+        op = "bulk_load",
+        latency_us = field::Empty,
+        outcome = field::Empty,
+    );
+    let started = Instant::now();
+    write_frame(stream, &response_frame(codec, id, Response::Ok(None))?).await?;
+    let mut summary = LoadSummary {
+        keys: 0,
+        batches: 0,
+        elapsed: Duration::ZERO,
+    };
+    let mut failure = None;
+    loop {
+        let pairs = match codec.decode(buf)? {
+            Some(BulkFrame::Pairs(pairs)) => pairs,
+            Some(BulkFrame::End) => break,
+            None if buf.len() > settings.limits().max_frame_size => {
+                return Err(KvError::FrameTooLarge(settings.limits().max_frame_size));
+            }
+            None => {
+                buf.reserve(READ_BUFFER_SIZE);
+                let n = select! {
+                    biased;
+                    _ = stop.cancelled() => {
+                        stream.shutdown().await?;
+                        return Ok(false);
+                    }
+                    n = read_some(stream, buf, settings.limits().idle_timeout) => n?,
+                };
+                if n == 0 {
+                    info!("client closed during a bulk load");
+                    return Ok(false);
+                }
+                continue;
+            }
+        };
+        if failure.is_some() {
+            continue;
+        }
+        let len = pairs.len();
+        let mut batch = WriteBatch::new();
+        for (key, value) in pairs {
+            batch.put(key, value);
+        }
+        let engine = engine.clone();
+        let job = move || engine.write_batch_unsynced(batch);
+        match wait_job(spawn_job(
+            &pool,
+            &span,
+            settings.limits().request_timeout,
+            job,
+        ))
+        .await?
+        {
+            Ok(()) => {
+                summary.keys += len;
+                summary.batches += 1;
+            }
+            Err(err) => failure = Some(err),
+        }
+    }
+    if failure.is_none() {
+        let engine = engine.clone();
+        let job = move || engine.sync();
+        failure = wait_job(spawn_job(
+            &pool,
+            &span,
+            settings.limits().request_timeout,
+            job,
+        ))
+        .await?
+        .err();
+    }
+
+    summary.elapsed = started.elapsed();
+    let (resp, outcome) = match failure {
+        None => (Response::Loaded(summary), "ok"),
+        Some(err) => {
+            let message = format!("{} after loading {} keys", err, summary.keys);
+            (Response::Err(message), "error")
+        }
+    };
+    record_outcome(&span, started, "bulk_load", outcome, None, &[], settings);
+    write_frame(stream, &response_frame(codec, id, resp)?).await?;
+    Ok(true)
+}
+
+/// Waits for the next message of `subscription`, forever if the connection
+/// did not subscribe.
+async fn next_message(subscription: &mut Option<Subscription>) -> Message {
+    match subscription {
+        Some(subscription) => subscription.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Spawns `command`, the command numbered `id` of a Redis client, returning
+/// a future of its reply.
+fn run_command<'a, E, T>(
+    id: u64,
+    command: resp::Command,
+    engine: Arc<E>,
+    pool: &T,
+    client: &Client,
+    settings: &'a ConnectionSettings,
+) -> impl Future<Output = Result<Vec<u8>>> + 'a
+where
+    E: KvEngine,
+    T: ThreadPool,
+{
+    let op = command.name();
+    let span = debug_span!(
+        "request",
+        id,
+        op,
+        key = command.key(),
+        latency_us = field::Empty,
+        outcome = field::Empty,
+    );
+    let started = Instant::now();
+    let refusal = command
+        .requests()
+        .iter()
+        .find_map(|request| settings.refusal(client, request));
+    let job = match refusal {
+        Some(err) => Err(err),
+        None => spawn_job(pool, &span, settings.limits().request_timeout, move || {
+            command.execute(&*engine)
+        }),
+    };
+
+    async move {
+        let (reply, outcome) = match job {
+            Ok(job) => match job.await? {
+                Ok(reply @ resp::Reply::Error(_)) => (reply, "error"),
+                Ok(reply) => (reply, "ok"),
+                Err(elapsed) => (
+                    resp::Reply::error(&KvError::RequestTimeout(elapsed)),
+                    "timeout",
+                ),
+            },
+            Err(err @ KvError::Busy) => (resp::Reply::error(&err), "busy"),
+            Err(err) => (resp::Reply::error(&err), "denied"),
+        };
+        let found = match (op, &reply) {
+            ("get", resp::Reply::Bulk(value)) => Some(value.is_some()),
+            _ => None,
+        };
+        record_outcome(&span, started, op, outcome, found, &[], settings);
+        Ok(reply.encode())
+    }
+}
+
+/// Runs `job` on `pool` within `span`, returning a future of its result, or
+/// of the timeout once the job takes longer than `request_timeout`.
+///
+/// Fails with `KvError::Busy` if the queue of the pool is full.
+fn spawn_job<T, F, R>(
+    pool: &T,
+    span: &Span,
+    request_timeout: Option<Duration>,
+    job: F,
+) -> Result<impl Future<Output = Result<std::result::Result<R, Duration>>> + Send + 'static>
+where
+    T: ThreadPool,
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let job_span = span.clone();
+    let handle = pool.try_spawn_with_handle(move || {
+        let _entered = job_span.enter();
+        job()
+    })?;
+
+    let span = span.clone();
+    Ok(async move {
+        let handle = handle.instrument(span.clone());
+        let res = match request_timeout {
+            Some(request_timeout) => match timeout(request_timeout, handle).await {
+                Ok(res) => res,
+                Err(_) => {
+                    // the job is still running, and its result is dropped
+                    warn!(parent: &span, "request timed out after {:?}", request_timeout);
+                    return Ok(Err(request_timeout));
+                }
+            },
+            None => handle.await,
+        };
+        res.map(Ok)
+            .map_err(|_| KvError::StringError("the request panicked".to_owned()))
+    })
+}
+
+/// Waits for `job`, a job of a bulk load, turning a full pool or a timeout
+/// into its error.
+async fn wait_job<F, R>(job: Result<F>) -> Result<Result<R>>
+where
+    F: Future<Output = Result<std::result::Result<Result<R>, Duration>>>,
+{
+    match job {
+        Ok(job) => Ok(job
+            .await?
+            .unwrap_or_else(|elapsed| Err(KvError::RequestTimeout(elapsed)))),
+        Err(err) => Ok(Err(err)),
+    }
+}
+
+/// Records how a request of operation `op` went on its span and in the
+/// metrics of the server, also in the metrics of the `namespaces` it ran in.
+///
+/// `found` tells whether a lookup found its key, `None` for the other requests.
+fn record_outcome(
+    span: &Span,
+    started: Instant,
+    op: &'static str,
+    outcome: &str,
+    found: Option<bool>,
+    namespaces: &[String],
+    settings: &ConnectionSettings,
+) {
+    let latency = started.elapsed();
+    span.record("latency_us", latency.as_micros() as u64);
+    span.record("outcome", outcome);
+    debug!(parent: span, "request handled");
+    if let Some(metrics) = &settings.metrics {
+        {
+            let mut ops = metrics.ops.lock().unwrap();
+            let stats = ops.entry(op).or_default();
+            stats.requests += 1;
+            if outcome != "ok" {
+                stats.failed_requests += 1;
+            }
+            match found {
+                Some(true) => stats.hits += 1,
+                Some(false) => stats.misses += 1,
+                None => {}
+            }
+            stats.latency.record(latency);
+        }
+        metrics.requests.fetch_add(1, Ordering::Relaxed);
+        if outcome != "ok" {
+            metrics.failed_requests.fetch_add(1, Ordering::Relaxed);
+        }
+        if outcome == "busy" {
+            metrics.busy_requests.fetch_add(1, Ordering::Relaxed);
+        }
+        if !namespaces.is_empty() {
+            let mut counts = metrics.namespaces.lock().unwrap();
+            for namespace in namespaces {
+                let counts = counts.entry(namespace.clone()).or_default();
+                counts.requests += 1;
+                if outcome != "ok" {
+                    counts.failed_requests += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Returns whether a lookup of operation `op` found its key, from its
+/// response, `None` for the other requests and the failed lookups.
+fn found(op: &str, resp: &Response) -> Option<bool> {
+    match (op, resp) {
+        ("get", Response::Ok(value)) => Some(value.is_some()),
+        ("get_bytes", Response::Bytes(value)) => Some(value.is_some()),
+        _ => None,
+    }
+}
+
+/// Tells the client of a connection speaking JSON that the server is shutting
+/// down, then writes the answers of the requests in flight until the shutdown
+/// timeout, dropping the ones still running.
+async fn drain<S>(
+    stream: &mut S,
+    in_flight: &mut FuturesUnordered<Answer<'_>>,
+    codec: Codec,
+    protocol: Protocol,
+    settings: &ConnectionSettings,
+) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    // a Redis client has no frame for it
+    if protocol == Protocol::Json {
+        let frame = codec.encode(&ResponseFrame {
+            id: None,
+            response: Response::ShuttingDown,
+        })?;
+        write_frame(stream, &frame).await?;
+    }
+    let deadline = settings.shutdown_timeout;
+    if timeout(deadline, write_answers(stream, in_flight))
+        .await
+        .is_err()
+    {
+        warn!(
+            "dropping {} requests still running after {:?}",
+            in_flight.len(),
+            deadline
+        );
+    }
+    Ok(())
+}
+
+/// Waits for the requests in flight, writing their answers.
+async fn write_answers<S>(
+    stream: &mut S,
+    in_flight: &mut FuturesUnordered<Answer<'_>>,
+) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    while let Some(answer) = in_flight.next().await {
+        write_frame(stream, &answer?).await?;
+    }
+    Ok(())
+}
 
-        let resp = rx
+/// Reads more bytes of `stream` into `buf`, failing if none come within `idle_timeout`.
+async fn read_some<S>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    idle_timeout: Option<Duration>,
+) -> io::Result<usize>
+where
+    S: AsyncRead + Unpin,
+{
+    match idle_timeout {
+        Some(idle_timeout) => timeout(idle_timeout, stream.read_buf(buf))
             .await
-            .map_err(|e| KvError::StringError(format!("{}", e)))?;
-        let data = serde_json::to_vec(&resp)?;
-        stream.write_all(&data).await?;
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+        None => stream.read_buf(buf).await,
+    }
+}
+
+/// Reads the preamble a client may start its connection with, returning the
+/// codec of the following frames, or `None` if the connection ends first.
+///
+/// A client starting with a frame speaks JSON. A codec the server does not
+/// accept is refused with an error frame in JSON, which the client can read
+/// whatever codec it asked for.
+async fn negotiate<S>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    settings: &ConnectionSettings,
+    stop: &CancellationToken,
+) -> Result<Option<Codec>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        if !buf.is_empty() && !Codec::is_preamble(buf) {
+            return Ok(Some(Codec::Json));
+        }
+        if buf.len() >= PREAMBLE_LEN {
+            let negotiated = Codec::from_preamble(&buf[..PREAMBLE_LEN]).and_then(|codec| {
+                if codec == Codec::Json || settings.codecs.contains(&codec) {
+                    Ok(codec)
+                } else {
+                    Err(KvError::Unsupported(format!("{} codec", codec.name())))
+                }
+            });
+            return match negotiated {
+                Ok(codec) => {
+                    buf.drain(..PREAMBLE_LEN);
+                    write_frame(stream, &codec.preamble()).await?;
+                    debug!("speaking {}", codec.name());
+                    Ok(Some(codec))
+                }
+                Err(err) => {
+                    let frame = Protocol::Json.error_frame(Codec::Json, &err)?;
+                    write_frame(stream, &frame).await?;
+                    Err(err)
+                }
+            };
+        }
+        buf.reserve(READ_BUFFER_SIZE);
+        let n = select! {
+            biased;
+            _ = stop.cancelled() => {
+                stream.shutdown().await?;
+                return Ok(None);
+            }
+            n = read_some(stream, buf, settings.limits().idle_timeout) => n?,
+        };
+        if n == 0 {
+            return Ok(None);
+        }
     }
+}
 
+/// Returns the frame answering the request numbered `id` with `response`.
+fn response_frame(codec: Codec, id: u64, response: Response) -> Result<Vec<u8>> {
+    codec.encode(&ResponseFrame {
+        id: Some(id),
+        response,
+    })
+}
+
+async fn write_frame<S: AsyncWrite + Unpin>(stream: &mut S, data: &[u8]) -> Result<()> {
+    stream.write_all(data).await?;
+    stream.flush().await?;
     Ok(())
 }
+
+/// Runs `request` against `engine`, with the custom `commands` of the server.
+fn execute<E: KvEngine>(
+    engine: &E,
+    commands: &HashMap<String, CustomHandler<E>>,
+    request: Request,
+) -> Response {
+    // the client only sees the message, so it tells which operation and key failed
+    let op = request.op();
+    let key = request.key().map(str::to_owned);
+    let failed = |err: KvError| Response::Err(format!("{}", err.with_op(op, key.as_deref())));
+    match request {
+        Request::Get(key) => match engine.get(key) {
+            Ok(value) => Response::Ok(value),
+            Err(err) => failed(err),
+        },
+        Request::Set(key, value) => match engine.set(key, value) {
+            Ok(_) => Response::Ok(None),
+            Err(err) => failed(err),
+        },
+        Request::Remove(key) => match engine.remove(key) {
+            Ok(_) => Response::Ok(None),
+            Err(err) => failed(err),
+        },
+        Request::Expire(key, value, ttl) => match engine.set_with_ttl(key, value, ttl) {
+            Ok(_) => Response::Ok(None),
+            Err(err) => failed(err),
+        },
+        Request::WriteBatch(batch) => match engine.write_batch(batch) {
+            Ok(_) => Response::Ok(None),
+            Err(err) => failed(err),
+        },
+        Request::Transaction(reads, batch) => match engine.write_batch_if(reads, batch) {
+            Ok(()) => Response::Bool(true),
+            Err(KvError::TransactionConflict) => Response::Bool(false),
+            Err(err) => failed(err),
+        },
+        Request::Ttl(key) => match engine.ttl(key) {
+            Ok(ttl) => Response::Ttl(ttl),
+            Err(err) => failed(err),
+        },
+        Request::SetBytes(key, value) => match engine.set_bytes(key, value) {
+            Ok(_) => Response::Ok(None),
+            Err(err) => failed(err),
+        },
+        Request::GetBytes(key) => match engine.get_bytes(key) {
+            Ok(value) => Response::Bytes(value),
+            Err(err) => failed(err),
+        },
+        Request::Len => match engine.len() {
+            Ok(len) => Response::Len(len),
+            Err(err) => failed(err),
+        },
+        Request::Incr(key, delta) => match engine.incr(key, delta) {
+            Ok(value) => Response::Int(value),
+            Err(err) => failed(err),
+        },
+        Request::SetIfAbsent(key, value) => match engine.set_if_absent(key, value) {
+            Ok(set) => Response::Bool(set),
+            Err(err) => failed(err),
+        },
+        Request::SetIfPresent(key, value) => match engine.set_if_present(key, value) {
+            Ok(set) => Response::Bool(set),
+            Err(err) => failed(err),
+        },
+        Request::MultiGet(keys) => match engine.multi_get(keys) {
+            Ok(values) => Response::Values(values),
+            Err(err) => failed(err),
+        },
+        Request::Scan {
+            cursor,
+            count,
+            pattern,
+        } => match scan_page(engine, cursor, count, pattern.as_deref()) {
+            Ok((keys, cursor)) => Response::Keys(keys, cursor),
+            Err(err) => failed(err),
+        },
+        Request::Admin(command) => run_admin(engine, command),
+        Request::Bucket(name, request) => match engine.bucket(&name) {
+            Ok(bucket) => execute(&bucket, commands, *request),
+            Err(err) => failed(err),
+        },
+        Request::Batch(requests) => Response::Batch(execute_batch(engine, commands, requests)),
+        Request::Ping(payload) => Response::Pong(payload),
+        Request::Custom(name, payload) => {
+            let result = match commands.get(&name) {
+                Some(handler) => handler(engine, payload),
+                None => Err(KvError::Unsupported(format!("custom command {}", name))),
+            };
+            match result {
+                Ok(value) => Response::Ok(value),
+                Err(err) => failed(err),
+            }
+        }
+        // these take over the connection or belong to the server, not a keyspace
+        request @ (Request::Replicate
+        | Request::Subscribe(_)
+        | Request::Publish(..)
+        | Request::Watch(_)
+        | Request::Changes(_)
+        | Request::ReplicateSnapshot
+        | Request::Use(_)
+        | Request::Info
+        | Request::Monitor
+        | Request::BulkLoad) => {
+            Response::Err(format!("{}", KvError::Unsupported(request.op().to_owned())))
+        }
+    }
+}
+
+/// Runs `requests` against `engine` in order, returning their responses.
+///
+/// Each run of sets and removes commits as one write batch. If the batch
+/// fails, which writes nothing, its requests run one by one instead, so
+/// each one gets its own answer.
+fn execute_batch<E: KvEngine>(
+    engine: &E,
+    commands: &HashMap<String, CustomHandler<E>>,
+    requests: Vec<Request>,
+) -> Vec<Response> {
+    let mut resps = Vec::with_capacity(requests.len());
+    let mut writes = Vec::new();
+    for request in requests {
+        match request {
+            request @ (Request::Set(..) | Request::Remove(_)) => writes.push(request),
+            request => {
+                execute_writes(engine, commands, mem::take(&mut writes), &mut resps);
+                resps.push(match request {
+                    // these belong to the connection or to the server
+                    request @ (Request::Batch(_)
+                    | Request::Admin(
+                        AdminCommand::Reload
+                        | AdminCommand::Connections
+                        | AdminCommand::ClientList
+                        | AdminCommand::ClientKill(_),
+                    )) => {
+                        let op = format!("{} in a batch", request.op());
+                        Response::Err(format!("{}", KvError::Unsupported(op)))
+                    }
+                    request => execute(engine, commands, request),
+                });
+            }
+        }
+    }
+    execute_writes(engine, commands, writes, &mut resps);
+    resps
+}
+
+/// Runs `writes`, sets and removes of a batch, as one write batch, pushing their responses.
+fn execute_writes<E: KvEngine>(
+    engine: &E,
+    commands: &HashMap<String, CustomHandler<E>>,
+    writes: Vec<Request>,
+    resps: &mut Vec<Response>,
+) {
+    if writes.len() > 1 {
+        let mut batch = WriteBatch::new();
+        for request in &writes {
+            match request {
+                Request::Set(key, value) => batch.put(key.clone(), value.clone()),
+                Request::Remove(key) => batch.delete(key.clone()),
+                _ => unreachable!("not a write of a batch"),
+            };
+        }
+        if engine.write_batch(batch).is_ok() {
+            resps.extend(writes.iter().map(|_| Response::Ok(None)));
+            return;
+        }
+    }
+    resps.extend(
+        writes
+            .into_iter()
+            .map(|request| execute(engine, commands, request)),
+    );
+}
+
+/// Runs the `reload` job of the server, failing if the server has no reload hook.
+fn run_reload<F: FnOnce() -> Result<()>>(reload: Option<F>) -> Response {
+    let result = match reload {
+        Some(reload) => reload(),
+        None => Err(KvError::Unsupported("reload".to_owned())),
+    };
+    match result {
+        Ok(()) => {
+            info!("settings reloaded");
+            Response::Ok(None)
+        }
+        Err(err) => Response::Err(format!("{}", err)),
+    }
+}
+
+/// Runs the admin `command` listing or killing the connections among `clients`.
+fn run_clients(clients: &Clients, command: AdminCommand) -> Response {
+    match command {
+        AdminCommand::ClientKill(id) if clients.kill(id) => {
+            info!("killed client {}", id);
+            Response::Ok(None)
+        }
+        AdminCommand::ClientKill(id) => Response::Err(format!("no client {}", id)),
+        _ => Response::Clients(clients.list()),
+    }
+}
+
+/// Runs the admin `command` against `engine`.
+fn run_admin<E: KvEngine>(engine: &E, command: AdminCommand) -> Response {
+    let result = match command {
+        AdminCommand::Compact => engine.compact().map(|_| Response::Ok(None)),
+        AdminCommand::Stats => engine.stats().map(Response::Stats),
+        AdminCommand::Flush => engine.fsync().map(|_| Response::Ok(None)),
+        // the settings belong to the server, not to a bucket
+        AdminCommand::Reload => Err(KvError::Unsupported("reload in a bucket".to_owned())),
+        AdminCommand::Connections | AdminCommand::ClientList | AdminCommand::ClientKill(_) => Err(
+            KvError::Unsupported(format!("{} in a bucket", command.op())),
+        ),
+    };
+    result.unwrap_or_else(|err| Response::Err(format!("{}", err.with_op(command.op(), None))))
+}
+
+/// Returns the keys matching `pattern` among the `count` keys following
+/// `cursor`, and the cursor of the next page, `None` once every key was
+/// examined.
+///
+/// The page is the keys examined, so it may hold fewer keys than `count`,
+/// or none, before the end.
+fn scan_page<E: KvEngine>(
+    engine: &E,
+    cursor: Option<String>,
+    count: usize,
+    pattern: Option<&str>,
+) -> Result<(Vec<String>, Option<String>)> {
+    let count = count.max(1);
+    let mut keys = engine.scan_keys(cursor, count)?;
+    let next = if keys.len() < count {
+        None
+    } else {
+        keys.last().cloned()
+    };
+    if let Some(pattern) = pattern {
+        keys.retain(|key| glob::matches(pattern, key));
+    }
+    Ok((keys, next))
+}
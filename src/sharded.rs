@@ -0,0 +1,321 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio_rustls::rustls::ClientConfig;
+
+use crate::{KvClient, KvError, Result};
+
+/// Points of each node on a ring by default.
+const DEFAULT_VIRTUAL_NODES: usize = 160;
+
+/// A consistent hash ring, mapping keys to the nodes owning them.
+///
+/// Each node owns many points of the ring, and a key belongs to the node of
+/// the first point at or after the hash of the key. Adding or removing a node
+/// only moves the keys of the ranges it gains or loses, about `1 / n` of
+/// them. Hashes do not depend on the process, so rings of the same nodes map
+/// each key to the same node everywhere.
+#[derive(Clone, Debug)]
+pub struct HashRing {
+    points: BTreeMap<u64, String>,
+    virtual_nodes: usize,
+}
+
+impl Default for HashRing {
+    fn default() -> HashRing {
+        HashRing::with_virtual_nodes(DEFAULT_VIRTUAL_NODES)
+    }
+}
+
+impl HashRing {
+    /// Creates an empty ring, with 160 points per node.
+    pub fn new() -> HashRing {
+        HashRing::default()
+    }
+
+    /// Creates an empty ring with `virtual_nodes` points per node.
+    ///
+    /// More points spread the keys more evenly, at the cost of memory.
+    pub fn with_virtual_nodes(virtual_nodes: usize) -> HashRing {
+        HashRing {
+            points: BTreeMap::new(),
+            virtual_nodes: virtual_nodes.max(1),
+        }
+    }
+
+    /// Adds `node` to the ring, returning whether it was not in it yet.
+    pub fn add(&mut self, node: &str) -> bool {
+        if self.contains(node) {
+            return false;
+        }
+        for i in 0..self.virtual_nodes {
+            self.points
+                .insert(hash(format!("{}#{}", node, i).as_bytes()), node.to_owned());
+        }
+        true
+    }
+
+    /// Removes `node` from the ring, returning whether it was in it.
+    pub fn remove(&mut self, node: &str) -> bool {
+        let len = self.points.len();
+        self.points.retain(|_, owner| owner != node);
+        self.points.len() != len
+    }
+
+    /// Returns whether `node` is in the ring.
+    pub fn contains(&self, node: &str) -> bool {
+        self.points.values().any(|owner| owner == node)
+    }
+
+    /// Returns the nodes of the ring, in order.
+    pub fn nodes(&self) -> Vec<&str> {
+        let mut nodes: Vec<&str> = self.points.values().map(String::as_str).collect();
+        nodes.sort_unstable();
+        nodes.dedup();
+        nodes
+    }
+
+    /// Returns the node owning `key`, or `None` if the ring is empty.
+    pub fn node(&self, key: &str) -> Option<&str> {
+        let hash = hash(key.as_bytes());
+        self.points
+            .range(hash..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, node)| node.as_str())
+    }
+
+    /// Returns the keys among `keys` owned by another node in `next`, the
+    /// keys to copy before switching to the `next` ring.
+    pub fn moves<I, K>(&self, next: &HashRing, keys: I) -> Vec<KeyMove>
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        keys.into_iter()
+            .filter_map(|key| {
+                let key = key.into();
+                let from = self.node(&key)?;
+                let to = next.node(&key)?;
+                (from != to).then(|| KeyMove {
+                    from: from.to_owned(),
+                    to: to.to_owned(),
+                    key,
+                })
+            })
+            .collect()
+    }
+}
+
+/// A key owned by different nodes in two rings, see `HashRing::moves`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyMove {
+    /// The key.
+    pub key: String,
+    /// The node owning the key in the current ring.
+    pub from: String,
+    /// The node owning the key in the next ring.
+    pub to: String,
+}
+
+/// Returns the 64-bit FNV-1a hash of `bytes`, mixed so that similar inputs
+/// spread over the whole ring.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    // the finalizer of MurmurHash3
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+/// A client of many servers, each storing the keys a hash ring maps to it.
+///
+/// Each shard is connected to on its first request, and reconnected to on
+/// the next request after its connection broke. Requests about one key go
+/// to the shard owning it, while `multi_get` and `len` ask every shard
+/// involved. No request is atomic across shards.
+pub struct ShardedKvClient {
+    ring: HashRing,
+    clients: HashMap<String, KvClient>,
+    tls: Option<Arc<ClientConfig>>,
+}
+
+impl ShardedKvClient {
+    /// Creates a client of the servers at `addrs`.
+    pub fn new<I, S>(addrs: I) -> ShardedKvClient
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut ring = HashRing::new();
+        for addr in addrs {
+            ring.add(addr.as_ref());
+        }
+        ShardedKvClient::with_ring(ring)
+    }
+
+    /// Creates a client of the servers of `ring`, whose nodes are their addresses.
+    pub fn with_ring(ring: HashRing) -> ShardedKvClient {
+        ShardedKvClient {
+            ring,
+            clients: HashMap::new(),
+            tls: None,
+        }
+    }
+
+    /// Connects to the servers over TLS with `config`, built by `client_tls_config`.
+    pub fn tls(mut self, config: Arc<ClientConfig>) -> ShardedKvClient {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Returns the hash ring of the client.
+    pub fn ring(&self) -> &HashRing {
+        &self.ring
+    }
+
+    /// Adds the server at `addr`, which then owns part of the keys.
+    ///
+    /// The keys it takes over are not copied: `HashRing::moves` lists them.
+    pub fn add_shard(&mut self, addr: &str) -> bool {
+        self.ring.add(addr)
+    }
+
+    /// Removes the server at `addr`, closing its connection.
+    pub fn remove_shard(&mut self, addr: &str) -> bool {
+        self.clients.remove(addr);
+        self.ring.remove(addr)
+    }
+
+    /// Returns the address of the server owning `key`.
+    pub fn shard(&self, key: &str) -> Option<&str> {
+        self.ring.node(key)
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.on_shard(&key.clone(), |client| client.get(key))
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.on_shard(&key.clone(), |client| client.set(key, value))
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.on_shard(&key.clone(), |client| client.remove(key))
+    }
+
+    /// Sets the value of `key`, which expires after `ttl`.
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.on_shard(&key.clone(), |client| client.set_with_ttl(key, value, ttl))
+    }
+
+    /// Gets the remaining time to live of `key`, `None` if it never expires.
+    pub fn ttl(&mut self, key: String) -> Result<Option<Duration>> {
+        self.on_shard(&key.clone(), |client| client.ttl(key))
+    }
+
+    /// Sets the value of `key` to bytes.
+    pub fn set_bytes(&mut self, key: String, value: Vec<u8>) -> Result<()> {
+        self.on_shard(&key.clone(), |client| client.set_bytes(key, value))
+    }
+
+    /// Gets the value of `key` as bytes.
+    pub fn get_bytes(&mut self, key: String) -> Result<Option<Vec<u8>>> {
+        self.on_shard(&key.clone(), |client| client.get_bytes(key))
+    }
+
+    /// Sets `key` only if it does not exist, returning whether it was set.
+    pub fn set_if_absent(&mut self, key: String, value: String) -> Result<bool> {
+        self.on_shard(&key.clone(), |client| client.set_if_absent(key, value))
+    }
+
+    /// Sets `key` only if it already exists, returning whether it was set.
+    pub fn set_if_present(&mut self, key: String, value: String) -> Result<bool> {
+        self.on_shard(&key.clone(), |client| client.set_if_present(key, value))
+    }
+
+    /// Adds `delta` to the integer value of `key`, returning the new value.
+    pub fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        self.on_shard(&key.clone(), |client| client.incr(key, delta))
+    }
+
+    /// Subtracts `delta` from the integer value of `key`, returning the new value.
+    pub fn decr(&mut self, key: String, delta: i64) -> Result<i64> {
+        self.on_shard(&key.clone(), |client| client.decr(key, delta))
+    }
+
+    /// Gets the values of many keys, in the order of `keys`, with one round
+    /// trip per shard involved.
+    pub fn multi_get(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        // the keys of each shard, with their index in `keys`
+        let mut shards: HashMap<String, Vec<(usize, String)>> = HashMap::new();
+        for (i, key) in keys.into_iter().enumerate() {
+            let shard = self.ring.node(&key).ok_or(KvError::NoShards)?;
+            shards.entry(shard.to_owned()).or_default().push((i, key));
+        }
+        let mut values = vec![None; shards.values().map(Vec::len).sum()];
+        for (shard, keys) in shards {
+            let (indexes, keys): (Vec<usize>, Vec<String>) = keys.into_iter().unzip();
+            let shard_values = self.on(&shard, |client| client.multi_get(keys))?;
+            for (i, value) in indexes.into_iter().zip(shard_values) {
+                values[i] = value;
+            }
+        }
+        Ok(values)
+    }
+
+    /// Returns the number of live keys of all shards.
+    pub fn len(&mut self) -> Result<usize> {
+        let shards: Vec<String> = self.ring.nodes().into_iter().map(str::to_owned).collect();
+        let mut len = 0;
+        for shard in shards {
+            len += self.on(&shard, |client| client.len())?;
+        }
+        Ok(len)
+    }
+
+    /// Returns whether no shard has a live key.
+    pub fn is_empty(&mut self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Runs `request` with the client of the shard owning `key`.
+    fn on_shard<T, F>(&mut self, key: &str, request: F) -> Result<T>
+    where
+        F: FnOnce(&mut KvClient) -> Result<T>,
+    {
+        let shard = self.ring.node(key).ok_or(KvError::NoShards)?.to_owned();
+        self.on(&shard, request)
+    }
+
+    /// Runs `request` with the client of `shard`, connecting to it if needed.
+    fn on<T, F>(&mut self, shard: &str, request: F) -> Result<T>
+    where
+        F: FnOnce(&mut KvClient) -> Result<T>,
+    {
+        if !self.clients.contains_key(shard) {
+            let addr = shard.to_owned();
+            let client = match &self.tls {
+                Some(config) => KvClient::connect_tls(&addr, config.clone())?,
+                None => KvClient::new(&addr)?,
+            };
+            self.clients.insert(addr, client);
+        }
+        let client = self.clients.get_mut(shard).expect("connected above");
+        let res = request(client);
+        // the connection may be left mid response, so the next request reconnects
+        if let Err(KvError::Io(_) | KvError::Serde(_)) = res {
+            self.clients.remove(shard);
+        }
+        res
+    }
+}
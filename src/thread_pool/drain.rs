@@ -0,0 +1,77 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Counts the jobs of a pool queued or running, so that shutting the pool
+/// down waits for them.
+///
+/// Spawning and running a job only touch atomics, the lock is taken once
+/// the pool shuts down.
+#[derive(Default)]
+pub(crate) struct Drain {
+    pending: AtomicUsize,
+    /// Set once the pool shuts down, refusing the new jobs.
+    closed: AtomicBool,
+    lock: Mutex<()>,
+    idle: Condvar,
+}
+
+impl Drain {
+    /// Counts a new job, unless the pool shut down.
+    pub(crate) fn start(&self) -> bool {
+        if self.closed.load(Ordering::SeqCst) {
+            return false;
+        }
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        // the pool shut down meanwhile, and may already wait
+        if self.closed.load(Ordering::SeqCst) {
+            self.done();
+            return false;
+        }
+        true
+    }
+
+    /// Returns whether the pool shut down.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Counts a job out once it ran, or will never run.
+    pub(crate) fn done(&self) {
+        if self.pending.fetch_sub(1, Ordering::SeqCst) == 1 && self.closed.load(Ordering::SeqCst) {
+            // once the shutdown waits, or saw the jobs still pending
+            let _locked = self.lock.lock().unwrap();
+            self.idle.notify_all();
+        }
+    }
+
+    /// Runs `job`, counting it out once it returned or panicked.
+    pub(crate) fn run<F: FnOnce()>(&self, job: F) {
+        let res = panic::catch_unwind(AssertUnwindSafe(job));
+        self.done();
+        if let Err(payload) = res {
+            panic::resume_unwind(payload);
+        }
+    }
+
+    /// Refuses the new jobs, then waits for the ones counted for at most
+    /// `timeout`, returning whether they all finished.
+    pub(crate) fn close(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        self.closed.store(true, Ordering::SeqCst);
+        let mut locked = self.lock.lock().unwrap();
+        while self.pending.load(Ordering::SeqCst) > 0 {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return false;
+            }
+            locked = self.idle.wait_timeout(locked, left).unwrap().0;
+        }
+        true
+    }
+}
@@ -0,0 +1,61 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    thread,
+};
+
+use tokio::sync::oneshot;
+
+/// The payload of the panic a job dropped without running resolves with.
+const DROPPED: &str = "the job was dropped without running";
+
+/// A job spawned with `ThreadPool::spawn_with_handle`: a future resolving
+/// with the value the job returned, or the payload of its panic, like
+/// `thread::JoinHandle::join`.
+///
+/// Dropping the handle leaves the job running, its result dropped.
+pub struct JobHandle<R> {
+    rx: oneshot::Receiver<thread::Result<R>>,
+}
+
+/// The sending end of a `JobHandle`, completed by the job.
+pub(crate) struct Completion<R> {
+    tx: oneshot::Sender<thread::Result<R>>,
+}
+
+impl<R> JobHandle<R> {
+    pub(crate) fn new() -> (Completion<R>, JobHandle<R>) {
+        let (tx, rx) = oneshot::channel();
+        (Completion { tx }, JobHandle { rx })
+    }
+
+    /// Blocks the thread until the job returned, outside of an async runtime.
+    ///
+    /// # Panics
+    ///
+    /// Panics when called within an async runtime, which should await the
+    /// handle instead.
+    pub fn join(self) -> thread::Result<R> {
+        self.rx
+            .blocking_recv()
+            .unwrap_or_else(|_| Err(Box::new(DROPPED)))
+    }
+}
+
+impl<R> Future for JobHandle<R> {
+    type Output = thread::Result<R>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<thread::Result<R>> {
+        Pin::new(&mut self.rx)
+            .poll(cx)
+            .map(|res| res.unwrap_or_else(|_| Err(Box::new(DROPPED))))
+    }
+}
+
+impl<R> Completion<R> {
+    /// Resolves the handle with `res`, unless it was dropped.
+    pub(crate) fn complete(self, res: thread::Result<R>) {
+        drop(self.tx.send(res));
+    }
+}
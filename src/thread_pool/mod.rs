@@ -1,23 +1,97 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    time::Duration,
+};
+
 use crate::Result;
 
+mod drain;
+mod handle;
 mod naive;
 mod rayon;
 mod shared_queue;
 
+/// The prefix of the names of the threads of the pools, numbered from 1.
+const DEFAULT_THREAD_NAME: &str = "kv-worker";
+
 /// The trait that all thread pools should implement.
 pub trait ThreadPool: Clone + Send + 'static {
-    /// Creates a new thread pool, immediately spawning the specified number of threads.
+    /// Creates a new thread pool, immediately spawning the specified number of threads,
+    /// named `kv-worker-1`, `kv-worker-2` and so on.
     fn new(threads_num: usize) -> Result<Self>
     where
         Self: Sized;
 
+    /// Creates a new thread pool like `new`, its threads named `prefix` followed by
+    /// their number, like `prefix-1`, so that stack traces and profilers tell the
+    /// pools apart.
+    /// Pools which do not name their threads ignore the prefix.
+    fn with_thread_name(threads_num: usize, prefix: &str) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        let _ = prefix;
+        Self::new(threads_num)
+    }
+
     /// Spawns a function into the thread pool.
     /// Spawning always succeeds, thread pool should ignore function panics.
+    /// Once the pool shut down, the function is dropped without running.
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static;
+
+    /// Spawns a function into the thread pool unless its queue is full, failing
+    /// with `KvError::Busy` then, or with `KvError::ShuttingDown` once the pool
+    /// shut down.
+    /// Pools without a bounded queue always spawn it.
+    fn try_spawn<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.spawn(job);
+        Ok(())
+    }
+
+    /// Spawns a function into the thread pool like `spawn`, returning a handle
+    /// resolving with its return value, or the payload of its panic.
+    fn spawn_with_handle<F, R>(&self, job: F) -> JobHandle<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (completion, handle) = JobHandle::new();
+        self.spawn(move || completion.complete(panic::catch_unwind(AssertUnwindSafe(job))));
+        handle
+    }
+
+    /// Spawns a function into the thread pool like `try_spawn`, returning a
+    /// handle resolving with its return value, or the payload of its panic.
+    fn try_spawn_with_handle<F, R>(&self, job: F) -> Result<JobHandle<R>>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (completion, handle) = JobHandle::new();
+        self.try_spawn(move || completion.complete(panic::catch_unwind(AssertUnwindSafe(job))))?;
+        Ok(handle)
+    }
+
+    /// Shuts the pool down, for every clone: the functions spawned from now on
+    /// are refused, and the ones queued or running may take up to `timeout`
+    /// to finish. Returns whether they all did.
+    ///
+    /// Then the threads of the pool stop, those still running a function once
+    /// it returns, the functions still queued dropped.
+    /// Pools which cannot wait for their functions return true at once.
+    fn shutdown(&self, timeout: Duration) -> bool {
+        let _ = timeout;
+        true
+    }
 }
 
+pub use handle::JobHandle;
+
 pub use self::rayon::RayonThreadPool;
 pub use naive::NaiveThreadPool;
 pub use shared_queue::SharedQueueThreadPool;
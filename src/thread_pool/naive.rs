@@ -1,22 +1,73 @@
-use std::thread;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
 
-use crate::{Result, ThreadPool};
+use log::warn;
+
+use crate::{KvError, Result, ThreadPool};
+
+use super::{drain::Drain, DEFAULT_THREAD_NAME};
 
 #[derive(Clone)]
-pub struct NaiveThreadPool;
+pub struct NaiveThreadPool {
+    prefix: Arc<str>,
+    /// The threads spawned so far, numbering the next one.
+    spawned: Arc<AtomicUsize>,
+    drain: Arc<Drain>,
+}
 
 impl ThreadPool for NaiveThreadPool {
-    fn new(_: usize) -> Result<Self>
+    fn new(threads_num: usize) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::with_thread_name(threads_num, DEFAULT_THREAD_NAME)
+    }
+
+    fn with_thread_name(_: usize, prefix: &str) -> Result<Self>
     where
         Self: Sized,
     {
-        Ok(NaiveThreadPool)
+        Ok(NaiveThreadPool {
+            prefix: prefix.into(),
+            spawned: Arc::default(),
+            drain: Arc::default(),
+        })
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        thread::spawn(job);
+        if !self.drain.start() {
+            warn!("dropping a job spawned once the pool shut down");
+            return;
+        }
+        let id = self.spawned.fetch_add(1, Ordering::Relaxed) + 1;
+        let drain = self.drain.clone();
+        thread::Builder::new()
+            .name(format!("{}-{}", self.prefix, id))
+            .spawn(move || drain.run(job))
+            .unwrap();
+    }
+
+    fn try_spawn<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if self.drain.is_closed() {
+            return Err(KvError::ShuttingDown);
+        }
+        self.spawn(job);
+        Ok(())
+    }
+
+    fn shutdown(&self, timeout: Duration) -> bool {
+        self.drain.close(timeout)
     }
 }
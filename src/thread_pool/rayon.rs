@@ -1,10 +1,15 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
-use crate::ThreadPool;
+use log::warn;
+
+use crate::{KvError, ThreadPool};
+
+use super::{drain::Drain, DEFAULT_THREAD_NAME};
 
 #[derive(Clone)]
 pub struct RayonThreadPool {
     pool: Arc<rayon::ThreadPool>,
+    drain: Arc<Drain>,
 }
 
 impl ThreadPool for RayonThreadPool {
@@ -12,11 +17,21 @@ impl ThreadPool for RayonThreadPool {
     where
         Self: Sized,
     {
+        Self::with_thread_name(threads_num, DEFAULT_THREAD_NAME)
+    }
+
+    fn with_thread_name(threads_num: usize, prefix: &str) -> crate::Result<Self>
+    where
+        Self: Sized,
+    {
+        let prefix = prefix.to_owned();
         let pool = rayon::ThreadPoolBuilder::new()
             .num_threads(threads_num)
+            .thread_name(move |i| format!("{}-{}", prefix, i + 1))
             .build()?;
         Ok(RayonThreadPool {
             pool: Arc::new(pool),
+            drain: Arc::default(),
         })
     }
 
@@ -24,6 +39,28 @@ impl ThreadPool for RayonThreadPool {
     where
         F: FnOnce() + Send + 'static,
     {
-        self.pool.spawn(job);
+        if !self.drain.start() {
+            warn!("dropping a job spawned once the pool shut down");
+            return;
+        }
+        let drain = self.drain.clone();
+        self.pool.spawn(move || drain.run(job));
+    }
+
+    fn try_spawn<F>(&self, job: F) -> crate::Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if self.drain.is_closed() {
+            return Err(KvError::ShuttingDown);
+        }
+        self.spawn(job);
+        Ok(())
+    }
+
+    /// Waits for the jobs like the other pools, but the rayon threads only
+    /// stop once every clone of the pool is dropped.
+    fn shutdown(&self, timeout: Duration) -> bool {
+        self.drain.close(timeout)
     }
 }
@@ -1,12 +1,16 @@
-use crate::{Result, ThreadPool};
-use log::warn;
+use super::{drain::Drain, DEFAULT_THREAD_NAME};
+use crate::{KvError, Result, ThreadPool};
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+use log::{error, warn};
 use std::{
+    io, mem,
     panic::{self, AssertUnwindSafe},
     sync::{
-        mpsc::{self, Receiver},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc, Mutex,
     },
-    thread,
+    thread::{self, JoinHandle},
+    time::Duration,
 };
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
@@ -16,8 +20,90 @@ enum Message {
 }
 
 pub struct SharedQueueThreadPool {
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<Message>,
+    workers: Arc<Workers>,
+    /// The sending end of the queue, bounded or not.
+    sender: Sender<Message>,
+    /// Whether this is the pool rather than a clone, stopping the threads
+    /// once dropped.
+    owner: bool,
+}
+
+/// The threads of a pool, shared with them so that a thread dying spawns
+/// its replacement.
+struct Workers {
+    prefix: String,
+    threads_num: AtomicUsize,
+    /// The receiving end of the queue, which the threads receive from at once.
+    receiver: Receiver<Message>,
+    /// The threads spawned and not joined yet, including the replacements.
+    handles: Mutex<Vec<JoinHandle<()>>>,
+    drain: Drain,
+    /// Set once the threads are told to stop.
+    terminated: AtomicBool,
+    /// Set once a shutdown timed out, dropping the jobs still queued.
+    abandoned: AtomicBool,
+}
+
+impl SharedQueueThreadPool {
+    /// Creates a thread pool whose queue holds at most `capacity` jobs waiting
+    /// for a thread, so `try_spawn` fails with `KvError::Busy` rather than
+    /// queueing more, and `spawn` waits for room.
+    ///
+    /// With a capacity of 0, a job only spawns on an idle thread.
+    pub fn with_queue_capacity(threads_num: usize, capacity: usize) -> Result<Self> {
+        Self::with_queue_capacity_and_thread_name(threads_num, capacity, DEFAULT_THREAD_NAME)
+    }
+
+    /// Creates a thread pool with a bounded queue like `with_queue_capacity`,
+    /// its threads named like `ThreadPool::with_thread_name`.
+    pub fn with_queue_capacity_and_thread_name(
+        threads_num: usize,
+        capacity: usize,
+        prefix: &str,
+    ) -> Result<Self> {
+        let (sender, receiver) = crossbeam_channel::bounded(capacity);
+        Self::start(threads_num, prefix, sender, receiver)
+    }
+
+    fn start(
+        threads_num: usize,
+        prefix: &str,
+        sender: Sender<Message>,
+        receiver: Receiver<Message>,
+    ) -> Result<Self> {
+        let workers = Arc::new(Workers {
+            prefix: prefix.to_owned(),
+            threads_num: AtomicUsize::new(0),
+            receiver,
+            handles: Mutex::new(Vec::with_capacity(threads_num)),
+            drain: Drain::default(),
+            terminated: AtomicBool::new(false),
+            abandoned: AtomicBool::new(false),
+        });
+        // counted as they start, so that the pool stops them if one fails to
+        let pool = SharedQueueThreadPool {
+            workers: workers.clone(),
+            sender,
+            owner: true,
+        };
+        for id in 1..=threads_num {
+            let handle = Worker::spawn(&workers, id)?;
+            workers.handles.lock().unwrap().push(handle);
+            workers.threads_num.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(pool)
+    }
+
+    /// Tells the threads to stop once done with the jobs queued, unless they
+    /// already were.
+    fn stop(&self) {
+        if self.workers.terminated.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        for _ in 0..self.workers.threads_num.load(Ordering::SeqCst) {
+            self.sender.send(Message::Terminate).unwrap();
+        }
+    }
 }
 
 impl ThreadPool for SharedQueueThreadPool {
@@ -25,68 +111,140 @@ impl ThreadPool for SharedQueueThreadPool {
     where
         Self: Sized,
     {
-        let (sender, receiver) = mpsc::channel();
-        let mut workers = Vec::with_capacity(threads_num);
-        let receiver = Arc::new(Mutex::new(receiver));
+        Self::with_thread_name(threads_num, DEFAULT_THREAD_NAME)
+    }
 
-        for i in 0..threads_num {
-            workers.push(Worker::new(i + 1, receiver.clone()));
-        }
-        Ok(SharedQueueThreadPool { workers, sender })
+    fn with_thread_name(threads_num: usize, prefix: &str) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        Self::start(threads_num, prefix, sender, receiver)
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
+        if !self.workers.drain.start() {
+            warn!("dropping a job spawned once the pool shut down");
+            return;
+        }
         self.sender.send(Message::NewJob(Box::new(job))).unwrap();
     }
+
+    fn try_spawn<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if !self.workers.drain.start() {
+            return Err(KvError::ShuttingDown);
+        }
+        // an unbounded queue is never full
+        match self.sender.try_send(Message::NewJob(Box::new(job))) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.workers.drain.done();
+                Err(KvError::Busy)
+            }
+            Err(TrySendError::Disconnected(_)) => panic!("the workers of the pool stopped"),
+        }
+    }
+
+    fn shutdown(&self, timeout: Duration) -> bool {
+        let drained = self.workers.drain.close(timeout);
+        if drained {
+            self.stop();
+            return true;
+        }
+        // the threads still running a job are left to end on their own, and a bounded
+        // queue may only take the stop messages as they do
+        warn!("the jobs of the pool did not finish within {:?}", timeout);
+        self.workers.abandoned.store(true, Ordering::SeqCst);
+        drop(mem::take(&mut *self.workers.handles.lock().unwrap()));
+        let pool = self.clone();
+        let stopping = thread::Builder::new()
+            .name(format!("{}-shutdown", self.workers.prefix))
+            .spawn(move || pool.stop());
+        if let Err(err) = stopping {
+            error!("failed to stop the threads of the pool: {}", err);
+        }
+        false
+    }
 }
 
 impl Clone for SharedQueueThreadPool {
     fn clone(&self) -> Self {
         Self {
-            workers: Vec::new(),
+            workers: self.workers.clone(),
             sender: self.sender.clone(),
+            owner: false,
         }
     }
 }
 
 impl Drop for SharedQueueThreadPool {
     fn drop(&mut self) {
-        for _ in &self.workers {
-            self.sender.send(Message::Terminate).unwrap();
+        if !self.owner {
+            return;
         }
+        self.stop();
 
-        for worker in &mut self.workers {
-            if let Some(handle) = worker.handle.take() {
-                handle.join().unwrap();
+        // a thread dying meanwhile adds its replacement before it ends
+        loop {
+            let handle = self.workers.handles.lock().unwrap().pop();
+            match handle {
+                Some(handle) => drop(handle.join()),
+                None => break,
             }
         }
     }
 }
 
-pub struct Worker {
-    handle: Option<thread::JoinHandle<()>>,
+/// A thread of a pool, which spawns its replacement if it dies, like when
+/// the payload of a job panic panics once dropped.
+struct Worker {
+    workers: Arc<Workers>,
+    id: usize,
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<Receiver<Message>>>) -> Worker {
-        let handle = thread::spawn(move || loop {
-            let msg = receiver.lock().unwrap().recv().unwrap();
-            match msg {
-                Message::NewJob(job) => {
-                    if let Err(err) = panic::catch_unwind(AssertUnwindSafe(job)) {
-                        warn!("[thread {}] job panic: {:?}", id, err);
-                    }
-                }
-                Message::Terminate => {
-                    break;
-                }
-            };
-        });
-        Worker {
-            handle: Some(handle),
+    fn spawn(workers: &Arc<Workers>, id: usize) -> io::Result<JoinHandle<()>> {
+        let worker = Worker {
+            workers: workers.clone(),
+            id,
+        };
+        thread::Builder::new()
+            .name(format!("{}-{}", workers.prefix, id))
+            .spawn(move || worker.run())
+    }
+
+    fn run(&self) {
+        // until told to stop
+        while let Ok(Message::NewJob(job)) = self.workers.receiver.recv() {
+            // the jobs still queued once a shutdown timed out are dropped
+            if self.workers.abandoned.load(Ordering::SeqCst) {
+                self.workers.drain.done();
+                continue;
+            }
+            let res = panic::catch_unwind(AssertUnwindSafe(job));
+            self.workers.drain.done();
+            if let Err(err) = res {
+                warn!("[thread {}] job panic: {:?}", self.id, err);
+            }
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            return;
+        }
+        error!("[thread {}] died, spawning a new one", self.id);
+        match Worker::spawn(&self.workers, self.id) {
+            Ok(handle) => self.workers.handles.lock().unwrap().push(handle),
+            Err(err) => error!("[thread {}] failed to spawn again: {}", self.id, err),
         }
     }
 }
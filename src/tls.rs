@@ -0,0 +1,126 @@
+use std::{path::Path, sync::Arc};
+
+use tokio_rustls::rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    ClientConfig, RootCertStore, ServerConfig,
+};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::{KvError, Result};
+
+/// The identity of a client, authenticated by the certificate it presented over TLS.
+#[derive(Clone, Debug)]
+pub struct ClientIdentity {
+    common_name: Option<String>,
+    certificate: CertificateDer<'static>,
+}
+
+impl ClientIdentity {
+    /// Returns the identity of the client presenting `certificates`, its own certificate first.
+    pub(crate) fn from_certificates(certificates: &[CertificateDer]) -> Option<ClientIdentity> {
+        let certificate = certificates.first()?.clone().into_owned();
+        let common_name = X509Certificate::from_der(&certificate)
+            .ok()
+            .and_then(|(_, cert)| {
+                cert.subject()
+                    .iter_common_name()
+                    .next()
+                    .and_then(|name| name.as_str().ok())
+                    .map(str::to_owned)
+            });
+        Some(ClientIdentity {
+            common_name,
+            certificate,
+        })
+    }
+
+    /// Returns the common name (CN) of the certificate subject, if it has one.
+    pub fn common_name(&self) -> Option<&str> {
+        self.common_name.as_deref()
+    }
+
+    /// Returns the DER encoded certificate, to check fields other than the common name.
+    pub fn certificate(&self) -> &[u8] {
+        &self.certificate
+    }
+}
+
+/// Builds the TLS configuration of a server from PEM files.
+///
+/// The server presents the certificate chain in `cert` with the private key
+/// in `key`. With `client_ca`, clients must present a certificate signed by
+/// one of the CA certificates in that file, and connections without one are
+/// refused during the handshake.
+pub fn server_tls_config(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+) -> Result<Arc<ServerConfig>> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?;
+    let builder = match client_ca {
+        Some(client_ca) => {
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(root_store(client_ca)?, provider)
+                    .build()
+                    .map_err(tls_error)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(read_certs(cert)?, read_key(key)?)
+        .map_err(tls_error)?;
+    Ok(Arc::new(config))
+}
+
+/// Builds the TLS configuration of a client from PEM files.
+///
+/// The server certificate is verified against the CA certificates in `ca`.
+/// With `identity`, a pair of certificate chain and private key files, the
+/// client presents that certificate to servers requiring one.
+pub fn client_tls_config(ca: &Path, identity: Option<(&Path, &Path)>) -> Result<Arc<ClientConfig>> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .with_root_certificates(root_store(ca)?);
+    let config = match identity {
+        Some((cert, key)) => builder
+            .with_client_auth_cert(read_certs(cert)?, read_key(key)?)
+            .map_err(tls_error)?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|err| KvError::Tls(format!("{}: {}", path.display(), err)))?;
+    if certs.is_empty() {
+        return Err(KvError::Tls(format!("{}: no certificate", path.display())));
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path)
+        .map_err(|err| KvError::Tls(format!("{}: {}", path.display(), err)))
+}
+
+fn root_store(path: &Path) -> Result<Arc<RootCertStore>> {
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(path)? {
+        roots.add(cert).map_err(tls_error)?;
+    }
+    Ok(Arc::new(roots))
+}
+
+fn tls_error<E: std::fmt::Display>(err: E) -> KvError {
+    KvError::Tls(err.to_string())
+}
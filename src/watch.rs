@@ -0,0 +1,87 @@
+use std::{
+    sync::{mpsc::Receiver, Arc},
+    thread,
+};
+
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::{
+    replication::{forward_messages, write_response},
+    ChangeKind, Codec, KvEngine, Response, Result, WatchEvent,
+};
+
+/// Events buffered for a watcher before the engine changes wait for it.
+const WATCHER_BUFFER: usize = 1024;
+
+/// Streams the changes of the keys of `engine` starting with `prefix` to a
+/// client connected over `stream`, whose request numbered `id` asked for
+/// them, until the server stops.
+pub(crate) async fn serve_watcher<E, S>(
+    engine: Arc<E>,
+    mut stream: S,
+    codec: Codec,
+    id: u64,
+    prefix: String,
+    stop: CancellationToken,
+) -> Result<()>
+where
+    E: KvEngine,
+    S: AsyncWrite + Unpin,
+{
+    let changes = match engine.subscribe() {
+        Ok(changes) => changes,
+        Err(err) => {
+            write_response(&mut stream, codec, id, Response::Err(format!("{}", err))).await?;
+            return Ok(());
+        }
+    };
+    write_response(&mut stream, codec, id, Response::Ok(None)).await?;
+    info!("watching keys starting with {:?}", prefix);
+
+    let (tx, rx) = mpsc::channel(WATCHER_BUFFER);
+    // reading the engine blocks, so it runs on a thread of its own
+    thread::spawn(move || send_events(&*engine, changes, &prefix, tx));
+    forward_messages(&mut stream, codec, rx, &stop).await?;
+    stream.shutdown().await?;
+    info!("watcher disconnected");
+    Ok(())
+}
+
+/// Sends the events of the `changes` of the keys starting with `prefix` to
+/// `tx` until the watcher is gone.
+fn send_events<E: KvEngine>(
+    engine: &E,
+    changes: Receiver<(String, ChangeKind)>,
+    prefix: &str,
+    tx: mpsc::Sender<WatchEvent>,
+) {
+    // waits for the next change, even once the watcher is gone
+    for (key, kind) in changes {
+        if !key.starts_with(prefix) {
+            continue;
+        }
+        let value = match kind {
+            ChangeKind::Set => match engine.get(key.clone()) {
+                Ok(value) => value,
+                Err(err) => {
+                    warn!("cannot send the change of key {}: {}", key, err);
+                    continue;
+                }
+            },
+            ChangeKind::Remove => None,
+        };
+        // a key set, then removed before being read, is reported removed
+        let kind = match value {
+            Some(_) => ChangeKind::Set,
+            None => ChangeKind::Remove,
+        };
+        if tx.blocking_send(WatchEvent { key, kind, value }).is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use std::{thread, time::Duration};
+
+use rust_kv::{
+    EngineStats, KvClient, KvEngine, KvServer, KvServerBuilder, KvStore, MemStore, PrefixedEngine,
+    SharedQueueThreadPool, ThreadPool,
+};
+use tempfile::TempDir;
+
+fn builder<E: KvEngine>(engine: E, addr: &str) -> KvServerBuilder<E, SharedQueueThreadPool> {
+    KvServer::builder(engine, SharedQueueThreadPool::new(2).unwrap()).addr(addr)
+}
+
+fn run<E: KvEngine>(builder: KvServerBuilder<E, SharedQueueThreadPool>) {
+    let mut server = builder.build();
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(500));
+}
+
+// Should compact, report the statistics of and sync a running kvs engine
+#[test]
+fn admin_kvs() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:5001".to_owned();
+    run(builder(KvStore::open(temp_dir.path()).unwrap(), &addr));
+
+    let mut client = KvClient::new(&addr).unwrap();
+    for i in 0..100 {
+        client
+            .set(format!("key{}", i % 10), format!("value{}", i))
+            .unwrap();
+    }
+    let before = client.stats().unwrap();
+    assert_eq!(before.keys, 10);
+    assert!(before.stale_size.unwrap() > 0);
+
+    client.compact().unwrap();
+    let after = client.stats().unwrap();
+    assert_eq!(after.keys, 10);
+    assert_eq!(after.stale_size, Some(0));
+    assert!(after.disk_size.unwrap() < before.disk_size.unwrap());
+    assert_eq!(
+        client.get("key3".to_owned()).unwrap(),
+        Some("value93".to_owned())
+    );
+
+    client.set("key10".to_owned(), "value".to_owned()).unwrap();
+    client.flush().unwrap();
+    assert_eq!(client.stats().unwrap().keys, 11);
+}
+
+// Should compact, report the statistics of and sync the kvs engine under a prefixed engine
+#[test]
+fn admin_prefixed() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:5008".to_owned();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("other".to_owned(), "value".to_owned()).unwrap();
+    run(builder(PrefixedEngine::new(store, "app/"), &addr));
+
+    let mut client = KvClient::new(&addr).unwrap();
+    for i in 0..100 {
+        client
+            .set(format!("key{}", i % 10), format!("value{}", i))
+            .unwrap();
+    }
+    let before = client.stats().unwrap();
+    assert_eq!(before.keys, 10);
+    assert!(before.stale_size.unwrap() > 0);
+
+    client.compact().unwrap();
+    let after = client.stats().unwrap();
+    assert_eq!(after.keys, 10);
+    assert_eq!(after.stale_size, Some(0));
+
+    client.set("key10".to_owned(), "value".to_owned()).unwrap();
+    client.flush().unwrap();
+    assert_eq!(client.stats().unwrap().keys, 11);
+}
+
+// Should report what an engine keeping nothing on the disk has, and refuse to compact it
+#[test]
+fn admin_mem() {
+    let addr = "127.0.0.1:5002".to_owned();
+    run(builder(MemStore::new(), &addr));
+
+    let mut client = KvClient::new(&addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.stats().unwrap(),
+        EngineStats {
+            keys: 1,
+            disk_size: None,
+            stale_size: None,
+            memory_size: Some(10),
+        }
+    );
+    client.flush().unwrap();
+    let err = client.compact().unwrap_err();
+    assert_eq!(err.to_string(), "Unsupported operation: compact");
+}
+
+// Should authorize the admin commands like other requests, and run them on a read-only server
+#[test]
+fn admin_authorize() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:5003".to_owned();
+    run(builder(KvStore::open(temp_dir.path()).unwrap(), &addr)
+        .read_only(true)
+        .authorize(|identity, request| !request.is_admin() || identity.is_some()));
+
+    let mut client = KvClient::new(&addr).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    for result in [
+        client.compact(),
+        client.stats().map(|_| ()),
+        client.flush(),
+        client.info().map(|_| ()),
+    ] {
+        assert_eq!(result.unwrap_err().to_string(), "Permission denied");
+    }
+
+    // in a bucket too
+    client.set_bucket(Some("bucket".to_owned()));
+    assert_eq!(client.flush().unwrap_err().to_string(), "Permission denied");
+
+    let addr = "127.0.0.1:5004".to_owned();
+    let temp_dir = TempDir::new().unwrap();
+    run(builder(KvStore::open(temp_dir.path()).unwrap(), &addr).read_only(true));
+    let mut client = KvClient::new(&addr).unwrap();
+    client.compact().unwrap();
+    client.flush().unwrap();
+    assert_eq!(client.stats().unwrap().keys, 0);
+}
+
+// Should report the version, uptime, engine and counts of the server, ignoring the bucket
+#[test]
+fn info() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:5005".to_owned();
+    run(builder(KvStore::open(temp_dir.path()).unwrap(), &addr).metrics(true));
+
+    let mut client = KvClient::new(&addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.set_bucket(Some("bucket".to_owned()));
+    let info = client.info().unwrap();
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert!(info.uptime >= Duration::from_millis(500));
+    assert_eq!(info.engine, "kvs");
+    assert_eq!(info.stats.keys, 1);
+    assert!(info.stats.disk_size.unwrap() > 0);
+    assert!(info.stats.memory_size.unwrap() > 0);
+    let counts = info.counts.unwrap();
+    assert_eq!(counts.connections, 1);
+    assert_eq!(counts.active_connections, 1);
+    assert_eq!(counts.requests, 1);
+
+    // without metrics, the server has no counts
+    let addr = "127.0.0.1:5006".to_owned();
+    run(builder(MemStore::new(), &addr));
+    let info = KvClient::new(&addr).unwrap().info().unwrap();
+    assert_eq!(info.engine, "mem");
+    assert_eq!(info.counts, None);
+}
+
+// Should list the connections open with their peer, last operation and traffic, and kill one
+#[test]
+fn client_list_and_kill() {
+    let addr = "127.0.0.1:5007".to_owned();
+    run(builder(MemStore::new(), &addr));
+
+    let mut stuck = KvClient::new(&addr).unwrap();
+    stuck.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let mut admin = KvClient::new(&addr).unwrap();
+    let clients = admin.client_list().unwrap();
+    assert_eq!(clients.len(), 2);
+    let (first, second) = (&clients[0], &clients[1]);
+    assert!(first.addr.starts_with("127.0.0.1:"));
+    assert_eq!(first.name, None);
+    assert_eq!(first.last_op.as_deref(), Some("set"));
+    assert!(first.bytes_in > 0);
+    assert!(first.bytes_out > 0);
+    assert!(first.age >= first.idle);
+    assert_eq!(second.last_op.as_deref(), Some("client_list"));
+
+    admin.client_kill(first.id).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    assert!(stuck.get("key1".to_owned()).is_err());
+    let clients = admin.client_list().unwrap();
+    assert_eq!(clients.len(), 1);
+    assert_eq!(clients[0].id, second.id);
+
+    // killing a closed connection fails
+    assert!(admin.client_kill(first.id).is_err());
+}
@@ -0,0 +1,118 @@
+use rust_kv::{
+    KvClient, KvServer, KvStore, MemStore, Request, Response, RunningServer, SharedQueueThreadPool,
+    ThreadPool,
+};
+use tempfile::TempDir;
+
+fn start_kvs(temp_dir: &TempDir) -> RunningServer {
+    KvServer::builder(
+        KvStore::open(temp_dir.path()).unwrap(),
+        SharedQueueThreadPool::new(2).unwrap(),
+    )
+    .addr("127.0.0.1:0")
+    .build()
+    .start()
+    .unwrap()
+}
+
+// Should run the requests of a batch in order, each one seeing the earlier writes
+#[test]
+fn batch_in_order() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_kvs(&temp_dir);
+    let mut client = KvClient::new(&server.addr().to_string()).unwrap();
+
+    let resps = client
+        .batch(vec![
+            Request::Set("key1".to_owned(), "value1".to_owned()),
+            Request::Set("key2".to_owned(), "value2".to_owned()),
+            Request::Get("key1".to_owned()),
+            Request::Remove("key1".to_owned()),
+            Request::Incr("counter".to_owned(), 3),
+            Request::MultiGet(vec!["key1".to_owned(), "key2".to_owned()]),
+        ])
+        .unwrap();
+    assert_eq!(resps.len(), 6);
+    assert!(matches!(resps[0], Ok(Response::Ok(None))));
+    assert!(matches!(resps[1], Ok(Response::Ok(None))));
+    assert!(matches!(&resps[2], Ok(Response::Ok(Some(value))) if value == "value1"));
+    assert!(matches!(resps[3], Ok(Response::Ok(None))));
+    assert!(matches!(resps[4], Ok(Response::Int(3))));
+    assert!(
+        matches!(&resps[5], Ok(Response::Values(values)) if *values == [None, Some("value2".to_owned())])
+    );
+
+    assert_eq!(client.batch(Vec::new()).unwrap().len(), 0);
+    server.shutdown().unwrap();
+}
+
+// Should answer each failed request of a batch on its own, writing the others
+#[test]
+fn batch_errors() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_kvs(&temp_dir);
+    let mut client = KvClient::new(&server.addr().to_string()).unwrap();
+
+    // the remove of a missing key fails the write batch, so the writes run one by one
+    let resps = client
+        .batch(vec![
+            Request::Set("key1".to_owned(), "value1".to_owned()),
+            Request::Remove("missing".to_owned()),
+            Request::Set("key2".to_owned(), "value2".to_owned()),
+            Request::Subscribe("news".to_owned()),
+            Request::Batch(Vec::new()),
+        ])
+        .unwrap();
+    assert!(matches!(resps[0], Ok(Response::Ok(None))));
+    assert!(matches!(&resps[1], Err(err) if err.to_string() == "Key not found"));
+    assert!(matches!(resps[2], Ok(Response::Ok(None))));
+    assert!(matches!(&resps[3], Err(err) if err.to_string() == "Unsupported operation: subscribe"));
+    assert!(
+        matches!(&resps[4], Err(err) if err.to_string() == "Unsupported operation: batch in a batch")
+    );
+    assert_eq!(
+        client.get("key2".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+
+    // in a bucket
+    client.set_bucket(Some("bucket".to_owned()));
+    client
+        .batch(vec![Request::Set("key1".to_owned(), "bucket".to_owned())])
+        .unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("bucket".to_owned())
+    );
+    client.set_bucket(None);
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    server.shutdown().unwrap();
+}
+
+// Should refuse a batch with a write on a read-only server
+#[test]
+fn batch_read_only() {
+    let server = KvServer::builder(MemStore::new(), SharedQueueThreadPool::new(2).unwrap())
+        .addr("127.0.0.1:0")
+        .read_only(true)
+        .build()
+        .start()
+        .unwrap();
+    let mut client = KvClient::new(&server.addr().to_string()).unwrap();
+    let err = client
+        .batch(vec![
+            Request::Get("key1".to_owned()),
+            Request::Set("key1".to_owned(), "value1".to_owned()),
+        ])
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Server is read-only");
+    let resps = client
+        .batch(vec![Request::Get("key1".to_owned()), Request::Len])
+        .unwrap();
+    assert!(matches!(resps[0], Ok(Response::Ok(None))));
+    assert!(matches!(resps[1], Ok(Response::Len(0))));
+    server.shutdown().unwrap();
+}
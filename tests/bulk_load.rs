@@ -0,0 +1,88 @@
+use std::{thread, time::Duration};
+
+use rust_kv::{
+    KvClient, KvEngine, KvServer, KvServerBuilder, KvStore, KvStoreOptions, SharedQueueThreadPool,
+    SyncPolicy, ThreadPool,
+};
+use tempfile::TempDir;
+
+fn builder(engine: KvStore, addr: &str) -> KvServerBuilder<KvStore, SharedQueueThreadPool> {
+    KvServer::builder(engine, SharedQueueThreadPool::new(2).unwrap()).addr(addr)
+}
+
+fn run(builder: KvServerBuilder<KvStore, SharedQueueThreadPool>, addr: &str) -> KvClient {
+    let mut server = builder.build();
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(500));
+    KvClient::new(addr).unwrap()
+}
+
+fn pairs(count: usize) -> impl Iterator<Item = (String, String)> {
+    (0..count).map(|i| (format!("key{}", i), format!("value{}", i)))
+}
+
+// Should set every pair in batches, then serve requests on the connection again
+#[test]
+fn bulk_load() {
+    let temp_dir = TempDir::new().unwrap();
+    let options = KvStoreOptions::new().sync_policy(SyncPolicy::Always);
+    let engine = KvStore::open_with(temp_dir.path(), options).unwrap();
+    let addr = "127.0.0.1:4901";
+    let mut client = run(builder(engine.clone(), addr), addr);
+
+    let summary = client.bulk_load(pairs(5000)).unwrap();
+    assert_eq!(summary.keys, 5000);
+    assert_eq!(summary.batches, 5);
+    assert_eq!(engine.len().unwrap(), 5000);
+    assert_eq!(
+        client.get("key4999".to_owned()).unwrap(),
+        Some("value4999".to_owned())
+    );
+
+    let summary = client.bulk_load(pairs(0)).unwrap();
+    assert_eq!(summary.keys, 0);
+    assert_eq!(summary.batches, 0);
+}
+
+// Should set every pair in the bucket of the client
+#[test]
+fn bulk_load_bucket() {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let addr = "127.0.0.1:4904";
+    let mut client = run(builder(engine.clone(), addr), addr);
+
+    client.set_bucket(Some("imports".to_owned()));
+    let summary = client.bulk_load(pairs(2000)).unwrap();
+    assert_eq!(summary.keys, 2000);
+    assert_eq!(engine.bucket("imports").unwrap().len().unwrap(), 2000);
+    assert_eq!(engine.len().unwrap(), 0);
+    assert_eq!(
+        client.get("key1999".to_owned()).unwrap(),
+        Some("value1999".to_owned())
+    );
+}
+
+// Should report the error stopping a load, and refuse loads a server does not allow
+#[test]
+fn bulk_load_errors() {
+    let temp_dir = TempDir::new().unwrap();
+    let options = KvStoreOptions::new().max_disk_size(16 * 1024);
+    let engine = KvStore::open_with(temp_dir.path(), options).unwrap();
+    let addr = "127.0.0.1:4902";
+    let mut client = run(builder(engine, addr), addr);
+
+    let err = client.bulk_load(pairs(5000)).unwrap_err();
+    assert!(err
+        .to_string()
+        .starts_with("Disk quota exceeded after loading"));
+    assert_eq!(client.len().unwrap() % 1024, 0);
+
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let addr = "127.0.0.1:4903";
+    let mut client = run(builder(engine, addr).read_only(true), addr);
+    let err = client.bulk_load(pairs(10)).unwrap_err();
+    assert_eq!(err.to_string(), "Server is read-only");
+    assert_eq!(client.len().unwrap(), 0);
+}
@@ -0,0 +1,125 @@
+use rust_kv::{
+    ChangeEvent, ChangeKind, KvClient, KvEngine, KvServer, KvStore, MemStore, RunningServer,
+    SharedQueueThreadPool, ThreadPool,
+};
+use tempfile::TempDir;
+
+fn start<E: KvEngine>(engine: E, change_log: Option<usize>) -> RunningServer {
+    let mut builder =
+        KvServer::builder(engine, SharedQueueThreadPool::new(2).unwrap()).addr("127.0.0.1:0");
+    if let Some(capacity) = change_log {
+        builder = builder.change_log(capacity);
+    }
+    builder.build().start().unwrap()
+}
+
+fn connect(server: &RunningServer) -> KvClient {
+    KvClient::new(&server.addr().to_string()).unwrap()
+}
+
+/// Reads the changes of `server` from the oldest one retained until a change of `key`.
+fn changes_until(server: &RunningServer, key: &str) -> Vec<ChangeEvent> {
+    let mut changes = Vec::new();
+    for change in connect(server).changes(None).unwrap() {
+        let change = change.unwrap();
+        let last = change.key == key;
+        changes.push(change);
+        if last {
+            return changes;
+        }
+    }
+    panic!("the changes ended before a change of {}", key);
+}
+
+// Should stream the committed changes with consecutive offsets, and resume from an offset
+#[test]
+fn changes() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = start(KvStore::open(temp_dir.path()).unwrap(), Some(100));
+    let mut client = connect(&server);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    client.remove("key1".to_owned()).unwrap();
+    client.set("last".to_owned(), "value".to_owned()).unwrap();
+
+    let changes = changes_until(&server, "last");
+    assert_eq!(changes.len(), 4);
+    let first = changes[0].offset;
+    for (i, change) in changes.iter().enumerate() {
+        assert_eq!(change.offset, first + i as u64);
+    }
+    assert_eq!(
+        changes[1],
+        ChangeEvent {
+            offset: first + 1,
+            key: "key2".to_owned(),
+            kind: ChangeKind::Set,
+            value: Some("value2".to_owned()),
+        }
+    );
+    assert_eq!(changes[2].kind, ChangeKind::Remove);
+    assert_eq!(changes[2].value, None);
+
+    // resumed after the changes seen, the stream waits for the next ones
+    let mut resumed = connect(&server).changes(Some(first + 4)).unwrap();
+    client.set("key3".to_owned(), "value3".to_owned()).unwrap();
+    let change = resumed.next().unwrap().unwrap();
+    assert_eq!((change.offset, change.key.as_str()), (first + 4, "key3"));
+    let mut resumed = connect(&server).changes(Some(first + 2)).unwrap();
+    assert_eq!(resumed.next().unwrap().unwrap().offset, first + 2);
+
+    let err = connect(&server).changes(Some(first + 10)).err().unwrap();
+    assert!(err.to_string().contains("past the end of the change log"));
+    server.shutdown().unwrap();
+}
+
+// Should refuse to resume from a change no longer retained
+#[test]
+fn changes_trimmed() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = start(KvStore::open(temp_dir.path()).unwrap(), Some(2));
+    let mut client = connect(&server);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    client.set("last".to_owned(), "value".to_owned()).unwrap();
+
+    // once the last change is recorded, the first one is no longer retained
+    changes_until(&server, "last");
+    let changes: Vec<ChangeEvent> = connect(&server)
+        .changes(None)
+        .unwrap()
+        .take(2)
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(changes[0].key, "key2");
+    assert_eq!(changes[1].key, "last");
+    let err = connect(&server)
+        .changes(Some(changes[0].offset - 1))
+        .err()
+        .unwrap();
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "Offset {} is no longer retained, the oldest is {}",
+            changes[0].offset - 1,
+            changes[0].offset
+        )
+    );
+    server.shutdown().unwrap();
+}
+
+// Should refuse the changes without a change log, or of an engine not reporting its changes
+#[test]
+fn changes_unsupported() {
+    let server = start(MemStore::new(), None);
+    let err = connect(&server).changes(None).err().unwrap();
+    assert_eq!(
+        err.to_string(),
+        "Unsupported operation: changes without a change log"
+    );
+    server.shutdown().unwrap();
+
+    let server = start(MemStore::new(), Some(100));
+    assert!(connect(&server).changes(None).is_err());
+    server.shutdown().unwrap();
+}
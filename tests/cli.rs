@@ -1,5 +1,5 @@
 use assert_cmd::prelude::*;
-use predicates::str::contains;
+use predicates::{prelude::*, str::contains};
 use std::fs::{self, File};
 use std::process::Command;
 use std::sync::mpsc;
@@ -13,13 +13,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kv-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait server");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -34,15 +35,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kv-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait server");
 
         let mut cmd = Command::cargo_bin("kv-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -53,15 +55,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kv-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait server");
 
         let mut cmd = Command::cargo_bin("kv-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -73,20 +76,21 @@ fn cli_access_server(engine: &str, addr: &str) {
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kv-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait server");
     });
 
     thread::sleep(Duration::from_secs(1));
 
     assert_cmd::Command::cargo_bin("kv-client")
         .unwrap()
-        .args(&["--addr", addr])
+        .args(["--addr", addr])
         .current_dir(&temp_dir)
         .write_stdin("set key1 value1")
         .assert()
@@ -95,7 +99,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     assert_cmd::Command::cargo_bin("kv-client")
         .unwrap()
-        .args(&["--addr", addr])
+        .args(["--addr", addr])
         .current_dir(&temp_dir)
         .write_stdin("get key1")
         .assert()
@@ -104,7 +108,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     assert_cmd::Command::cargo_bin("kv-client")
         .unwrap()
-        .args(&["--addr", addr])
+        .args(["--addr", addr])
         .current_dir(&temp_dir)
         .write_stdin("set key1 value2")
         .assert()
@@ -113,7 +117,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     assert_cmd::Command::cargo_bin("kv-client")
         .unwrap()
-        .args(&["--addr", addr])
+        .args(["--addr", addr])
         .current_dir(&temp_dir)
         .write_stdin("get key1")
         .assert()
@@ -122,7 +126,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     assert_cmd::Command::cargo_bin("kv-client")
         .unwrap()
-        .args(&["--addr", addr])
+        .args(["--addr", addr])
         .current_dir(&temp_dir)
         .write_stdin("get key2")
         .assert()
@@ -131,7 +135,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     assert_cmd::Command::cargo_bin("kv-client")
         .unwrap()
-        .args(&["--addr", addr])
+        .args(["--addr", addr])
         .current_dir(&temp_dir)
         .write_stdin("rm key2")
         .assert()
@@ -140,7 +144,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     assert_cmd::Command::cargo_bin("kv-client")
         .unwrap()
-        .args(&["--addr", addr])
+        .args(["--addr", addr])
         .current_dir(&temp_dir)
         .write_stdin("set key2 value3")
         .assert()
@@ -149,7 +153,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     assert_cmd::Command::cargo_bin("kv-client")
         .unwrap()
-        .args(&["--addr", addr])
+        .args(["--addr", addr])
         .current_dir(&temp_dir)
         .write_stdin("rm key1")
         .assert()
@@ -163,19 +167,20 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kv-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait server");
     });
     thread::sleep(Duration::from_secs(1));
 
     assert_cmd::Command::cargo_bin("kv-client")
         .unwrap()
-        .args(&["--addr", addr])
+        .args(["--addr", addr])
         .current_dir(&temp_dir)
         .write_stdin("get key2")
         .assert()
@@ -183,12 +188,28 @@ fn cli_access_server(engine: &str, addr: &str) {
         .stdout(contains("value3"));
     assert_cmd::Command::cargo_bin("kv-client")
         .unwrap()
-        .args(&["--addr", addr])
+        .args(["--addr", addr])
         .current_dir(&temp_dir)
         .write_stdin("get key1")
         .assert()
         .success()
         .stdout(contains("Key not found"));
+    assert_cmd::Command::cargo_bin("kv-client")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .write_stdin("len")
+        .assert()
+        .success()
+        .stdout(contains("> 1"));
+    assert_cmd::Command::cargo_bin("kv-client")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .write_stdin("bucket users\nset key2 user\nget key2\nbucket\nget key2")
+        .assert()
+        .success()
+        .stdout(contains("user").and(contains("value3")));
     sender.send(()).unwrap();
     handle.join().unwrap();
 }
@@ -202,3 +223,562 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_access_server_mem_engine() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4006";
+    let mut server = Command::cargo_bin("kv-server").unwrap();
+    let mut child = server
+        .args(["--engine", "mem", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    assert_cmd::Command::cargo_bin("kv-client")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .write_stdin("set key1 value1")
+        .assert()
+        .success()
+        .stdout(contains("Ok"));
+    assert_cmd::Command::cargo_bin("kv-client")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .write_stdin("get key1")
+        .assert()
+        .success()
+        .stdout(contains("value1"));
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+
+    // nothing is kept on disk
+    assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn cli_export_import() {
+    let temp_dir = TempDir::new().unwrap();
+    let source = temp_dir.path().join("source");
+    let target = temp_dir.path().join("target");
+    let export_path = temp_dir.path().join("export.jsonl");
+    fs::write(
+        temp_dir.path().join("input.jsonl"),
+        "{\"key\":\"key1\",\"value\":\"value1\"}\n\n{\"key\":\"key2\",\"value\":\"value2\"}\n",
+    )
+    .unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--dir", source.to_str().unwrap(), "import", "input.jsonl"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains("imported 2 pairs"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--dir", source.to_str().unwrap(), "export"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("{\"key\":\"key2\",\"value\":\"value2\"}"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--dir", source.to_str().unwrap(), "export", "export.jsonl"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    // into another engine
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--dir", target.to_str().unwrap(), "--engine", "sled"])
+        .args(["import", export_path.to_str().unwrap()])
+        .assert()
+        .success()
+        .stderr(contains("imported 2 pairs"));
+    assert_eq!(fs::read_to_string(target.join("engine")).unwrap(), "sled");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--dir", target.to_str().unwrap(), "export"])
+        .assert()
+        .success()
+        .stdout(contains("{\"key\":\"key1\",\"value\":\"value1\"}"));
+}
+
+#[test]
+fn cli_scan() {
+    let temp_dir = TempDir::new().unwrap();
+    // more keys than a page
+    let mut input = String::from("{\"key\":\"user\",\"value\":\"root\"}\n");
+    input.push_str("{\"key\":\"other\",\"value\":\"value\"}\n");
+    for i in 0..1500 {
+        input.push_str(&format!(
+            "{{\"key\":\"user:{:04}\",\"value\":\"{}\"}}\n",
+            i, i
+        ));
+    }
+    fs::write(temp_dir.path().join("input.jsonl"), input).unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "input.jsonl"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("other\nuser\nuser:0000\n"))
+        .stderr(contains("listed 1502 keys"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan", "--prefix", "user"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("user:1499\n"))
+        .stderr(contains("listed 1501 keys"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan", "--prefix", "user:", "--limit", "2", "--values"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("user:0000\t0\nuser:0001\t1\n")
+        .stderr(contains("listed 2 keys"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan", "--prefix", "none"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("")
+        .stderr(contains("listed 0 keys"));
+}
+
+#[test]
+fn cli_config_file() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+        temp_dir.path().join("kv.toml"),
+        r#"
+engine = "sled"
+dir = "db"
+addr = "127.0.0.1:4007"
+
+[pool]
+kind = "rayon"
+threads = 2
+
+[durability]
+sync = "interval"
+sync_interval_ms = 100
+"#,
+    )
+    .unwrap();
+    // the address on the command line overrides the file
+    let addr = "127.0.0.1:4008";
+    let mut server = Command::cargo_bin("kv-server").unwrap();
+    let mut child = server
+        .args(["--config", "kv.toml", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    assert_cmd::Command::cargo_bin("kv-client")
+        .unwrap()
+        .args(["--addr", addr])
+        .write_stdin("set key1 value1")
+        .assert()
+        .success()
+        .stdout(contains("Ok"));
+    assert_cmd::Command::cargo_bin("kv-client")
+        .unwrap()
+        .args(["--addr", addr])
+        .write_stdin("get key1")
+        .assert()
+        .success()
+        .stdout(contains("value1"));
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("db").join("engine")).unwrap(),
+        "sled"
+    );
+
+    // the engine on the command line does not match the db dir
+    Command::cargo_bin("kv-server")
+        .unwrap()
+        .args(["--config", "kv.toml", "--engine", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    fs::write(temp_dir.path().join("bad.toml"), "engin = \"kvs\"\n").unwrap();
+    Command::cargo_bin("kv-server")
+        .unwrap()
+        .args(["--config", "bad.toml"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("unknown field"));
+}
+
+#[test]
+fn cli_reload_config() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let config = temp_dir.path().join("kv.toml");
+    fs::write(&config, "engine = \"mem\"\naddr = \"127.0.0.1:4009\"\n").unwrap();
+    let addr = "127.0.0.1:4009";
+    let mut server = Command::cargo_bin("kv-server").unwrap();
+    let mut child = server
+        .args(["--config", "kv.toml"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    // an invalid file keeps the settings as they were
+    fs::write(&config, "engine = \"mem\"\n[limits]\nmax_frame = 16\n").unwrap();
+    assert_cmd::Command::cargo_bin("kv-client")
+        .unwrap()
+        .args(["--addr", addr])
+        .write_stdin("reload\nset key1 value1\n")
+        .assert()
+        .success()
+        .stdout(contains("unknown field").and(contains("Ok")));
+
+    fs::write(&config, "engine = \"mem\"\n[limits]\nmax_frame_size = 16\n").unwrap();
+    let value = "v".repeat(100_000);
+    assert_cmd::Command::cargo_bin("kv-client")
+        .unwrap()
+        .args(["--addr", addr])
+        .write_stdin(format!("reload\nset key2 {}\n", value))
+        .assert()
+        .success()
+        .stdout(contains("Ok").and(contains("maximum frame size of 16 bytes")));
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn cli_sigterm() {
+    let temp_dir = TempDir::new().unwrap();
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut child = Command::cargo_bin("kv-server")
+        .unwrap()
+        .args(["--engine", "mem", "--addr", "127.0.0.1:4010"])
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let status = Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    // exits cleanly, rather than killed by the signal
+    assert!(child.wait().unwrap().success());
+    let content = fs::read_to_string(&stderr_path).unwrap();
+    assert!(content.contains("receive SIGTERM"));
+    assert!(content.contains("server exited"));
+}
+
+/// Runs `kv-client` on a pseudo terminal, typing the keys of each line once
+/// the prompt is shown, then waiting for the output expected of the line.
+#[cfg(unix)]
+fn client_session(args: &[&str], lines: &[(&str, &str)]) {
+    use std::io::{Read, Write};
+    use std::os::fd::FromRawFd;
+    use std::process::Stdio;
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    let (mut master, mut slave) = (0, 0);
+    // SAFETY: openpty only writes the two descriptors it opens
+    let res = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            std::ptr::null(),
+        )
+    };
+    assert_eq!(res, 0);
+    // SAFETY: the descriptors were just opened, and are owned by the files alone
+    let (mut terminal, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+    let mut client = Command::cargo_bin("kv-client")
+        .unwrap()
+        .args(args)
+        .stdin(Stdio::from(slave.try_clone().unwrap()))
+        .stdout(Stdio::from(slave.try_clone().unwrap()))
+        .stderr(Stdio::from(slave))
+        .spawn()
+        .unwrap();
+
+    let output = Arc::new(Mutex::new(String::new()));
+    let mut reader = terminal.try_clone().unwrap();
+    let read = output.clone();
+    // ends once the client exited, the terminal having no other process
+    thread::spawn(move || {
+        let mut buf = [0; 1024];
+        while let Ok(n @ 1..) = reader.read(&mut buf) {
+            read.lock()
+                .unwrap()
+                .push_str(&String::from_utf8_lossy(&buf[..n]));
+        }
+    });
+    let mut seen = 0;
+    let mut wait_for = |text: &str| {
+        let start = Instant::now();
+        loop {
+            let output = output.lock().unwrap();
+            if let Some(pos) = output[seen..].find(text) {
+                seen += pos + text.len();
+                return;
+            }
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "{:?} not in {:?}",
+                text,
+                &output[seen..]
+            );
+            drop(output);
+            thread::sleep(Duration::from_millis(10));
+        }
+    };
+    for (keys, expected) in lines {
+        wait_for("> ");
+        terminal.write_all(keys.as_bytes()).unwrap();
+        wait_for(expected);
+    }
+    assert!(client.wait().unwrap().success());
+}
+
+/// Starts a `kv-server` keeping its keys in memory on `addr`, killed once the
+/// sender returned is sent to, or dropped, and the handle joined.
+fn mem_server(addr: &str) -> (mpsc::SyncSender<()>, thread::JoinHandle<()>) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut child = Command::cargo_bin("kv-server")
+        .unwrap()
+        .args(["--engine", "mem", "--addr", addr])
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait server");
+    });
+    thread::sleep(Duration::from_secs(1));
+    (sender, handle)
+}
+
+#[cfg(unix)]
+#[test]
+fn cli_client_line_editing() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4011";
+    let (sender, handle) = mem_server(addr);
+    let history = temp_dir.path().join("history");
+    let args = ["--addr", addr, "--history", history.to_str().unwrap()];
+
+    client_session(
+        &args,
+        &[
+            ("set a value1\r", "Ok"),
+            ("get a\r", "value1\r\n"),
+            // the up arrow goes back to the set
+            ("\x1b[A\x1b[A\r", "Ok"),
+            // Ctrl-R finds the get
+            ("\x12get\r", "value1\r\n"),
+            // the line is edited before being sent
+            ("gt a\x1b[D\x1b[D\x1b[De\r", "value1\r\n"),
+            ("exit\r", "client exited"),
+        ],
+    );
+    assert_eq!(
+        fs::read_to_string(&history).unwrap(),
+        // after the header of the file format of rustyline
+        "#V2\nset a value1\nget a\nset a value1\nget a\nexit\n"
+    );
+
+    // the history of the last session, Ctrl-D exiting
+    client_session(
+        &args,
+        &[("\x1b[A\x1b[A\r", "value1\r\n"), ("\x04", "client exited")],
+    );
+    assert!(fs::read_to_string(&history)
+        .unwrap()
+        .ends_with("exit\nget a\n"));
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn cli_client_completion() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4012";
+    let (sender, handle) = mem_server(addr);
+    let history = temp_dir.path().join("history");
+    let args = ["--addr", addr, "--history", history.to_str().unwrap()];
+
+    client_session(
+        &args,
+        &[
+            ("set user:1 alice\r", "Ok"),
+            ("set user:2 bob\r", "Ok"),
+            ("set us*r x\r", "Ok"),
+            // the command, then the prefix the keys share
+            ("ge\tuse\t1\r", "alice\r\n"),
+            // a second Tab lists the keys
+            ("get user:\t\t", "user:1  user:2\r\n"),
+            ("2\r", "bob\r\n"),
+            // the pattern characters of the prefix match themselves
+            ("get us*\t\r", "x\r\n"),
+            ("le\t\r", "3\r\n"),
+            ("exit\r", "client exited"),
+        ],
+    );
+    assert!(fs::read_to_string(&history)
+        .unwrap()
+        .contains("get user:1\nget user:2\nget us*r \nlen \n"));
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_client_one_shot() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4013";
+    let (sender, handle) = mem_server(addr);
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kv-client").unwrap();
+        cmd.args(["--addr", addr]).args(args).current_dir(&temp_dir);
+        cmd
+    };
+
+    client(&["set", "key1", "value 1"])
+        .assert()
+        .success()
+        .stdout("");
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout("value 1\n");
+    client(&["get", "key2"])
+        .assert()
+        .code(1)
+        .stdout("")
+        .stderr(contains("Key not found"));
+    client(&["rm", "key1"]).assert().success();
+    client(&["rm", "key1"])
+        .assert()
+        .code(1)
+        .stderr(contains("Key not found"));
+    Command::cargo_bin("kv-client")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4014", "get", "key1"])
+        .assert()
+        .code(2)
+        .stderr(contains("Error"));
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_client_script() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4015";
+    let (sender, handle) = mem_server(addr);
+    let script = temp_dir.path().join("commands.txt");
+    fs::write(
+        &script,
+        "# fill the store\nset key1 value1\n\nget key1\nget key2\nincr key1\nlen\n",
+    )
+    .unwrap();
+    let client = |args: &[&str]| {
+        let mut cmd = assert_cmd::Command::cargo_bin("kv-client").unwrap();
+        cmd.args(["--addr", addr]).args(args).current_dir(&temp_dir);
+        cmd
+    };
+
+    // stops at the first command failing
+    let output = client(&["--file", "commands.txt"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let lines: Vec<_> = std::str::from_utf8(&output.stdout)
+        .unwrap()
+        .lines()
+        .collect();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(
+        lines[0],
+        r#"{"command":"set key1 value1","line":2,"ok":true,"output":["Ok"]}"#
+    );
+    assert_eq!(
+        lines[1],
+        r#"{"command":"get key1","line":4,"ok":true,"output":["value1"]}"#
+    );
+    assert_eq!(
+        lines[2],
+        r#"{"command":"get key2","line":5,"ok":true,"output":[null]}"#
+    );
+    assert!(lines[3].starts_with(r#"{"command":"incr key1","error":"#));
+    assert!(lines[3].ends_with(r#""line":6,"ok":false}"#));
+
+    client(&["--file", "commands.txt", "--continue-on-error"])
+        .assert()
+        .code(2)
+        .stdout(contains(
+            r#"{"command":"len","line":7,"ok":true,"output":["1"]}"#,
+        ));
+
+    // the commands piped to the client with a file of -, without a prompt
+    client(&["--file", "-"])
+        .write_stdin("set key2 value2\nget key2\nexit\nget key1\n")
+        .assert()
+        .success()
+        .stdout(
+            "{\"command\":\"set key2 value2\",\"line\":1,\"ok\":true,\"output\":[\"Ok\"]}\n\
+             {\"command\":\"get key2\",\"line\":2,\"ok\":true,\"output\":[\"value2\"]}\n",
+        );
+    // without it, as text after the prompt, going on after an error
+    client(&[])
+        .write_stdin("incr key2\nget key2\n")
+        .assert()
+        .success()
+        .stdout(contains("> Error: ").and(contains("> value2\n")));
+    client(&["--file", "missing.txt"]).assert().code(2);
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
@@ -0,0 +1,153 @@
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    thread,
+    time::Duration,
+};
+
+use rust_kv::{
+    ChangeKind, Codec, KvClient, KvEngine, KvServer, KvServerBuilder, KvStore, Request, Response,
+    SharedQueueThreadPool, ThreadPool,
+};
+use tempfile::TempDir;
+
+fn builder<E: KvEngine>(engine: E, addr: &str) -> KvServerBuilder<E, SharedQueueThreadPool> {
+    KvServer::builder(engine, SharedQueueThreadPool::new(2).unwrap()).addr(addr)
+}
+
+fn run<E: KvEngine>(builder: KvServerBuilder<E, SharedQueueThreadPool>) {
+    let mut server = builder.build();
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(500));
+}
+
+fn bincode_client(addr: &str) -> KvClient {
+    KvClient::builder(addr)
+        .codec(Codec::Bincode)
+        .build()
+        .unwrap()
+}
+
+// Should run requests, pipelines, pub/sub and bulk loads over bincode, next to JSON clients
+#[test]
+fn bincode_requests() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:5101";
+    run(builder(KvStore::open(temp_dir.path()).unwrap(), addr));
+
+    let mut client = bincode_client(addr);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    client
+        .set_bytes("bytes".to_owned(), vec![0, 1, 255])
+        .unwrap();
+    assert_eq!(
+        client.get_bytes("bytes".to_owned()).unwrap(),
+        Some(vec![0, 1, 255])
+    );
+    assert_eq!(client.ttl("key1".to_owned()).unwrap(), None);
+    let err = client.remove("missing".to_owned()).unwrap_err();
+    assert_eq!(err.to_string(), "Key not found");
+
+    let resps = client
+        .pipeline()
+        .request(Request::Incr("counter".to_owned(), 2))
+        .get("key1".to_owned())
+        .send()
+        .unwrap();
+    assert!(matches!(resps[0], Ok(Response::Int(2))));
+    assert!(matches!(&resps[1], Ok(Response::Ok(Some(value))) if value == "value1"));
+
+    let summary = client
+        .bulk_load((0..3000).map(|i| (format!("bulk{}", i), i.to_string())))
+        .unwrap();
+    assert_eq!(summary.keys, 3000);
+
+    // a JSON client reads what the bincode client wrote
+    let mut json = KvClient::new(addr).unwrap();
+    assert_eq!(
+        json.get("bulk2999".to_owned()).unwrap(),
+        Some("2999".to_owned())
+    );
+
+    let mut subscriber = bincode_client(addr);
+    subscriber.subscribe("news".to_owned()).unwrap();
+    assert_eq!(
+        json.publish("news".to_owned(), "hello".to_owned()).unwrap(),
+        1
+    );
+    assert_eq!(
+        subscriber.next_message().unwrap(),
+        ("news".to_owned(), "hello".to_owned())
+    );
+}
+
+// Should stream watch events over bincode
+#[test]
+fn bincode_watch() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:5102";
+    run(builder(KvStore::open(temp_dir.path()).unwrap(), addr));
+
+    let mut events = bincode_client(addr).watch("config:".to_owned()).unwrap();
+    let mut client = bincode_client(addr);
+    client
+        .set("config:mode".to_owned(), "fast".to_owned())
+        .unwrap();
+    client.set("other".to_owned(), "value".to_owned()).unwrap();
+    client.remove("config:mode".to_owned()).unwrap();
+
+    let event = events.next().unwrap().unwrap();
+    assert_eq!(event.key, "config:mode");
+    assert_eq!(event.kind, ChangeKind::Set);
+    let event = events.next().unwrap().unwrap();
+    assert_eq!(event.key, "config:mode");
+    assert_eq!(event.kind, ChangeKind::Remove);
+}
+
+// Should refuse a codec the server does not accept, while JSON clients still connect
+#[test]
+fn refused_codec() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:5103";
+    run(builder(KvStore::open(temp_dir.path()).unwrap(), addr).codecs(&[Codec::Json]));
+
+    let mut client = bincode_client(addr);
+    let err = client.get("key1".to_owned()).unwrap_err();
+    assert_eq!(err.to_string(), "Unsupported operation: bincode codec");
+
+    let mut client = KvClient::new(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let mut client = KvClient::builder(addr).codec(Codec::Json).build().unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// Should echo the preamble of an accepted codec, and refuse an unknown one in JSON
+#[test]
+fn negotiate_raw() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:5104";
+    run(builder(KvStore::open(temp_dir.path()).unwrap(), addr));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(&[0, b'K', b'V', 1]).unwrap();
+    let mut preamble = [0; 4];
+    stream.read_exact(&mut preamble).unwrap();
+    assert_eq!(preamble, [0, b'K', b'V', 1]);
+
+    // an unknown codec
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(&[0, b'K', b'V', 9]).unwrap();
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer).unwrap();
+    assert_eq!(
+        answer,
+        r#"{"id":null,"response":{"Err":"Unsupported operation: codec 9"}}"#
+    );
+}
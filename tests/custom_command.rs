@@ -0,0 +1,91 @@
+use rust_kv::{
+    KvClient, KvEngine, KvError, KvServer, KvServerBuilder, MemStore, Request, RunningServer,
+    SharedQueueThreadPool, ThreadPool,
+};
+
+fn builder() -> KvServerBuilder<MemStore, SharedQueueThreadPool> {
+    KvServer::builder(MemStore::new(), SharedQueueThreadPool::new(2).unwrap())
+        .addr("127.0.0.1:0")
+        .custom_command("append", |engine, payload| {
+            let (key, suffix) = payload.split_once(' ').unwrap_or((&payload, ""));
+            let value = engine.get(key.to_owned())?.unwrap_or_default() + suffix;
+            engine.set(key.to_owned(), value.clone())?;
+            Ok(Some(value))
+        })
+        .custom_command("fail", |_, payload| Err(KvError::StringError(payload)))
+}
+
+fn connect(server: &RunningServer) -> KvClient {
+    KvClient::new(&server.addr().to_string()).unwrap()
+}
+
+// Should run the registered handlers against the engine, or the bucket of the client
+#[test]
+fn custom_commands() {
+    let server = builder().build().start().unwrap();
+    let mut client = connect(&server);
+
+    client.set("key1".to_owned(), "a".to_owned()).unwrap();
+    assert_eq!(
+        client
+            .custom("append".to_owned(), "key1 bc".to_owned())
+            .unwrap(),
+        Some("abc".to_owned())
+    );
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("abc".to_owned())
+    );
+
+    let err = client
+        .custom("fail".to_owned(), "version mismatch".to_owned())
+        .unwrap_err();
+    assert_eq!(err.to_string(), "version mismatch");
+    let err = client
+        .custom("missing".to_owned(), String::new())
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Unsupported operation: custom command missing"
+    );
+
+    client.set_bucket(Some("bucket".to_owned()));
+    assert_eq!(
+        client
+            .custom("append".to_owned(), "key1 x".to_owned())
+            .unwrap(),
+        Some("x".to_owned())
+    );
+    client.set_bucket(None);
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("abc".to_owned())
+    );
+
+    server.shutdown().unwrap();
+}
+
+// Should let the authorization hook see the name of the command, and refuse them read-only
+#[test]
+fn custom_command_refused() {
+    let server = builder()
+        .authorize(|_, request| !matches!(request, Request::Custom(name, _) if name == "fail"))
+        .build()
+        .start()
+        .unwrap();
+    let mut client = connect(&server);
+    let err = client.custom("fail".to_owned(), String::new()).unwrap_err();
+    assert_eq!(err.to_string(), "Permission denied");
+    client
+        .custom("append".to_owned(), "key1 a".to_owned())
+        .unwrap();
+    server.shutdown().unwrap();
+
+    let server = builder().read_only(true).build().start().unwrap();
+    let mut client = connect(&server);
+    let err = client
+        .custom("append".to_owned(), "key1 a".to_owned())
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Server is read-only");
+    server.shutdown().unwrap();
+}
@@ -1,16 +1,23 @@
 use std::{
+    error::Error,
+    fs, io,
+    path::Path,
     sync::{Arc, Barrier},
     thread,
+    time::Duration,
 };
 
-use rust_kv::{KvEngine, KvStore, Result};
+use rust_kv::{
+    ChangeKind, Compression, KvEngine, KvError, KvStore, KvStoreOptions, LogFormat, MemStore,
+    Result, SyncPolicy, WriteBatch,
+};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
 #[test]
 fn get_store_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -20,7 +27,7 @@ fn get_store_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -31,7 +38,7 @@ fn get_store_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -40,7 +47,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -52,14 +59,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -68,7 +75,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -76,19 +83,454 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
     Ok(())
 }
 
+// Should return pairs in key order, limited to the given range
+#[test]
+fn scan_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for key_id in [3, 1, 4, 5, 9, 2, 6] {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key4".to_owned())?;
+
+    let pairs = store.scan("key2".to_owned().."key6".to_owned())?;
+    let keys: Vec<&str> = pairs.iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(keys, vec!["key2", "key3", "key5"]);
+    assert_eq!(pairs[2].1, "value5");
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let pairs = store.scan(..)?;
+    assert_eq!(pairs.len(), 6);
+    assert_eq!(pairs.first().unwrap().0, "key1");
+    assert_eq!(pairs.last().unwrap().0, "key9");
+
+    Ok(())
+}
+
+// Should treat expired keys as missing, also after reopening
+#[test]
+fn expire_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value2".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value3".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert!(store.ttl("key1".to_owned())?.unwrap() <= Duration::from_millis(200));
+    assert!(store.ttl("key2".to_owned())?.unwrap() > Duration::from_secs(50));
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.ttl("key1".to_owned()).is_err());
+    assert_eq!(store.scan(..)?.len(), 1);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1".to_owned()).is_err());
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    store.set("key2".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.ttl("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should apply all writes of a batch, or none of them
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .put("key2".to_owned(), "value2".to_owned())
+        .put("key3".to_owned(), "value3".to_owned())
+        .delete("key1".to_owned())
+        .delete("key3".to_owned());
+    store.write_batch(batch)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    // deleting a missing key fails the whole batch
+    let mut batch = WriteBatch::new();
+    batch
+        .put("key4".to_owned(), "value4".to_owned())
+        .delete("key5".to_owned());
+    assert!(store.write_batch(batch).is_err());
+    assert_eq!(store.get("key4".to_owned())?, None);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should find every key of a batch larger than the bloom filter of the log
+#[test]
+fn large_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    for i in 1..3000 {
+        batch.put(format!("key{}", i), format!("value{}", i));
+    }
+    store.write_batch(batch)?;
+    for i in 0..3000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+// Should ignore a batch torn by a crash, and keep appending after it
+#[test]
+fn torn_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .put("key2".to_owned(), "value2".to_owned())
+        .put("key3".to_owned(), "value3".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
+
+    // cut the last record of the batch in half
+    let log = fs::read_dir(temp_dir.path())
+        .expect("unable to read directory")
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension() == Some("log".as_ref()))
+        .expect("no log file");
+    let len = fs::metadata(&log).unwrap().len();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&log)
+        .unwrap()
+        .set_len(len - 5)
+        .unwrap();
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    store.set("key4".to_owned(), "value4".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Should commit buffered writes atomically and detect conflicting writers
+#[test]
+fn transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("from".to_owned(), "10".to_owned())?;
+    store.set("to".to_owned(), "0".to_owned())?;
+
+    let observer = store.clone();
+    store.transaction(|txn| {
+        let from: u64 = txn.get("from".to_owned())?.unwrap().parse().unwrap();
+        let to: u64 = txn.get("to".to_owned())?.unwrap().parse().unwrap();
+        txn.set("from".to_owned(), (from - 3).to_string());
+        txn.set("to".to_owned(), (to + 3).to_string());
+        assert_eq!(txn.get("from".to_owned())?, Some("7".to_owned()));
+        // not visible outside before commit
+        assert_eq!(observer.get("from".to_owned())?, Some("10".to_owned()));
+        Ok(())
+    })?;
+    assert_eq!(store.get("from".to_owned())?, Some("7".to_owned()));
+    assert_eq!(store.get("to".to_owned())?, Some("3".to_owned()));
+
+    // a concurrent write to a key read by the transaction aborts it
+    let other = store.clone();
+    let result = store.transaction(|txn| {
+        txn.get("from".to_owned())?;
+        other.set("from".to_owned(), "100".to_owned())?;
+        txn.set("to".to_owned(), "5".to_owned());
+        Ok(())
+    });
+    assert!(matches!(result, Err(KvError::TransactionConflict)));
+    assert_eq!(store.get("to".to_owned())?, Some("3".to_owned()));
+
+    // an error in the closure discards the writes
+    let result: Result<()> = store.transaction(|txn| {
+        txn.set("to".to_owned(), "5".to_owned());
+        txn.remove("missing".to_owned())
+    });
+    assert!(matches!(result, Err(KvError::KeyNotFound)));
+    assert_eq!(store.get("to".to_owned())?, Some("3".to_owned()));
+
+    // removing a key the transaction set, missing from the store, writes nothing
+    store.transaction(|txn| {
+        txn.set("temp".to_owned(), "value".to_owned());
+        txn.remove("temp".to_owned())?;
+        assert_eq!(txn.get("temp".to_owned())?, None);
+        txn.set("to".to_owned(), "4".to_owned());
+        Ok(())
+    })?;
+    assert_eq!(store.get("temp".to_owned())?, None);
+    assert_eq!(store.get("to".to_owned())?, Some("4".to_owned()));
+
+    Ok(())
+}
+
+// Should keep seeing the data as of snapshot time, also across compactions
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+    }
+
+    let snapshot = store.snapshot();
+    store.remove("key0".to_owned())?;
+    store.set("key100".to_owned(), "new".to_owned())?;
+    // overwrite enough data to trigger compactions
+    for iter in 0..500 {
+        for key_id in 1..100 {
+            store.set(format!("key{}", key_id), format!("new{}", iter))?;
+        }
+    }
+
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(snapshot.get("key0".to_owned())?, Some("old".to_owned()));
+    assert_eq!(snapshot.get("key100".to_owned())?, None);
+    let pairs = snapshot.scan(..)?;
+    assert_eq!(pairs.len(), 100);
+    assert!(pairs.iter().all(|(_, value)| value == "old"));
+
+    let log_files = || {
+        fs::read_dir(temp_dir.path())
+            .expect("unable to read directory")
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+            .count()
+    };
+    let pinned_files = log_files();
+    drop(snapshot);
+    assert!(log_files() < pinned_files);
+    assert_eq!(store.get("key1".to_owned())?, Some("new499".to_owned()));
+
+    Ok(())
+}
+
+// Should replace the keys of a store with a snapshot segment, across a reopen,
+// and keep them as they are on a truncated segment
+#[test]
+fn install_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let source = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        source.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    source.remove("key0".to_owned())?;
+    let mut segment = Vec::new();
+    assert_eq!(source.snapshot().write_segment(&mut segment)?, 99);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("stale".to_owned(), "value".to_owned())?;
+    let changes = store.subscribe();
+    assert!(matches!(
+        store.install_snapshot(&segment[..segment.len() - 1]),
+        Err(KvError::Corruption { .. })
+    ));
+    assert_eq!(store.len()?, 1);
+
+    store.install_snapshot(&segment[..])?;
+    assert_eq!(store.get("stale".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(changes.try_iter().count(), 100);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len()?, 99);
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    store.set("key100".to_owned(), "value100".to_owned())?;
+    assert_eq!(store.get("key100".to_owned())?, Some("value100".to_owned()));
+
+    Ok(())
+}
+
+// Should report a record failing its checksum as corruption
+#[test]
+fn corrupted_record() -> Result<()> {
+    for format in [LogFormat::Json, LogFormat::Bincode] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let log = temp_dir.path().join("0.log");
+        let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().format(format))?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let second_record = fs::metadata(&log).expect("unable to stat log").len();
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        drop(store);
+
+        let mut content = fs::read(&log).expect("unable to read log");
+        let value_pos = content.windows(6).position(|w| w == b"value2").unwrap();
+        content[value_pos + 5] = b'3';
+        fs::write(&log, content).expect("unable to write log");
+
+        // The persisted index skips the replay, so the corruption shows on read
+        let store = KvStore::open(temp_dir.path())?;
+        match store.get("key2".to_owned()) {
+            Err(KvError::Corruption { file_id, offset }) => {
+                assert_eq!(file_id, 0);
+                assert_eq!(offset, second_record);
+            }
+            _ => panic!("corruption not detected"),
+        }
+        drop(store);
+
+        fs::remove_file(temp_dir.path().join("index.snapshot"))
+            .expect("unable to remove index snapshot");
+        match KvStore::open(temp_dir.path()) {
+            Err(KvError::Corruption { file_id, offset }) => {
+                assert_eq!(file_id, 0);
+                assert_eq!(offset, second_record);
+            }
+            _ => panic!("corruption not detected"),
+        }
+    }
+
+    Ok(())
+}
+
+// Should read records of both formats from the same log
+#[test]
+fn mixed_log_formats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().format(LogFormat::Json),
+    )?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().format(LogFormat::Bincode),
+    )?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions::new().format(LogFormat::Json),
+    )?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should read logs written before records carried a checksum
+#[test]
+fn legacy_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("0.log"),
+        r#"{"Set":["key1","value1"]}{"Set":["key2","value2"]}{"Remove":"key1"}"#,
+    )
+    .expect("unable to write log");
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should upgrade a directory of logs without a header to the current format
+#[test]
+fn migrate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let legacy_log = temp_dir.path().join("0.log");
+    fs::write(
+        &legacy_log,
+        r#"{"Set":["key1","value1"]}{"Set":["key2","value2"]}{"Remove":"key1"}"#,
+    )
+    .expect("unable to write log");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    // new records are not appended to the legacy log
+    assert_eq!(fs::metadata(&legacy_log)?.len(), 67);
+    store.migrate()?;
+    assert!(!legacy_log.exists());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Should refuse to open logs written in a newer format version
+#[test]
+fn unsupported_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("0.log");
+    let mut content = fs::read(&log).expect("unable to read log");
+    content[4..8].copy_from_slice(&99u32.to_le_bytes());
+    fs::write(&log, content).expect("unable to write log");
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::UnsupportedVersion(path, 99)) => assert!(path.ends_with("0.log")),
+        _ => panic!("unsupported version not detected"),
+    }
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -118,7 +560,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -132,17 +574,18 @@ fn compaction() -> Result<()> {
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     let barrier = Arc::new(Barrier::new(1001));
+    let mut handles = Vec::new();
     for i in 0..1000 {
-        let mut store = store.clone();
+        let store = store.clone();
         let barrier = barrier.clone();
-        thread::spawn(move || {
+        handles.push(thread::spawn(move || {
             store
                 .set(format!("key{}", i), format!("value{}", i))
                 .unwrap();
             barrier.wait();
-        });
+        }));
     }
     barrier.wait();
 
@@ -150,9 +593,12 @@ fn concurrent_set() -> Result<()> {
         assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
     }
 
-    // Open from disk again and check persistent data
+    // Open from disk again and check persistent data, once every handle is dropped
+    for handle in handles {
+        handle.join().unwrap();
+    }
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     for i in 0..1000 {
         assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
     }
@@ -163,7 +609,7 @@ fn concurrent_set() -> Result<()> {
 #[test]
 fn concurrent_get() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     for i in 0..100 {
         store
             .set(format!("key{}", i), format!("value{}", i))
@@ -172,7 +618,7 @@ fn concurrent_get() -> Result<()> {
 
     let mut handles = Vec::new();
     for thread_id in 0..100 {
-        let mut store = store.clone();
+        let store = store.clone();
         let handle = thread::spawn(move || {
             for i in 0..100 {
                 let key_id = (i + thread_id) % 100;
@@ -193,7 +639,7 @@ fn concurrent_get() -> Result<()> {
     let store = KvStore::open(temp_dir.path())?;
     let mut handles = Vec::new();
     for thread_id in 0..100 {
-        let mut store = store.clone();
+        let store = store.clone();
         let handle = thread::spawn(move || {
             for i in 0..100 {
                 let key_id = (i + thread_id) % 100;
@@ -212,12 +658,13 @@ fn concurrent_get() -> Result<()> {
     Ok(())
 }
 
-/// 1. change COMPACTION_THRESHOLD to 1024
-/// 2. change for loop i in 0..100000
+/// For a stress test, change for loop i in 0..100000
 #[test]
 fn concurrent_get_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    // a small threshold makes compaction run concurrently with reads
+    let options = KvStoreOptions::new().compaction_threshold(1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
     for i in 0..100 {
         store
             .set(format!("key{}", i), format!("value{}", i))
@@ -226,7 +673,7 @@ fn concurrent_get_set() -> Result<()> {
 
     let mut handles = Vec::new();
     for thread_id in 0..100 {
-        let mut store = store.clone();
+        let store = store.clone();
         let handle = thread::spawn(move || {
             for i in 0..100 {
                 let key_id = (i + thread_id) % 100;
@@ -250,3 +697,966 @@ fn concurrent_get_set() -> Result<()> {
 
     Ok(())
 }
+
+// Should allow one handle to be shared by threads without cloning it
+#[test]
+fn shared_handle() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+
+    thread::scope(|scope| {
+        for thread_id in 0..8 {
+            let store = &store;
+            scope.spawn(move || {
+                for i in 0..100 {
+                    let key = format!("key{}-{}", thread_id, i % 10);
+                    store.set(key.clone(), format!("value{}", i)).unwrap();
+                    assert_eq!(store.get(key).unwrap(), Some(format!("value{}", i)));
+                }
+            });
+        }
+    });
+    assert_eq!(store.len()?, 80);
+    assert_eq!(store.get("key3-9".to_owned())?, Some("value99".to_owned()));
+
+    Ok(())
+}
+
+// Should read values back from compressed segments after compaction and reopening
+#[test]
+fn compressed_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compression(Compression::Lz4);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    let dir_size = || {
+        fs::read_dir(temp_dir.path())
+            .expect("unable to read directory")
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum::<u64>()
+    };
+    let mut current_size = dir_size();
+    for iter in 0..1000 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+        let new_size = dir_size();
+        if new_size > current_size {
+            current_size = new_size;
+            continue;
+        }
+        // Compaction triggered
+        drop(store);
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        for key_id in 0..1000 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", iter))
+            );
+        }
+        return Ok(());
+    }
+
+    panic!("No compaction detected");
+}
+
+// Should start new log files at the configured size and compact at the configured threshold
+#[test]
+fn open_with_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_segment_size(4 * 1024)
+        .compaction_threshold(64 * 1024)
+        .sync_policy(SyncPolicy::Always)
+        .read_buffer_size(512);
+    let log_files = || {
+        fs::read_dir(temp_dir.path())
+            .expect("unable to read directory")
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+            .count()
+    };
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    assert!(log_files() > 1);
+
+    // overwrite enough data to pass the compaction threshold
+    for iter in 0..20 {
+        for key_id in 0..200 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    assert!(log_files() < 16);
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..200 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value19".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// Should store binary values alongside string values
+#[test]
+fn bytes_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let binary = vec![0, 159, 146, 150, 255];
+
+    store.set_bytes("key1".to_owned(), binary.clone())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get_bytes("key1".to_owned())?, Some(binary.clone()));
+    assert_eq!(
+        store.get_bytes("key2".to_owned())?,
+        Some(b"value2".to_vec())
+    );
+    assert!(matches!(
+        store.get("key1".to_owned()),
+        Err(KvError::Utf8(_))
+    ));
+    assert_eq!(store.get_bytes("key3".to_owned())?, None);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes("key1".to_owned())?, Some(binary));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get_bytes("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should skip binary values in scan and export rather than failing them, and fail multi_get like get
+#[test]
+fn bytes_value_skipped_by_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set_bytes("key1".to_owned(), vec![0xff, 0x00])?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(
+        store.scan(..)?,
+        vec![("key2".to_owned(), "value2".to_owned())]
+    );
+    assert_eq!(
+        store.snapshot().scan(..)?,
+        vec![("key2".to_owned(), "value2".to_owned())]
+    );
+    assert_eq!(
+        store.multi_get(vec!["key2".to_owned()])?,
+        vec![Some("value2".to_owned())]
+    );
+    assert!(matches!(
+        store.multi_get(vec!["key1".to_owned(), "key2".to_owned()]),
+        Err(KvError::Utf8(_))
+    ));
+    let mut exported = Vec::new();
+    assert_eq!(store.export(&mut exported)?, 1);
+    assert_eq!(
+        String::from_utf8(exported).unwrap(),
+        "{\"key\":\"key2\",\"value\":\"value2\"}\n"
+    );
+    // the keys skipped are still listed, and read as bytes
+    assert_eq!(
+        store.scan_keys(None, 10)?,
+        vec!["key1".to_owned(), "key2".to_owned()]
+    );
+    assert_eq!(store.get_bytes("key1".to_owned())?, Some(vec![0xff, 0x00]));
+
+    Ok(())
+}
+
+// Should count live keys only
+#[test]
+fn len() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.is_empty()?);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    store.remove("key3".to_owned())?;
+    store.set_with_ttl(
+        "key4".to_owned(),
+        "value5".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    assert_eq!(store.len()?, 3);
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.len()?, 2);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len()?, 2);
+    assert!(!store.is_empty()?);
+
+    Ok(())
+}
+
+// Should increment counters atomically, keeping their expiration
+#[test]
+fn incr_decr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.incr("counter".to_owned(), 5)?, 5);
+    assert_eq!(store.decr("counter".to_owned(), 7)?, -2);
+    store.set("name".to_owned(), "value".to_owned())?;
+    assert!(matches!(
+        store.incr("name".to_owned(), 1),
+        Err(KvError::NotAnInteger)
+    ));
+    store.set("max".to_owned(), i64::MAX.to_string())?;
+    assert!(matches!(
+        store.incr("max".to_owned(), 1),
+        Err(KvError::NotAnInteger)
+    ));
+    store.set_with_ttl(
+        "expiring".to_owned(),
+        "1".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    assert_eq!(store.incr("expiring".to_owned(), 1)?, 2);
+    assert!(store.ttl("expiring".to_owned())?.is_some());
+
+    let mut handles = Vec::new();
+    for _ in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for _ in 0..100 {
+                store.incr("counter".to_owned(), 1).unwrap();
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("798".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.incr("counter".to_owned(), 2)?, 800);
+
+    Ok(())
+}
+
+// Should open from the persisted index and the log after it, and ignore a stale or damaged one
+#[test]
+fn index_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let snapshot_path = temp_dir.path().join("index.snapshot");
+    let options = KvStoreOptions::new()
+        .index_snapshot_interval(1024)
+        .compaction_threshold(64 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for iter in 0..5 {
+        for key_id in 0..50 {
+            store.set(
+                format!("key{}", key_id),
+                format!("value{}-{}", key_id, iter),
+            )?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    assert!(snapshot_path.exists());
+
+    // A copy of the open store is what a crash leaves: the index and a log tail
+    let crash_dir = TempDir::new().expect("unable to create temporary working directory");
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.file_name() != Some("LOCK".as_ref()) {
+            fs::copy(&path, crash_dir.path().join(path.file_name().unwrap()))?;
+        }
+    }
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for key_id in 1..50 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}-4", key_id))
+            );
+        }
+        assert_eq!(store.len()?, 49);
+        Ok(())
+    };
+    check(&KvStore::open_with(crash_dir.path(), options.clone())?)?;
+
+    // An index from before a compaction no longer matches the log files
+    let old_snapshot = fs::read(&snapshot_path)?;
+    for iter in 5..100 {
+        for key_id in 1..50 {
+            store.set(
+                format!("key{}", key_id),
+                format!("value{}-{}", key_id, iter),
+            )?;
+        }
+    }
+    for key_id in 1..50 {
+        store.set(format!("key{}", key_id), format!("value{}-4", key_id))?;
+    }
+    drop(store);
+    check(&KvStore::open_with(temp_dir.path(), options.clone())?)?;
+    fs::write(&snapshot_path, old_snapshot)?;
+    check(&KvStore::open_with(temp_dir.path(), options.clone())?)?;
+
+    // A damaged index is rebuilt from the log
+    fs::write(&snapshot_path, b"damaged")?;
+    check(&KvStore::open_with(temp_dir.path(), options)?)?;
+
+    Ok(())
+}
+
+// Should keep large values in value logs, which compaction only rewrites once mostly stale
+#[test]
+fn value_log() -> Result<()> {
+    for compression in [Compression::None, Compression::Lz4] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let files_size = |extension: &str| -> Result<u64> {
+            let mut size = 0;
+            for entry in fs::read_dir(temp_dir.path())? {
+                let path = entry?.path();
+                if path.extension() == Some(extension.as_ref()) {
+                    size += fs::metadata(path)?.len();
+                }
+            }
+            Ok(size)
+        };
+        let options = KvStoreOptions::new()
+            .value_log_threshold(1024)
+            .compression(compression)
+            .compaction_threshold(256 * 1024)
+            .max_segment_size(128 * 1024);
+        let large = |key_id: usize, iter: usize| format!("{}-{}-", key_id, iter).repeat(400);
+
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        store.set("small".to_owned(), "value".to_owned())?;
+        store.set_bytes("bytes".to_owned(), vec![0xFF; 4096])?;
+        for key_id in 0..20 {
+            store.set(format!("key{}", key_id), large(key_id, 0))?;
+        }
+        // only pointers are in the log of keys
+        assert!(files_size("log")? < 20 * 1024);
+        assert!(files_size("vlog")? > 20 * 1024);
+
+        let snapshot = store.snapshot();
+        for iter in 1..40 {
+            for key_id in 0..20 {
+                store.set(format!("key{}", key_id), large(key_id, iter))?;
+            }
+        }
+        assert_eq!(snapshot.get("key3".to_owned())?, Some(large(3, 0)));
+        drop(snapshot);
+
+        // the next compaction removes the stale value logs
+        for iter in 40..60 {
+            for key_id in 0..20 {
+                store.set(format!("key{}", key_id), large(key_id, iter))?;
+            }
+        }
+        assert!(files_size("vlog")? < 60 * 20 * 1024);
+        let check = |store: &KvStore| -> Result<()> {
+            assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+            assert_eq!(store.get_bytes("bytes".to_owned())?, Some(vec![0xFF; 4096]));
+            for key_id in 0..20 {
+                assert_eq!(
+                    store.get(format!("key{}", key_id))?,
+                    Some(large(key_id, 59))
+                );
+            }
+            Ok(())
+        };
+        check(&store)?;
+        store.remove("key0".to_owned())?;
+        drop(store);
+
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        store.set("key0".to_owned(), large(0, 59))?;
+        check(&store)?;
+        drop(store);
+        fs::remove_file(temp_dir.path().join("index.snapshot"))?;
+        check(&KvStore::open_with(temp_dir.path(), options)?)?;
+    }
+    Ok(())
+}
+
+// Should serve small values from the index without reading the log
+#[test]
+fn inline_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .inline_value_size(64)
+        .compaction_threshold(1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for iter in 0..50 {
+        store.set("counter".to_owned(), iter.to_string())?;
+    }
+    store.set_bytes("flag".to_owned(), vec![1])?;
+    store.set("large".to_owned(), "x".repeat(100))?;
+    assert_eq!(store.get("counter".to_owned())?, Some("49".to_owned()));
+    assert_eq!(store.incr("counter".to_owned(), 1)?, 50);
+    drop(store);
+
+    // replayed from the log, small values are back in the index
+    fs::remove_file(temp_dir.path().join("index.snapshot"))?;
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    assert_eq!(store.get("counter".to_owned())?, Some("50".to_owned()));
+    drop(store);
+
+    // the log is not read for small values, which only the large one notices
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension() == Some("log".as_ref()) {
+            let len = fs::metadata(&path)?.len();
+            fs::write(&path, vec![0; len as usize])?;
+        }
+    }
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("counter".to_owned())?, Some("50".to_owned()));
+    assert_eq!(store.get_bytes("flag".to_owned())?, Some(vec![1]));
+    assert!(matches!(
+        store.get("large".to_owned()),
+        Err(KvError::Corruption { .. })
+    ));
+    Ok(())
+}
+
+// Should evict the least recently used keys once over the memory budget
+#[test]
+fn max_memory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_memory(64 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    let events = store.subscribe();
+    let value = "v".repeat(1000);
+    store.set("hot".to_owned(), value.clone())?;
+    for key_id in 0..500 {
+        store.set(format!("key{}", key_id), value.clone())?;
+        assert!(store.get("hot".to_owned())?.is_some());
+    }
+
+    // about 60 values fit in the budget
+    let len = store.len()?;
+    assert!((30..64).contains(&len), "{} keys left", len);
+    assert_eq!(store.get("hot".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key499".to_owned())?, Some(value.clone()));
+    let evicted = events
+        .try_iter()
+        .filter(|(_, kind)| *kind == ChangeKind::Remove)
+        .count();
+    assert_eq!(evicted, 501 - len);
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.len()?, len);
+    for key_id in 500..600 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    assert!(store.len()? < 64);
+    assert_eq!(store.get("key599".to_owned())?, Some(value));
+    Ok(())
+}
+
+// Should refuse to open a directory that another store has open
+#[test]
+fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvError::AlreadyLocked(_))
+    ));
+    // Clones share the lock, which is released with the last of them
+    let clone = store.clone();
+    drop(store);
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvError::AlreadyLocked(_))
+    ));
+    drop(clone);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Should get many keys at once, in the order asked, across log files and compressed blocks
+#[test]
+fn multi_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_segment_size(4 * 1024)
+        .compaction_threshold(16 * 1024)
+        .compression(Compression::Lz4);
+    let mut store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    for iter in 0..5 {
+        for key_id in 0..200 {
+            store.set(
+                format!("key{}", key_id),
+                format!("value{}-{}", key_id, iter),
+            )?;
+        }
+    }
+    store.remove("key7".to_owned())?;
+    store.set_with_ttl(
+        "key8".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    thread::sleep(Duration::from_millis(100));
+
+    let check = |store: &mut KvStore| -> Result<()> {
+        let keys: Vec<String> = (0..200)
+            .rev()
+            .map(|key_id| format!("key{}", key_id))
+            .chain(["missing".to_owned(), "key0".to_owned()])
+            .collect();
+        let values = store.multi_get(keys.clone())?;
+        assert_eq!(values.len(), keys.len());
+        for (key, value) in keys.into_iter().zip(values) {
+            assert_eq!(value, store.get(key)?);
+        }
+        assert_eq!(store.multi_get(vec![])?, Vec::<Option<String>>::new());
+        Ok(())
+    };
+    check(&mut store)?;
+    assert_eq!(
+        store.multi_get(vec![
+            "key1".to_owned(),
+            "key7".to_owned(),
+            "key8".to_owned()
+        ])?,
+        vec![Some("value1-4".to_owned()), None, None]
+    );
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open_with(temp_dir.path(), options)?;
+    check(&mut store)?;
+
+    Ok(())
+}
+
+// Should set a key only if it is absent or present, exactly once under contention
+#[test]
+fn conditional_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert!(!store.set_if_present("key".to_owned(), "value".to_owned())?);
+    assert_eq!(store.get("key".to_owned())?, None);
+    assert!(store.set_if_absent("key".to_owned(), "value1".to_owned())?);
+    assert!(!store.set_if_absent("key".to_owned(), "value2".to_owned())?);
+    assert_eq!(store.get("key".to_owned())?, Some("value1".to_owned()));
+    assert!(store.set_if_present("key".to_owned(), "value3".to_owned())?);
+    assert_eq!(store.get("key".to_owned())?, Some("value3".to_owned()));
+
+    // An expired key counts as absent
+    store.set_with_ttl(
+        "expiring".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    thread::sleep(Duration::from_millis(100));
+    assert!(!store.set_if_present("expiring".to_owned(), "value".to_owned())?);
+    assert!(store.set_if_absent("expiring".to_owned(), "value".to_owned())?);
+    assert_eq!(store.ttl("expiring".to_owned())?, None);
+
+    let mut handles = Vec::new();
+    for i in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            store
+                .set_if_absent("lock".to_owned(), format!("owner{}", i))
+                .unwrap()
+        }));
+    }
+    let winners = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .filter(|&set| set)
+        .count();
+    assert_eq!(winners, 1);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value3".to_owned()));
+    assert!(!store.set_if_absent("lock".to_owned(), "owner".to_owned())?);
+
+    Ok(())
+}
+
+// Should persist a filter per sealed log file, and rebuild missing or damaged ones
+#[test]
+fn bloom_filters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_segment_size(4 * 1024)
+        .compaction_threshold(64 * 1024);
+    let filter_files = || -> Vec<_> {
+        fs::read_dir(temp_dir.path())
+            .expect("unable to read directory")
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension() == Some("bloom".as_ref()))
+            .collect()
+    };
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for iter in 0..10 {
+        for key_id in 0..2000 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    let files = filter_files();
+    assert!(!files.is_empty());
+    for key_id in 0..2000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value9".to_owned())
+        );
+        assert_eq!(store.get(format!("missing{}", key_id))?, None);
+    }
+    drop(store);
+
+    // damage one filter and remove the others
+    fs::write(&files[0], b"garbage").expect("unable to write filter");
+    for path in &files[1..] {
+        fs::remove_file(path).expect("unable to remove filter");
+    }
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(filter_files().len(), files.len());
+    for key_id in 0..2000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value9".to_owned())
+        );
+    }
+
+    Ok(())
+}
+
+// Should serve fresh values through the cache across overwrites, evictions and compactions
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .value_cache_size(4 * 1024)
+        .compaction_threshold(16 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+
+    for iter in 0..50 {
+        for key_id in 0..100 {
+            store.set(
+                format!("key{}", key_id),
+                format!("value{}-{}", key_id, iter),
+            )?;
+        }
+        for key_id in 0..100 {
+            // the hot key is read more than once per round
+            for _ in 0..3 {
+                assert_eq!(
+                    store.get("key0".to_owned())?,
+                    Some(format!("value0-{}", iter))
+                );
+            }
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}-{}", key_id, iter))
+            );
+        }
+    }
+    store.remove("key0".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should get from memory the inline and cached values only, and the keys it rules out
+#[test]
+fn get_in_memory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .inline_value_size(8)
+        .value_cache_size(4 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+
+    store.set("small".to_owned(), "value".to_owned())?;
+    store.set("large".to_owned(), "a larger value".to_owned())?;
+    assert_eq!(
+        store.get_in_memory("small").transpose()?,
+        Some(Some("value".to_owned()))
+    );
+    assert_eq!(store.get_in_memory("missing").transpose()?, Some(None));
+    // read from the disk once, then cached
+    assert!(store.get_in_memory("large").is_none());
+    assert_eq!(
+        store.get("large".to_owned())?,
+        Some("a larger value".to_owned())
+    );
+    assert_eq!(
+        store.get_in_memory("large").transpose()?,
+        Some(Some("a larger value".to_owned()))
+    );
+
+    store.set_with_ttl(
+        "expiring".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(store.get_in_memory("expiring").transpose()?, Some(None));
+
+    // without a cache, only inline values
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    store.get("key".to_owned())?;
+    assert!(store.get_in_memory("key").is_none());
+    assert!(MemStore::new().get_in_memory("key").is_some());
+
+    Ok(())
+}
+
+// Should seal the active log once it exceeds the max segment size, and recover across segments
+#[test]
+fn segment_rotation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_segment_size(1024);
+    let log_sizes = || -> Vec<u64> {
+        let mut logs: Vec<_> = fs::read_dir(temp_dir.path())
+            .expect("unable to read directory")
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension() == Some("log".as_ref()))
+            .collect();
+        logs.sort_by_key(|path| {
+            let stem = path.file_stem().unwrap().to_str().unwrap();
+            stem.parse::<u64>().unwrap()
+        });
+        logs.iter()
+            .map(|path| fs::metadata(path).unwrap().len())
+            .collect()
+    };
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in (0..200).step_by(2) {
+        store.remove(format!("key{}", key_id))?;
+    }
+    let sizes = log_sizes();
+    assert!(sizes.len() > 5);
+    // a record may cross the limit, but never by more than its own size
+    let (_, sealed) = sizes.split_last().unwrap();
+    assert!(sealed.iter().all(|&size| (1024..1024 + 64).contains(&size)));
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.len()?, 100);
+    for key_id in 0..200 {
+        let expected = (key_id % 2 == 1).then(|| format!("value{}", key_id));
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+
+    Ok(())
+}
+
+// Should move all pairs between engines through JSON lines
+#[test]
+fn export_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..2000 {
+        store.set(format!("key{}", key_id), format!("value \"{}\"\n", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+
+    let mut exported = Vec::new();
+    assert_eq!(store.export(&mut exported)?, 1999);
+    let first_line = exported.split(|&byte| byte == b'\n').next().unwrap();
+    assert_eq!(
+        first_line,
+        br#"{"key":"key1","value":"value \"1\"\n"}"#.as_slice()
+    );
+
+    let mem_store = MemStore::new();
+    assert_eq!(mem_store.import(exported.as_slice())?, 1999);
+    assert_eq!(mem_store.scan(..)?, store.scan(..)?);
+
+    assert!(mem_store.import(&b"{\"key\":\"a\"}\n"[..]).is_err());
+
+    Ok(())
+}
+
+// Should compact before refusing writes over the disk quota, and accept removes
+#[test]
+fn disk_quota() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().max_disk_size(64 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+
+    // overwrites are compacted away instead of hitting the quota
+    for iter in 0..5000 {
+        store.set("key".to_owned(), format!("value{}", iter))?;
+    }
+    store.remove("key".to_owned())?;
+
+    let mut written = 0;
+    let err = loop {
+        match store.set(format!("key{}", written), "x".repeat(100)) {
+            Ok(()) => written += 1,
+            Err(err) => break err,
+        }
+    };
+    assert!(matches!(err, KvError::QuotaExceeded));
+    assert!(written > 100);
+    assert_eq!(store.get("key0".to_owned())?, Some("x".repeat(100)));
+
+    // removing keys makes room again
+    for key_id in 0..written / 2 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    store.set("key0".to_owned(), "value".to_owned())?;
+
+    Ok(())
+}
+
+// Should keep the keys of each bucket apart, in their own log files
+#[test]
+fn buckets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let users = store.bucket("users")?;
+    let orders = store.bucket("orders")?;
+
+    store.set("key1".to_owned(), "root".to_owned())?;
+    users.set("key1".to_owned(), "user".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("root".to_owned()));
+    assert_eq!(users.get("key1".to_owned())?, Some("user".to_owned()));
+    assert_eq!(orders.get("key1".to_owned())?, None);
+    // handles to the same bucket share their data
+    assert_eq!(
+        store.bucket("users")?.get("key1".to_owned())?,
+        Some("user".to_owned())
+    );
+    assert!(temp_dir.path().join("buckets").join("users").is_dir());
+
+    assert!(matches!(
+        store.bucket("../escape"),
+        Err(KvError::InvalidBucketName(_))
+    ));
+    assert!(store.bucket("").is_err());
+
+    // Open from disk again and check persistent data
+    drop((store, users, orders));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len()?, 1);
+    assert_eq!(
+        store.bucket("users")?.get("key1".to_owned())?,
+        Some("user".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should report committed changes in commit order
+#[test]
+fn subscribe() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let changes = store.subscribe();
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert!(store.remove("key1".to_owned()).is_err());
+    let mut batch = WriteBatch::new();
+    batch
+        .put("key2".to_owned(), "value2".to_owned())
+        .delete("key2".to_owned());
+    store.write_batch(batch)?;
+    store.incr("counter".to_owned(), 1)?;
+
+    let events: Vec<_> = changes.try_iter().collect();
+    assert_eq!(
+        events,
+        vec![
+            ("key1".to_owned(), ChangeKind::Set),
+            ("key1".to_owned(), ChangeKind::Remove),
+            ("key2".to_owned(), ChangeKind::Set),
+            ("key2".to_owned(), ChangeKind::Remove),
+            ("counter".to_owned(), ChangeKind::Set),
+        ]
+    );
+
+    // dropped receivers are forgotten
+    drop(changes);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    Ok(())
+}
+
+// Should be a std error, chaining the error it wraps as its source
+#[test]
+fn error_source() {
+    fn open(path: &Path) -> std::result::Result<KvStore, Box<dyn Error + Send + Sync>> {
+        Ok(KvStore::open(path)?)
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let file = temp_dir.path().join("file");
+    fs::write(&file, "not a directory").unwrap();
+    let err = open(&file).err().unwrap();
+    let err = err.downcast_ref::<KvError>().unwrap();
+    assert!(matches!(err, KvError::Io(_)));
+    let source = err.source().unwrap();
+    assert_eq!(source.to_string(), err.to_string());
+    assert!(source.downcast_ref::<io::Error>().is_some());
+
+    assert!(KvError::KeyNotFound.source().is_none());
+}
+
+// Should tell which operation and key failed, keeping the errors which tell it on their own
+#[test]
+fn error_context() {
+    let disk_full = io::Error::new(io::ErrorKind::StorageFull, "No space left on device");
+    let err = KvError::Io(disk_full).with_op("set", Some("user:42"));
+    assert_eq!(
+        err.to_string(),
+        "set key 'user:42' failed: No space left on device"
+    );
+    assert!(matches!(err.root(), KvError::Io(_)));
+    assert_eq!(err.source().unwrap().to_string(), "No space left on device");
+
+    // never wrapped twice
+    let err = err.with_op("batch", None);
+    assert!(matches!(&err, KvError::Op { op: "set", .. }));
+
+    let err = KvError::Corruption {
+        file_id: 3,
+        offset: 120,
+    }
+    .with_op("len", None);
+    assert_eq!(
+        err.to_string(),
+        "len failed: Corrupted record in log 3 at offset 120"
+    );
+
+    let err = KvError::KeyNotFound.with_op("remove", Some("key"));
+    assert!(matches!(err, KvError::KeyNotFound));
+    assert!(matches!(err.root(), KvError::KeyNotFound));
+}
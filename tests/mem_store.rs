@@ -0,0 +1,249 @@
+use std::{thread, time::Duration};
+
+use rust_kv::{KvEngine, KvError, MemStore, Result, WriteBatch};
+
+#[test]
+fn get_store_value() -> Result<()> {
+    let store = MemStore::new();
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    // clones share the same data
+    let clone = store.clone();
+    clone.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn remove_key() -> Result<()> {
+    let store = MemStore::new();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.remove("key1".to_owned()).is_ok());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1".to_owned()).is_err());
+    Ok(())
+}
+
+// Should return pairs in key order, limited to the given range
+#[test]
+fn scan_range() -> Result<()> {
+    let store = MemStore::new();
+    for key_id in [3, 1, 4, 5, 9, 2, 6] {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key4".to_owned())?;
+
+    let pairs = store.scan("key2".to_owned().."key6".to_owned())?;
+    let keys: Vec<&str> = pairs.iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(keys, vec!["key2", "key3", "key5"]);
+    assert_eq!(pairs[2].1, "value5");
+    assert_eq!(store.scan(..)?.len(), 6);
+
+    Ok(())
+}
+
+// Should treat expired keys as missing
+#[test]
+fn expire_key() -> Result<()> {
+    let store = MemStore::new();
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(store.ttl("key1".to_owned())?.unwrap() <= Duration::from_millis(200));
+    assert_eq!(store.ttl("key2".to_owned())?, None);
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.ttl("key1".to_owned()).is_err());
+    assert!(store.remove("key1".to_owned()).is_err());
+    assert_eq!(store.scan(..)?.len(), 1);
+
+    Ok(())
+}
+
+// Should apply all writes of a batch, or none of them
+#[test]
+fn write_batch() -> Result<()> {
+    let store = MemStore::new();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .put("key2".to_owned(), "value2".to_owned())
+        .put("key3".to_owned(), "value3".to_owned())
+        .delete("key1".to_owned())
+        .delete("key3".to_owned());
+    store.write_batch(batch)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    // deleting a missing key fails the whole batch
+    let mut batch = WriteBatch::new();
+    batch
+        .put("key4".to_owned(), "value4".to_owned())
+        .delete("key5".to_owned());
+    assert!(store.write_batch(batch).is_err());
+    assert_eq!(store.get("key4".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should store binary values alongside string values
+#[test]
+fn bytes_value() -> Result<()> {
+    let store = MemStore::new();
+    let binary = vec![0, 159, 146, 150, 255];
+
+    store.set_bytes("key1".to_owned(), binary.clone())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get_bytes("key1".to_owned())?, Some(binary));
+    assert_eq!(
+        store.get_bytes("key2".to_owned())?,
+        Some(b"value2".to_vec())
+    );
+    assert!(matches!(
+        store.get("key1".to_owned()),
+        Err(KvError::Utf8(_))
+    ));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get_bytes("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should skip binary values in scan and export rather than failing them, and fail multi_get like get
+#[test]
+fn bytes_value_skipped_by_scan() -> Result<()> {
+    let store = MemStore::new();
+
+    store.set_bytes("key1".to_owned(), vec![0xff, 0x00])?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(
+        store.scan(..)?,
+        vec![("key2".to_owned(), "value2".to_owned())]
+    );
+    assert_eq!(
+        store.multi_get(vec!["key2".to_owned()])?,
+        vec![Some("value2".to_owned())]
+    );
+    assert!(matches!(
+        store.multi_get(vec!["key1".to_owned(), "key2".to_owned()]),
+        Err(KvError::Utf8(_))
+    ));
+    let mut exported = Vec::new();
+    assert_eq!(store.export(&mut exported)?, 1);
+    assert_eq!(
+        String::from_utf8(exported).unwrap(),
+        "{\"key\":\"key2\",\"value\":\"value2\"}\n"
+    );
+    // the keys skipped are still listed, and read as bytes
+    assert_eq!(
+        store.scan_keys(None, 10)?,
+        vec!["key1".to_owned(), "key2".to_owned()]
+    );
+    assert_eq!(store.get_bytes("key1".to_owned())?, Some(vec![0xff, 0x00]));
+
+    Ok(())
+}
+
+// Should count live keys only
+#[test]
+fn len() -> Result<()> {
+    let store = MemStore::new();
+    assert!(store.is_empty()?);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set_with_ttl(
+        "key3".to_owned(),
+        "value4".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    assert_eq!(store.len()?, 2);
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.len()?, 1);
+    assert!(!store.is_empty()?);
+
+    Ok(())
+}
+
+// Should increment counters, keeping their expiration
+#[test]
+fn incr_decr() -> Result<()> {
+    let store = MemStore::new();
+
+    assert_eq!(store.incr("counter".to_owned(), 5)?, 5);
+    assert_eq!(store.decr("counter".to_owned(), 7)?, -2);
+    store.set("name".to_owned(), "value".to_owned())?;
+    assert!(matches!(
+        store.incr("name".to_owned(), 1),
+        Err(KvError::NotAnInteger)
+    ));
+    store.set_with_ttl(
+        "expiring".to_owned(),
+        "1".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    assert_eq!(store.incr("expiring".to_owned(), 1)?, 2);
+    assert!(store.ttl("expiring".to_owned())?.is_some());
+
+    Ok(())
+}
+
+// Should set a key only if it is absent or present
+#[test]
+fn conditional_set() -> Result<()> {
+    let store = MemStore::new();
+
+    assert!(!store.set_if_present("key".to_owned(), "value".to_owned())?);
+    assert!(store.set_if_absent("key".to_owned(), "value1".to_owned())?);
+    assert!(!store.set_if_absent("key".to_owned(), "value2".to_owned())?);
+    assert_eq!(store.get("key".to_owned())?, Some("value1".to_owned()));
+    assert!(store.set_if_present("key".to_owned(), "value3".to_owned())?);
+    assert_eq!(store.get("key".to_owned())?, Some("value3".to_owned()));
+
+    store.set_with_ttl(
+        "expiring".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    thread::sleep(Duration::from_millis(100));
+    assert!(!store.set_if_present("expiring".to_owned(), "value".to_owned())?);
+    assert!(store.set_if_absent("expiring".to_owned(), "value".to_owned())?);
+
+    Ok(())
+}
+
+// Should keep the keys of each bucket apart
+#[test]
+fn buckets() -> Result<()> {
+    let store = MemStore::new();
+    let users = store.bucket("users")?;
+
+    store.set("key1".to_owned(), "root".to_owned())?;
+    users.set("key1".to_owned(), "user".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("root".to_owned()));
+    assert_eq!(
+        store.bucket("users")?.get("key1".to_owned())?,
+        Some("user".to_owned())
+    );
+    assert_eq!(store.bucket("orders")?.len()?, 0);
+    assert!(matches!(
+        store.bucket("a b"),
+        Err(KvError::InvalidBucketName(_))
+    ));
+
+    Ok(())
+}
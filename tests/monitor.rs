@@ -0,0 +1,80 @@
+use std::{thread, time::Duration};
+
+use rust_kv::{
+    KvClient, KvServer, KvServerBuilder, MemStore, MonitorEvent, SharedQueueThreadPool, ThreadPool,
+};
+
+fn builder(addr: &str) -> KvServerBuilder<MemStore, SharedQueueThreadPool> {
+    KvServer::builder(MemStore::new(), SharedQueueThreadPool::new(2).unwrap()).addr(addr)
+}
+
+fn run(builder: KvServerBuilder<MemStore, SharedQueueThreadPool>) {
+    let mut server = builder.build();
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(500));
+}
+
+fn op(event: &MonitorEvent) -> (&str, Option<&str>, u64) {
+    (&event.op, event.key.as_deref(), event.dropped)
+}
+
+// Should stream the requests of every connection, with their peer, in the order they are read
+#[test]
+fn monitor() {
+    let addr = "127.0.0.1:5301".to_owned();
+    run(builder(&addr));
+
+    let mut events = KvClient::new(&addr).unwrap().monitor().unwrap();
+    let mut client = KvClient::new(&addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.set_bucket(Some("bucket".to_owned()));
+    client.get("key1".to_owned()).unwrap();
+    client.ping(None).unwrap();
+
+    let event = events.next().unwrap().unwrap();
+    assert_eq!(op(&event), ("set", Some("key1"), 0));
+    assert!(event.addr.starts_with("127.0.0.1:"));
+    let next = events.next().unwrap().unwrap();
+    assert_eq!(op(&next), ("get", Some("key1"), 0));
+    assert_eq!(next.addr, event.addr);
+    assert!(next.time >= event.time);
+    assert_eq!(op(&events.next().unwrap().unwrap()), ("ping", None, 0));
+}
+
+// Should send a monitor the requests of a second up to the rate, then count the ones dropped
+#[test]
+fn monitor_rate() {
+    let addr = "127.0.0.1:5302".to_owned();
+    run(builder(&addr).monitor_rate(2));
+
+    let mut events = KvClient::new(&addr).unwrap().monitor().unwrap();
+    let mut client = KvClient::new(&addr).unwrap();
+    for i in 0..5 {
+        client.set(format!("key{}", i), "value".to_owned()).unwrap();
+    }
+    thread::sleep(Duration::from_millis(1100));
+    client.get("key0".to_owned()).unwrap();
+
+    assert_eq!(
+        op(&events.next().unwrap().unwrap()),
+        ("set", Some("key0"), 0)
+    );
+    assert_eq!(
+        op(&events.next().unwrap().unwrap()),
+        ("set", Some("key1"), 0)
+    );
+    assert_eq!(
+        op(&events.next().unwrap().unwrap()),
+        ("get", Some("key0"), 3)
+    );
+}
+
+// Should refuse to monitor the server to the clients the authorizer refuses admin requests
+#[test]
+fn monitor_authorize() {
+    let addr = "127.0.0.1:5303".to_owned();
+    run(builder(&addr).authorize(|identity, request| !request.is_admin() || identity.is_some()));
+
+    let result = KvClient::new(&addr).unwrap().monitor();
+    assert_eq!(result.err().unwrap().to_string(), "Permission denied");
+}
@@ -0,0 +1,89 @@
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    sync::Arc,
+};
+
+use rust_kv::{
+    KvClient, KvServer, MemStore, NamespaceMetrics, RunningServer, ServerMetrics,
+    SharedQueueThreadPool, ThreadPool,
+};
+
+fn start() -> (RunningServer, Arc<ServerMetrics>) {
+    let server = KvServer::builder(MemStore::new(), SharedQueueThreadPool::new(2).unwrap())
+        .addr("127.0.0.1:0")
+        .metrics(true)
+        .authorize(|_, request| !request.namespaces().contains(&"secret"))
+        .build();
+    let metrics = server.metrics().unwrap();
+    (server.start().unwrap(), metrics)
+}
+
+/// Sends `request`, in JSON, over `stream`, returning the response.
+fn round_trip(stream: &mut TcpStream, request: &str) -> String {
+    let frame = format!(r#"{{"id":0,"request":{}}}"#, request);
+    stream.write_all(frame.as_bytes()).unwrap();
+    let mut buf = [0; 1024];
+    let n = stream.read(&mut buf).unwrap();
+    String::from_utf8_lossy(&buf[..n]).into_owned()
+}
+
+// Should run the requests of a connection in the bucket it uses, until it uses another
+#[test]
+fn use_namespace() {
+    let (server, _) = start();
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    assert!(round_trip(&mut stream, r#"{"Use":"app1"}"#).contains("Ok"));
+    assert!(round_trip(&mut stream, r#"{"Set":["key1","value1"]}"#).contains("Ok"));
+    assert!(round_trip(&mut stream, r#"{"Get":"key1"}"#).contains("value1"));
+    // the server answers a ping itself, whatever the namespace
+    assert!(round_trip(&mut stream, r#"{"Ping":null}"#).contains("Pong"));
+
+    let mut client = KvClient::new(&server.addr().to_string()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    client.set_bucket(Some("app1".to_owned()));
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    assert!(round_trip(&mut stream, r#"{"Use":null}"#).contains("Ok"));
+    assert!(round_trip(&mut stream, r#"{"Get":"key1"}"#).contains(r#"{"Ok":null}"#));
+    assert!(round_trip(&mut stream, r#"{"Use":"../escape"}"#).contains("Err"));
+    server.shutdown().unwrap();
+}
+
+// Should refuse the namespaces the authorizer denies, and count the requests of each
+#[test]
+fn namespace_auth_and_metrics() {
+    let (server, metrics) = start();
+    let mut stream = TcpStream::connect(server.addr()).unwrap();
+    assert!(round_trip(&mut stream, r#"{"Use":"secret"}"#).contains("Permission denied"));
+    // the connection stays in the default keyspace
+    assert!(round_trip(&mut stream, r#"{"Set":["key1","value1"]}"#).contains("Ok"));
+
+    let mut client = KvClient::new(&server.addr().to_string()).unwrap();
+    client.set_bucket(Some("secret".to_owned()));
+    assert!(client.get("key1".to_owned()).is_err());
+    client.set_bucket(Some("app1".to_owned()));
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.get("key1".to_owned()).unwrap();
+
+    let namespaces = metrics.namespaces();
+    assert_eq!(
+        namespaces["secret"],
+        NamespaceMetrics {
+            requests: 2,
+            failed_requests: 2,
+        }
+    );
+    assert_eq!(
+        namespaces["app1"],
+        NamespaceMetrics {
+            requests: 2,
+            failed_requests: 0,
+        }
+    );
+    assert_eq!(namespaces.len(), 2);
+    server.shutdown().unwrap();
+}
@@ -0,0 +1,114 @@
+use tempfile::TempDir;
+
+use rust_kv::{KvEngine, KvError, KvStore, MemStore, PrefixedEngine, Result, WriteBatch};
+
+// Should keep the keys of each prefix apart, whatever the engine
+fn namespaces<E: KvEngine>(engine: E) -> Result<()> {
+    let users = PrefixedEngine::new(engine.clone(), "users/");
+    let orders = PrefixedEngine::new(engine.clone(), "orders/");
+    engine.set("other".to_owned(), "value".to_owned())?;
+
+    users.set("alice".to_owned(), "Alice".to_owned())?;
+    users.set("bob".to_owned(), "Bob".to_owned())?;
+    orders.set("alice".to_owned(), "order1".to_owned())?;
+    assert_eq!(users.get("alice".to_owned())?, Some("Alice".to_owned()));
+    assert_eq!(orders.get("alice".to_owned())?, Some("order1".to_owned()));
+    assert_eq!(orders.get("bob".to_owned())?, None);
+    assert_eq!(
+        engine.get("users/alice".to_owned())?,
+        Some("Alice".to_owned())
+    );
+
+    assert_eq!(
+        users.scan(..)?,
+        vec![
+            ("alice".to_owned(), "Alice".to_owned()),
+            ("bob".to_owned(), "Bob".to_owned())
+        ]
+    );
+    assert_eq!(
+        users.scan("b".to_owned()..)?,
+        vec![("bob".to_owned(), "Bob".to_owned())]
+    );
+    assert_eq!(users.len()?, 2);
+    assert_eq!(orders.len()?, 1);
+
+    let mut batch = WriteBatch::new();
+    batch.delete("alice".to_owned());
+    batch.put("carol".to_owned(), "Carol".to_owned());
+    users.write_batch(batch)?;
+    assert_eq!(users.get("alice".to_owned())?, None);
+    assert_eq!(orders.get("alice".to_owned())?, Some("order1".to_owned()));
+    assert_eq!(
+        engine.get("users/carol".to_owned())?,
+        Some("Carol".to_owned())
+    );
+
+    assert_eq!(orders.incr("count".to_owned(), 2)?, 2);
+    assert!(matches!(
+        orders.remove("carol".to_owned()),
+        Err(KvError::KeyNotFound)
+    ));
+    assert_eq!(engine.len()?, 5);
+    Ok(())
+}
+
+#[test]
+fn namespaces_mem() -> Result<()> {
+    namespaces(MemStore::new())
+}
+
+#[test]
+fn namespaces_kvs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    namespaces(KvStore::open(temp_dir.path())?)
+}
+
+// Should scan every key under a prefix ending with the largest character
+#[test]
+fn prefix_end() -> Result<()> {
+    let engine = MemStore::new();
+    let prefix = format!("a{}", char::MAX);
+    let prefixed = PrefixedEngine::new(engine.clone(), prefix.clone());
+    prefixed.set("key".to_owned(), "value".to_owned())?;
+    engine.set(format!("{}\u{10FFFF}", prefix), "max".to_owned())?;
+    engine.set("b".to_owned(), "outside".to_owned())?;
+    assert_eq!(
+        prefixed.scan(..)?,
+        vec![
+            ("key".to_owned(), "value".to_owned()),
+            ("\u{10FFFF}".to_owned(), "max".to_owned())
+        ]
+    );
+    Ok(())
+}
+
+// Should list and count the keys under the prefix whose value is binary, which scan skips
+#[test]
+fn bytes_value_keys() -> Result<()> {
+    let engine = MemStore::new();
+    let prefixed = PrefixedEngine::new(engine.clone(), "app/");
+    engine.set("app".to_owned(), "before".to_owned())?;
+    engine.set("apq".to_owned(), "after".to_owned())?;
+    prefixed.set(String::new(), "empty".to_owned())?;
+    prefixed.set_bytes("key1".to_owned(), vec![0xff, 0x00])?;
+    prefixed.set("key2".to_owned(), "value2".to_owned())?;
+
+    assert_eq!(
+        prefixed.scan(..)?,
+        vec![
+            (String::new(), "empty".to_owned()),
+            ("key2".to_owned(), "value2".to_owned())
+        ]
+    );
+    assert_eq!(
+        prefixed.scan_keys(None, 10)?,
+        vec![String::new(), "key1".to_owned(), "key2".to_owned()]
+    );
+    assert_eq!(
+        prefixed.scan_keys(Some(String::new()), 1)?,
+        vec!["key1".to_owned()]
+    );
+    assert_eq!(prefixed.len()?, 3);
+    Ok(())
+}
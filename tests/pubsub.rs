@@ -0,0 +1,126 @@
+use std::{thread, time::Duration};
+
+use rust_kv::{
+    KvClient, KvServer, KvServerBuilder, MemStore, Request, SharedQueueThreadPool, ThreadPool,
+};
+
+fn builder(addr: &str) -> KvServerBuilder<MemStore, SharedQueueThreadPool> {
+    KvServer::builder(MemStore::new(), SharedQueueThreadPool::new(2).unwrap()).addr(addr)
+}
+
+fn run(builder: KvServerBuilder<MemStore, SharedQueueThreadPool>) {
+    let mut server = builder.build();
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(500));
+}
+
+// Should send each message to the connections subscribed to its channel
+#[test]
+fn publish_subscribe() {
+    let addr = "127.0.0.1:4601".to_owned();
+    run(builder(&addr));
+
+    let mut subscriber1 = KvClient::new(&addr).unwrap();
+    subscriber1.subscribe("news".to_owned()).unwrap();
+    subscriber1.subscribe("news".to_owned()).unwrap();
+    subscriber1.subscribe("sports".to_owned()).unwrap();
+    let mut subscriber2 = KvClient::new(&addr).unwrap();
+    subscriber2.subscribe("news".to_owned()).unwrap();
+
+    let mut publisher = KvClient::new(&addr).unwrap();
+    assert_eq!(
+        publisher
+            .publish("news".to_owned(), "hello".to_owned())
+            .unwrap(),
+        2
+    );
+    assert_eq!(
+        publisher
+            .publish("sports".to_owned(), "goal".to_owned())
+            .unwrap(),
+        1
+    );
+    assert_eq!(
+        publisher
+            .publish("weather".to_owned(), "rain".to_owned())
+            .unwrap(),
+        0
+    );
+
+    assert_eq!(
+        subscriber1.next_message().unwrap(),
+        ("news".to_owned(), "hello".to_owned())
+    );
+    assert_eq!(
+        subscriber1.next_message().unwrap(),
+        ("sports".to_owned(), "goal".to_owned())
+    );
+    assert_eq!(
+        subscriber2.next_message().unwrap(),
+        ("news".to_owned(), "hello".to_owned())
+    );
+
+    // a subscribed connection still runs requests, keeping the messages read meanwhile
+    publisher
+        .publish("news".to_owned(), "again".to_owned())
+        .unwrap();
+    thread::sleep(Duration::from_millis(100));
+    subscriber2
+        .set("key1".to_owned(), "value1".to_owned())
+        .unwrap();
+    assert_eq!(
+        subscriber2.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        subscriber2.next_message().unwrap(),
+        ("news".to_owned(), "again".to_owned())
+    );
+
+    // a closed connection is no longer subscribed
+    drop(subscriber2);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(
+        publisher
+            .publish("news".to_owned(), "bye".to_owned())
+            .unwrap(),
+        1
+    );
+}
+
+// Should keep a subscribed connection open past the idle timeout
+#[test]
+fn idle_subscriber() {
+    let addr = "127.0.0.1:4602".to_owned();
+    run(builder(&addr).idle_timeout(Duration::from_millis(200)));
+
+    let mut subscriber = KvClient::new(&addr).unwrap();
+    subscriber.subscribe("news".to_owned()).unwrap();
+    thread::sleep(Duration::from_millis(500));
+
+    let mut publisher = KvClient::new(&addr).unwrap();
+    assert_eq!(
+        publisher
+            .publish("news".to_owned(), "hello".to_owned())
+            .unwrap(),
+        1
+    );
+    assert_eq!(
+        subscriber.next_message().unwrap(),
+        ("news".to_owned(), "hello".to_owned())
+    );
+}
+
+// Should authorize subscribing and publishing like other requests
+#[test]
+fn authorize() {
+    let addr = "127.0.0.1:4603".to_owned();
+    run(builder(&addr).authorize(|_, request| !matches!(request, Request::Publish(..))));
+
+    let mut client = KvClient::new(&addr).unwrap();
+    client.subscribe("news".to_owned()).unwrap();
+    let err = client
+        .publish("news".to_owned(), "hello".to_owned())
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Permission denied");
+}
@@ -0,0 +1,105 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use rust_kv::{
+    KvClient, KvError, KvServer, KvServerBuilder, MemStore, SharedQueueThreadPool, ThreadPool,
+};
+
+fn builder(addr: &str) -> KvServerBuilder<MemStore, SharedQueueThreadPool> {
+    KvServer::builder(MemStore::new(), SharedQueueThreadPool::new(2).unwrap()).addr(addr)
+}
+
+fn start(mut server: KvServer<MemStore, SharedQueueThreadPool>) {
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(500));
+}
+
+// Should apply new settings to the open connections
+#[test]
+fn reload_handle() {
+    let addr = "127.0.0.1:5201";
+    let server = builder(addr).build();
+    let handle = server.reload_handle();
+    start(server);
+
+    let mut client = KvClient::new(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    handle.authorize(|_, request| !request.is_write());
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    let err = client
+        .set("key2".to_owned(), "value2".to_owned())
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Permission denied");
+    handle.allow_all();
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+
+    handle.set_max_frame_size(64);
+    let err = client
+        .set("key3".to_owned(), "v".repeat(100_000))
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Request larger than the maximum frame size of 64 bytes"
+    );
+}
+
+// Should run the reload hook for the reload admin command and on SIGHUP, refused without a hook
+#[test]
+fn reload_command() {
+    let addr = "127.0.0.1:5202";
+    let reloads = Arc::new(AtomicUsize::new(0));
+    let counted = reloads.clone();
+    start(
+        builder(addr)
+            .on_reload(move |handle| match counted.fetch_add(1, Ordering::SeqCst) {
+                0 => {
+                    handle.set_request_timeout(Some(Duration::from_secs(1)));
+                    Ok(())
+                }
+                _ => Err(KvError::StringError("invalid config".to_owned())),
+            })
+            .build(),
+    );
+
+    let mut client = KvClient::new(addr).unwrap();
+    client.reload().unwrap();
+    assert_eq!(reloads.load(Ordering::SeqCst), 1);
+    assert_eq!(client.reload().unwrap_err().to_string(), "invalid config");
+    assert_eq!(reloads.load(Ordering::SeqCst), 2);
+
+    // a client in a bucket reloads the server too
+    client.set_bucket(Some("bucket".to_owned()));
+    client.reload().unwrap_err();
+    assert_eq!(reloads.load(Ordering::SeqCst), 3);
+
+    // the only server of these tests listening to SIGHUP
+    #[cfg(unix)]
+    {
+        let status = std::process::Command::new("kill")
+            .args(["-HUP", &std::process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(reloads.load(Ordering::SeqCst), 4);
+        client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    }
+
+    let addr = "127.0.0.1:5203";
+    start(builder(addr).build());
+    let mut client = KvClient::new(addr).unwrap();
+    assert_eq!(
+        client.reload().unwrap_err().to_string(),
+        "Unsupported operation: reload"
+    );
+}
@@ -0,0 +1,232 @@
+use std::{
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use rust_kv::{
+    KvClient, KvEngine, KvServer, KvStore, MemStore, Replica, Result, SharedQueueThreadPool,
+    ShutdownHandle, ThreadPool,
+};
+use tempfile::TempDir;
+
+fn run_primary<E: KvEngine>(engine: E, addr: &str) -> (ShutdownHandle, JoinHandle<Result<()>>) {
+    let mut server = KvServer::builder(engine, SharedQueueThreadPool::new(2).unwrap())
+        .addr(addr)
+        .build();
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(500));
+    (shutdown, handle)
+}
+
+/// Waits at most 5 seconds for `condition` to hold.
+#[track_caller]
+fn wait_until<F: Fn() -> bool>(condition: F) {
+    let start = Instant::now();
+    while !condition() {
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "condition not met in time"
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+// Should copy every key of the primary, then apply its changes
+#[test]
+fn replication() {
+    let temp_dir = TempDir::new().unwrap();
+    let primary = KvStore::open(temp_dir.path()).unwrap();
+    primary.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    primary
+        .set_with_ttl(
+            "key2".to_owned(),
+            "value2".to_owned(),
+            Duration::from_secs(60),
+        )
+        .unwrap();
+    let addr = "127.0.0.1:4401".to_owned();
+    run_primary(primary, &addr);
+
+    let replica = MemStore::new();
+    replica.set("stale".to_owned(), "value".to_owned()).unwrap();
+    let handle = Replica::new(replica.clone(), addr.as_str()).start();
+    wait_until(|| handle.is_synced());
+    assert_eq!(
+        replica.scan(..).unwrap(),
+        vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "value2".to_owned())
+        ]
+    );
+    assert!(replica.ttl("key2".to_owned()).unwrap().is_some());
+
+    let mut client = KvClient::new(&addr).unwrap();
+    client.set("key3".to_owned(), "value3".to_owned()).unwrap();
+    client.set("key1".to_owned(), "value4".to_owned()).unwrap();
+    client.remove("key2".to_owned()).unwrap();
+    wait_until(|| replica.get("key3".to_owned()).unwrap().is_some());
+    wait_until(|| replica.get("key2".to_owned()).unwrap().is_none());
+    assert_eq!(
+        replica.scan(..).unwrap(),
+        vec![
+            ("key1".to_owned(), "value4".to_owned()),
+            ("key3".to_owned(), "value3".to_owned())
+        ]
+    );
+}
+
+// Should copy binary values, and keys on several pages
+#[test]
+fn binary_replication() {
+    let temp_dir = TempDir::new().unwrap();
+    let primary = KvStore::open(temp_dir.path()).unwrap();
+    for i in 0..3000 {
+        primary
+            .set(format!("key{:04}", i), format!("value{}", i))
+            .unwrap();
+    }
+    primary
+        .set_bytes("binary".to_owned(), vec![0xff, 0x00, 0xfe])
+        .unwrap();
+    let addr = "127.0.0.1:4406".to_owned();
+    run_primary(primary, &addr);
+
+    let replica = MemStore::new();
+    let handle = Replica::new(replica.clone(), addr.as_str()).start();
+    wait_until(|| handle.is_synced());
+    assert_eq!(replica.len().unwrap(), 3001);
+    assert_eq!(
+        replica.get_bytes("binary".to_owned()).unwrap(),
+        Some(vec![0xff, 0x00, 0xfe])
+    );
+    assert_eq!(
+        replica.get("key2999".to_owned()).unwrap(),
+        Some("value2999".to_owned())
+    );
+
+    let mut client = KvClient::new(&addr).unwrap();
+    client
+        .set_bytes("binary".to_owned(), vec![0x80, 0x81])
+        .unwrap();
+    wait_until(|| replica.get_bytes("binary".to_owned()).unwrap() == Some(vec![0x80, 0x81]));
+    handle.stop();
+}
+
+// Should reconnect to a restarted primary, and catch up with the changes it missed
+#[test]
+fn reconnection() {
+    let temp_dir = TempDir::new().unwrap();
+    let primary = KvStore::open(temp_dir.path()).unwrap();
+    primary.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let addr = "127.0.0.1:4402".to_owned();
+    let (shutdown, server) = run_primary(primary.clone(), &addr);
+
+    let replica = MemStore::new();
+    let handle = Replica::new(replica.clone(), addr.as_str())
+        .reconnect_delay(Duration::from_millis(100))
+        .start();
+    wait_until(|| handle.is_synced());
+
+    shutdown.shutdown();
+    server.join().unwrap().unwrap();
+    wait_until(|| !handle.is_synced());
+    primary.remove("key1".to_owned()).unwrap();
+    primary.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    run_primary(primary, &addr);
+
+    wait_until(|| handle.is_synced());
+    assert_eq!(
+        replica.scan(..).unwrap(),
+        vec![("key2".to_owned(), "value2".to_owned())]
+    );
+}
+
+// Should refuse writes on a read-only server, like a replica
+#[test]
+fn read_only() {
+    let addr = "127.0.0.1:4403".to_owned();
+    let engine = MemStore::new();
+    engine.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let mut server = KvServer::builder(engine, SharedQueueThreadPool::new(2).unwrap())
+        .addr(addr.as_str())
+        .read_only(true)
+        .build();
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvClient::new(&addr).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    let err = client
+        .set("key1".to_owned(), "value2".to_owned())
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Server is read-only");
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// Should not sync from a primary whose engine has no change feed
+#[test]
+fn unsupported_primary() {
+    let addr = "127.0.0.1:4404".to_owned();
+    let primary = MemStore::new();
+    primary.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    run_primary(primary, &addr);
+
+    let replica = MemStore::new();
+    let handle = Replica::new(replica.clone(), addr.as_str()).start();
+    thread::sleep(Duration::from_millis(500));
+    assert!(!handle.is_synced());
+    assert_eq!(replica.len().unwrap(), 0);
+    handle.stop();
+}
+
+// Should copy a kvs primary from a snapshot streamed to a kvs replica, then apply its changes
+#[test]
+fn snapshot_replication() {
+    let temp_dir = TempDir::new().unwrap();
+    let primary = KvStore::open(temp_dir.path()).unwrap();
+    for i in 0..1000 {
+        primary
+            .set(format!("key{:04}", i), format!("value{}", i).repeat(100))
+            .unwrap();
+    }
+    primary
+        .set_with_ttl(
+            "ttl".to_owned(),
+            "value".to_owned(),
+            Duration::from_secs(60),
+        )
+        .unwrap();
+    let addr = "127.0.0.1:4405".to_owned();
+    run_primary(primary, &addr);
+
+    let replica_dir = TempDir::new().unwrap();
+    let replica = KvStore::open(replica_dir.path()).unwrap();
+    replica.set("stale".to_owned(), "value".to_owned()).unwrap();
+    let handle = Replica::new(replica.clone(), addr.as_str())
+        .snapshots(true)
+        .start();
+    wait_until(|| handle.is_synced());
+    assert_eq!(replica.len().unwrap(), 1001);
+    assert_eq!(replica.get("stale".to_owned()).unwrap(), None);
+    assert_eq!(
+        replica.get("key0999".to_owned()).unwrap(),
+        Some("value999".repeat(100))
+    );
+    assert!(replica.ttl("ttl".to_owned()).unwrap().is_some());
+
+    let mut client = KvClient::new(&addr).unwrap();
+    client
+        .set("key1000".to_owned(), "value".to_owned())
+        .unwrap();
+    client.remove("key0000".to_owned()).unwrap();
+    wait_until(|| replica.get("key1000".to_owned()).unwrap().is_some());
+    wait_until(|| replica.get("key0000".to_owned()).unwrap().is_none());
+    handle.stop();
+}
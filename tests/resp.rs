@@ -0,0 +1,125 @@
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    thread,
+    time::Duration,
+};
+
+use rust_kv::{
+    KvEngine, KvServer, KvServerBuilder, MemStore, Request, SharedQueueThreadPool, ThreadPool,
+};
+
+fn builder(engine: MemStore) -> KvServerBuilder<MemStore, SharedQueueThreadPool> {
+    KvServer::builder(engine, SharedQueueThreadPool::new(2).unwrap())
+}
+
+fn run_resp(builder: KvServerBuilder<MemStore, SharedQueueThreadPool>, addr: &str) -> TcpStream {
+    let mut server = builder.build();
+    let addr = addr.to_owned();
+    let server_addr = addr.clone();
+    thread::spawn(move || server.run_resp(&server_addr));
+    thread::sleep(Duration::from_millis(500));
+    TcpStream::connect(addr).unwrap()
+}
+
+/// Encodes a command the way Redis clients send it.
+fn command(args: &[&str]) -> Vec<u8> {
+    let mut data = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        data.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+    }
+    data
+}
+
+/// Sends `data` and checks the server answers exactly `expected`.
+fn assert_reply(stream: &mut TcpStream, data: &[u8], expected: &str) {
+    stream.write_all(data).unwrap();
+    let mut reply = vec![0; expected.len()];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(String::from_utf8_lossy(&reply), expected);
+}
+
+// Should map the Redis commands onto the engine
+#[test]
+fn commands() {
+    let engine = MemStore::new();
+    let mut stream = run_resp(builder(engine.clone()), "127.0.0.1:4301");
+
+    assert_reply(&mut stream, &command(&["PING"]), "+PONG\r\n");
+    assert_reply(&mut stream, &command(&["ping", "hello"]), "$5\r\nhello\r\n");
+    assert_reply(&mut stream, &command(&["SET", "key1", "value1"]), "+OK\r\n");
+    assert_reply(&mut stream, &command(&["GET", "key1"]), "$6\r\nvalue1\r\n");
+    assert_reply(&mut stream, &command(&["GET", "key2"]), "$-1\r\n");
+    assert_reply(
+        &mut stream,
+        &command(&["EXISTS", "key1", "key2", "key1"]),
+        ":2\r\n",
+    );
+    assert_reply(&mut stream, &command(&["DEL", "key1", "key2"]), ":1\r\n");
+    assert_eq!(engine.get("key1".to_owned()).unwrap(), None);
+
+    // values hold any bytes, line breaks included
+    assert_reply(&mut stream, &command(&["SET", "key1", "a\r\nb"]), "+OK\r\n");
+    assert_reply(&mut stream, &command(&["GET", "key1"]), "$4\r\na\r\nb\r\n");
+
+    // inline, like typed in telnet
+    assert_reply(&mut stream, b"EXISTS key1\r\n", ":1\r\n");
+}
+
+// Should reply errors to commands it does not understand, and keep the connection
+#[test]
+fn command_errors() {
+    let mut stream = run_resp(builder(MemStore::new()), "127.0.0.1:4302");
+
+    assert_reply(
+        &mut stream,
+        &command(&["FLUSHALL"]),
+        "-ERR unknown command 'FLUSHALL'\r\n",
+    );
+    assert_reply(
+        &mut stream,
+        &command(&["GET"]),
+        "-ERR wrong number of arguments for 'get' command\r\n",
+    );
+    assert_reply(&mut stream, &command(&["PING"]), "+PONG\r\n");
+
+    // malformed input closes the connection
+    assert_reply(
+        &mut stream,
+        b"*1\r\n:5\r\n",
+        "-ERR Protocol error: expected '$'\r\n",
+    );
+    let mut buf = Vec::new();
+    assert_eq!(stream.read_to_end(&mut buf).unwrap(), 0);
+}
+
+// Should answer commands sent at once in their order
+#[test]
+fn pipelining() {
+    let mut stream = run_resp(builder(MemStore::new()), "127.0.0.1:4303");
+
+    let mut data = Vec::new();
+    for i in 0..10 {
+        data.extend(command(&["SET", &format!("key{}", i), &i.to_string()]));
+        data.extend(command(&["GET", &format!("key{}", i)]));
+    }
+    let expected: String = (0..10).map(|i| format!("+OK\r\n$1\r\n{}\r\n", i)).collect();
+    assert_reply(&mut stream, &data, &expected);
+}
+
+// Should authorize commands as the requests doing the same
+#[test]
+fn authorize() {
+    let builder =
+        builder(MemStore::new()).authorize(|_, request| !matches!(request, Request::Remove(_)));
+    let mut stream = run_resp(builder, "127.0.0.1:4304");
+
+    assert_reply(&mut stream, &command(&["SET", "key1", "value1"]), "+OK\r\n");
+    assert_reply(
+        &mut stream,
+        &command(&["DEL", "key1"]),
+        "-ERR Permission denied\r\n",
+    );
+    assert_reply(&mut stream, &command(&["EXISTS", "key1"]), ":1\r\n");
+    assert_reply(&mut stream, &command(&["PING"]), "+PONG\r\n");
+}
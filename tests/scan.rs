@@ -0,0 +1,151 @@
+use std::{thread, time::Duration};
+
+use rust_kv::{KvClient, KvEngine, KvServer, KvStore, MemStore, SharedQueueThreadPool, ThreadPool};
+use tempfile::TempDir;
+
+fn run<E: KvEngine>(engine: E, addr: &str) -> KvClient {
+    let mut server = KvServer::builder(engine, SharedQueueThreadPool::new(2).unwrap())
+        .addr(addr)
+        .build();
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(500));
+    KvClient::new(addr).unwrap()
+}
+
+/// Scans every page, returning the keys and the number of pages.
+fn scan_all(client: &mut KvClient, count: usize, pattern: Option<&str>) -> (Vec<String>, usize) {
+    let mut keys = Vec::new();
+    let mut pages = 0;
+    let mut cursor = None;
+    loop {
+        let (page, next) = client
+            .scan(cursor, count, pattern.map(str::to_owned))
+            .unwrap();
+        assert!(page.len() <= count);
+        keys.extend(page);
+        pages += 1;
+        cursor = next;
+        if cursor.is_none() {
+            return (keys, pages);
+        }
+    }
+}
+
+fn scan_pages<E: KvEngine>(engine: E, addr: &str) {
+    for i in 0..25 {
+        engine.set(format!("key{:02}", i), i.to_string()).unwrap();
+    }
+    engine
+        .set_with_ttl(
+            "key99".to_owned(),
+            "expired".to_owned(),
+            Duration::from_millis(1),
+        )
+        .unwrap();
+    thread::sleep(Duration::from_millis(10));
+    let mut client = run(engine, addr);
+
+    let (keys, pages) = scan_all(&mut client, 10, None);
+    let expected: Vec<String> = (0..25).map(|i| format!("key{:02}", i)).collect();
+    assert_eq!(keys, expected);
+    assert_eq!(pages, 3);
+
+    // a key set after the cursor shows up in a later page
+    let (page, cursor) = client.scan(None, 10, None).unwrap();
+    assert_eq!(page.len(), 10);
+    client.set("key10a".to_owned(), "new".to_owned()).unwrap();
+    let (page, _) = client.scan(cursor, 10, None).unwrap();
+    assert_eq!(page[0], "key10");
+    assert_eq!(page[1], "key10a");
+}
+
+// Should page through the live keys in key order
+#[test]
+fn scan_pages_kvs() {
+    let temp_dir = TempDir::new().unwrap();
+    scan_pages(KvStore::open(temp_dir.path()).unwrap(), "127.0.0.1:4801");
+}
+
+// Should page through the live keys in key order
+#[test]
+fn scan_pages_mem() {
+    scan_pages(MemStore::new(), "127.0.0.1:4802");
+}
+
+// Should return the keys matching a glob pattern, and scan buckets apart
+#[test]
+fn scan_pattern() {
+    let engine = MemStore::new();
+    for key in [
+        "user:1", "user:2", "user:10", "users", "config:a", "a*b", "axb",
+    ] {
+        engine.set(key.to_owned(), "value".to_owned()).unwrap();
+    }
+    let mut client = run(engine.clone(), "127.0.0.1:4803");
+
+    let mut scan = |pattern: &str| scan_all(&mut client, 2, Some(pattern)).0;
+    assert_eq!(scan("user:*"), ["user:1", "user:10", "user:2"]);
+    assert_eq!(scan("user:?"), ["user:1", "user:2"]);
+    assert_eq!(scan("user[s:]*"), ["user:1", "user:10", "user:2", "users"]);
+    assert_eq!(scan("user:[^1]"), ["user:2"]);
+    assert_eq!(scan("user:[0-1]*"), ["user:1", "user:10"]);
+    assert_eq!(scan("a\\*b"), ["a*b"]);
+    assert_eq!(scan("*:a"), ["config:a"]);
+    assert!(scan("missing*").is_empty());
+
+    engine
+        .bucket("bucket")
+        .unwrap()
+        .set("user:3".to_owned(), "value".to_owned())
+        .unwrap();
+    client.set_bucket(Some("bucket".to_owned()));
+    assert_eq!(scan_all(&mut client, 10, None).0, ["user:3"]);
+}
+
+// Should iterate over the keys starting with a prefix and their values, across pages
+#[test]
+fn scan_prefix() {
+    let engine = MemStore::new();
+    let mut expected = vec![("user:".to_owned(), "none".to_owned())];
+    for i in 0..600 {
+        expected.push((format!("user:{:03}", i), i.to_string()));
+    }
+    for (key, value) in expected.iter().chain([
+        &("use".to_owned(), "value".to_owned()),
+        &("users".to_owned(), "value".to_owned()),
+        &("a*b*".to_owned(), "1".to_owned()),
+        &("a*bc".to_owned(), "2".to_owned()),
+        &("axbc".to_owned(), "value".to_owned()),
+    ]) {
+        engine.set(key.clone(), value.clone()).unwrap();
+    }
+    let mut client = run(engine.clone(), "127.0.0.1:4804");
+
+    let scan = |client: &KvClient, prefix: &str| {
+        client
+            .scan_prefix(prefix.to_owned())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    };
+    assert_eq!(scan(&client, "user:"), expected);
+    let pairs = scan(&client, "a*b");
+    assert_eq!(
+        pairs,
+        [
+            ("a*b*".to_owned(), "1".to_owned()),
+            ("a*bc".to_owned(), "2".to_owned())
+        ]
+    );
+    assert!(scan(&client, "missing").is_empty());
+
+    engine
+        .bucket("bucket")
+        .unwrap()
+        .set("user:1".to_owned(), "in bucket".to_owned())
+        .unwrap();
+    client.set_bucket(Some("bucket".to_owned()));
+    assert_eq!(
+        scan(&client, "user:"),
+        [("user:1".to_owned(), "in bucket".to_owned())]
+    );
+}
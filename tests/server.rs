@@ -0,0 +1,1088 @@
+use std::{
+    fs,
+    io::{Read, Write},
+    mem,
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use rust_kv::{
+    CircuitState, Codec, KvClient, KvEngine, KvError, KvServer, KvServerBuilder, KvStore,
+    LatencyHistogram, MemStore, Request, Response, Result, SharedQueueThreadPool, ThreadPool,
+    WriteBatch,
+};
+
+fn builder(engine: MemStore, addr: &str) -> KvServerBuilder<MemStore, SharedQueueThreadPool> {
+    KvServer::builder(engine, SharedQueueThreadPool::new(2).unwrap()).addr(addr)
+}
+
+/// A pool whose jobs take 200ms longer, like on a slow disk.
+#[derive(Clone)]
+struct SlowPool(SharedQueueThreadPool);
+
+impl ThreadPool for SlowPool {
+    fn new(threads: usize) -> Result<SlowPool> {
+        Ok(SlowPool(SharedQueueThreadPool::new(threads)?))
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.0.spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            job();
+        });
+    }
+}
+
+/// A pool whose jobs take 300ms longer while its flag is set.
+#[derive(Clone)]
+struct GatedPool(SharedQueueThreadPool, Arc<AtomicBool>);
+
+impl ThreadPool for GatedPool {
+    fn new(threads: usize) -> Result<GatedPool> {
+        Ok(GatedPool(
+            SharedQueueThreadPool::new(threads)?,
+            Arc::default(),
+        ))
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let slow = self.1.load(Ordering::SeqCst);
+        self.0.spawn(move || {
+            if slow {
+                thread::sleep(Duration::from_millis(300));
+            }
+            job();
+        });
+    }
+}
+
+// Should close idle connections and return from `run` once shut down
+#[test]
+fn shutdown() {
+    let addr = "127.0.0.1:4201".to_owned();
+    let mut server = builder(MemStore::new(), &addr).build();
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvClient::new(&addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    let start = Instant::now();
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert!(client.get("key1".to_owned()).is_err());
+    assert!(KvClient::new(&addr).is_err());
+}
+
+// Should let in-flight requests finish before returning
+#[test]
+fn shutdown_drains_requests() {
+    let addr = "127.0.0.1:4202".to_owned();
+    let engine = MemStore::new();
+    let mut server = builder(engine.clone(), &addr)
+        .authorize(|_, _| {
+            thread::sleep(Duration::from_millis(500));
+            true
+        })
+        .build();
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(500));
+
+    let client = thread::spawn(move || {
+        let mut client = KvClient::new(&addr).unwrap();
+        client.set("key1".to_owned(), "value1".to_owned())
+    });
+    thread::sleep(Duration::from_millis(200));
+    shutdown.shutdown();
+    client.join().unwrap().unwrap();
+    handle.join().unwrap().unwrap();
+    assert_eq!(
+        engine.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// Should tell the clients about a shutdown, failing their next requests while
+// answering the ones in flight, or failing these too past the shutdown timeout
+#[test]
+fn shutdown_notifies_clients() {
+    for (shutdown_timeout, drained) in [(Duration::from_secs(5), true), (Duration::ZERO, false)] {
+        let server = KvServer::builder(MemStore::new(), SlowPool::new(2).unwrap())
+            .addr("127.0.0.1:0")
+            .shutdown_timeout(shutdown_timeout)
+            .build()
+            .start()
+            .unwrap();
+        let mut client = KvClient::new(&server.addr().to_string()).unwrap();
+        let mut in_flight = client.clone();
+        let in_flight =
+            thread::spawn(move || in_flight.set("key1".to_owned(), "value1".to_owned()));
+        thread::sleep(Duration::from_millis(50));
+        let shutdown = thread::spawn(move || server.shutdown());
+        thread::sleep(Duration::from_millis(100));
+
+        assert!(matches!(
+            client.get("key1".to_owned()),
+            Err(KvError::ShuttingDown)
+        ));
+        match in_flight.join().unwrap() {
+            Ok(()) => assert!(drained),
+            Err(err) => assert!(!drained && matches!(err, KvError::ShuttingDown)),
+        }
+        shutdown.join().unwrap().unwrap();
+    }
+}
+
+// Should reconnect to a restarted server, retrying the request sent meanwhile
+#[test]
+fn reconnect() {
+    let addr = "127.0.0.1:4212".to_owned();
+    let engine = MemStore::new();
+    let server = builder(engine.clone(), &addr).build().start().unwrap();
+    let mut client = KvClient::builder(&addr)
+        .reconnect(Duration::from_secs(5))
+        .build()
+        .unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    server.shutdown().unwrap();
+
+    let restart_addr = addr.clone();
+    let restarted = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(300));
+        builder(engine, &restart_addr).build().start().unwrap()
+    });
+    let started = Instant::now();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert!(started.elapsed() >= Duration::from_millis(300));
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    let server = restarted.join().unwrap();
+
+    // and give up once the budget is spent
+    let mut client = KvClient::builder(&addr)
+        .reconnect(Duration::from_millis(300))
+        .build()
+        .unwrap();
+    server.shutdown().unwrap();
+    let started = Instant::now();
+    assert!(matches!(client.get("key1".to_owned()), Err(KvError::Io(_))));
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+// Should retry the requests in flight on a broken connection if they are idempotent only
+#[test]
+fn reconnect_retries_idempotent() {
+    let pool = GatedPool::new(2).unwrap();
+    let slow = pool.1.clone();
+    let server = KvServer::builder(MemStore::new(), pool)
+        .addr("127.0.0.1:0")
+        .build()
+        .start()
+        .unwrap();
+    let addr = server.addr().to_string();
+    let mut admin = KvClient::new(&addr).unwrap();
+    let len = |client: &mut KvClient| client.len().map(|_| ());
+    let set = |client: &mut KvClient| client.set("key1".to_owned(), "value1".to_owned());
+    let incr = |client: &mut KvClient| client.incr("count".to_owned(), 1).map(|_| ());
+    for (request, idempotent_writes, retried) in [
+        (len as fn(&mut KvClient) -> Result<()>, false, true),
+        (set, false, false),
+        (set, true, true),
+        (incr, true, false),
+    ] {
+        let mut client = KvClient::builder(&addr)
+            .reconnect(Duration::from_secs(5))
+            .idempotent_writes(idempotent_writes)
+            .build()
+            .unwrap();
+        slow.store(true, Ordering::SeqCst);
+        let in_flight = thread::spawn(move || request(&mut client));
+        thread::sleep(Duration::from_millis(100));
+        slow.store(false, Ordering::SeqCst);
+        let clients = admin.client_list().unwrap();
+        let id = clients.iter().map(|client| client.id).max().unwrap();
+        admin.client_kill(id).unwrap();
+
+        match in_flight.join().unwrap() {
+            Ok(()) => assert!(retried),
+            Err(err) => assert!(!retried && matches!(err, KvError::Io(_))),
+        }
+    }
+    server.shutdown().unwrap();
+}
+
+// Should connect to the first server accepting the connection, and fail over to the next
+// once it stops
+#[test]
+fn failover() {
+    let addrs: Vec<_> = (4213..4216)
+        .map(|port| format!("127.0.0.1:{}", port))
+        .collect();
+    let engine = MemStore::new();
+    // nothing listens on the first address
+    let first = builder(engine.clone(), &addrs[1]).metrics(true).build();
+    let first_metrics = first.metrics().unwrap();
+    let first = first.start().unwrap();
+    let second = builder(engine, &addrs[2]).metrics(true).build();
+    let second_metrics = second.metrics().unwrap();
+    let second = second.start().unwrap();
+    let mut client = KvClient::new_multi(addrs.clone()).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(first_metrics.connections(), 1);
+    assert_eq!(second_metrics.connections(), 0);
+
+    first.shutdown().unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert_eq!(second_metrics.connections(), 1);
+    second.shutdown().unwrap();
+
+    // and fail with the last error once no server accepts the connection
+    let result = KvClient::new_multi(addrs[..2].to_vec());
+    assert!(matches!(result, Err(KvError::Io(_))));
+}
+
+// Should drain the connections once the shutdown signal resolves
+#[test]
+fn shutdown_signal() {
+    let addr = "127.0.0.1:4211".to_owned();
+    let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+    let mut server = builder(MemStore::new(), &addr)
+        .authorize(|_, _| {
+            thread::sleep(Duration::from_millis(300));
+            true
+        })
+        .shutdown_signal(async {
+            let _ = rx.await;
+        })
+        .build();
+    let handle = thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(500));
+
+    let client = thread::spawn(move || {
+        let mut client = KvClient::new(&addr).unwrap();
+        client.set("key1".to_owned(), "value1".to_owned())
+    });
+    thread::sleep(Duration::from_millis(100));
+    tx.send(()).unwrap();
+    client.join().unwrap().unwrap();
+    handle.join().unwrap().unwrap();
+}
+
+// Should listen on a free port when bound to port 0, and stop when shut down
+#[test]
+fn start_on_port_zero() {
+    let first = builder(MemStore::new(), "127.0.0.1:0")
+        .build()
+        .start()
+        .unwrap();
+    let second = builder(MemStore::new(), "127.0.0.1:0")
+        .build()
+        .start()
+        .unwrap();
+    assert_ne!(first.addr().port(), 0);
+    assert_ne!(first.addr(), second.addr());
+
+    // listening already, without waiting
+    let mut client = KvClient::new(&first.addr().to_string()).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let mut other = KvClient::new(&second.addr().to_string()).unwrap();
+    assert_eq!(other.get("key1".to_owned()).unwrap(), None);
+
+    first.shutdown().unwrap();
+    assert!(client.get("key1".to_owned()).is_err());
+    second.shutdown_handle().shutdown();
+    second.join().unwrap();
+
+    // an address in use
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    assert!(builder(MemStore::new(), &addr).build().start().is_err());
+}
+
+// Should serve on the runtime of the caller, stopping with the handle of the task
+#[tokio::test(flavor = "multi_thread")]
+async fn serve_on_caller_runtime() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = builder(MemStore::new(), "unused")
+        .build()
+        .spawn(listener)
+        .unwrap();
+    let addr = server.addr().to_string();
+    let value = tokio::task::spawn_blocking(move || {
+        let mut client = KvClient::new(&addr).unwrap();
+        client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        client.get("key1".to_owned()).unwrap()
+    })
+    .await
+    .unwrap();
+    assert_eq!(value, Some("value1".to_owned()));
+    server.shutdown().await.unwrap();
+
+    // awaited in place, until a shutdown handle stops it
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = builder(MemStore::new(), "unused").build();
+    server.shutdown_handle().shutdown();
+    server.serve(listener).await.unwrap();
+}
+
+// Should return right away when shut down before running
+#[test]
+fn shutdown_before_run() {
+    let mut server = builder(MemStore::new(), "127.0.0.1:4203").build();
+    server.shutdown_handle().shutdown();
+    server.run().unwrap();
+}
+
+// Should read requests split over many reads, and refuse larger ones than the max frame size
+#[test]
+fn max_frame_size() {
+    let addr = "127.0.0.1:4204".to_owned();
+    let mut server = builder(MemStore::new(), &addr)
+        .max_frame_size(64 * 1024)
+        .build();
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvClient::new(&addr).unwrap();
+    let mut batch = WriteBatch::new();
+    for i in 0..1000 {
+        batch.put(format!("key{}", i), "value".repeat(10));
+    }
+    client.write_batch(batch).unwrap();
+    assert_eq!(client.len().unwrap(), 1000);
+
+    let err = client
+        .set("key".to_owned(), "v".repeat(100 * 1024))
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Request larger than the maximum frame size of 65536 bytes"
+    );
+    assert!(client.get("key".to_owned()).is_err());
+}
+
+// Should refuse to send requests larger than the max frame size of the client, keeping the
+// connection, and to read larger responses, in either codec
+#[test]
+fn client_max_frame_size() {
+    let server = KvServer::builder(MemStore::new(), SharedQueueThreadPool::new(2).unwrap())
+        .addr("127.0.0.1:0")
+        .build()
+        .start()
+        .unwrap();
+    let addr = server.addr().to_string();
+    let mut writer = KvClient::new(&addr).unwrap();
+    writer.set("large".to_owned(), "v".repeat(2048)).unwrap();
+
+    for codec in [Codec::Json, Codec::Bincode] {
+        let mut client = KvClient::builder(&addr)
+            .codec(codec)
+            .max_frame_size(1024)
+            .build()
+            .unwrap();
+        let err = client.set("key1".to_owned(), "v".repeat(2048)).unwrap_err();
+        assert!(matches!(err, KvError::FrameTooLarge(1024)));
+        client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        assert_eq!(
+            client.get("key1".to_owned()).unwrap(),
+            Some("value1".to_owned())
+        );
+
+        // the next request goes on, on a new connection with JSON
+        let err = client.get("large".to_owned()).unwrap_err();
+        assert!(matches!(err, KvError::ResponseTooLarge(1024)));
+        assert_eq!(
+            client.get("key1".to_owned()).unwrap(),
+            Some("value1".to_owned())
+        );
+    }
+
+    // with bincode, the other requests of the connection do not fail
+    let mut client = KvClient::builder(&addr)
+        .codec(Codec::Bincode)
+        .max_frame_size(1024)
+        .build()
+        .unwrap();
+    let resps = client
+        .pipeline()
+        .get("large".to_owned())
+        .get("key1".to_owned())
+        .send()
+        .unwrap();
+    assert!(matches!(resps[0], Err(KvError::ResponseTooLarge(1024))));
+    assert!(matches!(&resps[1], Ok(Response::Ok(Some(value))) if value == "value1"));
+    server.shutdown().unwrap();
+}
+
+// Should make connections over the limit wait until another one closes
+#[test]
+fn max_connections() {
+    let addr = "127.0.0.1:4205".to_owned();
+    let mut server = builder(MemStore::new(), &addr)
+        .max_connections(1)
+        .metrics(true)
+        .build();
+    let metrics = server.metrics().unwrap();
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut first = KvClient::new(&addr).unwrap();
+    first.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let second = thread::spawn(move || {
+        let mut second = KvClient::new(&addr).unwrap();
+        second.get("key1".to_owned())
+    });
+    thread::sleep(Duration::from_millis(300));
+    assert!(!second.is_finished());
+    assert_eq!(metrics.active_connections(), 1);
+
+    drop(first);
+    assert_eq!(second.join().unwrap().unwrap(), Some("value1".to_owned()));
+    assert_eq!(metrics.connections(), 2);
+    assert_eq!(metrics.requests(), 2);
+    assert_eq!(metrics.failed_requests(), 0);
+}
+
+// Should close the connections of an IP over its limit, and report the connections of each IP
+#[test]
+fn max_connections_per_ip() {
+    let server = builder(MemStore::new(), "127.0.0.1:0")
+        .max_connections_per_ip(2)
+        .metrics(true)
+        .build();
+    let metrics = server.metrics().unwrap();
+    let server = server.start().unwrap();
+    let addr = server.addr().to_string();
+    let localhost = "127.0.0.1".parse().unwrap();
+
+    let mut first = KvClient::new(&addr).unwrap();
+    first.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let mut second = KvClient::new(&addr).unwrap();
+    second.get("key1".to_owned()).unwrap();
+    let mut third = KvClient::new(&addr).unwrap();
+    assert!(third.get("key1".to_owned()).is_err());
+    assert_eq!(metrics.refused_connections(), 1);
+    assert_eq!(first.connections().unwrap(), vec![(localhost, 2)]);
+
+    // the connection is closed on the server a little later
+    drop(second);
+    let started = Instant::now();
+    while first.connections().unwrap() != vec![(localhost, 1)] {
+        assert!(started.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(10));
+    }
+    let mut third = KvClient::new(&addr).unwrap();
+    assert_eq!(
+        third.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    server.shutdown().unwrap();
+}
+
+// Should count the requests, hits, misses and latencies of each operation
+#[test]
+fn op_metrics() {
+    let server = builder(MemStore::new(), "127.0.0.1:0")
+        .metrics(true)
+        .build();
+    let metrics = server.metrics().unwrap();
+    let server = server.start().unwrap();
+    let mut client = KvClient::new(&server.addr().to_string()).unwrap();
+
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.get("key1".to_owned()).unwrap();
+    client.get("key2".to_owned()).unwrap();
+    client.remove("key1".to_owned()).unwrap();
+    assert!(client.remove("key2".to_owned()).is_err());
+
+    let ops = metrics.ops();
+    assert_eq!(ops.keys().collect::<Vec<_>>(), vec!["get", "remove", "set"]);
+    let get = &ops["get"];
+    assert_eq!((get.requests, get.failed_requests), (2, 0));
+    assert_eq!((get.hits, get.misses), (1, 1));
+    assert_eq!(get.latency.count(), 2);
+    assert!(get.latency.percentile(0.5) <= get.latency.percentile(1.0));
+    assert!(get.latency.mean() <= get.latency.percentile(1.0));
+    let remove = &ops["remove"];
+    assert_eq!((remove.requests, remove.failed_requests), (2, 1));
+    assert_eq!((remove.hits, remove.misses), (0, 0));
+    assert_eq!(ops["set"].requests, 1);
+
+    // and report them in the info of the server
+    assert_eq!(client.info().unwrap().ops, ops);
+    server.shutdown().unwrap();
+}
+
+// Should bound the latencies of a histogram by the powers of two microseconds of its buckets
+#[test]
+fn latency_histogram() {
+    let mut latency = LatencyHistogram::default();
+    assert_eq!(latency.percentile(0.99), Duration::ZERO);
+    // 90 latencies in [2us, 4us), 10 in [512us, 1024us)
+    latency.buckets[2] = 90;
+    latency.buckets[10] = 10;
+    latency.total_us = 90 * 3 + 10 * 600;
+    assert_eq!(latency.count(), 100);
+    assert_eq!(latency.mean(), Duration::from_micros(62));
+    assert_eq!(latency.percentile(0.5), Duration::from_micros(4));
+    assert_eq!(latency.percentile(0.9), Duration::from_micros(4));
+    assert_eq!(latency.percentile(0.99), Duration::from_micros(1024));
+}
+
+// Should accept connections on several listeners sharing the address
+#[cfg(unix)]
+#[test]
+fn acceptors() {
+    let server = builder(MemStore::new(), "127.0.0.1:0")
+        .acceptors(4)
+        .metrics(true)
+        .build();
+    let metrics = server.metrics().unwrap();
+    let server = server.start().unwrap();
+    let addr = server.addr().to_string();
+
+    let clients: Vec<_> = (0..16)
+        .map(|i| {
+            let addr = addr.clone();
+            thread::spawn(move || {
+                let mut client = KvClient::new(&addr).unwrap();
+                client.set(format!("key{}", i), i.to_string()).unwrap();
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+    let mut client = KvClient::new(&addr).unwrap();
+    assert_eq!(client.len().unwrap(), 16);
+    assert_eq!(metrics.connections(), 17);
+
+    // every listener closes on shutdown
+    server.shutdown().unwrap();
+    assert!(TcpStream::connect(&addr).is_err());
+}
+
+// Should serve the clients of every address, a unix socket included, from one engine
+#[cfg(unix)]
+#[test]
+fn several_addrs() {
+    use std::os::unix::net::UnixStream;
+
+    let dir = tempfile::TempDir::new().unwrap();
+    let socket = dir.path().join("kv.sock");
+    let server = builder(MemStore::new(), "unused")
+        .addrs([
+            "127.0.0.1:0".to_owned(),
+            format!("unix:{}", socket.display()),
+        ])
+        .build()
+        .start()
+        .unwrap();
+    let mut client = KvClient::new(&server.addr().to_string()).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    let mut stream = UnixStream::connect(&socket).unwrap();
+    stream
+        .write_all(br#"{"id":0,"request":{"Get":"key1"}}"#)
+        .unwrap();
+    let mut buf = [0; 256];
+    let n = stream.read(&mut buf).unwrap();
+    assert!(String::from_utf8_lossy(&buf[..n]).contains("value1"));
+
+    // the socket is removed on shutdown
+    drop(stream);
+    server.shutdown().unwrap();
+    assert!(!socket.exists());
+}
+
+// Should answer pings with their payload, in a bucket and in a batch too
+#[test]
+fn ping() {
+    let server = builder(MemStore::new(), "127.0.0.1:0")
+        .build()
+        .start()
+        .unwrap();
+    let mut client = KvClient::new(&server.addr().to_string()).unwrap();
+    assert_eq!(client.ping(None).unwrap(), None);
+    assert_eq!(
+        client.ping(Some("hello".to_owned())).unwrap(),
+        Some("hello".to_owned())
+    );
+    client.set_bucket(Some("bucket".to_owned()));
+    assert_eq!(client.ping(None).unwrap(), None);
+    let resps = client
+        .batch(vec![Request::Ping(Some("hello".to_owned()))])
+        .unwrap();
+    assert!(matches!(&resps[0], Ok(Response::Pong(Some(payload))) if payload == "hello"));
+    server.shutdown().unwrap();
+}
+
+// Should tell which operation and key failed in the error of a request
+#[test]
+fn error_context() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let store = KvStore::open(dir.path()).unwrap();
+    store.set("user:42".to_owned(), "value".to_owned()).unwrap();
+    drop(store);
+    let log = dir.path().join("0.log");
+    let mut content = fs::read(&log).unwrap();
+    let pos = content.windows(5).position(|w| w == b"value").unwrap();
+    content[pos] = b'V';
+    fs::write(&log, content).unwrap();
+
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    let server = KvServer::builder(KvStore::open(dir.path()).unwrap(), pool)
+        .addr("127.0.0.1:0")
+        .build()
+        .start()
+        .unwrap();
+    let mut client = KvClient::new(&server.addr().to_string()).unwrap();
+    let err = client.get("user:42".to_owned()).unwrap_err();
+    assert!(
+        err.to_string()
+            .starts_with("get key 'user:42' failed: Corrupted record in log 0 at offset "),
+        "{}",
+        err
+    );
+    let err = client.remove("missing".to_owned()).unwrap_err();
+    assert_eq!(err.to_string(), "Key not found");
+    server.shutdown().unwrap();
+}
+
+// Should answer busy rather than queue a request while the pool has no room for it
+#[test]
+fn busy_pool() {
+    let pool = SharedQueueThreadPool::with_queue_capacity(1, 1).unwrap();
+    let server = KvServer::builder(MemStore::new(), pool.clone())
+        .addr("127.0.0.1:0")
+        .metrics(true)
+        .build();
+    let metrics = server.metrics().unwrap();
+    let server = server.start().unwrap();
+    let mut client = KvClient::new(&server.addr().to_string()).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    // the thread waits on a job, the queue holds another
+    let (started_tx, started_rx) = std::sync::mpsc::channel();
+    let (tx, rx) = std::sync::mpsc::channel::<()>();
+    pool.spawn(move || {
+        started_tx.send(()).unwrap();
+        rx.recv().unwrap();
+    });
+    started_rx.recv().unwrap();
+    pool.spawn(|| {});
+    let err = client.get_bytes("key1".to_owned()).unwrap_err();
+    assert!(matches!(err, KvError::Busy));
+    assert_eq!(err.to_string(), "Server is busy");
+    assert_eq!(metrics.busy_requests(), 1);
+    assert_eq!(metrics.failed_requests(), 1);
+    // a get the engine answers from memory, and a ping, skip the pool
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert_eq!(client.ping(None).unwrap(), None);
+
+    tx.send(()).unwrap();
+    // the queue has room again once the thread takes the next job
+    let started = Instant::now();
+    loop {
+        match client.get_bytes("key1".to_owned()) {
+            Ok(value) => break assert_eq!(value, Some(b"value1".to_vec())),
+            Err(KvError::Busy) if started.elapsed() < Duration::from_secs(5) => {
+                thread::sleep(Duration::from_millis(10))
+            }
+            Err(err) => panic!("{}", err),
+        }
+    }
+    server.shutdown().unwrap();
+}
+
+// Should close connections sending no request within the idle timeout
+#[test]
+fn idle_timeout() {
+    let addr = "127.0.0.1:4206".to_owned();
+    let mut server = builder(MemStore::new(), &addr)
+        .idle_timeout(Duration::from_millis(200))
+        .build();
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut stream = TcpStream::connect(&addr).unwrap();
+    thread::sleep(Duration::from_millis(500));
+    // the server closed the connection, so nothing is answered
+    let _ = stream.write_all(br#"{"id":0,"request":{"Get":"key1"}}"#);
+    let mut buf = Vec::new();
+    assert!(stream.read_to_end(&mut buf).map_or(true, |n| n == 0));
+}
+
+// Should fail requests not run within the request timeout, and keep serving the connection
+#[test]
+fn request_timeout() {
+    let addr = "127.0.0.1:4207".to_owned();
+    let pool = SharedQueueThreadPool::new(1).unwrap();
+    let mut server = KvServer::builder(MemStore::new(), pool.clone())
+        .addr(addr.as_str())
+        .request_timeout(Duration::from_millis(200))
+        .build();
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvClient::new(&addr).unwrap();
+    // a stuck job holds the only thread of the pool
+    pool.spawn(|| thread::sleep(Duration::from_millis(1000)));
+    let start = Instant::now();
+    let err = client
+        .set("key1".to_owned(), "value1".to_owned())
+        .unwrap_err();
+    assert!(matches!(err, KvError::RequestTimeout(_)));
+    assert_eq!(err.to_string(), "Request timed out after 200ms");
+    assert!(start.elapsed() < Duration::from_millis(800));
+
+    // the timed out job still runs once the pool frees up
+    thread::sleep(Duration::from_millis(1000));
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// Should report each request to the hook of the client, with its latency, retries and error
+#[test]
+fn client_request_hook() {
+    let pool = GatedPool::new(2).unwrap();
+    let slow = pool.1.clone();
+    let server = KvServer::builder(MemStore::new(), pool)
+        .addr("127.0.0.1:0")
+        .build()
+        .start()
+        .unwrap();
+    let addr = server.addr().to_string();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let reported = events.clone();
+    let mut client = KvClient::builder(&addr)
+        .reconnect(Duration::from_secs(5))
+        .on_request(move |event| {
+            let error = event.error.map(ToString::to_string);
+            let mut events = reported.lock().unwrap();
+            events.push((event.op, event.latency, event.retries, error));
+        })
+        .build()
+        .unwrap();
+    let take = || -> Vec<_> {
+        let events = mem::take(&mut *events.lock().unwrap());
+        let events = events.into_iter();
+        events
+            .map(|(op, _, retries, error)| (op, retries, error))
+            .collect()
+    };
+
+    slow.store(true, Ordering::SeqCst);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    slow.store(false, Ordering::SeqCst);
+    let latency = events.lock().unwrap()[0].1;
+    assert!(latency >= Duration::from_millis(300));
+    client.remove("missing".to_owned()).unwrap_err();
+    client
+        .pipeline()
+        .get("key1".to_owned())
+        .request(Request::Len)
+        .send()
+        .unwrap();
+    assert_eq!(
+        take(),
+        [
+            ("set", 0, None),
+            ("remove", 0, Some("Key not found".to_owned())),
+            ("get", 0, None),
+            ("len", 0, None)
+        ]
+    );
+
+    // a request sent again on a new connection
+    let mut admin = KvClient::new(&addr).unwrap();
+    let clients = admin.client_list().unwrap();
+    let id = clients.iter().map(|client| client.id).min().unwrap();
+    slow.store(true, Ordering::SeqCst);
+    let in_flight = thread::spawn(move || client.len());
+    thread::sleep(Duration::from_millis(100));
+    slow.store(false, Ordering::SeqCst);
+    admin.client_kill(id).unwrap();
+    in_flight.join().unwrap().unwrap();
+    assert_eq!(take(), [("len", 1, None)]);
+    server.shutdown().unwrap();
+}
+
+// Should ping the server while idle, keeping the connection open, and close it once the server
+// stops answering
+#[test]
+fn client_keepalive() {
+    let server = KvServer::builder(MemStore::new(), SharedQueueThreadPool::new(2).unwrap())
+        .addr("127.0.0.1:0")
+        .idle_timeout(Duration::from_millis(200))
+        .metrics(true)
+        .build();
+    let metrics = server.metrics().unwrap();
+    let server = server.start().unwrap();
+    let mut client = KvClient::builder(&server.addr().to_string())
+        .keepalive(Duration::from_millis(50))
+        .build()
+        .unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert!(metrics.ops()["ping"].requests >= 5);
+    server.shutdown().unwrap();
+
+    // a server accepting the connection but never answering
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let silent = thread::spawn(move || listener.accept().unwrap());
+    let mut client = KvClient::builder(&addr)
+        .keepalive(Duration::from_millis(50))
+        .build()
+        .unwrap();
+    let _socket = silent.join().unwrap();
+    thread::sleep(Duration::from_millis(300));
+    assert!(matches!(client.get("key1".to_owned()), Err(KvError::Io(_))));
+}
+
+// Should fail the requests not answered within the request timeout of the client, or of the call
+#[test]
+fn client_request_timeout() {
+    let pool = GatedPool::new(2).unwrap();
+    let slow = pool.1.clone();
+    let server = KvServer::builder(MemStore::new(), pool)
+        .addr("127.0.0.1:0")
+        .build()
+        .start()
+        .unwrap();
+    let mut client = KvClient::builder(&server.addr().to_string())
+        .request_timeout(Duration::from_millis(100))
+        .build()
+        .unwrap();
+
+    slow.store(true, Ordering::SeqCst);
+    let started = Instant::now();
+    let err = client
+        .set("key1".to_owned(), "value1".to_owned())
+        .unwrap_err();
+    assert!(
+        matches!(err, KvError::RequestTimeout(timeout) if timeout == Duration::from_millis(100))
+    );
+    assert!(started.elapsed() < Duration::from_millis(300));
+    let err = client
+        .pipeline()
+        .request(Request::Len)
+        .request(Request::Len)
+        .send()
+        .unwrap_err();
+    assert!(matches!(err, KvError::RequestTimeout(_)));
+
+    // for one call
+    client
+        .with_request_timeout(Duration::from_secs(1))
+        .set("key2".to_owned(), "value2".to_owned())
+        .unwrap();
+
+    // the connection is still usable, the late responses dropped
+    slow.store(false, Ordering::SeqCst);
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(client.len().unwrap(), 2);
+    server.shutdown().unwrap();
+}
+
+// Should fail fast once the requests failed in a row, then probe the server after the cooldown
+#[test]
+fn client_circuit_breaker() {
+    let pool = GatedPool::new(2).unwrap();
+    let slow = pool.1.clone();
+    let server = KvServer::builder(MemStore::new(), pool)
+        .addr("127.0.0.1:0")
+        .build()
+        .start()
+        .unwrap();
+    let states = Arc::new(Mutex::new(Vec::new()));
+    let reported = states.clone();
+    let mut client = KvClient::builder(&server.addr().to_string())
+        .request_timeout(Duration::from_millis(100))
+        .circuit_breaker(2, Duration::from_millis(300))
+        .on_request(move |event| reported.lock().unwrap().push(event.circuit.unwrap()))
+        .build()
+        .unwrap();
+    let take = || mem::take(&mut *states.lock().unwrap());
+
+    // the errors of the server do not count
+    client.remove("missing".to_owned()).unwrap_err();
+    client.remove("missing".to_owned()).unwrap_err();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(take(), [CircuitState::Closed; 3]);
+
+    slow.store(true, Ordering::SeqCst);
+    assert!(matches!(client.len(), Err(KvError::RequestTimeout(_))));
+    assert!(matches!(client.len(), Err(KvError::RequestTimeout(_))));
+    let started = Instant::now();
+    assert!(matches!(
+        client.get("key1".to_owned()),
+        Err(KvError::CircuitOpen)
+    ));
+    assert!(matches!(
+        client.pipeline().get("key1".to_owned()).send(),
+        Err(KvError::CircuitOpen)
+    ));
+    assert!(started.elapsed() < Duration::from_millis(50));
+    assert_eq!(
+        take(),
+        [
+            CircuitState::Closed,
+            CircuitState::Open,
+            CircuitState::Open,
+            CircuitState::Open
+        ]
+    );
+
+    // a failed probe opens the circuit again
+    thread::sleep(Duration::from_millis(400));
+    assert!(matches!(client.len(), Err(KvError::RequestTimeout(_))));
+    assert!(matches!(client.len(), Err(KvError::CircuitOpen)));
+    assert_eq!(take(), [CircuitState::Open; 2]);
+
+    // and one which succeeds closes it
+    slow.store(false, Ordering::SeqCst);
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(client.len().unwrap(), 1);
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert_eq!(take(), [CircuitState::Closed; 2]);
+    server.shutdown().unwrap();
+}
+
+// Should run the pipelined requests of a connection concurrently, returning them in their order
+#[test]
+fn pipelining() {
+    let addr = "127.0.0.1:4208".to_owned();
+    let engine = MemStore::new();
+    for i in 0..8 {
+        engine
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    let mut server = KvServer::builder(engine, SlowPool::new(8).unwrap())
+        .addr(addr.as_str())
+        .build();
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvClient::new(&addr).unwrap();
+    let mut pipeline = client.pipeline();
+    for i in 0..8 {
+        pipeline.get(format!("key{}", i));
+    }
+    pipeline.remove("missing".to_owned());
+    assert_eq!(pipeline.len(), 9);
+    let start = Instant::now();
+    let responses = pipeline.send().unwrap();
+    assert!(pipeline.is_empty());
+    // one after another, the requests would take 1.8s
+    assert!(start.elapsed() < Duration::from_millis(800));
+    assert_eq!(responses.len(), 9);
+    for (i, response) in responses[..8].iter().enumerate() {
+        assert!(
+            matches!(response, Ok(Response::Ok(Some(value))) if *value == format!("value{}", i))
+        );
+    }
+    assert!(responses[8].is_err());
+
+    // the connection serves single requests after a pipeline
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// Should stop reading a connection with too many requests in flight
+#[test]
+fn max_in_flight() {
+    let addr = "127.0.0.1:4209".to_owned();
+    let mut server = KvServer::builder(MemStore::new(), SlowPool::new(8).unwrap())
+        .addr(addr.as_str())
+        .max_in_flight(2)
+        .build();
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvClient::new(&addr).unwrap();
+    let requests = (0..6).map(|i| Request::Set(format!("key{}", i), format!("value{}", i)));
+    let start = Instant::now();
+    let responses = client.pipeline().requests(requests).send().unwrap();
+    // two at a time, the requests take at least 3 times 200ms
+    assert!(start.elapsed() >= Duration::from_millis(600));
+    assert!(responses.iter().all(|response| response.is_ok()));
+}
+
+// Should share one connection among the clones of a client, each with its own bucket
+#[test]
+fn multiplexing() {
+    let addr = "127.0.0.1:4210".to_owned();
+    let engine = MemStore::new();
+    for i in 0..8 {
+        engine
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    let mut server = KvServer::builder(engine, SlowPool::new(8).unwrap())
+        .addr(addr.as_str())
+        .metrics(true)
+        .build();
+    let metrics = server.metrics().unwrap();
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(500));
+
+    let client = KvClient::new(&addr).unwrap();
+    let start = Instant::now();
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            let mut client = client.clone();
+            thread::spawn(move || client.get(format!("key{}", i)).unwrap())
+        })
+        .collect();
+    for (i, handle) in handles.into_iter().enumerate() {
+        assert_eq!(handle.join().unwrap(), Some(format!("value{}", i)));
+    }
+    // one after another, the requests would take 1.6s
+    assert!(start.elapsed() < Duration::from_millis(800));
+    assert_eq!(metrics.connections(), 1);
+
+    let mut in_bucket = client.clone();
+    in_bucket.set_bucket(Some("bucket".to_owned()));
+    assert_eq!(in_bucket.get("key1".to_owned()).unwrap(), None);
+    let mut client = client;
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
@@ -0,0 +1,122 @@
+use std::{collections::HashMap, thread, time::Duration};
+
+use rust_kv::{
+    HashRing, KvEngine, KvServer, MemStore, ShardedKvClient, SharedQueueThreadPool, ThreadPool,
+};
+
+fn ring(nodes: &[&str]) -> HashRing {
+    let mut ring = HashRing::new();
+    for node in nodes {
+        assert!(ring.add(node));
+    }
+    ring
+}
+
+fn keys() -> Vec<String> {
+    (0..10_000).map(|i| format!("key{}", i)).collect()
+}
+
+// Should spread keys evenly over the nodes, the same way in every ring
+#[test]
+fn hash_ring_distribution() {
+    let nodes = ["node1", "node2", "node3", "node4"];
+    let ring1 = ring(&nodes);
+    let mut reversed = nodes;
+    reversed.reverse();
+    let ring2 = ring(&reversed);
+
+    let mut counts = HashMap::new();
+    for key in keys() {
+        let node = ring1.node(&key).unwrap();
+        assert_eq!(ring2.node(&key), Some(node));
+        *counts.entry(node).or_insert(0) += 1;
+    }
+    assert_eq!(counts.len(), 4);
+    for count in counts.values() {
+        assert!((1_500..3_500).contains(count), "unbalanced: {:?}", counts);
+    }
+
+    assert_eq!(ring1.nodes(), nodes);
+    assert_eq!(HashRing::new().node("key"), None);
+}
+
+// Should move only the keys of an added or removed node
+#[test]
+fn hash_ring_rebalance() {
+    let before = ring(&["node1", "node2", "node3"]);
+    let mut after = before.clone();
+    assert!(!after.add("node1"));
+    assert!(after.add("node4"));
+
+    let moves = before.moves(&after, keys());
+    assert!((1_500..3_500).contains(&moves.len()));
+    for m in &moves {
+        assert_eq!(m.to, "node4");
+        assert_eq!(before.node(&m.key), Some(m.from.as_str()));
+    }
+
+    assert!(after.remove("node4"));
+    assert!(!after.remove("node4"));
+    assert!(after.moves(&before, keys()).is_empty());
+}
+
+// Should store each key on the server owning it
+#[test]
+fn sharded_client() {
+    let addrs = ["127.0.0.1:4501", "127.0.0.1:4502", "127.0.0.1:4503"];
+    let mut engines = HashMap::new();
+    for addr in addrs {
+        let engine = MemStore::new();
+        engines.insert(addr, engine.clone());
+        let mut server = KvServer::builder(engine, SharedQueueThreadPool::new(2).unwrap())
+            .addr(addr)
+            .build();
+        thread::spawn(move || server.run());
+    }
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = ShardedKvClient::new(addrs);
+    for i in 0..30 {
+        client
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+    for i in 0..30 {
+        let key = format!("key{}", i);
+        let engine = &engines[client.shard(&key).unwrap()];
+        assert_eq!(
+            engine.get(key.clone()).unwrap(),
+            Some(format!("value{}", i))
+        );
+        assert_eq!(client.get(key).unwrap(), Some(format!("value{}", i)));
+    }
+    for engine in engines.values() {
+        assert!(engine.len().unwrap() > 0);
+    }
+    assert_eq!(client.len().unwrap(), 30);
+
+    assert_eq!(
+        client
+            .multi_get(vec![
+                "key2".to_owned(),
+                "missing".to_owned(),
+                "key1".to_owned()
+            ])
+            .unwrap(),
+        vec![Some("value2".to_owned()), None, Some("value1".to_owned())]
+    );
+    assert_eq!(client.incr("counter".to_owned(), 5).unwrap(), 5);
+    assert_eq!(client.decr("counter".to_owned(), 2).unwrap(), 3);
+    client.remove("key1".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+
+    // the keys of a removed shard are gone for the client
+    let shard = client.shard("key2").unwrap().to_owned();
+    assert!(client.remove_shard(&shard));
+    assert_ne!(client.shard("key2"), Some(shard.as_str()));
+    assert_eq!(client.get("key2".to_owned()).unwrap(), None);
+
+    let mut empty = ShardedKvClient::new(Vec::<String>::new());
+    let err = empty.get("key1".to_owned()).unwrap_err();
+    assert_eq!(err.to_string(), "No shard in the hash ring");
+}
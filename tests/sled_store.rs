@@ -0,0 +1,126 @@
+use std::{path::Path, thread, time::Duration};
+
+use rust_kv::{FlushMode, KvEngine, KvError, Result, SledStore};
+use tempfile::TempDir;
+
+/// Opens the store again once sled has let go of the directory.
+///
+/// Sled releases its file lock from a background thread, shortly after the
+/// last handle is dropped.
+fn reopen(path: &Path, flush_mode: FlushMode) -> Result<SledStore> {
+    for _ in 0..100 {
+        match SledStore::open_with(path, flush_mode) {
+            Err(KvError::Sled(_)) => thread::sleep(Duration::from_millis(10)),
+            result => return result,
+        }
+    }
+    SledStore::open_with(path, flush_mode)
+}
+
+// Should keep the writes of every flush mode once the store is dropped and reopened
+#[test]
+fn flush_modes() -> Result<()> {
+    let flush_modes = [
+        FlushMode::EveryOp,
+        FlushMode::Interval(Duration::from_millis(10)),
+        FlushMode::OnDrop,
+    ];
+    for flush_mode in flush_modes {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = SledStore::open_with(temp_dir.path(), flush_mode)?;
+        let clone = store.clone();
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        clone.set("key2".to_owned(), "value2".to_owned())?;
+        store.remove("key1".to_owned())?;
+        thread::sleep(Duration::from_millis(50));
+        drop(store);
+        clone.set("key3".to_owned(), "value3".to_owned())?;
+        drop(clone);
+
+        let store = reopen(temp_dir.path(), flush_mode)?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Should skip binary values in scan and export rather than failing them, and fail multi_get like get
+#[test]
+fn bytes_value_skipped_by_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledStore::open(temp_dir.path())?;
+
+    store.set_bytes("key1".to_owned(), vec![0xff, 0x00])?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(
+        store.scan(..)?,
+        vec![("key2".to_owned(), "value2".to_owned())]
+    );
+    assert_eq!(
+        store.multi_get(vec!["key2".to_owned()])?,
+        vec![Some("value2".to_owned())]
+    );
+    assert!(matches!(
+        store.multi_get(vec!["key1".to_owned(), "key2".to_owned()]),
+        Err(KvError::Utf8(_))
+    ));
+    let mut exported = Vec::new();
+    assert_eq!(store.export(&mut exported)?, 1);
+    assert_eq!(
+        String::from_utf8(exported).unwrap(),
+        "{\"key\":\"key2\",\"value\":\"value2\"}\n"
+    );
+    // the keys skipped are still listed, and read as bytes
+    assert_eq!(
+        store.scan_keys(None, 10)?,
+        vec!["key1".to_owned(), "key2".to_owned()]
+    );
+    assert_eq!(store.get_bytes("key1".to_owned())?, Some(vec![0xff, 0x00]));
+
+    Ok(())
+}
+
+// Should keep the keys of each tree apart, and in the same db
+#[test]
+fn trees() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledStore::open(temp_dir.path())?;
+    let users = store.open_tree("users")?;
+
+    store.set("key".to_owned(), "default".to_owned())?;
+    users.set("key".to_owned(), "user".to_owned())?;
+    users.set_with_ttl(
+        "expiring".to_owned(),
+        "user".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    store.set("expiring".to_owned(), "default".to_owned())?;
+    assert_eq!(store.get("key".to_owned())?, Some("default".to_owned()));
+    assert_eq!(users.get("key".to_owned())?, Some("user".to_owned()));
+    assert_eq!(store.ttl("expiring".to_owned())?, None);
+    assert!(users.ttl("expiring".to_owned())?.is_some());
+    assert_eq!(users.len()?, 2);
+    assert_eq!(
+        store.bucket("users")?.get("key".to_owned())?,
+        Some("user".to_owned())
+    );
+
+    for name in ["", "a/b", "__sled__default", "__rust_kv_expirations"] {
+        assert!(matches!(
+            store.open_tree(name),
+            Err(KvError::InvalidBucketName(_))
+        ));
+    }
+
+    // Open from disk again and check persistent data
+    drop(users);
+    drop(store);
+    let store = reopen(temp_dir.path(), FlushMode::EveryOp)?;
+    let users = store.open_tree("users")?;
+    assert_eq!(users.get("key".to_owned())?, Some("user".to_owned()));
+    assert_eq!(store.get("key".to_owned())?, Some("default".to_owned()));
+
+    Ok(())
+}
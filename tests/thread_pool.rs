@@ -1,10 +1,17 @@
-use std::sync::{
-    atomic::{AtomicUsize, Ordering},
-    Arc,
+use std::{
+    panic,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 use crossbeam_utils::sync::WaitGroup;
-use rust_kv::{NaiveThreadPool, RayonThreadPool, Result, SharedQueueThreadPool, ThreadPool};
+use rust_kv::{
+    KvError, NaiveThreadPool, RayonThreadPool, Result, SharedQueueThreadPool, ThreadPool,
+};
 
 fn spawn_counter<P: ThreadPool>(pool: P) -> Result<()> {
     const TASK_NUM: usize = 20;
@@ -63,8 +70,200 @@ fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
 
+#[test]
+fn shared_queue_thread_pool_bounded_spawn_counter() -> Result<()> {
+    let pool = SharedQueueThreadPool::with_queue_capacity(4, 2)?;
+    spawn_counter(pool)
+}
+
+#[test]
+fn shared_queue_thread_pool_try_spawn_busy() -> Result<()> {
+    let pool = SharedQueueThreadPool::with_queue_capacity(1, 1)?;
+    let (started_tx, started_rx) = mpsc::channel();
+    let (tx, rx) = mpsc::channel::<()>();
+    // the thread waits on the first job, the queue holds the second
+    pool.spawn(move || {
+        started_tx.send(()).unwrap();
+        rx.recv().unwrap();
+    });
+    started_rx.recv().unwrap();
+    pool.try_spawn(|| {})?;
+    assert!(matches!(pool.try_spawn(|| {}), Err(KvError::Busy)));
+
+    tx.send(()).unwrap();
+    spawn_counter(pool)
+}
+
 #[test]
 fn rayon_thread_pool_spawn_counter() -> Result<()> {
     let pool = RayonThreadPool::new(4)?;
     spawn_counter(pool)
 }
+
+fn thread_names<P: ThreadPool>(pool: P, prefix: &str) -> Result<()> {
+    let (tx, rx) = mpsc::channel();
+    for _ in 0..4 {
+        let tx = tx.clone();
+        pool.spawn(move || {
+            let name = thread::current().name().map(str::to_owned);
+            tx.send(name).unwrap();
+        });
+    }
+    for _ in 0..4 {
+        let name = rx.recv().unwrap().unwrap();
+        let number = name.strip_prefix(&format!("{}-", prefix)).unwrap();
+        assert!((1..=4).contains(&number.parse::<usize>().unwrap()));
+    }
+    Ok(())
+}
+
+#[test]
+fn thread_pool_thread_names() -> Result<()> {
+    thread_names(NaiveThreadPool::with_thread_name(4, "naive")?, "naive")?;
+    thread_names(SharedQueueThreadPool::new(4)?, "kv-worker")?;
+    thread_names(
+        SharedQueueThreadPool::with_thread_name(4, "shared")?,
+        "shared",
+    )?;
+    thread_names(
+        SharedQueueThreadPool::with_queue_capacity_and_thread_name(4, 4, "bounded")?,
+        "bounded",
+    )?;
+    thread_names(RayonThreadPool::with_thread_name(4, "rayon")?, "rayon")
+}
+
+/// A panic payload which panics again once dropped, escaping the `catch_unwind` of the pool.
+struct PanicOnDrop;
+
+impl Drop for PanicOnDrop {
+    fn drop(&mut self) {
+        panic_control::disable_hook_in_current_thread();
+        panic!();
+    }
+}
+
+#[test]
+fn shared_queue_thread_pool_replaces_dead_threads() -> Result<()> {
+    let pool = SharedQueueThreadPool::with_thread_name(1, "healing")?;
+    for _ in 0..3 {
+        pool.spawn(|| {
+            panic_control::disable_hook_in_current_thread();
+            panic::panic_any(PanicOnDrop);
+        });
+    }
+
+    let (tx, rx) = mpsc::channel();
+    pool.spawn(move || {
+        let name = thread::current().name().map(str::to_owned);
+        tx.send(name).unwrap();
+    });
+    let name = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(name.as_deref(), Some("healing-1"));
+    spawn_counter(pool)
+}
+
+fn spawn_with_handle<P: ThreadPool>(pool: P) -> Result<()> {
+    let handles: Vec<_> = (0..4)
+        .map(|i| pool.spawn_with_handle(move || i * 2))
+        .collect();
+    let results: Vec<_> = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect();
+    assert_eq!(results, [0, 2, 4, 6]);
+
+    let handle = pool.spawn_with_handle(|| -> usize {
+        panic_control::disable_hook_in_current_thread();
+        panic!("job failed")
+    });
+    let payload = handle.join().unwrap_err();
+    assert_eq!(payload.downcast_ref::<&str>(), Some(&"job failed"));
+
+    // or awaited, like within a server
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let sum = runtime.block_on(async {
+        let first = pool.spawn_with_handle(|| 1);
+        let second = pool.spawn_with_handle(|| 2);
+        first.await.unwrap() + second.await.unwrap()
+    });
+    assert_eq!(sum, 3);
+    Ok(())
+}
+
+#[test]
+fn thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle(NaiveThreadPool::new(4)?)?;
+    spawn_with_handle(SharedQueueThreadPool::new(4)?)?;
+    spawn_with_handle(RayonThreadPool::new(4)?)
+}
+
+#[test]
+fn shared_queue_thread_pool_try_spawn_with_handle_busy() -> Result<()> {
+    let pool = SharedQueueThreadPool::with_queue_capacity(1, 1)?;
+    let (started_tx, started_rx) = mpsc::channel();
+    let (tx, rx) = mpsc::channel::<()>();
+    // the thread waits on the first job, the queue holds the second
+    let blocked = pool.try_spawn_with_handle(move || {
+        started_tx.send(()).unwrap();
+        rx.recv().unwrap();
+        1
+    })?;
+    started_rx.recv().unwrap();
+    let queued = pool.try_spawn_with_handle(|| 2)?;
+    assert!(matches!(
+        pool.try_spawn_with_handle(|| 3),
+        Err(KvError::Busy)
+    ));
+
+    tx.send(()).unwrap();
+    assert_eq!(blocked.join().unwrap(), 1);
+    assert_eq!(queued.join().unwrap(), 2);
+    Ok(())
+}
+
+fn shutdown<P: ThreadPool>(pool: P) -> Result<()> {
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..4 {
+        let counter = counter.clone();
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    assert!(pool.clone().shutdown(Duration::from_secs(5)));
+    assert_eq!(counter.load(Ordering::SeqCst), 4);
+
+    // the jobs spawned from now on are refused
+    assert!(matches!(pool.try_spawn(|| {}), Err(KvError::ShuttingDown)));
+    assert!(pool.spawn_with_handle(|| {}).join().is_err());
+    Ok(())
+}
+
+#[test]
+fn thread_pool_shutdown() -> Result<()> {
+    shutdown(NaiveThreadPool::new(4)?)?;
+    shutdown(SharedQueueThreadPool::new(2)?)?;
+    shutdown(SharedQueueThreadPool::with_queue_capacity(2, 4)?)?;
+    shutdown(RayonThreadPool::new(2)?)
+}
+
+#[test]
+fn shared_queue_thread_pool_shutdown_timeout() -> Result<()> {
+    let pool = SharedQueueThreadPool::with_queue_capacity(1, 1)?;
+    let (tx, rx) = mpsc::channel::<()>();
+    let blocked = pool.spawn_with_handle(move || rx.recv().unwrap());
+    let queued = pool.spawn_with_handle(|| ());
+
+    let started = Instant::now();
+    assert!(!pool.shutdown(Duration::from_millis(100)));
+    assert!(started.elapsed() >= Duration::from_millis(100));
+
+    // the job running finishes, the one queued is dropped
+    tx.send(()).unwrap();
+    blocked.join().unwrap();
+    assert!(queued.join().is_err());
+    drop(pool);
+    Ok(())
+}
@@ -0,0 +1,229 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, Issuer, KeyPair};
+use rust_kv::{
+    client_tls_config, server_tls_config, KvClient, KvServer, MemStore, Request, Response,
+    SharedQueueThreadPool, ThreadPool,
+};
+use tempfile::TempDir;
+
+/// A CA, writing the certificates it signs as PEM files into a directory.
+struct Ca {
+    dir: PathBuf,
+    issuer: Issuer<'static, KeyPair>,
+}
+
+impl Ca {
+    fn new(dir: &Path, name: &str) -> Ca {
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, name);
+        let key = KeyPair::generate().unwrap();
+        let cert = params.self_signed(&key).unwrap();
+        fs::write(dir.join(format!("{}.pem", name)), cert.pem()).unwrap();
+        Ca {
+            dir: dir.to_owned(),
+            issuer: Issuer::new(params, key),
+        }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.pem", name))
+    }
+
+    /// Signs a certificate for `name`, returning the paths of the certificate and key files.
+    fn sign(&self, name: &str) -> (PathBuf, PathBuf) {
+        let mut params =
+            CertificateParams::new(vec!["localhost".to_owned(), "127.0.0.1".to_owned()]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        let key = KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, &self.issuer).unwrap();
+        let cert_path = self.dir.join(format!("{}.crt.pem", name));
+        let key_path = self.dir.join(format!("{}.key.pem", name));
+        fs::write(&cert_path, cert.pem()).unwrap();
+        fs::write(&key_path, key.serialize_pem()).unwrap();
+        (cert_path, key_path)
+    }
+}
+
+fn run_server(mut server: KvServer<MemStore, SharedQueueThreadPool>) {
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(500));
+}
+
+// Should serve clients presenting a certificate of the client CA, and pass
+// their identity to the authorization hook
+#[test]
+fn mutual_tls() {
+    let temp_dir = TempDir::new().unwrap();
+    let ca = Ca::new(temp_dir.path(), "ca");
+    let (server_cert, server_key) = ca.sign("server");
+    let (alice_cert, alice_key) = ca.sign("alice");
+    let (bob_cert, bob_key) = ca.sign("bob");
+    let other_ca = Ca::new(temp_dir.path(), "other-ca");
+    let (mallory_cert, mallory_key) = other_ca.sign("mallory");
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let seen_clone = seen.clone();
+    let config = server_tls_config(&server_cert, &server_key, Some(&ca.path("ca"))).unwrap();
+    let addr = "127.0.0.1:4101".to_owned();
+    let server = KvServer::builder(MemStore::new(), SharedQueueThreadPool::new(2).unwrap())
+        .addr(addr.as_str())
+        .tls(config)
+        .authorize(move |identity, request| {
+            let name = identity.and_then(|identity| identity.common_name().map(str::to_owned));
+            seen_clone.lock().unwrap().push(name.clone());
+            // only alice may write
+            name.as_deref() == Some("alice") || matches!(request, Request::Get(_))
+        })
+        .build();
+    run_server(server);
+
+    let config = client_tls_config(&ca.path("ca"), Some((&alice_cert, &alice_key))).unwrap();
+    let mut alice = KvClient::connect_tls(&addr, config).unwrap();
+    alice.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        alice.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    let config = client_tls_config(&ca.path("ca"), Some((&bob_cert, &bob_key))).unwrap();
+    let mut bob = KvClient::connect_tls(&addr, config).unwrap();
+    assert_eq!(
+        bob.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    let err = bob.set("key1".to_owned(), "value2".to_owned()).unwrap_err();
+    assert_eq!(err.to_string(), "Permission denied");
+    assert_eq!(
+        bob.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    assert_eq!(
+        *seen.lock().unwrap(),
+        vec![
+            Some("alice".to_owned()),
+            Some("alice".to_owned()),
+            Some("bob".to_owned()),
+            Some("bob".to_owned()),
+            Some("bob".to_owned()),
+        ]
+    );
+
+    // without a certificate
+    let config = client_tls_config(&ca.path("ca"), None).unwrap();
+    let mut anonymous = KvClient::connect_tls(&addr, config).unwrap();
+    assert!(anonymous.get("key1".to_owned()).is_err());
+
+    // with a certificate of another CA
+    let config = client_tls_config(&ca.path("ca"), Some((&mallory_cert, &mallory_key))).unwrap();
+    let mut mallory = KvClient::connect_tls(&addr, config).unwrap();
+    assert!(mallory.get("key1".to_owned()).is_err());
+
+    // without TLS
+    let mut plain = KvClient::new(&addr).unwrap();
+    assert!(plain.get("key1".to_owned()).is_err());
+    assert_eq!(seen.lock().unwrap().len(), 5);
+}
+
+// Should serve any client over TLS without a client CA, with no identity
+#[test]
+fn server_tls() {
+    let temp_dir = TempDir::new().unwrap();
+    let ca = Ca::new(temp_dir.path(), "ca");
+    let (server_cert, server_key) = ca.sign("server");
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let seen_clone = seen.clone();
+    let config = server_tls_config(&server_cert, &server_key, None).unwrap();
+    let addr = "127.0.0.1:4102".to_owned();
+    let server = KvServer::builder(MemStore::new(), SharedQueueThreadPool::new(2).unwrap())
+        .addr(addr.as_str())
+        .tls(config)
+        .authorize(move |identity, _| {
+            seen_clone.lock().unwrap().push(identity.is_some());
+            true
+        })
+        .build();
+    run_server(server);
+
+    let config = client_tls_config(&ca.path("ca"), None).unwrap();
+    let mut client = KvClient::connect_tls(&addr, config).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    assert_eq!(*seen.lock().unwrap(), vec![false, false]);
+
+    // a server certificate of an unknown CA
+    let other_ca = Ca::new(temp_dir.path(), "other-ca");
+    let config = client_tls_config(&other_ca.path("other-ca"), None).unwrap();
+    let mut client = KvClient::connect_tls(&addr, config).unwrap();
+    assert!(client.get("key1".to_owned()).is_err());
+}
+
+// Should pipeline large requests over TLS while reading large responses
+#[test]
+fn large_pipeline() {
+    let temp_dir = TempDir::new().unwrap();
+    let ca = Ca::new(temp_dir.path(), "ca");
+    let (server_cert, server_key) = ca.sign("server");
+    let config = server_tls_config(&server_cert, &server_key, None).unwrap();
+    let addr = "127.0.0.1:4103".to_owned();
+    let server = KvServer::builder(MemStore::new(), SharedQueueThreadPool::new(4).unwrap())
+        .addr(addr.as_str())
+        .tls(config)
+        .build();
+    run_server(server);
+
+    let config = client_tls_config(&ca.path("ca"), None).unwrap();
+    let mut client = KvClient::connect_tls(&addr, config).unwrap();
+    let value = "v".repeat(1024 * 1024);
+    client.set("key".to_owned(), value.clone()).unwrap();
+
+    // a client writing requests while the server is blocked writing responses
+    // must keep reading them
+    let (tx, rx) = mpsc::channel();
+    let expected = value.clone();
+    thread::spawn(move || {
+        let mut pipeline = client.pipeline();
+        for i in 0..64 {
+            pipeline
+                .get("key".to_owned())
+                .set(format!("key{}", i), value.clone());
+        }
+        drop(tx.send(pipeline.send().unwrap()));
+    });
+    let resps = rx
+        .recv_timeout(Duration::from_secs(60))
+        .expect("the pipeline is stuck");
+    assert_eq!(resps.len(), 128);
+    for resp in resps.chunks(2) {
+        assert!(matches!(&resp[0], Ok(Response::Ok(Some(value))) if *value == expected));
+        assert!(matches!(&resp[1], Ok(Response::Ok(None))));
+    }
+}
+
+// Should fail on missing or invalid PEM files
+#[test]
+fn invalid_config() {
+    let temp_dir = TempDir::new().unwrap();
+    let missing = temp_dir.path().join("missing.pem");
+    assert!(server_tls_config(&missing, &missing, None).is_err());
+    assert!(client_tls_config(&missing, None).is_err());
+
+    let empty = temp_dir.path().join("empty.pem");
+    fs::write(&empty, "").unwrap();
+    let ca = Ca::new(temp_dir.path(), "ca");
+    let (cert, _) = ca.sign("server");
+    assert!(server_tls_config(&cert, &empty, None).is_err());
+    assert!(client_tls_config(&empty, None).is_err());
+}
@@ -0,0 +1,123 @@
+use std::thread;
+
+use rust_kv::{
+    KvClient, KvEngine, KvError, KvServer, KvStore, MemStore, RunningServer, SharedQueueThreadPool,
+    SledStore, ThreadPool,
+};
+use tempfile::TempDir;
+
+fn start<E: KvEngine>(engine: E) -> (RunningServer, KvClient) {
+    let server = KvServer::builder(engine, SharedQueueThreadPool::new(4).unwrap())
+        .addr("127.0.0.1:0")
+        .build()
+        .start()
+        .unwrap();
+    let client = KvClient::new(&server.addr().to_string()).unwrap();
+    (server, client)
+}
+
+fn transaction<E: KvEngine>(engine: E) {
+    let (server, mut client) = start(engine);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+
+    // the writes wait in the transaction, which sees them
+    let mut txn = client.txn();
+    assert_eq!(
+        txn.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    txn.set("key3".to_owned(), "value3".to_owned());
+    txn.remove("key2".to_owned()).unwrap();
+    // a key set then removed by the transaction only is not written
+    txn.set("temp".to_owned(), "value".to_owned());
+    txn.remove("temp".to_owned()).unwrap();
+    assert_eq!(txn.get("key2".to_owned()).unwrap(), None);
+    assert!(matches!(
+        txn.remove("missing".to_owned()),
+        Err(KvError::KeyNotFound)
+    ));
+    assert_eq!(
+        client.get("key2".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+    txn.commit().unwrap();
+    assert_eq!(client.get("key2".to_owned()).unwrap(), None);
+    assert_eq!(client.get("temp".to_owned()).unwrap(), None);
+    assert_eq!(
+        client.get("key3".to_owned()).unwrap(),
+        Some("value3".to_owned())
+    );
+
+    // a key read changed since fails the commit, writing nothing
+    let mut txn = client.txn();
+    txn.get("key1".to_owned()).unwrap();
+    txn.get("key4".to_owned()).unwrap();
+    txn.set("key5".to_owned(), "value5".to_owned());
+    client.set("key4".to_owned(), "value4".to_owned()).unwrap();
+    assert!(matches!(txn.commit(), Err(KvError::TransactionConflict)));
+    assert_eq!(client.get("key5".to_owned()).unwrap(), None);
+
+    // and the helper runs it again
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let client = client.clone();
+            thread::spawn(move || {
+                for _ in 0..5 {
+                    client
+                        .transaction(|txn| {
+                            let count = txn.get("count".to_owned())?;
+                            let count = count.map_or(0, |count| count.parse().unwrap());
+                            txn.set("count".to_owned(), (count + 1).to_string());
+                            Ok(())
+                        })
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(
+        client.get("count".to_owned()).unwrap(),
+        Some("20".to_owned())
+    );
+
+    // in the bucket of the client
+    client.set_bucket(Some("bucket".to_owned()));
+    let mut txn = client.txn();
+    assert_eq!(txn.get("key1".to_owned()).unwrap(), None);
+    txn.set("key1".to_owned(), "in bucket".to_owned());
+    txn.commit().unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("in bucket".to_owned())
+    );
+    client.set_bucket(None);
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    server.shutdown().unwrap();
+}
+
+// Should commit the writes of a transaction atomically, unless a key read changed
+#[test]
+fn transaction_kvs() {
+    let temp_dir = TempDir::new().unwrap();
+    transaction(KvStore::open(temp_dir.path()).unwrap());
+}
+
+// Should commit the writes of a transaction atomically, unless a key read changed
+#[test]
+fn transaction_mem() {
+    transaction(MemStore::new());
+}
+
+// Should commit the writes of a transaction atomically, unless a key read changed
+#[test]
+fn transaction_sled() {
+    let temp_dir = TempDir::new().unwrap();
+    transaction(SledStore::open(temp_dir.path()).unwrap());
+}
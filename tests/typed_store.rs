@@ -0,0 +1,98 @@
+use serde::{Deserialize, Serialize};
+use tempfile::TempDir;
+
+use rust_kv::{
+    KvClient, KvEngine, KvError, KvServer, KvStore, MemStore, Result, SharedQueueThreadPool,
+    ThreadPool, TypedStore,
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct User {
+    name: String,
+    age: u32,
+}
+
+fn user(name: &str, age: u32) -> User {
+    User {
+        name: name.to_owned(),
+        age,
+    }
+}
+
+// Should read back the structs written, whatever the engine
+fn typed_values<E: KvEngine>(engine: E) -> Result<()> {
+    let users: TypedStore<E, User> = TypedStore::new(engine);
+
+    users.set("alice".to_owned(), &user("Alice", 30))?;
+    users.set("bob".to_owned(), &user("Bob", 25))?;
+    assert_eq!(users.get("alice".to_owned())?, Some(user("Alice", 30)));
+    assert_eq!(users.get("carol".to_owned())?, None);
+    assert!(!users.set_if_absent("bob".to_owned(), &user("Robert", 26))?);
+    assert_eq!(
+        users.multi_get(vec!["bob".to_owned(), "carol".to_owned()])?,
+        vec![Some(user("Bob", 25)), None]
+    );
+    assert_eq!(
+        users.scan(..)?,
+        vec![
+            ("alice".to_owned(), user("Alice", 30)),
+            ("bob".to_owned(), user("Bob", 25))
+        ]
+    );
+    users.remove("alice".to_owned())?;
+    assert_eq!(users.get("alice".to_owned())?, None);
+
+    // Values are JSON in the engine, and other values fail to decode
+    assert_eq!(
+        users.engine().get("bob".to_owned())?,
+        Some(r#"{"name":"Bob","age":25}"#.to_owned())
+    );
+    users
+        .engine()
+        .set("bad".to_owned(), "not json".to_owned())?;
+    assert!(matches!(
+        users.get("bad".to_owned()),
+        Err(KvError::Serde(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn typed_values_mem_store() -> Result<()> {
+    typed_values(MemStore::new())
+}
+
+#[test]
+fn typed_values_kv_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    typed_values(KvStore::open(temp_dir.path())?)
+}
+
+// Should read and write typed values through a client, alike the values of a TypedStore
+#[test]
+fn typed_values_client() -> Result<()> {
+    let engine = MemStore::new();
+    let server = KvServer::builder(engine.clone(), SharedQueueThreadPool::new(2)?)
+        .addr("127.0.0.1:0")
+        .build()
+        .start()?;
+    let mut client = KvClient::new(&server.addr().to_string())?;
+
+    client.set_as("alice".to_owned(), &user("Alice", 30))?;
+    assert_eq!(client.get_as("alice".to_owned())?, Some(user("Alice", 30)));
+    assert_eq!(client.get_as::<User>("carol".to_owned())?, None);
+    let users: TypedStore<MemStore, User> = TypedStore::new(engine);
+    assert_eq!(users.get("alice".to_owned())?, Some(user("Alice", 30)));
+    users.set("bob".to_owned(), &user("Bob", 25))?;
+    assert_eq!(client.get_as("bob".to_owned())?, Some(user("Bob", 25)));
+    client.set_as("numbers".to_owned(), &[1, 2, 3][..])?;
+    assert_eq!(client.get_as("numbers".to_owned())?, Some(vec![1, 2, 3]));
+
+    client.set("bad".to_owned(), "not json".to_owned())?;
+    assert!(matches!(
+        client.get_as::<User>("bad".to_owned()),
+        Err(KvError::Serde(_))
+    ));
+    server.shutdown()
+}
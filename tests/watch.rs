@@ -0,0 +1,227 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use rust_kv::{
+    ChangeKind, KvClient, KvEngine, KvServer, KvStore, MemStore, Request, SharedQueueThreadPool,
+    ThreadPool, WatchEvent, WriteBatch,
+};
+use tempfile::TempDir;
+
+fn run<E: KvEngine>(engine: E, addr: &str) {
+    let mut server = KvServer::builder(engine, SharedQueueThreadPool::new(2).unwrap())
+        .addr(addr)
+        .authorize(|_, request| !matches!(request, Request::Watch(prefix) if prefix == "secret"))
+        .build();
+    thread::spawn(move || server.run());
+    thread::sleep(Duration::from_millis(500));
+}
+
+fn event(key: &str, value: Option<&str>) -> WatchEvent {
+    WatchEvent {
+        key: key.to_owned(),
+        kind: match value {
+            Some(_) => ChangeKind::Set,
+            None => ChangeKind::Remove,
+        },
+        value: value.map(str::to_owned),
+    }
+}
+
+// Should stream the changes of the keys starting with the prefix, in commit order
+#[test]
+fn watch() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4701".to_owned();
+    run(KvStore::open(temp_dir.path()).unwrap(), &addr);
+
+    let events = KvClient::new(&addr)
+        .unwrap()
+        .watch("user:".to_owned())
+        .unwrap();
+
+    let mut client = KvClient::new(&addr).unwrap();
+    client.set("user:1".to_owned(), "ann".to_owned()).unwrap();
+    client.set("config".to_owned(), "on".to_owned()).unwrap();
+    client.set("user:1".to_owned(), "bob".to_owned()).unwrap();
+    client.remove("user:1".to_owned()).unwrap();
+    let mut batch = WriteBatch::new();
+    batch
+        .put("user:2".to_owned(), "eve".to_owned())
+        .put("other".to_owned(), "value".to_owned());
+    client.write_batch(batch).unwrap();
+
+    let events: Vec<WatchEvent> = events.take(4).map(Result::unwrap).collect();
+    // a set event has the value of the key when sent, which may be newer
+    assert_eq!(events[0].key, "user:1");
+    assert_eq!(events[1].key, "user:1");
+    assert_eq!(events[2], event("user:1", None));
+    assert_eq!(events[3], event("user:2", Some("eve")));
+}
+
+// Should stream the changes of the keys of the bucket of the client only
+#[test]
+fn watch_bucket() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4707".to_owned();
+    run(KvStore::open(temp_dir.path()).unwrap(), &addr);
+
+    let mut watcher = KvClient::new(&addr).unwrap();
+    watcher.set_bucket(Some("users".to_owned()));
+    let mut events = watcher.watch("user:".to_owned()).unwrap();
+
+    let mut client = KvClient::new(&addr).unwrap();
+    client.set("user:1".to_owned(), "ann".to_owned()).unwrap();
+    client.set_bucket(Some("users".to_owned()));
+    client.set("user:2".to_owned(), "bob".to_owned()).unwrap();
+    assert_eq!(
+        events.next().unwrap().unwrap(),
+        event("user:2", Some("bob"))
+    );
+}
+
+// Should refuse to watch an engine that does not report its changes, or when not allowed
+#[test]
+fn watch_errors() {
+    let addr = "127.0.0.1:4702".to_owned();
+    run(MemStore::new(), &addr);
+    assert!(KvClient::new(&addr)
+        .unwrap()
+        .watch("user:".to_owned())
+        .is_err());
+
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4703".to_owned();
+    run(KvStore::open(temp_dir.path()).unwrap(), &addr);
+    let err = KvClient::new(&addr)
+        .unwrap()
+        .watch("secret".to_owned())
+        .err()
+        .unwrap();
+    assert_eq!(err.to_string(), "Permission denied");
+}
+
+// Should watch the keys again on a new connection once the connection broke, if the client
+// reconnects
+#[test]
+fn watch_reconnect() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4704".to_owned();
+    run(KvStore::open(temp_dir.path()).unwrap(), &addr);
+
+    let mut events = KvClient::builder(&addr)
+        .reconnect(Duration::from_secs(5))
+        .build()
+        .unwrap()
+        .watch("user:".to_owned())
+        .unwrap();
+    let mut client = KvClient::new(&addr).unwrap();
+    client.set("user:1".to_owned(), "ann".to_owned()).unwrap();
+    assert_eq!(
+        events.next().unwrap().unwrap(),
+        event("user:1", Some("ann"))
+    );
+
+    // the watching connection is the last one opened but the one of this client
+    let clients = client.client_list().unwrap();
+    let mut ids: Vec<_> = clients.iter().map(|client| client.id).collect();
+    ids.sort_unstable();
+    client.client_kill(ids[ids.len() - 2]).unwrap();
+    let next = thread::spawn(move || events.next().unwrap().unwrap());
+    thread::sleep(Duration::from_millis(300));
+    client.set("user:2".to_owned(), "bob".to_owned()).unwrap();
+    assert_eq!(next.join().unwrap(), event("user:2", Some("bob")));
+}
+
+// Should cache the values read, until they change or the keys are no longer watched
+#[test]
+fn read_cache() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4705".to_owned();
+    run(KvStore::open(temp_dir.path()).unwrap(), &addr);
+    let mut other = KvClient::new(&addr).unwrap();
+    other.set("key1".to_owned(), "value1".to_owned()).unwrap();
+
+    let gets = Arc::new(AtomicUsize::new(0));
+    let cached = |budget| {
+        let gets = gets.clone();
+        KvClient::builder(&addr)
+            .reconnect(Duration::from_secs(5))
+            .read_cache(budget)
+            .on_request(move |event| {
+                if event.op == "get" {
+                    gets.fetch_add(1, Ordering::SeqCst);
+                }
+            })
+            .build()
+            .unwrap()
+    };
+    let mut client = cached(1024);
+    for _ in 0..2 {
+        assert_eq!(
+            client.get("key1".to_owned()).unwrap(),
+            Some("value1".to_owned())
+        );
+        assert_eq!(client.get("missing".to_owned()).unwrap(), None);
+    }
+    assert_eq!(gets.load(Ordering::SeqCst), 2);
+
+    // a change of another client drops the value once it arrives, one of the client at once
+    other.set("key1".to_owned(), "value2".to_owned()).unwrap();
+    let started = Instant::now();
+    while client.get("key1".to_owned()).unwrap() != Some("value2".to_owned()) {
+        assert!(started.elapsed() < Duration::from_secs(2));
+        thread::sleep(Duration::from_millis(10));
+    }
+    client.set("key1".to_owned(), "value3".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value3".to_owned())
+    );
+
+    // nothing is cached while the keys are not watched, the changes meanwhile missed
+    let clients = other.client_list().unwrap();
+    let watcher = clients.iter().map(|client| client.id).max().unwrap();
+    other.client_kill(watcher).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    other.set("key1".to_owned(), "value4".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value4".to_owned())
+    );
+    thread::sleep(Duration::from_millis(300));
+    client.get("key1".to_owned()).unwrap();
+    let sent = gets.load(Ordering::SeqCst);
+    client.get("key1".to_owned()).unwrap();
+    assert_eq!(gets.load(Ordering::SeqCst), sent);
+
+    // the least recently read values are dropped over budget
+    let value = "v".repeat(19);
+    for key in ["a", "b", "c", "d"] {
+        other.set(key.to_owned(), value.clone()).unwrap();
+    }
+    let mut client = cached(64);
+    let sent = gets.load(Ordering::SeqCst);
+    for key in ["a", "b", "c", "a", "d", "a", "c", "b"] {
+        assert_eq!(client.get(key.to_owned()).unwrap(), Some(value.clone()));
+    }
+    assert_eq!(gets.load(Ordering::SeqCst), sent + 5);
+
+    // or when the engine does not report its changes
+    let addr = "127.0.0.1:4706".to_owned();
+    run(MemStore::new(), &addr);
+    let mut client = KvClient::builder(&addr).read_cache(1024).build().unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let mut other = KvClient::new(&addr).unwrap();
+    client.get("key1".to_owned()).unwrap();
+    other.set("key1".to_owned(), "value2".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+}